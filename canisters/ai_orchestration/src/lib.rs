@@ -4,6 +4,7 @@ use ic_cdk_macros::{init, post_upgrade, pre_upgrade};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use candid::{CandidType, Deserialize};
+use common::CanisterStatus;
 use serde::Serialize;
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -98,4 +99,12 @@ fn get_avg_risk_score() -> u8 {
     })
 }
 
+// Operational status for monitoring (cycles, memory, record counts)
+#[query]
+fn get_canister_status() -> CanisterStatus {
+    common::canister_status(vec![
+        ("ai_results".to_string(), AI_RESULTS.with(|r| r.borrow().len() as u64)),
+    ])
+}
+
 ic_cdk::export_candid!();
@@ -0,0 +1,137 @@
+// Types shared between the Safro canisters
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+// Assets the platform can hold. Amounts are always `u64` in the asset's
+// smallest unit (satoshi, e8s, ...), see `Asset::decimals`.
+#[derive(
+    CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub enum Asset {
+    BTC,
+    CkBTC,
+    CkETH,
+    CkUSDC,
+    ICP,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AssetInfo {
+    pub asset: Asset,
+    pub symbol: String,
+    pub name: String,
+    pub decimals: u8,
+}
+
+impl Asset {
+    pub const ALL: [Asset; 5] = [Asset::BTC, Asset::CkBTC, Asset::CkETH, Asset::CkUSDC, Asset::ICP];
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Asset::BTC => "BTC",
+            Asset::CkBTC => "ckBTC",
+            Asset::CkETH => "ckETH",
+            Asset::CkUSDC => "ckUSDC",
+            Asset::ICP => "ICP",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Asset::BTC => "Bitcoin",
+            Asset::CkBTC => "Chain-key Bitcoin",
+            Asset::CkETH => "Chain-key Ether",
+            Asset::CkUSDC => "Chain-key USDC",
+            Asset::ICP => "Internet Computer",
+        }
+    }
+
+    // Decimals of the smallest unit. ckETH is held in gwei rather than wei
+    // so that realistic balances fit in a `u64`.
+    pub fn decimals(&self) -> u8 {
+        match self {
+            Asset::BTC | Asset::CkBTC | Asset::ICP => 8,
+            Asset::CkETH => 9,
+            Asset::CkUSDC => 6,
+        }
+    }
+
+    pub fn from_symbol(symbol: &str) -> Option<Asset> {
+        Asset::ALL.into_iter().find(|a| a.symbol() == symbol)
+    }
+
+    pub fn info(&self) -> AssetInfo {
+        AssetInfo {
+            asset: *self,
+            symbol: self.symbol().to_string(),
+            name: self.name().to_string(),
+            decimals: self.decimals(),
+        }
+    }
+}
+
+// Errors returned by the wallet canister, shared so callers such as the
+// escrow canister can decode them
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum WalletError {
+    InsufficientBalance,
+    InvalidAddress,
+    InvalidAmount,
+    TransferFailed(String),
+    NotFound,
+    Unauthorized,
+    DuplicateCredit,
+    InvalidConfig,
+    BitcoinError(String),
+    IdempotencyKeyReused,
+    HoldConflict,
+    InvalidArgument(String),
+    AddressNotAllowed,
+    AddressCoolingOff { usable_from: u64 },
+    WithdrawalLimitExceeded { remaining: u64 },
+    // A balance would overflow or a reserved amount go negative
+    ArithmeticOverflow,
+    NotAnAgent,
+    // The agent's cash float cannot cover a cash-out
+    InsufficientFloat,
+    InvalidConfirmationCode { attempts_left: u8 },
+    // Too many requests; try again from `retry_at`
+    RateLimited { retry_at: u64 },
+}
+
+// Request from the escrow canister to hold part of a wallet balance
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HoldParams {
+    pub escrow_id: String,
+    pub user_id: Principal,
+    pub amount: u64,
+    pub currency: Asset,
+}
+
+// Operational status every canister reports from `get_canister_status`
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CanisterStatus {
+    pub cycles: u128,
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub record_counts: Vec<(String, u64)>,
+}
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    return core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE;
+    #[cfg(not(target_arch = "wasm32"))]
+    return 0;
+}
+
+// Cycles and memory of the calling canister, with its own record counts
+pub fn canister_status(record_counts: Vec<(String, u64)>) -> CanisterStatus {
+    CanisterStatus {
+        cycles: ic_cdk::api::canister_balance128(),
+        heap_memory_bytes: heap_memory_bytes(),
+        stable_memory_bytes: ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE,
+        record_counts,
+    }
+}
@@ -0,0 +1,262 @@
+use crate::types::*;
+use crate::MAX_INSPECTION_PERIOD_DAYS;
+
+const MAX_MILESTONES: usize = 20;
+const MAX_METADATA_ENTRIES: usize = 20;
+const MAX_METADATA_KEY_LEN: usize = 64;
+const MAX_METADATA_VALUE_LEN: usize = 256;
+// Proposals kept per escrow, pending and resolved alike; every one is part of
+// the certified record
+const MAX_AMENDMENTS: usize = 20;
+
+// Escrows whose terms can still be renegotiated
+pub fn is_amendable(status: &EscrowStatus) -> bool {
+    matches!(
+        status,
+        EscrowStatus::Created | EscrowStatus::Funded | EscrowStatus::Shipped
+    )
+}
+
+pub fn check_capacity(escrow: &EscrowRecord) -> Result<()> {
+    if escrow.amendments.len() >= MAX_AMENDMENTS {
+        return Err(EscrowError::InvalidAmendment(format!(
+            "At most {} amendments can be proposed per escrow",
+            MAX_AMENDMENTS
+        )));
+    }
+    Ok(())
+}
+
+pub fn validate_milestones(milestones: &[Milestone], amount_satoshis: u64) -> Result<()> {
+    if milestones.is_empty() {
+        return Ok(());
+    }
+    if milestones.len() > MAX_MILESTONES {
+        return Err(EscrowError::InvalidAmendment(format!(
+            "At most {} milestones are allowed",
+            MAX_MILESTONES
+        )));
+    }
+    if milestones.iter().any(|m| m.amount_satoshis == 0 || m.description.is_empty()) {
+        return Err(EscrowError::InvalidAmendment(
+            "Milestones need a description and a non-zero amount".to_string(),
+        ));
+    }
+
+    let total = milestones
+        .iter()
+        .try_fold(0u64, |acc, m| acc.checked_add(m.amount_satoshis))
+        .ok_or(EscrowError::InvalidAmount)?;
+    if total != amount_satoshis {
+        return Err(EscrowError::InvalidAmendment(
+            "Milestone amounts must add up to the escrow amount".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn validate_metadata(metadata: &[(String, String)]) -> Result<()> {
+    if metadata.len() > MAX_METADATA_ENTRIES {
+        return Err(EscrowError::InvalidAmendment(format!(
+            "At most {} metadata entries are allowed",
+            MAX_METADATA_ENTRIES
+        )));
+    }
+    let invalid = metadata.iter().any(|(k, v)| {
+        k.is_empty() || k.len() > MAX_METADATA_KEY_LEN || v.len() > MAX_METADATA_VALUE_LEN
+    });
+    if invalid {
+        return Err(EscrowError::InvalidAmendment(
+            "Metadata keys must be 1-64 characters and values at most 256".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn validate_changes(escrow: &EscrowRecord, changes: &AmendmentChanges) -> Result<()> {
+    if changes.amount_satoshis.is_none()
+        && changes.time_lock_unix.is_none()
+        && changes.inspection_period_days.is_none()
+        && changes.milestones.is_none()
+        && changes.metadata.is_none()
+    {
+        return Err(EscrowError::InvalidAmendment("No changes proposed".to_string()));
+    }
+
+    if let Some(amount) = changes.amount_satoshis {
+        if amount == 0 {
+            return Err(EscrowError::InvalidAmount);
+        }
+        crate::limits::check_amount(&escrow.currency, amount)?;
+        // Once the seller has shipped, the price is locked in
+        if escrow.status == EscrowStatus::Shipped {
+            return Err(EscrowError::InvalidAmendment(
+                "Amount cannot change after shipping".to_string(),
+            ));
+        }
+        // The wallet hold is placed for a fixed amount
+        if escrow.wallet_hold.is_some() {
+            return Err(EscrowError::InvalidAmendment(
+                "Amount of a wallet-funded escrow cannot change".to_string(),
+            ));
+        }
+    }
+
+    if let Some(days) = changes.inspection_period_days {
+        if days == 0 || days > MAX_INSPECTION_PERIOD_DAYS {
+            return Err(EscrowError::InvalidInspectionPeriod);
+        }
+    }
+
+    // Existing milestones must still add up if only the amount changes
+    let amount = changes.amount_satoshis.unwrap_or(escrow.amount_satoshis);
+    let milestones = changes.milestones.as_ref().unwrap_or(&escrow.milestones);
+    validate_milestones(milestones, amount)?;
+
+    if let Some(metadata) = &changes.metadata {
+        validate_metadata(metadata)?;
+    }
+
+    Ok(())
+}
+
+// Apply accepted changes to the record and work out what the buyer still
+// owes (or is owed) against what has already been deposited
+pub fn apply(escrow: &mut EscrowRecord, changes: &AmendmentChanges) -> Option<FundingAdjustment> {
+    if let Some(time_lock) = changes.time_lock_unix {
+        escrow.time_lock_unix = Some(time_lock);
+    }
+    if let Some(milestones) = &changes.milestones {
+        escrow.milestones = milestones.clone();
+    }
+    if let Some(metadata) = &changes.metadata {
+        escrow.metadata = metadata.clone();
+    }
+    if let Some(days) = changes.inspection_period_days {
+        escrow.inspection_period_days = days;
+        // A running inspection window is re-based on the original ship date
+        if let Some(shipment) = escrow.shipment.as_mut() {
+            shipment.inspection_deadline =
+                shipment.shipped_at + days as u64 * crate::NANOS_PER_DAY;
+        }
+    }
+
+    let amount = changes.amount_satoshis?;
+    escrow.amount_satoshis = amount;
+
+    let deposited: u64 = escrow.utxos.iter().map(|u| u.amount_satoshis).sum();
+    let adjustment = if deposited == 0 {
+        None
+    } else if amount > deposited {
+        // Funded escrows go back to awaiting the top-up deposit
+        if escrow.status == EscrowStatus::Funded {
+            escrow.status = EscrowStatus::Created;
+        }
+        Some(FundingAdjustment::TopUp(amount - deposited))
+    } else {
+        if escrow.status == EscrowStatus::Created {
+            escrow.status = EscrowStatus::Funded;
+        }
+        // In production, trigger partial refund transaction for the excess
+        (deposited > amount).then(|| FundingAdjustment::Refund(deposited - amount))
+    };
+
+    escrow.funding_adjustment = adjustment.clone();
+    adjustment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_changes() -> AmendmentChanges {
+        AmendmentChanges {
+            amount_satoshis: None,
+            time_lock_unix: None,
+            inspection_period_days: None,
+            milestones: None,
+            metadata: None,
+        }
+    }
+
+    fn milestone(amount_satoshis: u64) -> Milestone {
+        Milestone { description: "part".to_string(), amount_satoshis }
+    }
+
+    #[test]
+    fn an_empty_proposal_is_rejected() {
+        let escrow = EscrowRecord::sample("a", 50_000, 0);
+        assert!(matches!(
+            validate_changes(&escrow, &no_changes()),
+            Err(EscrowError::InvalidAmendment(_))
+        ));
+    }
+
+    #[test]
+    fn amount_changes_are_checked() {
+        let mut escrow = EscrowRecord::sample("a", 50_000, 0);
+        let changes = AmendmentChanges { amount_satoshis: Some(60_000), ..no_changes() };
+        assert!(validate_changes(&escrow, &changes).is_ok());
+
+        let too_small = AmendmentChanges { amount_satoshis: Some(1), ..no_changes() };
+        assert!(matches!(
+            validate_changes(&escrow, &too_small),
+            Err(EscrowError::AmountOutOfRange { .. })
+        ));
+
+        escrow.status = EscrowStatus::Shipped;
+        assert!(validate_changes(&escrow, &changes).is_err());
+
+        escrow.status = EscrowStatus::Funded;
+        escrow.wallet_hold = Some(WalletHoldStatus::Held);
+        assert!(validate_changes(&escrow, &changes).is_err());
+    }
+
+    #[test]
+    fn milestones_must_add_up_to_the_new_amount() {
+        let mut escrow = EscrowRecord::sample("a", 50_000, 0);
+        escrow.milestones = vec![milestone(20_000), milestone(30_000)];
+
+        // Only the amount changes, so the existing milestones no longer fit
+        let amount_only = AmendmentChanges { amount_satoshis: Some(60_000), ..no_changes() };
+        assert!(validate_changes(&escrow, &amount_only).is_err());
+
+        let both = AmendmentChanges {
+            amount_satoshis: Some(60_000),
+            milestones: Some(vec![milestone(30_000), milestone(30_000)]),
+            ..no_changes()
+        };
+        assert!(validate_changes(&escrow, &both).is_ok());
+
+        let unnamed = AmendmentChanges {
+            milestones: Some(vec![Milestone { description: String::new(), amount_satoshis: 50_000 }]),
+            ..no_changes()
+        };
+        assert!(validate_changes(&escrow, &unnamed).is_err());
+    }
+
+    #[test]
+    fn inspection_period_and_metadata_are_bounded() {
+        let escrow = EscrowRecord::sample("a", 50_000, 0);
+        for days in [0, MAX_INSPECTION_PERIOD_DAYS + 1] {
+            let changes = AmendmentChanges { inspection_period_days: Some(days), ..no_changes() };
+            assert!(matches!(
+                validate_changes(&escrow, &changes),
+                Err(EscrowError::InvalidInspectionPeriod)
+            ));
+        }
+
+        let long_value = AmendmentChanges {
+            metadata: Some(vec![("note".to_string(), "x".repeat(MAX_METADATA_VALUE_LEN + 1))]),
+            ..no_changes()
+        };
+        assert!(validate_changes(&escrow, &long_value).is_err());
+
+        let valid = AmendmentChanges {
+            inspection_period_days: Some(MAX_INSPECTION_PERIOD_DAYS),
+            metadata: Some(vec![("note".to_string(), "fragile".to_string())]),
+            ..no_changes()
+        };
+        assert!(validate_changes(&escrow, &valid).is_ok());
+    }
+}
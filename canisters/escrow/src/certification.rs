@@ -0,0 +1,130 @@
+// Certified data for escrow records.
+//
+// Each escrow is certified as `escrows/<escrow_id> -> sha256(candid(EscrowRecord))`
+// in an IC hash tree whose root hash is set as the canister's certified data.
+// The tree is a red-black tree that re-hashes only the path to a changed
+// escrow, so certifying a write costs O(log n).
+// Query responses carry the system certificate plus a witness (the tree with
+// everything except the requested path pruned), so clients can check a
+// single replica's answer against the subnet signature.
+use crate::types::{CertifiedEscrow, EscrowRecord};
+use ic_certified_map::{labeled, labeled_hash, AsHashTree, Hash, HashTree, RbTree};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const ESCROWS_LABEL: &[u8] = b"escrows";
+
+thread_local! {
+    static LEAVES: RefCell<RbTree<String, Hash>> = RefCell::new(RbTree::new());
+}
+
+// CBOR encoding as expected by IC agents: [0], [1, l, r], [2, label, t],
+// [3, value], [4, hash]
+fn encode_tree(tree: &HashTree, out: &mut Vec<u8>) {
+    match tree {
+        HashTree::Empty => {
+            cbor_header(out, 4, 1);
+            cbor_header(out, 0, 0);
+        }
+        HashTree::Fork(children) => {
+            cbor_header(out, 4, 3);
+            cbor_header(out, 0, 1);
+            encode_tree(&children.0, out);
+            encode_tree(&children.1, out);
+        }
+        HashTree::Labeled(label, subtree) => {
+            cbor_header(out, 4, 3);
+            cbor_header(out, 0, 2);
+            cbor_bytes(out, label);
+            encode_tree(subtree, out);
+        }
+        HashTree::Leaf(value) => {
+            cbor_header(out, 4, 2);
+            cbor_header(out, 0, 3);
+            cbor_bytes(out, value);
+        }
+        HashTree::Pruned(hash) => {
+            cbor_header(out, 4, 2);
+            cbor_header(out, 0, 4);
+            cbor_bytes(out, hash);
+        }
+    }
+}
+
+fn cbor_header(out: &mut Vec<u8>, major: u8, len: u64) {
+    let major = major << 5;
+    if len < 24 {
+        out.push(major | len as u8);
+    } else if len <= u8::MAX as u64 {
+        out.extend_from_slice(&[major | 24, len as u8]);
+    } else if len <= u16::MAX as u64 {
+        out.push(major | 25);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else if len <= u32::MAX as u64 {
+        out.push(major | 26);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn cbor_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    cbor_header(out, 2, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn set_root() {
+    let root = LEAVES.with(|leaves| labeled_hash(ESCROWS_LABEL, &leaves.borrow().root_hash()));
+    ic_cdk::api::set_certified_data(&root);
+}
+
+pub fn encode_escrow(escrow: &EscrowRecord) -> Vec<u8> {
+    candid::encode_one(escrow).expect("EscrowRecord is always candid-encodable")
+}
+
+fn escrow_hash(escrow: &EscrowRecord) -> Hash {
+    Sha256::digest(encode_escrow(escrow)).into()
+}
+
+// Re-hash one escrow and publish the new root; call after every mutation
+pub fn certify(escrow: &EscrowRecord) {
+    LEAVES.with(|leaves| {
+        leaves.borrow_mut().insert(escrow.escrow_id.clone(), escrow_hash(escrow));
+    });
+    set_root();
+}
+
+pub fn certify_all<'a>(escrows: impl Iterator<Item = &'a EscrowRecord>) {
+    LEAVES.with(|leaves| {
+        let mut tree = RbTree::new();
+        for escrow in escrows {
+            tree.insert(escrow.escrow_id.clone(), escrow_hash(escrow));
+        }
+        *leaves.borrow_mut() = tree;
+    });
+    set_root();
+}
+
+// Witness for `escrows/<escrow_id>`; for unknown IDs it reveals the
+// neighbouring labels so clients can verify absence
+fn witness(escrow_id: &str) -> Vec<u8> {
+    LEAVES.with(|leaves| {
+        let leaves = leaves.borrow();
+        let tree = labeled(ESCROWS_LABEL, leaves.witness(escrow_id.as_bytes()));
+
+        // Self-describing CBOR tag, as used for IC certificates
+        let mut out = vec![0xd9, 0xd9, 0xf7];
+        encode_tree(&tree, &mut out);
+        out
+    })
+}
+
+pub fn certified_escrow(escrow_id: String, escrow: Option<&EscrowRecord>) -> CertifiedEscrow {
+    CertifiedEscrow {
+        escrow_candid: escrow.map(encode_escrow),
+        certificate: ic_cdk::api::data_certificate(),
+        witness: witness(&escrow_id),
+        escrow_id,
+    }
+}
@@ -0,0 +1,197 @@
+// Controller-managed configuration, the admin role set and the emergency
+// pause switches.
+//
+// Controllers manage the configuration and the admin set; admins (and
+// controllers) manage creation limits, the pause switches and dispute
+// resolution. Configuration, admins, limits and pause state survive upgrades.
+use crate::limits;
+use crate::types::*;
+use candid::Principal;
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use ic_cdk::caller;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+const MAX_PAUSE_REASON_LEN: usize = 256;
+
+thread_local! {
+    static CONFIG: RefCell<EscrowConfig> = RefCell::new(EscrowConfig::default());
+    static ADMINS: RefCell<BTreeSet<Principal>> = RefCell::new(BTreeSet::new());
+    static PAUSE: RefCell<PauseState> = RefCell::new(PauseState::default());
+}
+
+impl Default for EscrowConfig {
+    fn default() -> Self {
+        EscrowConfig {
+            bitcoin_network: BitcoinNetwork::Testnet,
+            ecdsa_key_name: "test_key_1".to_string(),
+            wallet_canister: None,
+            reputation_canister: None,
+            identity_canister: None,
+            ai_orchestration_canister: None,
+            fallback_fee_rate_sat_per_vb: 2,
+            max_fee_rate_sat_per_vb: 500,
+            // Headroom for a few dozen threshold signatures and Bitcoin API
+            // calls before the freezing threshold
+            min_cycles_balance: 1_000_000_000_000,
+        }
+    }
+}
+
+pub fn get() -> EscrowConfig {
+    CONFIG.with(|config| config.borrow().clone())
+}
+
+pub fn set(new_config: EscrowConfig) -> Result<()> {
+    if new_config.ecdsa_key_name.is_empty() {
+        return Err(EscrowError::InternalError("ecdsa_key_name must not be empty".to_string()));
+    }
+    if new_config.fallback_fee_rate_sat_per_vb == 0
+        || new_config.fallback_fee_rate_sat_per_vb > new_config.max_fee_rate_sat_per_vb
+    {
+        return Err(EscrowError::InternalError(
+            "Fee rates need 0 < fallback <= max".to_string(),
+        ));
+    }
+
+    CONFIG.with(|config| *config.borrow_mut() = new_config);
+    Ok(())
+}
+
+pub fn bitcoin_network() -> BitcoinNetwork {
+    CONFIG.with(|config| config.borrow().bitcoin_network)
+}
+
+pub fn ecdsa_key_name() -> String {
+    CONFIG.with(|config| config.borrow().ecdsa_key_name.clone())
+}
+
+// Apply init/upgrade arguments on top of the current (default or restored)
+// state
+pub fn apply_init_args(args: EscrowInitArgs) -> Result<()> {
+    if let Some(config) = args.config {
+        set(config)?;
+    }
+    if let Some(creation_limits) = args.creation_limits {
+        limits::set(creation_limits)?;
+    }
+    if let Some(admins) = args.admins {
+        ADMINS.with(|a| *a.borrow_mut() = admins.into_iter().collect());
+    }
+    Ok(())
+}
+
+pub fn require_controller() -> Result<()> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err(EscrowError::Unauthorized);
+    }
+    Ok(())
+}
+
+pub fn is_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal) || ADMINS.with(|a| a.borrow().contains(principal))
+}
+
+pub fn require_admin() -> Result<()> {
+    if !is_admin(&caller()) {
+        return Err(EscrowError::Unauthorized);
+    }
+    Ok(())
+}
+
+pub fn admins() -> Vec<Principal> {
+    ADMINS.with(|a| a.borrow().iter().cloned().collect())
+}
+
+pub fn add_admin(principal: Principal) {
+    ADMINS.with(|a| a.borrow_mut().insert(principal));
+}
+
+pub fn remove_admin(principal: &Principal) {
+    ADMINS.with(|a| a.borrow_mut().remove(principal));
+}
+
+pub fn pause_state() -> PauseState {
+    PAUSE.with(|p| p.borrow().clone())
+}
+
+pub fn set_pause(
+    creation_paused: bool,
+    payouts_paused: bool,
+    reason: Option<String>,
+    now: u64,
+) -> Result<PauseState> {
+    if reason.as_ref().is_some_and(|r| r.len() > MAX_PAUSE_REASON_LEN) {
+        return Err(EscrowError::InternalError(format!(
+            "Pause reason must be at most {} characters",
+            MAX_PAUSE_REASON_LEN
+        )));
+    }
+
+    PAUSE.with(|p| {
+        let mut p = p.borrow_mut();
+        *p = PauseState {
+            creation_paused,
+            payouts_paused,
+            reason,
+            updated_at: now,
+            updated_by: Some(caller()),
+        };
+        Ok(p.clone())
+    })
+}
+
+pub fn check_creation_allowed() -> Result<()> {
+    if PAUSE.with(|p| p.borrow().creation_paused) {
+        return Err(EscrowError::Paused);
+    }
+    Ok(())
+}
+
+// Covers every transition into Released/Refunded and on-chain settlement
+pub fn check_payouts_allowed() -> Result<()> {
+    if payouts_paused() {
+        return Err(EscrowError::Paused);
+    }
+    Ok(())
+}
+
+// Starting work that ends in threshold signing or Bitcoin API calls with too
+// few cycles risks freezing the canister halfway through
+pub fn check_cycles() -> Result<()> {
+    let min = CONFIG.with(|config| config.borrow().min_cycles_balance);
+    if ic_cdk::api::canister_balance128() < min {
+        return Err(EscrowError::LowCycles);
+    }
+    Ok(())
+}
+
+pub fn payouts_paused() -> bool {
+    PAUSE.with(|p| p.borrow().payouts_paused)
+}
+
+type StableState = (EscrowConfig, Vec<Principal>, PauseState, CreationLimits);
+
+pub fn save() {
+    let state: StableState = (get(), admins(), pause_state(), limits::get());
+    if let Err(err) = ic_cdk::storage::stable_save((state,)) {
+        ic_cdk::trap(&format!("Failed to save escrow configuration: {}", err));
+    }
+}
+
+// Keeps the defaults when stable memory holds nothing (e.g. upgrading from a
+// version that did not save configuration). Saved state that does not decode
+// or apply is not silently replaced.
+pub fn restore() {
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return;
+    }
+    let ((config, admins, pause, creation_limits),) = ic_cdk::storage::stable_restore::<(StableState,)>()
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to restore escrow configuration: {}", err)));
+    CONFIG.with(|c| *c.borrow_mut() = config);
+    ADMINS.with(|a| *a.borrow_mut() = admins.into_iter().collect());
+    PAUSE.with(|p| *p.borrow_mut() = pause);
+    if let Err(err) = limits::set(creation_limits) {
+        ic_cdk::trap(&format!("Invalid saved creation limits: {:?}", err));
+    }
+}
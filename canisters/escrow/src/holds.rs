@@ -0,0 +1,168 @@
+// Escrows funded from the buyer's wallet balance.
+//
+// Instead of a deposit, the wallet canister places a hold on the buyer's
+// balance for the escrow amount. When the escrow closes the hold is settled
+// in the wallet: released to the seller, or returned to the buyer. A hold
+// that no longer matches its escrow (the escrow changed while the hold was
+// being placed) is a stray and is returned right away, whatever the escrow's
+// status. Settlement calls are idempotent on the wallet side, so failed ones
+// are simply retried from the heartbeat.
+use crate::types::*;
+use crate::{current_timestamp, record_change, ESCROWS};
+use candid::{Principal, Reserved};
+use common::{HoldParams, WalletError};
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+// The hold itself is not needed here, only whether the call succeeded
+type WalletResult = std::result::Result<Reserved, WalletError>;
+
+thread_local! {
+    // Escrows with a settlement call in flight
+    static SETTLING: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+    // Escrows with a `place_hold` call in flight
+    static PLACING: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+    // Escrows whose stray hold has not been returned yet
+    static STRAY: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+// Marks a hold placement in flight until dropped, including on a trap in the
+// callback
+pub struct Placing(String);
+
+impl Placing {
+    pub fn start(escrow_id: &str) -> Option<Placing> {
+        if !PLACING.with(|p| p.borrow_mut().insert(escrow_id.to_string())) {
+            return None;
+        }
+        Some(Placing(escrow_id.to_string()))
+    }
+}
+
+impl Drop for Placing {
+    fn drop(&mut self) {
+        PLACING.with(|p| p.borrow_mut().remove(&self.0));
+    }
+}
+
+pub fn is_placing(escrow_id: &str) -> bool {
+    PLACING.with(|p| p.borrow().contains(escrow_id))
+}
+
+fn wallet_canister() -> Result<Principal> {
+    crate::config::get()
+        .wallet_canister
+        .ok_or_else(|| EscrowError::InternalError("Wallet canister is not configured".to_string()))
+}
+
+async fn call_wallet<A: candid::utils::ArgumentEncoder>(method: &str, args: A) -> Result<()> {
+    let (result,): (WalletResult,) = ic_cdk::call(wallet_canister()?, method, args)
+        .await
+        .map_err(|(code, msg)| {
+            EscrowError::InternalError(format!("Wallet call {} failed: {:?} {}", method, code, msg))
+        })?;
+    result.map(|_| ()).map_err(|err| match err {
+        WalletError::InsufficientBalance => EscrowError::InsufficientFunds,
+        other => EscrowError::InternalError(format!("Wallet rejected {}: {:?}", method, other)),
+    })
+}
+
+pub async fn place(escrow: &EscrowRecord) -> Result<()> {
+    let params = HoldParams {
+        escrow_id: escrow.escrow_id.clone(),
+        user_id: escrow.buyer_id(),
+        amount: escrow.amount_satoshis,
+        currency: escrow.currency,
+    };
+    call_wallet("place_hold", (params,)).await
+}
+
+// What to do with an escrow's hold now, if anything
+fn due_settlement(escrow: &EscrowRecord) -> Option<WalletHoldStatus> {
+    if escrow.wallet_hold != Some(WalletHoldStatus::Held) {
+        return None;
+    }
+    match escrow.status {
+        EscrowStatus::Released => Some(WalletHoldStatus::Released),
+        EscrowStatus::Refunded | EscrowStatus::Created => Some(WalletHoldStatus::Returned),
+        _ => None,
+    }
+}
+
+// Start settling the escrow's hold if it is due. Does not touch ESCROWS
+// before its first await, so it is safe to call while they are borrowed.
+pub fn settle_if_due(escrow: &EscrowRecord) {
+    let Some(outcome) = due_settlement(escrow) else {
+        return;
+    };
+    let escrow_id = escrow.escrow_id.clone();
+    if !SETTLING.with(|s| s.borrow_mut().insert(escrow_id.clone())) {
+        return;
+    }
+    let seller = escrow.seller_id();
+
+    ic_cdk::spawn(async move {
+        let result = match outcome {
+            WalletHoldStatus::Released => call_wallet("release_hold", (escrow_id.clone(), seller)).await,
+            _ => call_wallet("return_hold", (escrow_id.clone(),)).await,
+        };
+        SETTLING.with(|s| s.borrow_mut().remove(&escrow_id));
+
+        match result {
+            Ok(()) => ESCROWS.with(|escrows| {
+                if let Some(escrow) = escrows.borrow_mut().get_mut(&escrow_id) {
+                    escrow.wallet_hold = Some(outcome);
+                    escrow.updated_at = current_timestamp();
+                    record_change(escrow);
+                }
+            }),
+            Err(err) => ic_cdk::println!("Settling wallet hold for {} failed: {:?}", escrow_id, err),
+        }
+    });
+}
+
+// Return a hold that was placed for an escrow that changed meanwhile. The
+// escrow already records it as `Returned`; until the call succeeds it is
+// retried from the heartbeat.
+pub fn return_stray(escrow_id: &str) {
+    STRAY.with(|s| s.borrow_mut().insert(escrow_id.to_string()));
+    if !SETTLING.with(|s| s.borrow_mut().insert(escrow_id.to_string())) {
+        return;
+    }
+    let escrow_id = escrow_id.to_string();
+
+    ic_cdk::spawn(async move {
+        let result = call_wallet("return_hold", (escrow_id.clone(),)).await;
+        SETTLING.with(|s| s.borrow_mut().remove(&escrow_id));
+        match result {
+            Ok(()) => {
+                STRAY.with(|s| s.borrow_mut().remove(&escrow_id));
+            }
+            Err(err) => ic_cdk::println!("Returning stray hold for {} failed: {:?}", escrow_id, err),
+        }
+    });
+}
+
+// A stray hold of this escrow is still being returned
+pub fn is_stray(escrow_id: &str) -> bool {
+    STRAY.with(|s| s.borrow().contains(escrow_id))
+}
+
+pub fn retry_unsettled() {
+    let stray: Vec<String> = STRAY.with(|s| s.borrow().iter().cloned().collect());
+    for escrow_id in &stray {
+        return_stray(escrow_id);
+    }
+
+    let due: Vec<EscrowRecord> = ESCROWS.with(|escrows| {
+        escrows
+            .borrow()
+            .values()
+            .filter(|e| due_settlement(e).is_some())
+            .cloned()
+            .collect()
+    });
+    for escrow in &due {
+        settle_if_due(escrow);
+    }
+}
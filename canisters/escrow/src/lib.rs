@@ -1,21 +1,52 @@
 use ic_cdk::api::time;
 use ic_cdk::{caller, query, update};
-use ic_cdk_macros::{init, post_upgrade, pre_upgrade};
+use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use candid::Principal;
 
+mod amendments;
+mod certification;
+mod config;
+mod holds;
+mod limits;
+mod multisig;
+mod stats;
 mod types;
 use types::*;
 
+pub(crate) const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Buyer inspection window applied once the seller marks an escrow as shipped
+const DEFAULT_INSPECTION_PERIOD_DAYS: u32 = 7;
+pub(crate) const MAX_INSPECTION_PERIOD_DAYS: u32 = 90;
+const MAX_TRACKING_REFERENCE_LEN: usize = 128;
+
+// How often the heartbeat looks for expired inspection windows
+const INSPECTION_SWEEP_INTERVAL_NANOS: u64 = 10 * 60 * 1_000_000_000;
+
 // Thread-local storage for escrow records
 thread_local! {
     static ESCROWS: RefCell<HashMap<String, EscrowRecord>> = RefCell::new(HashMap::new());
     static ESCROW_COUNTER: RefCell<u64> = RefCell::new(0);
+    static LAST_INSPECTION_SWEEP: RefCell<u64> = RefCell::new(0);
+    static INSPECTION_DEADLINES: RefCell<InspectionIndex> = RefCell::new(InspectionIndex::default());
+}
+
+// Deadlines of shipped escrows, soonest first, and the deadline each escrow
+// is indexed under so a changed deadline replaces the old entry
+#[derive(Default)]
+struct InspectionIndex {
+    by_deadline: BTreeSet<(u64, String)>,
+    deadlines: HashMap<String, u64>,
 }
 
 #[init]
-fn init() {
+fn init(args: Option<EscrowInitArgs>) {
+    if let Err(err) = config::apply_init_args(args.unwrap_or_default()) {
+        ic_cdk::trap(&format!("Invalid init arguments: {:?}", err));
+    }
+    certification::certify_all(std::iter::empty());
     ic_cdk::println!("Escrow canister initialized");
 }
 
@@ -23,11 +54,112 @@ fn init() {
 fn pre_upgrade() {
     // Store state before upgrade
     // In production, you'd serialize ESCROWS to stable memory
+    config::save();
 }
 
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<EscrowInitArgs>) {
     // Restore state after upgrade
+    config::restore();
+    if let Err(err) = config::apply_init_args(args.unwrap_or_default()) {
+        ic_cdk::trap(&format!("Invalid upgrade arguments: {:?}", err));
+    }
+    ESCROWS.with(|escrows| {
+        let escrows = escrows.borrow();
+        certification::certify_all(escrows.values());
+        escrows.values().for_each(index_inspection);
+    });
+}
+
+#[heartbeat]
+fn heartbeat() {
+    let now = current_timestamp();
+    let due = LAST_INSPECTION_SWEEP.with(|last| {
+        let mut last = last.borrow_mut();
+        if now.saturating_sub(*last) < INSPECTION_SWEEP_INTERVAL_NANOS {
+            return false;
+        }
+        *last = now;
+        true
+    });
+
+    // Expired inspection windows and unsettled wallet holds are picked up
+    // once payouts resume
+    if due && !config::payouts_paused() {
+        let released = release_expired_inspections(now);
+        if released > 0 {
+            ic_cdk::println!("Auto-released {} escrows after inspection period", released);
+        }
+        holds::retry_unsettled();
+    }
+}
+
+// Keep INSPECTION_DEADLINES in step with the escrow's status and deadline
+fn index_inspection(escrow: &EscrowRecord) {
+    let deadline = escrow
+        .shipment
+        .as_ref()
+        .filter(|_| escrow.status == EscrowStatus::Shipped)
+        .map(|shipment| shipment.inspection_deadline);
+    INSPECTION_DEADLINES.with(|index| {
+        let index = &mut *index.borrow_mut();
+        if let Some(previous) = index.deadlines.remove(&escrow.escrow_id) {
+            index.by_deadline.remove(&(previous, escrow.escrow_id.clone()));
+        }
+        if let Some(deadline) = deadline {
+            index.by_deadline.insert((deadline, escrow.escrow_id.clone()));
+            index.deadlines.insert(escrow.escrow_id.clone(), deadline);
+        }
+    });
+}
+
+// Shipped escrows whose inspection window has passed. The record is checked
+// too, in case the index lags behind it.
+fn due_inspections(now: u64) -> Vec<String> {
+    let indexed: Vec<String> = INSPECTION_DEADLINES.with(|index| {
+        index
+            .borrow()
+            .by_deadline
+            .iter()
+            .take_while(|(deadline, _)| *deadline <= now)
+            .map(|(_, escrow_id)| escrow_id.clone())
+            .collect()
+    });
+    ESCROWS.with(|escrows| {
+        let escrows = escrows.borrow();
+        indexed
+            .into_iter()
+            .filter(|escrow_id| {
+                escrows.get(escrow_id).is_some_and(|escrow| {
+                    escrow.status == EscrowStatus::Shipped
+                        && escrow.shipment.as_ref().is_some_and(|s| s.inspection_deadline <= now)
+                })
+            })
+            .collect()
+    })
+}
+
+// Auto-release shipped escrows whose buyer inspection window has passed
+// without a confirmation or dispute
+fn release_expired_inspections(now: u64) -> u64 {
+    let due = due_inspections(now);
+
+    ESCROWS.with(|escrows| {
+        let mut escrows = escrows.borrow_mut();
+        let mut released = 0;
+        for escrow_id in due {
+            let Some(escrow) = escrows.get_mut(&escrow_id) else {
+                continue;
+            };
+            // In production, this would trigger threshold signature transaction
+            escrow.status = EscrowStatus::Released;
+            escrow.tags.push("auto_released: inspection period expired".to_string());
+            escrow.updated_at = now;
+            record_change(escrow);
+            released += 1;
+        }
+        released
+    })
 }
 
 // Helper function to generate escrow ID
@@ -44,7 +176,7 @@ fn generate_escrow_id() -> String {
 fn generate_deposit_address(escrow_id: &str, currency: &Currency) -> String {
     match currency {
         Currency::BTC => format!("tb1q{}", &escrow_id[4..].to_lowercase()),
-        Currency::CkBTC => format!("ckbtc-{}", escrow_id),
+        other => format!("{}-{}", other.symbol().to_lowercase(), escrow_id),
     }
 }
 
@@ -53,21 +185,78 @@ fn current_timestamp() -> u64 {
     time()
 }
 
+// Bookkeeping that must follow every escrow mutation
+fn record_change(escrow: &EscrowRecord) {
+    stats::observe(escrow);
+    index_inspection(escrow);
+    certification::certify(escrow);
+    holds::settle_if_due(escrow);
+}
+
+fn is_closed(status: &EscrowStatus) -> bool {
+    matches!(status, EscrowStatus::Released | EscrowStatus::Refunded)
+}
+
 #[update]
-fn create_escrow(params: CreateEscrowParams) -> std::result::Result<CreateEscrowResult, EscrowError> {
+async fn create_escrow(params: CreateEscrowParams) -> std::result::Result<CreateEscrowResult, EscrowError> {
     let creator = caller();
     
+    if creator == Principal::anonymous() {
+        return Err(EscrowError::Unauthorized);
+    }
+    config::check_creation_allowed()?;
+    config::check_cycles()?;
+    
     // Validate params
     if params.amount_satoshis == 0 {
         return Err(EscrowError::InvalidAmount);
     }
+    limits::check_amount(&params.currency, params.amount_satoshis)?;
     
     if creator == params.counterparty_id {
         return Err(EscrowError::InternalError("Cannot create escrow with yourself".to_string()));
     }
     
+    let inspection_period_days = params
+        .inspection_period_days
+        .unwrap_or(DEFAULT_INSPECTION_PERIOD_DAYS);
+    if inspection_period_days == 0 || inspection_period_days > MAX_INSPECTION_PERIOD_DAYS {
+        return Err(EscrowError::InvalidInspectionPeriod);
+    }
+    
+    let milestones = params.milestones.unwrap_or_default();
+    amendments::validate_milestones(&milestones, params.amount_satoshis)?;
+    let metadata = params.metadata.unwrap_or_default();
+    amendments::validate_metadata(&metadata)?;
+    
+    if let Some(keys) = &params.multisig {
+        if params.currency != Currency::BTC {
+            return Err(EscrowError::InternalError("Multisig escrows must use BTC".to_string()));
+        }
+        multisig::validate_keys(keys)?;
+    }
+    
+    let open_escrows = ESCROWS.with(|escrows| {
+        escrows
+            .borrow()
+            .values()
+            .filter(|e| e.creator_id == creator && !is_closed(&e.status))
+            .count()
+    });
+    // Released once the escrow is inserted, or on an error or trap before
+    let _slot = limits::reserve_open_escrow(creator, open_escrows)?;
+    limits::record_creation(creator, current_timestamp())?;
+    
     let escrow_id = generate_escrow_id();
-    let deposit_address = generate_deposit_address(&escrow_id, &params.currency);
+    // A multisig escrow has no deposit address until the counterparty has
+    // registered its own key
+    let (deposit_address, multisig) = match &params.multisig {
+        Some(keys) => {
+            let canister_pubkey = multisig::canister_public_key(&escrow_id).await?;
+            (String::new(), Some(multisig::setup(keys, canister_pubkey)?))
+        }
+        None => (generate_deposit_address(&escrow_id, &params.currency), None),
+    };
     let now = current_timestamp();
     
     // Mock AI risk score for demo (TODO: Replace with actual AI gateway integration)
@@ -88,8 +277,9 @@ fn create_escrow(params: CreateEscrowParams) -> std::result::Result<CreateEscrow
         escrow_id: escrow_id.clone(),
         creator_id: creator,
         counterparty_id: params.counterparty_id,
+        creator_role: params.creator_role.unwrap_or(PartyRole::Buyer),
         amount_satoshis: params.amount_satoshis,
-        currency: params.currency.clone(),
+        currency: params.currency,
         deposit_address: deposit_address.clone(),
         utxos: vec![],
         status: EscrowStatus::Created,
@@ -100,8 +290,18 @@ fn create_escrow(params: CreateEscrowParams) -> std::result::Result<CreateEscrow
         tags: vec![],
         creator_confirmed_delivery: false,
         counterparty_confirmed_delivery: false,
+        inspection_period_days,
+        shipment: None,
+        milestones,
+        metadata,
+        amendments: vec![],
+        funding_adjustment: None,
+        multisig,
+        settlement: None,
+        wallet_hold: None,
     };
     
+    record_change(&escrow);
     ESCROWS.with(|escrows| {
         escrows.borrow_mut().insert(escrow_id.clone(), escrow);
     });
@@ -119,6 +319,16 @@ fn get_escrow(escrow_id: String) -> Option<EscrowRecord> {
     })
 }
 
+// Same as `get_escrow`, plus a certificate and witness so callers can
+// verify the answer without an update call
+#[query]
+fn get_certified_escrow(escrow_id: String) -> CertifiedEscrow {
+    ESCROWS.with(|escrows| {
+        let escrows = escrows.borrow();
+        certification::certified_escrow(escrow_id.clone(), escrows.get(&escrow_id))
+    })
+}
+
 #[query]
 fn get_user_escrows(user_id: Principal) -> Vec<EscrowRecord> {
     ESCROWS.with(|escrows| {
@@ -131,16 +341,58 @@ fn get_user_escrows(user_id: Principal) -> Vec<EscrowRecord> {
     })
 }
 
+// The counterparty of a multisig escrow adds its cosigner key from its own
+// principal; this issues the escrow's deposit address
 #[update]
-fn notify_deposit(escrow_id: String, utxo: UTXO) -> Result<EscrowRecord> {
+fn register_multisig_key(escrow_id: String, pubkey: Vec<u8>) -> Result<EscrowRecord> {
+    let caller_id = caller();
+    
     ESCROWS.with(|escrows| {
         let mut escrows_map = escrows.borrow_mut();
         let escrow = escrows_map.get_mut(&escrow_id).ok_or(EscrowError::NotFound)?;
         
-        // Verify status
+        if caller_id != escrow.counterparty_id {
+            return Err(EscrowError::Unauthorized);
+        }
         if escrow.status != EscrowStatus::Created {
             return Err(EscrowError::InvalidStatus);
         }
+        let info = escrow
+            .multisig
+            .as_mut()
+            .ok_or_else(|| EscrowError::InternalError("Not a multisig escrow".to_string()))?;
+        
+        multisig::register_counterparty_key(info, pubkey)?;
+        escrow.deposit_address = multisig::deposit_address(info)?;
+        escrow.updated_at = current_timestamp();
+        
+        Ok(escrow.clone())
+    })
+    .inspect(record_change)
+}
+
+#[update]
+fn notify_deposit(escrow_id: String, utxo: UTXO) -> Result<EscrowRecord> {
+    let caller_id = caller();
+    
+    ESCROWS.with(|escrows| {
+        let mut escrows_map = escrows.borrow_mut();
+        let escrow = escrows_map.get_mut(&escrow_id).ok_or(EscrowError::NotFound)?;
+        
+        // Nothing here checks the UTXO on chain, so only admins report deposits
+        if !config::is_admin(&caller_id) {
+            return Err(EscrowError::Unauthorized);
+        }
+        
+        // Verify status; a wallet hold being placed will fund the escrow
+        if escrow.status != EscrowStatus::Created || holds::is_placing(&escrow_id) {
+            return Err(EscrowError::InvalidStatus);
+        }
+        
+        // No deposit address has been issued yet
+        if escrow.deposit_address.is_empty() {
+            return Err(EscrowError::InvalidStatus);
+        }
         
         // Add UTXO
         escrow.utxos.push(utxo.clone());
@@ -151,12 +403,73 @@ fn notify_deposit(escrow_id: String, utxo: UTXO) -> Result<EscrowRecord> {
         // Update status if fully funded
         if total_deposited >= escrow.amount_satoshis {
             escrow.status = EscrowStatus::Funded;
+            if matches!(escrow.funding_adjustment, Some(FundingAdjustment::TopUp(_))) {
+                escrow.funding_adjustment = None;
+            }
         }
         
         escrow.updated_at = current_timestamp();
         
         Ok(escrow.clone())
     })
+    .inspect(record_change)
+}
+
+// Fund the escrow by holding the amount on the buyer's wallet balance
+#[update]
+async fn fund_from_wallet(escrow_id: String) -> Result<EscrowRecord> {
+    let caller_id = caller();
+    config::check_cycles()?;
+
+    let escrow = ESCROWS.with(|escrows| escrows.borrow().get(&escrow_id).cloned())
+        .ok_or(EscrowError::NotFound)?;
+    if caller_id != escrow.buyer_id() {
+        return Err(EscrowError::Unauthorized);
+    }
+    if escrow.status != EscrowStatus::Created
+        || escrow.multisig.is_some()
+        || !escrow.utxos.is_empty()
+        || escrow.wallet_hold.is_some()
+    {
+        return Err(EscrowError::InvalidStatus);
+    }
+    if escrow.amendments.iter().any(|a| a.status == AmendmentStatus::Pending) {
+        return Err(EscrowError::AmendmentPending);
+    }
+
+    // A new hold would be taken for the stray one still being returned
+    if holds::is_stray(&escrow_id) {
+        return Err(EscrowError::InvalidStatus);
+    }
+    let Some(_placing) = holds::Placing::start(&escrow_id) else {
+        return Err(EscrowError::InvalidStatus);
+    };
+    holds::place(&escrow).await?;
+
+    // The escrow may have changed during the call (e.g. an amendment); a
+    // hold that no longer matches it is returned right away and the escrow
+    // is left without one
+    let funded = ESCROWS.with(|escrows| {
+        let mut escrows_map = escrows.borrow_mut();
+        let current = escrows_map.get_mut(&escrow_id)?;
+        let unchanged = current.status == EscrowStatus::Created
+            && current.amount_satoshis == escrow.amount_satoshis
+            && current.utxos.is_empty();
+        if unchanged {
+            current.wallet_hold = Some(WalletHoldStatus::Held);
+            current.status = EscrowStatus::Funded;
+            current.updated_at = current_timestamp();
+            record_change(current);
+        }
+        unchanged.then(|| current.clone())
+    });
+    match funded {
+        Some(escrow) => Ok(escrow),
+        None => {
+            holds::return_stray(&escrow_id);
+            Err(EscrowError::InvalidStatus)
+        }
+    }
 }
 
 #[update]
@@ -173,7 +486,7 @@ fn confirm_delivery(escrow_id: String) -> Result<EscrowRecord> {
         }
         
         // Verify status
-        if escrow.status != EscrowStatus::Funded {
+        if escrow.status != EscrowStatus::Funded && escrow.status != EscrowStatus::Shipped {
             return Err(EscrowError::InvalidStatus);
         }
         
@@ -199,11 +512,67 @@ fn confirm_delivery(escrow_id: String) -> Result<EscrowRecord> {
         
         Ok(escrow.clone())
     })
+    .inspect(record_change)
+}
+
+#[update]
+fn mark_shipped(escrow_id: String, tracking_reference: Option<String>) -> Result<EscrowRecord> {
+    let caller_id = caller();
+    
+    if tracking_reference
+        .as_ref()
+        .is_some_and(|r| r.is_empty() || r.len() > MAX_TRACKING_REFERENCE_LEN)
+    {
+        return Err(EscrowError::InternalError(format!(
+            "Tracking reference must be 1-{} characters",
+            MAX_TRACKING_REFERENCE_LEN
+        )));
+    }
+    
+    ESCROWS.with(|escrows| {
+        let mut escrows_map = escrows.borrow_mut();
+        let escrow = escrows_map.get_mut(&escrow_id).ok_or(EscrowError::NotFound)?;
+        
+        // Only the seller can mark the item as shipped
+        if caller_id != escrow.seller_id() {
+            return Err(EscrowError::Unauthorized);
+        }
+        
+        if escrow.status != EscrowStatus::Funded {
+            return Err(EscrowError::InvalidStatus);
+        }
+        
+        // Shipping counts as the seller's delivery confirmation and starts
+        // the buyer's inspection window
+        let now = current_timestamp();
+        escrow.shipment = Some(ShipmentInfo {
+            tracking_reference,
+            shipped_at: now,
+            inspection_deadline: now + escrow.inspection_period_days as u64 * NANOS_PER_DAY,
+        });
+        let buyer_confirmed = if escrow.creator_role == PartyRole::Seller {
+            escrow.creator_confirmed_delivery = true;
+            escrow.counterparty_confirmed_delivery
+        } else {
+            escrow.counterparty_confirmed_delivery = true;
+            escrow.creator_confirmed_delivery
+        };
+        escrow.status = if buyer_confirmed {
+            EscrowStatus::Delivered
+        } else {
+            EscrowStatus::Shipped
+        };
+        escrow.updated_at = now;
+        
+        Ok(escrow.clone())
+    })
+    .inspect(record_change)
 }
 
 #[update]
 fn request_release(escrow_id: String) -> Result<EscrowRecord> {
     let caller_id = caller();
+    config::check_payouts_allowed()?;
     
     ESCROWS.with(|escrows| {
         let mut escrows_map = escrows.borrow_mut();
@@ -214,10 +583,13 @@ fn request_release(escrow_id: String) -> Result<EscrowRecord> {
             return Err(EscrowError::Unauthorized);
         }
         
-        // Check status (must be Delivered or time-lock expired)
+        // Check status (must be Delivered, time-lock expired or inspection window expired)
+        let now = current_timestamp();
         let can_release = escrow.status == EscrowStatus::Delivered
             || (escrow.status == EscrowStatus::Funded 
-                && escrow.time_lock_unix.map_or(false, |lock| current_timestamp() >= lock));
+                && escrow.time_lock_unix.map_or(false, |lock| now >= lock))
+            || (escrow.status == EscrowStatus::Shipped
+                && escrow.shipment.as_ref().is_some_and(|s| now >= s.inspection_deadline));
         
         if !can_release {
             return Err(EscrowError::InvalidStatus);
@@ -229,18 +601,20 @@ fn request_release(escrow_id: String) -> Result<EscrowRecord> {
         
         Ok(escrow.clone())
     })
+    .inspect(record_change)
 }
 
 #[update]
 fn force_refund(escrow_id: String, reason: String) -> Result<EscrowRecord> {
     let caller_id = caller();
+    config::check_payouts_allowed()?;
     
     ESCROWS.with(|escrows| {
         let mut escrows_map = escrows.borrow_mut();
         let escrow = escrows_map.get_mut(&escrow_id).ok_or(EscrowError::NotFound)?;
         
-        // Only creator can force refund before delivery
-        if caller_id != escrow.creator_id {
+        // Only the buyer can force a refund before delivery
+        if caller_id != escrow.buyer_id() {
             return Err(EscrowError::Unauthorized);
         }
         
@@ -256,6 +630,7 @@ fn force_refund(escrow_id: String, reason: String) -> Result<EscrowRecord> {
         
         Ok(escrow.clone())
     })
+    .inspect(record_change)
 }
 
 #[update]
@@ -271,8 +646,11 @@ fn mark_disputed(escrow_id: String, reason: String) -> Result<EscrowRecord> {
             return Err(EscrowError::Unauthorized);
         }
         
-        // Can dispute funded or delivered escrows
-        if escrow.status != EscrowStatus::Funded && escrow.status != EscrowStatus::Delivered {
+        // Can dispute funded, shipped or delivered escrows
+        if escrow.status != EscrowStatus::Funded
+            && escrow.status != EscrowStatus::Shipped
+            && escrow.status != EscrowStatus::Delivered
+        {
             return Err(EscrowError::InvalidStatus);
         }
         
@@ -282,11 +660,14 @@ fn mark_disputed(escrow_id: String, reason: String) -> Result<EscrowRecord> {
         
         Ok(escrow.clone())
     })
+    .inspect(record_change)
 }
 
 #[update]
 fn resolve_dispute(escrow_id: String, resolution: String) -> Result<EscrowRecord> {
-    // In production, only admin/arbitrator can call this
+    config::require_admin()?;
+    config::check_payouts_allowed()?;
+    
     ESCROWS.with(|escrows| {
         let mut escrows_map = escrows.borrow_mut();
         let escrow = escrows_map.get_mut(&escrow_id).ok_or(EscrowError::NotFound)?;
@@ -307,6 +688,7 @@ fn resolve_dispute(escrow_id: String, resolution: String) -> Result<EscrowRecord
         
         Ok(escrow.clone())
     })
+    .inspect(record_change)
 }
 
 #[update]
@@ -322,6 +704,360 @@ fn attach_ai_result(escrow_id: String, risk_score: u8, tags: Vec<String>) -> Res
         
         Ok(escrow.clone())
     })
+    .inspect(record_change)
+}
+
+#[update]
+fn propose_amendment(escrow_id: String, changes: AmendmentChanges) -> Result<EscrowRecord> {
+    let caller_id = caller();
+    
+    ESCROWS.with(|escrows| {
+        let mut escrows_map = escrows.borrow_mut();
+        let escrow = escrows_map.get_mut(&escrow_id).ok_or(EscrowError::NotFound)?;
+        
+        // Verify caller is participant
+        if caller_id != escrow.creator_id && caller_id != escrow.counterparty_id {
+            return Err(EscrowError::Unauthorized);
+        }
+        
+        if !amendments::is_amendable(&escrow.status) {
+            return Err(EscrowError::InvalidStatus);
+        }
+        
+        // Only one open proposal at a time
+        if escrow.amendments.iter().any(|a| a.status == AmendmentStatus::Pending) {
+            return Err(EscrowError::AmendmentPending);
+        }
+        amendments::check_capacity(escrow)?;
+        
+        amendments::validate_changes(escrow, &changes)?;
+        
+        let now = current_timestamp();
+        escrow.amendments.push(Amendment {
+            amendment_id: escrow.amendments.len() as u32 + 1,
+            proposed_by: caller_id,
+            changes,
+            status: AmendmentStatus::Pending,
+            proposed_at: now,
+            resolved_at: None,
+            funding_adjustment: None,
+        });
+        escrow.updated_at = now;
+        
+        Ok(escrow.clone())
+    })
+    .inspect(record_change)
+}
+
+#[update]
+fn accept_amendment(escrow_id: String, amendment_id: u32) -> Result<EscrowRecord> {
+    let caller_id = caller();
+    
+    ESCROWS.with(|escrows| {
+        let mut escrows_map = escrows.borrow_mut();
+        let escrow = escrows_map.get_mut(&escrow_id).ok_or(EscrowError::NotFound)?;
+        
+        if caller_id != escrow.creator_id && caller_id != escrow.counterparty_id {
+            return Err(EscrowError::Unauthorized);
+        }
+        
+        let index = escrow
+            .amendments
+            .iter()
+            .position(|a| a.amendment_id == amendment_id)
+            .ok_or(EscrowError::NotFound)?;
+        
+        if escrow.amendments[index].status != AmendmentStatus::Pending {
+            return Err(EscrowError::InvalidStatus);
+        }
+        
+        // Only the other party can accept a proposal
+        if escrow.amendments[index].proposed_by == caller_id {
+            return Err(EscrowError::Unauthorized);
+        }
+        
+        // The escrow may have moved on since the proposal was made
+        if !amendments::is_amendable(&escrow.status) {
+            return Err(EscrowError::InvalidStatus);
+        }
+        let changes = escrow.amendments[index].changes.clone();
+        amendments::validate_changes(escrow, &changes)?;
+        
+        let adjustment = amendments::apply(escrow, &changes);
+        
+        let now = current_timestamp();
+        let amendment = &mut escrow.amendments[index];
+        amendment.status = AmendmentStatus::Accepted;
+        amendment.resolved_at = Some(now);
+        amendment.funding_adjustment = adjustment;
+        escrow.updated_at = now;
+        
+        Ok(escrow.clone())
+    })
+    .inspect(record_change)
+}
+
+#[update]
+fn reject_amendment(escrow_id: String, amendment_id: u32) -> Result<EscrowRecord> {
+    let caller_id = caller();
+    
+    ESCROWS.with(|escrows| {
+        let mut escrows_map = escrows.borrow_mut();
+        let escrow = escrows_map.get_mut(&escrow_id).ok_or(EscrowError::NotFound)?;
+        
+        if caller_id != escrow.creator_id && caller_id != escrow.counterparty_id {
+            return Err(EscrowError::Unauthorized);
+        }
+        
+        let amendment = escrow
+            .amendments
+            .iter_mut()
+            .find(|a| a.amendment_id == amendment_id)
+            .ok_or(EscrowError::NotFound)?;
+        
+        if amendment.status != AmendmentStatus::Pending {
+            return Err(EscrowError::InvalidStatus);
+        }
+        
+        // The proposer withdraws, the other party rejects
+        amendment.status = if amendment.proposed_by == caller_id {
+            AmendmentStatus::Withdrawn
+        } else {
+            AmendmentStatus::Rejected
+        };
+        
+        let now = current_timestamp();
+        amendment.resolved_at = Some(now);
+        escrow.updated_at = now;
+        
+        Ok(escrow.clone())
+    })
+    .inspect(record_change)
+}
+
+// Build the payout PSBT for a released or refunded multisig escrow. Only the
+// party receiving the funds chooses the destination address.
+#[update]
+async fn prepare_settlement(escrow_id: String, destination_address: String) -> Result<EscrowRecord> {
+    let caller_id = caller();
+    config::check_payouts_allowed()?;
+    config::check_cycles()?;
+    let destination = multisig::parse_address(&destination_address)?;
+    
+    let escrow = get_escrow(escrow_id.clone()).ok_or(EscrowError::NotFound)?;
+    let info = escrow
+        .multisig
+        .clone()
+        .ok_or_else(|| EscrowError::InternalError("Not a multisig escrow".to_string()))?;
+    
+    let (kind, beneficiary) = match escrow.status {
+        EscrowStatus::Released => (SettlementKind::Release, escrow.seller_id()),
+        EscrowStatus::Refunded => (SettlementKind::Refund, escrow.buyer_id()),
+        _ => return Err(EscrowError::InvalidStatus),
+    };
+    if caller_id != beneficiary {
+        return Err(EscrowError::Unauthorized);
+    }
+    
+    // A PSBT can be rebuilt until a signed transaction has been broadcast
+    let can_prepare = |escrow: &EscrowRecord| {
+        escrow
+            .settlement
+            .as_ref()
+            .is_none_or(|s| s.status == SettlementStatus::AwaitingSignatures)
+    };
+    if !can_prepare(&escrow) {
+        return Err(EscrowError::InvalidStatus);
+    }
+    
+    let (psbt, fee_satoshis) = multisig::build_settlement_psbt(&escrow, &info, &destination).await?;
+    
+    ESCROWS.with(|escrows| {
+        let mut escrows_map = escrows.borrow_mut();
+        let escrow = escrows_map.get_mut(&escrow_id).ok_or(EscrowError::NotFound)?;
+        
+        // Re-check after the signing round trip
+        if !can_prepare(escrow) {
+            return Err(EscrowError::InvalidStatus);
+        }
+        
+        let now = current_timestamp();
+        escrow.settlement = Some(Settlement {
+            kind,
+            destination_address,
+            txid: psbt.unsigned_tx.compute_txid().to_string(),
+            psbt: psbt.serialize(),
+            fee_satoshis,
+            status: SettlementStatus::AwaitingSignatures,
+            block_height: None,
+            confirmations: 0,
+            created_at: now,
+            updated_at: now,
+        });
+        escrow.updated_at = now;
+        
+        Ok(escrow.clone())
+    })
+    .inspect(record_change)
+}
+
+// Accept the transaction finalized by the cosigners' wallets and broadcast it
+#[update]
+async fn submit_signed_transaction(escrow_id: String, transaction: Vec<u8>) -> Result<EscrowRecord> {
+    let caller_id = caller();
+    config::check_payouts_allowed()?;
+    config::check_cycles()?;
+    
+    let escrow = get_escrow(escrow_id.clone()).ok_or(EscrowError::NotFound)?;
+    if caller_id != escrow.creator_id && caller_id != escrow.counterparty_id {
+        return Err(EscrowError::Unauthorized);
+    }
+    
+    let settlement = escrow.settlement.ok_or(EscrowError::InvalidStatus)?;
+    if settlement.status != SettlementStatus::AwaitingSignatures {
+        return Err(EscrowError::InvalidStatus);
+    }
+    
+    let tx = multisig::verify_signed_transaction(&settlement, &transaction)?;
+    multisig::broadcast(&tx).await?;
+    
+    ESCROWS.with(|escrows| {
+        let mut escrows_map = escrows.borrow_mut();
+        let escrow = escrows_map.get_mut(&escrow_id).ok_or(EscrowError::NotFound)?;
+        let settlement = escrow.settlement.as_mut().ok_or(EscrowError::InvalidStatus)?;
+        
+        let now = current_timestamp();
+        settlement.status = SettlementStatus::Broadcast;
+        settlement.updated_at = now;
+        escrow.updated_at = now;
+        
+        Ok(escrow.clone())
+    })
+    .inspect(record_change)
+}
+
+// Poll the Bitcoin API for the settlement transaction and its
+// confirmations. The transaction is found by txid, so one finalized and
+// broadcast by a party's own wallet is picked up too.
+#[update]
+async fn refresh_settlement(escrow_id: String) -> Result<EscrowRecord> {
+    config::check_cycles()?;
+    
+    let escrow = get_escrow(escrow_id.clone()).ok_or(EscrowError::NotFound)?;
+    let settlement = escrow.settlement.ok_or(EscrowError::InvalidStatus)?;
+    if settlement.status == SettlementStatus::Confirmed {
+        return Err(EscrowError::InvalidStatus);
+    }
+    
+    let txid = settlement.txid.clone();
+    let (found_height, tip_height) = multisig::locate_settlement(&settlement).await?;
+    
+    ESCROWS.with(|escrows| {
+        let mut escrows_map = escrows.borrow_mut();
+        let escrow = escrows_map.get_mut(&escrow_id).ok_or(EscrowError::NotFound)?;
+        let settlement = escrow.settlement.as_mut().ok_or(EscrowError::InvalidStatus)?;
+        
+        // Ignore the lookup if the PSBT was rebuilt (new txid) meanwhile
+        let found_height = found_height.filter(|_| settlement.txid == txid);
+        if let Some(height) = settlement.block_height.or(found_height) {
+            let now = current_timestamp();
+            settlement.block_height = Some(height);
+            settlement.confirmations = tip_height.saturating_sub(height) + 1;
+            settlement.status = if settlement.confirmations >= multisig::MIN_SETTLEMENT_CONFIRMATIONS {
+                SettlementStatus::Confirmed
+            } else {
+                SettlementStatus::Broadcast
+            };
+            settlement.updated_at = now;
+            escrow.updated_at = now;
+        }
+        
+        Ok(escrow.clone())
+    })
+    .inspect(record_change)
+}
+
+#[query]
+fn get_creation_limits() -> CreationLimits {
+    limits::get()
+}
+
+#[update]
+fn set_creation_limits(new_limits: CreationLimits) -> std::result::Result<CreationLimits, EscrowError> {
+    config::require_admin()?;
+    limits::set(new_limits)?;
+    Ok(limits::get())
+}
+
+#[query]
+fn get_config() -> EscrowConfig {
+    config::get()
+}
+
+#[update]
+fn set_config(new_config: EscrowConfig) -> std::result::Result<EscrowConfig, EscrowError> {
+    config::require_controller()?;
+    
+    // Multisig deposit addresses and canister keys are derived from the
+    // network and key name; changing either would orphan them
+    let current = config::get();
+    let changes_keys = new_config.bitcoin_network != current.bitcoin_network
+        || new_config.ecdsa_key_name != current.ecdsa_key_name;
+    let has_multisig = ESCROWS.with(|escrows| escrows.borrow().values().any(|e| e.multisig.is_some()));
+    if changes_keys && has_multisig {
+        return Err(EscrowError::InternalError(
+            "bitcoin_network and ecdsa_key_name cannot change once multisig escrows exist".to_string(),
+        ));
+    }
+    
+    config::set(new_config)?;
+    Ok(config::get())
+}
+
+#[query]
+fn get_admins() -> Vec<Principal> {
+    config::admins()
+}
+
+#[update]
+fn add_admin(principal: Principal) -> std::result::Result<Vec<Principal>, EscrowError> {
+    config::require_controller()?;
+    if principal == Principal::anonymous() {
+        return Err(EscrowError::InternalError("Anonymous principal cannot be an admin".to_string()));
+    }
+    config::add_admin(principal);
+    Ok(config::admins())
+}
+
+#[update]
+fn remove_admin(principal: Principal) -> std::result::Result<Vec<Principal>, EscrowError> {
+    config::require_controller()?;
+    config::remove_admin(&principal);
+    Ok(config::admins())
+}
+
+#[query]
+fn get_pause_state() -> PauseState {
+    config::pause_state()
+}
+
+// Circuit breaker for incidents: pausing creation and/or payouts leaves
+// queries, deposits, shipping and disputes available
+#[update]
+fn set_pause(
+    creation_paused: bool,
+    payouts_paused: bool,
+    reason: Option<String>,
+) -> std::result::Result<PauseState, EscrowError> {
+    config::require_admin()?;
+    let state = config::set_pause(creation_paused, payouts_paused, reason, current_timestamp())?;
+    ic_cdk::println!(
+        "Pause state changed by {}: creation={}, payouts={}",
+        caller(),
+        state.creation_paused,
+        state.payouts_paused
+    );
+    Ok(state)
 }
 
 #[query]
@@ -329,6 +1065,42 @@ fn get_total_escrows() -> u64 {
     ESCROWS.with(|escrows| escrows.borrow().len() as u64)
 }
 
+// Operational status for monitoring (cycles, memory, record counts)
+#[query]
+fn get_canister_status() -> CanisterStatus {
+    let (escrows, open_escrows, pending_settlements) = ESCROWS.with(|escrows| {
+        let escrows = escrows.borrow();
+        let open = escrows.values().filter(|e| !is_closed(&e.status)).count();
+        let pending = escrows
+            .values()
+            .filter(|e| {
+                e.settlement
+                    .as_ref()
+                    .is_some_and(|s| s.status != SettlementStatus::Confirmed)
+            })
+            .count();
+        (escrows.len(), open, pending)
+    });
+    
+    common::canister_status(vec![
+        ("escrows".to_string(), escrows as u64),
+        ("open_escrows".to_string(), open_escrows as u64),
+        ("pending_settlements".to_string(), pending_settlements as u64),
+    ])
+}
+
+#[query]
+fn get_escrow_stats() -> EscrowStats {
+    stats::summary()
+}
+
+// Time series for charts; `from`/`to` are timestamps, buckets are whole
+// days. An empty or inverted range gives an empty series.
+#[query]
+fn get_escrow_series(from: u64, to: u64, bucket_days: u32) -> Vec<SeriesPoint> {
+    stats::series(from, to, bucket_days)
+}
+
 #[query]
 fn get_escrows_by_status(status: EscrowStatus) -> Vec<EscrowRecord> {
     ESCROWS.with(|escrows| {
@@ -343,3 +1115,69 @@ fn get_escrows_by_status(status: EscrowStatus) -> Vec<EscrowRecord> {
 
 // Export candid interface
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ship(escrow_id: &str, inspection_period_days: u32) -> EscrowRecord {
+        let mut escrow = EscrowRecord::sample(escrow_id, 50_000, 0);
+        escrow.status = EscrowStatus::Shipped;
+        escrow.inspection_period_days = inspection_period_days;
+        escrow.shipment = Some(ShipmentInfo {
+            tracking_reference: None,
+            shipped_at: 0,
+            inspection_deadline: inspection_period_days as u64 * NANOS_PER_DAY,
+        });
+        store(&escrow);
+        escrow
+    }
+
+    fn store(escrow: &EscrowRecord) {
+        ESCROWS.with(|escrows| escrows.borrow_mut().insert(escrow.escrow_id.clone(), escrow.clone()));
+        index_inspection(escrow);
+    }
+
+    #[test]
+    fn an_extended_window_replaces_the_old_deadline() {
+        let mut escrow = ship("a", 7);
+        ship("b", 7);
+
+        let changes = AmendmentChanges {
+            amount_satoshis: None,
+            time_lock_unix: None,
+            inspection_period_days: Some(14),
+            milestones: None,
+            metadata: None,
+        };
+        amendments::apply(&mut escrow, &changes);
+        store(&escrow);
+
+        assert_eq!(due_inspections(7 * NANOS_PER_DAY), vec!["b".to_string()]);
+        assert_eq!(due_inspections(14 * NANOS_PER_DAY), vec!["b".to_string(), "a".to_string()]);
+        INSPECTION_DEADLINES.with(|index| assert_eq!(index.borrow().by_deadline.len(), 2));
+    }
+
+    #[test]
+    fn closed_escrows_leave_the_index() {
+        let mut escrow = ship("a", 7);
+        escrow.status = EscrowStatus::Disputed;
+        store(&escrow);
+
+        assert!(due_inspections(30 * NANOS_PER_DAY).is_empty());
+        INSPECTION_DEADLINES.with(|index| {
+            let index = index.borrow();
+            assert!(index.by_deadline.is_empty() && index.deadlines.is_empty());
+        });
+    }
+
+    #[test]
+    fn the_sweep_rechecks_the_record() {
+        let mut escrow = ship("a", 7);
+        // Changed without going through `record_change`
+        escrow.shipment.as_mut().unwrap().inspection_deadline = 14 * NANOS_PER_DAY;
+        ESCROWS.with(|escrows| escrows.borrow_mut().insert("a".to_string(), escrow));
+
+        assert!(due_inspections(7 * NANOS_PER_DAY).is_empty());
+    }
+}
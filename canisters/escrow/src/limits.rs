@@ -0,0 +1,244 @@
+// Anti-spam guards for `create_escrow`: per-principal open escrow cap,
+// sliding-window creation rate limit and per-currency amount bounds.
+use crate::types::*;
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const MAX_WINDOW_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+thread_local! {
+    static LIMITS: RefCell<CreationLimits> = RefCell::new(CreationLimits::default());
+    static CREATION_LOG: RefCell<HashMap<Principal, VecDeque<u64>>> = RefCell::new(HashMap::new());
+    static LAST_PRUNE: RefCell<u64> = RefCell::new(0);
+    // Escrows being created per principal, not yet in ESCROWS
+    static CREATING: RefCell<HashMap<Principal, usize>> = RefCell::new(HashMap::new());
+}
+
+impl Default for CreationLimits {
+    fn default() -> Self {
+        CreationLimits {
+            max_open_escrows_per_principal: 20,
+            max_creations_per_window: 10,
+            window_seconds: 60 * 60,
+            amount_limits: vec![
+                CurrencyLimits {
+                    currency: Currency::BTC,
+                    // Keep on-chain payouts comfortably above dust
+                    min_amount_satoshis: 10_000,
+                    max_amount_satoshis: 100_000_000,
+                },
+                CurrencyLimits {
+                    currency: Currency::CkBTC,
+                    min_amount_satoshis: 1_000,
+                    max_amount_satoshis: 100_000_000,
+                },
+            ],
+        }
+    }
+}
+
+pub fn get() -> CreationLimits {
+    LIMITS.with(|limits| limits.borrow().clone())
+}
+
+pub fn set(new_limits: CreationLimits) -> Result<()> {
+    if new_limits.window_seconds == 0 || new_limits.window_seconds > MAX_WINDOW_SECONDS {
+        return Err(EscrowError::InternalError(format!(
+            "window_seconds must be between 1 and {}",
+            MAX_WINDOW_SECONDS
+        )));
+    }
+    if new_limits
+        .amount_limits
+        .iter()
+        .any(|l| l.min_amount_satoshis == 0 || l.min_amount_satoshis > l.max_amount_satoshis)
+    {
+        return Err(EscrowError::InternalError(
+            "Amount limits need 0 < min <= max".to_string(),
+        ));
+    }
+
+    LIMITS.with(|limits| *limits.borrow_mut() = new_limits);
+    Ok(())
+}
+
+// Currencies without configured limits are not accepted
+pub fn check_amount(currency: &Currency, amount_satoshis: u64) -> Result<()> {
+    LIMITS.with(|limits| {
+        let limits = limits.borrow();
+        match limits.amount_limits.iter().find(|l| &l.currency == currency) {
+            Some(l) if amount_satoshis < l.min_amount_satoshis || amount_satoshis > l.max_amount_satoshis => {
+                Err(EscrowError::AmountOutOfRange {
+                    min: l.min_amount_satoshis,
+                    max: l.max_amount_satoshis,
+                })
+            }
+            Some(_) => Ok(()),
+            None => Err(EscrowError::UnsupportedCurrency),
+        }
+    })
+}
+
+// Counts an escrow being created against the principal's open escrows until
+// dropped, including on a trap in a callback, so concurrent calls cannot get
+// past the cap while one of them awaits
+pub struct OpenEscrowSlot(Principal);
+
+impl Drop for OpenEscrowSlot {
+    fn drop(&mut self) {
+        CREATING.with(|creating| {
+            let mut creating = creating.borrow_mut();
+            if let Some(count) = creating.get_mut(&self.0) {
+                *count -= 1;
+                if *count == 0 {
+                    creating.remove(&self.0);
+                }
+            }
+        });
+    }
+}
+
+// Hold the slot until the escrow has been inserted
+pub fn reserve_open_escrow(principal: Principal, open_escrows: usize) -> Result<OpenEscrowSlot> {
+    let max = LIMITS.with(|limits| limits.borrow().max_open_escrows_per_principal);
+    CREATING.with(|creating| {
+        let mut creating = creating.borrow_mut();
+        let count = creating.entry(principal).or_default();
+        if open_escrows + *count >= max as usize {
+            if *count == 0 {
+                creating.remove(&principal);
+            }
+            return Err(EscrowError::TooManyOpenEscrows);
+        }
+        *count += 1;
+        Ok(OpenEscrowSlot(principal))
+    })
+}
+
+fn expire(timestamps: &mut VecDeque<u64>, now: u64, window: u64) {
+    while timestamps.front().is_some_and(|t| now.saturating_sub(*t) >= window) {
+        timestamps.pop_front();
+    }
+}
+
+// Check the sliding window and, if allowed, count this creation against it
+pub fn record_creation(principal: Principal, now: u64) -> Result<()> {
+    let (max, window) = LIMITS.with(|limits| {
+        let limits = limits.borrow();
+        (
+            limits.max_creations_per_window,
+            limits.window_seconds.saturating_mul(NANOS_PER_SECOND),
+        )
+    });
+
+    CREATION_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+
+        // Forget principals with nothing left in the window; at most once
+        // per window, so the scan is amortised over the creations in it
+        let prune_due = LAST_PRUNE.with(|last| {
+            let mut last = last.borrow_mut();
+            let due = now.saturating_sub(*last) >= window;
+            if due {
+                *last = now;
+            }
+            due
+        });
+        if prune_due {
+            log.retain(|_, timestamps| {
+                expire(timestamps, now, window);
+                !timestamps.is_empty()
+            });
+        }
+
+        let timestamps = log.entry(principal).or_default();
+        expire(timestamps, now, window);
+        if timestamps.len() >= max as usize {
+            if timestamps.is_empty() {
+                log.remove(&principal);
+            }
+            return Err(EscrowError::RateLimited);
+        }
+        timestamps.push_back(now);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60 * NANOS_PER_SECOND;
+
+    #[test]
+    fn amounts_outside_the_currency_bounds_are_rejected() {
+        assert!(check_amount(&Currency::BTC, 10_000).is_ok());
+        assert!(check_amount(&Currency::BTC, 100_000_000).is_ok());
+        assert!(matches!(
+            check_amount(&Currency::BTC, 9_999),
+            Err(EscrowError::AmountOutOfRange { min: 10_000, max: 100_000_000 })
+        ));
+        assert!(matches!(
+            check_amount(&Currency::CkBTC, 100_000_001),
+            Err(EscrowError::AmountOutOfRange { .. })
+        ));
+        assert!(matches!(
+            check_amount(&Currency::ICP, 10_000),
+            Err(EscrowError::UnsupportedCurrency)
+        ));
+    }
+
+    #[test]
+    fn invalid_limits_are_not_applied() {
+        assert!(set(CreationLimits { window_seconds: 0, ..CreationLimits::default() }).is_err());
+
+        let mut limits = CreationLimits::default();
+        limits.amount_limits[0].min_amount_satoshis = limits.amount_limits[0].max_amount_satoshis + 1;
+        assert!(set(limits).is_err());
+
+        assert_eq!(get().window_seconds, CreationLimits::default().window_seconds);
+    }
+
+    #[test]
+    fn open_escrows_are_capped() {
+        let alice = Principal::from_slice(&[1]);
+        assert!(reserve_open_escrow(alice, 19).is_ok());
+        assert!(matches!(reserve_open_escrow(alice, 20), Err(EscrowError::TooManyOpenEscrows)));
+    }
+
+    #[test]
+    fn escrows_being_created_count_as_open() {
+        let alice = Principal::from_slice(&[1]);
+        let first = reserve_open_escrow(alice, 18).unwrap();
+        let second = reserve_open_escrow(alice, 18).unwrap();
+        // 18 open plus 2 in creation reach the cap of 20
+        assert!(reserve_open_escrow(alice, 18).is_err());
+        assert!(reserve_open_escrow(Principal::from_slice(&[2]), 18).is_ok());
+
+        drop(first);
+        let third = reserve_open_escrow(alice, 18).unwrap();
+        drop((second, third));
+        CREATING.with(|creating| assert!(creating.borrow().is_empty()));
+    }
+
+    #[test]
+    fn creations_are_limited_per_sliding_window() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let start = 100 * HOUR;
+
+        for i in 0..10 {
+            assert!(record_creation(alice, start + i).is_ok());
+        }
+        assert!(matches!(record_creation(alice, start + 10), Err(EscrowError::RateLimited)));
+        // Other principals have their own window
+        assert!(record_creation(bob, start + 10).is_ok());
+
+        // The first creation leaves the window after an hour
+        assert!(record_creation(alice, start + HOUR - 1).is_err());
+        assert!(record_creation(alice, start + HOUR).is_ok());
+        assert!(record_creation(alice, start + HOUR).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+// 2-of-3 multisig escrows (buyer, seller and the canister's threshold ECDSA key).
+//
+// The creator supplies its own key when creating the escrow and the
+// counterparty registers its key from its own principal; only then is the
+// P2WSH `sortedmulti(2, ...)` deposit address issued. Once an escrow is
+// released or refunded, the canister builds a PSBT paying out the deposited
+// UTXOs, adds its own signature and hands it to the parties' wallets, which
+// add the second signature, finalize and submit the transaction back.
+use crate::config;
+use crate::types::*;
+use bitcoin::absolute::LockTime;
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::Hash;
+use bitcoin::opcodes::all::{OP_CHECKMULTISIG, OP_PUSHNUM_2, OP_PUSHNUM_3};
+use bitcoin::script::Builder;
+use bitcoin::secp256k1::ecdsa::Signature as SecpSignature;
+use bitcoin::sighash::{EcdsaSighashType, SighashCache};
+use bitcoin::transaction::Version;
+use bitcoin::{
+    Address, Amount, Network, OutPoint, Psbt, PublicKey, ScriptBuf, Sequence, Transaction, TxIn,
+    TxOut, Txid, Witness,
+};
+use ic_cdk::api::management_canister::bitcoin::{
+    bitcoin_get_current_fee_percentiles, bitcoin_get_utxos, bitcoin_send_transaction,
+    BitcoinNetwork, GetCurrentFeePercentilesRequest, GetUtxosRequest, SendTransactionRequest,
+};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+    SignWithEcdsaArgument,
+};
+use std::str::FromStr;
+
+// Settlements are considered final after this many confirmations
+pub const MIN_SETTLEMENT_CONFIRMATIONS: u32 = 6;
+
+// Virtual size estimates for a P2WSH 2-of-3 spend
+const TX_OVERHEAD_VBYTES: u64 = 11;
+const MULTISIG_INPUT_VBYTES: u64 = 105;
+const OUTPUT_VBYTES: u64 = 43;
+
+fn network() -> Network {
+    match config::bitcoin_network() {
+        BitcoinNetwork::Mainnet => Network::Bitcoin,
+        BitcoinNetwork::Testnet => Network::Testnet,
+        BitcoinNetwork::Regtest => Network::Regtest,
+    }
+}
+
+fn key_id() -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: config::ecdsa_key_name(),
+    }
+}
+
+// Every escrow gets its own canister key
+fn derivation_path(escrow_id: &str) -> Vec<Vec<u8>> {
+    vec![escrow_id.as_bytes().to_vec()]
+}
+
+fn bitcoin_error(err: impl std::fmt::Debug) -> EscrowError {
+    EscrowError::InternalError(format!("Bitcoin error: {:?}", err))
+}
+
+pub fn parse_public_key(bytes: &[u8]) -> Result<PublicKey> {
+    match PublicKey::from_slice(bytes) {
+        Ok(key) if key.compressed => Ok(key),
+        _ => Err(EscrowError::InvalidPublicKey),
+    }
+}
+
+pub fn parse_address(address: &str) -> Result<Address> {
+    Address::from_str(address)
+        .and_then(|a| a.require_network(network()))
+        .map_err(|_| EscrowError::InvalidAddress)
+}
+
+pub async fn canister_public_key(escrow_id: &str) -> Result<Vec<u8>> {
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: derivation_path(escrow_id),
+        key_id: key_id(),
+    })
+    .await
+    .map_err(bitcoin_error)?;
+    Ok(response.public_key)
+}
+
+// `sortedmulti(2, buyer, seller, canister)` so any BIP-67 wallet derives the
+// same script
+pub fn witness_script(keys: [&PublicKey; 3]) -> ScriptBuf {
+    let mut keys = keys;
+    keys.sort_by_key(|k| k.to_bytes());
+
+    let mut builder = Builder::new().push_opcode(OP_PUSHNUM_2);
+    for key in keys {
+        builder = builder.push_key(key);
+    }
+    builder
+        .push_opcode(OP_PUSHNUM_3)
+        .push_opcode(OP_CHECKMULTISIG)
+        .into_script()
+}
+
+fn witness_script_of(multisig: &MultisigInfo) -> Result<ScriptBuf> {
+    multisig
+        .witness_script
+        .clone()
+        .map(ScriptBuf::from_bytes)
+        .ok_or_else(|| EscrowError::InternalError("Counterparty key not registered".to_string()))
+}
+
+pub fn deposit_address(multisig: &MultisigInfo) -> Result<String> {
+    Ok(Address::p2wsh(&witness_script_of(multisig)?, network()).to_string())
+}
+
+pub fn validate_keys(keys: &MultisigKeys) -> Result<()> {
+    parse_public_key(&keys.creator_pubkey).map(|_| ())
+}
+
+// The deposit address is only derived once the counterparty registers
+pub fn setup(keys: &MultisigKeys, canister_pubkey: Vec<u8>) -> Result<MultisigInfo> {
+    let creator = parse_public_key(&keys.creator_pubkey)?;
+    let canister = parse_public_key(&canister_pubkey)?;
+    if creator == canister {
+        return Err(EscrowError::InvalidPublicKey);
+    }
+
+    Ok(MultisigInfo {
+        creator_pubkey: keys.creator_pubkey.clone(),
+        counterparty_pubkey: None,
+        canister_pubkey,
+        witness_script: None,
+    })
+}
+
+// Add the counterparty's own key and derive the 2-of-3 script
+pub fn register_counterparty_key(multisig: &mut MultisigInfo, pubkey: Vec<u8>) -> Result<()> {
+    if multisig.counterparty_pubkey.is_some() {
+        return Err(EscrowError::InvalidStatus);
+    }
+    let creator = parse_public_key(&multisig.creator_pubkey)?;
+    let canister = parse_public_key(&multisig.canister_pubkey)?;
+    let counterparty = parse_public_key(&pubkey)?;
+    if counterparty == creator || counterparty == canister {
+        return Err(EscrowError::InvalidPublicKey);
+    }
+
+    multisig.witness_script = Some(witness_script([&creator, &counterparty, &canister]).into_bytes());
+    multisig.counterparty_pubkey = Some(pubkey);
+    Ok(())
+}
+
+// Falls back to the configured rate when the percentiles are empty (e.g.
+// regtest) and never exceeds the configured cap
+async fn fee_rate_sat_per_vb() -> u64 {
+    let config = config::get();
+    let percentiles = bitcoin_get_current_fee_percentiles(GetCurrentFeePercentilesRequest {
+        network: config::bitcoin_network(),
+    })
+    .await
+    .map(|(p,)| p)
+    .unwrap_or_default();
+
+    // Median of the last blocks, in millisatoshi per byte
+    percentiles
+        .get(percentiles.len() / 2)
+        .map(|msat| (msat / 1000).max(1))
+        .unwrap_or(config.fallback_fee_rate_sat_per_vb)
+        .min(config.max_fee_rate_sat_per_vb)
+}
+
+// Build the payout PSBT for all deposited UTXOs and add the canister's
+// signature to every input
+pub async fn build_settlement_psbt(
+    escrow: &EscrowRecord,
+    multisig: &MultisigInfo,
+    destination: &Address,
+) -> Result<(Psbt, u64)> {
+    if escrow.utxos.is_empty() {
+        return Err(EscrowError::InsufficientFunds);
+    }
+
+    let witness_script = witness_script_of(multisig)?;
+    let funding_script = witness_script.to_p2wsh();
+
+    let mut inputs = Vec::with_capacity(escrow.utxos.len());
+    let mut spent = Vec::with_capacity(escrow.utxos.len());
+    for utxo in &escrow.utxos {
+        let txid = Txid::from_str(&utxo.txid).map_err(|_| {
+            EscrowError::InternalError(format!("Invalid deposit txid {}", utxo.txid))
+        })?;
+        inputs.push(TxIn {
+            previous_output: OutPoint::new(txid, utxo.vout),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new(),
+        });
+        spent.push(TxOut {
+            value: Amount::from_sat(utxo.amount_satoshis),
+            script_pubkey: funding_script.clone(),
+        });
+    }
+
+    let total: u64 = escrow.utxos.iter().map(|u| u.amount_satoshis).sum();
+    let vsize = TX_OVERHEAD_VBYTES + MULTISIG_INPUT_VBYTES * inputs.len() as u64 + OUTPUT_VBYTES;
+    let fee = vsize * fee_rate_sat_per_vb().await;
+    let payout = total
+        .checked_sub(fee)
+        .filter(|p| *p > destination.script_pubkey().minimal_non_dust().to_sat())
+        .ok_or(EscrowError::InsufficientFunds)?;
+
+    let unsigned_tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: inputs,
+        output: vec![TxOut {
+            value: Amount::from_sat(payout),
+            script_pubkey: destination.script_pubkey(),
+        }],
+    };
+
+    let mut psbt = Psbt::from_unsigned_tx(unsigned_tx.clone()).map_err(bitcoin_error)?;
+    let canister_key = parse_public_key(&multisig.canister_pubkey)?;
+    let mut sighasher = SighashCache::new(&unsigned_tx);
+
+    for (index, (input, spent_output)) in psbt.inputs.iter_mut().zip(spent).enumerate() {
+        let sighash = sighasher
+            .p2wsh_signature_hash(index, &witness_script, spent_output.value, EcdsaSighashType::All)
+            .map_err(bitcoin_error)?;
+
+        let (response,) = sign_with_ecdsa(SignWithEcdsaArgument {
+            message_hash: sighash.to_byte_array().to_vec(),
+            derivation_path: derivation_path(&escrow.escrow_id),
+            key_id: key_id(),
+        })
+        .await
+        .map_err(bitcoin_error)?;
+
+        let mut signature = SecpSignature::from_compact(&response.signature).map_err(bitcoin_error)?;
+        signature.normalize_s();
+
+        input.witness_utxo = Some(spent_output);
+        input.witness_script = Some(witness_script.clone());
+        input.sighash_type = Some(EcdsaSighashType::All.into());
+        input.partial_sigs.insert(
+            canister_key,
+            bitcoin::ecdsa::Signature {
+                signature,
+                sighash_type: EcdsaSighashType::All,
+            },
+        );
+    }
+
+    Ok((psbt, fee))
+}
+
+// Check a finalized transaction against the PSBT the canister signed. The
+// txid excludes witness data, so it only matches if inputs and outputs are
+// exactly the ones agreed.
+pub fn verify_signed_transaction(settlement: &Settlement, raw_tx: &[u8]) -> Result<Transaction> {
+    let tx: Transaction = deserialize(raw_tx)
+        .map_err(|_| EscrowError::InternalError("Malformed transaction".to_string()))?;
+    if tx.compute_txid().to_string() != settlement.txid {
+        return Err(EscrowError::InternalError(
+            "Transaction does not match the settlement PSBT".to_string(),
+        ));
+    }
+    if tx.input.iter().any(|input| input.witness.is_empty()) {
+        return Err(EscrowError::InternalError("Transaction is not fully signed".to_string()));
+    }
+    Ok(tx)
+}
+
+pub async fn broadcast(tx: &Transaction) -> Result<()> {
+    bitcoin_send_transaction(SendTransactionRequest {
+        transaction: serialize(tx),
+        network: config::bitcoin_network(),
+    })
+    .await
+    .map_err(bitcoin_error)
+}
+
+// The height of the block that mined the settlement transaction, if it is
+// found at the destination address, and the current tip height. Works no
+// matter who broadcast the transaction.
+pub async fn locate_settlement(settlement: &Settlement) -> Result<(Option<u32>, u32)> {
+    let txid = Txid::from_str(&settlement.txid).map_err(bitcoin_error)?;
+    let (response,) = bitcoin_get_utxos(GetUtxosRequest {
+        address: settlement.destination_address.clone(),
+        network: config::bitcoin_network(),
+        filter: None,
+    })
+    .await
+    .map_err(bitcoin_error)?;
+
+    let height = response
+        .utxos
+        .iter()
+        .find(|u| u.outpoint.txid == txid.as_byte_array().to_vec())
+        .map(|u| u.height);
+    Ok((height, response.tip_height))
+}
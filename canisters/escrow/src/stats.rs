@@ -0,0 +1,323 @@
+// Escrow analytics maintained incrementally from record changes, so the
+// stats queries never scan the full escrow map.
+use crate::types::*;
+use crate::NANOS_PER_DAY;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+// What the stats currently account for, per escrow
+struct Snapshot {
+    status: EscrowStatus,
+    currency: Currency,
+    amount_satoshis: u64,
+    disputed: bool,
+}
+
+#[derive(Default)]
+struct Counter {
+    count: u64,
+    volume_satoshis: u64,
+}
+
+impl Counter {
+    fn add(&mut self, amount: u64) {
+        self.count += 1;
+        self.volume_satoshis += amount;
+    }
+
+    fn remove(&mut self, amount: u64) {
+        self.count = self.count.saturating_sub(1);
+        self.volume_satoshis = self.volume_satoshis.saturating_sub(amount);
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    snapshots: HashMap<String, Snapshot>,
+    by_status: HashMap<EscrowStatus, Counter>,
+    by_currency: HashMap<Currency, Counter>,
+    // Keyed by day number since the epoch
+    by_day: BTreeMap<u64, SeriesPoint>,
+    // Number of releases per time to release, in seconds
+    release_durations: BTreeMap<u64, u64>,
+    disputed: u64,
+    released: u64,
+    refunded: u64,
+}
+
+thread_local! {
+    static STATS: RefCell<Stats> = RefCell::new(Stats::default());
+}
+
+fn day_bucket(stats: &mut Stats, timestamp: u64) -> &mut SeriesPoint {
+    let day = timestamp / NANOS_PER_DAY;
+    stats.by_day.entry(day).or_insert_with(|| SeriesPoint {
+        bucket_start: day * NANOS_PER_DAY,
+        created: 0,
+        created_volume_satoshis: 0,
+        released: 0,
+        released_volume_satoshis: 0,
+        refunded: 0,
+        disputed: 0,
+    })
+}
+
+// Account for a created or changed escrow; call after every mutation
+pub fn observe(escrow: &EscrowRecord) {
+    STATS.with(|stats| {
+        let stats = &mut *stats.borrow_mut();
+        let now = escrow.updated_at;
+
+        let previous = stats.snapshots.remove(&escrow.escrow_id);
+        let disputed = previous.as_ref().is_some_and(|p| p.disputed)
+            || escrow.status == EscrowStatus::Disputed;
+
+        match &previous {
+            Some(prev) => {
+                stats.by_status.entry(prev.status.clone()).or_default().remove(prev.amount_satoshis);
+                stats.by_currency.entry(prev.currency).or_default().remove(prev.amount_satoshis);
+            }
+            None => {
+                let bucket = day_bucket(stats, escrow.created_at);
+                bucket.created += 1;
+                bucket.created_volume_satoshis += escrow.amount_satoshis;
+            }
+        }
+        stats.by_status.entry(escrow.status.clone()).or_default().add(escrow.amount_satoshis);
+        stats.by_currency.entry(escrow.currency).or_default().add(escrow.amount_satoshis);
+
+        let status_changed = previous.as_ref().is_none_or(|p| p.status != escrow.status);
+        if status_changed {
+            match escrow.status {
+                EscrowStatus::Released => {
+                    stats.released += 1;
+                    let seconds = now.saturating_sub(escrow.created_at) / 1_000_000_000;
+                    *stats.release_durations.entry(seconds).or_default() += 1;
+
+                    let bucket = day_bucket(stats, now);
+                    bucket.released += 1;
+                    bucket.released_volume_satoshis += escrow.amount_satoshis;
+                }
+                EscrowStatus::Refunded => {
+                    stats.refunded += 1;
+                    day_bucket(stats, now).refunded += 1;
+                }
+                EscrowStatus::Disputed => {
+                    day_bucket(stats, now).disputed += 1;
+                }
+                _ => {}
+            }
+        }
+        if disputed && !previous.as_ref().is_some_and(|p| p.disputed) {
+            stats.disputed += 1;
+        }
+
+        stats.snapshots.insert(
+            escrow.escrow_id.clone(),
+            Snapshot {
+                status: escrow.status.clone(),
+                currency: escrow.currency,
+                amount_satoshis: escrow.amount_satoshis,
+                disputed,
+            },
+        );
+    })
+}
+
+// Median of `count` values given as value -> occurrences
+fn median(counts: &BTreeMap<u64, u64>, count: u64) -> Option<u64> {
+    if count == 0 {
+        return None;
+    }
+    // Value at a 0-based position in sorted order
+    let nth = |position: u64| {
+        let mut seen = 0;
+        counts.iter().find_map(|(value, n)| {
+            seen += n;
+            (seen > position).then_some(*value)
+        })
+    };
+    let upper = nth(count / 2)?;
+    if count % 2 == 1 {
+        return Some(upper);
+    }
+    Some((nth(count / 2 - 1)? + upper) / 2)
+}
+
+fn rate_bps(part: u64, total: u64) -> u32 {
+    if total == 0 {
+        return 0;
+    }
+    (part * 10_000 / total) as u32
+}
+
+pub fn summary() -> EscrowStats {
+    STATS.with(|stats| {
+        let stats = stats.borrow();
+        let total = stats.snapshots.len() as u64;
+
+        let mut by_status: Vec<StatusStats> = stats
+            .by_status
+            .iter()
+            .filter(|(_, c)| c.count > 0)
+            .map(|(status, c)| StatusStats {
+                status: status.clone(),
+                count: c.count,
+                volume_satoshis: c.volume_satoshis,
+            })
+            .collect();
+        by_status.sort_by_key(|s| std::cmp::Reverse(s.count));
+
+        let mut by_currency: Vec<CurrencyStats> = stats
+            .by_currency
+            .iter()
+            .filter(|(_, c)| c.count > 0)
+            .map(|(currency, c)| CurrencyStats {
+                currency: *currency,
+                count: c.count,
+                volume_satoshis: c.volume_satoshis,
+            })
+            .collect();
+        by_currency.sort_by_key(|c| std::cmp::Reverse(c.count));
+
+        let median_time_to_release_seconds = median(&stats.release_durations, stats.released);
+
+        EscrowStats {
+            total_escrows: total,
+            by_status,
+            by_currency,
+            median_time_to_release_seconds,
+            dispute_rate_bps: rate_bps(stats.disputed, total),
+            refund_rate_bps: rate_bps(stats.refunded, stats.released + stats.refunded),
+        }
+    })
+}
+
+// Day buckets in [from, to), merged into buckets of `bucket_days`; empty
+// when the range is
+pub fn series(from: u64, to: u64, bucket_days: u32) -> Vec<SeriesPoint> {
+    if from >= to {
+        return Vec::new();
+    }
+    let bucket_days = bucket_days.max(1) as u64;
+    STATS.with(|stats| {
+        let stats = stats.borrow();
+        let first_day = from / NANOS_PER_DAY;
+        let last_day = to.div_ceil(NANOS_PER_DAY);
+
+        let mut points: Vec<SeriesPoint> = Vec::new();
+        for (day, point) in stats.by_day.range(first_day..last_day) {
+            let bucket = first_day + (day - first_day) / bucket_days * bucket_days;
+            let bucket_start = bucket * NANOS_PER_DAY;
+            if points.last().is_none_or(|p| p.bucket_start != bucket_start) {
+                points.push(SeriesPoint {
+                    bucket_start,
+                    created: 0,
+                    created_volume_satoshis: 0,
+                    released: 0,
+                    released_volume_satoshis: 0,
+                    refunded: 0,
+                    disputed: 0,
+                });
+            }
+            let merged = points.last_mut().unwrap();
+            merged.created += point.created;
+            merged.created_volume_satoshis += point.created_volume_satoshis;
+            merged.released += point.released;
+            merged.released_volume_satoshis += point.released_volume_satoshis;
+            merged.refunded += point.refunded;
+            merged.disputed += point.disputed;
+        }
+        points
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn created(escrow_id: &str, amount: u64, day: u64) -> EscrowRecord {
+        let escrow = EscrowRecord::sample(escrow_id, amount, day * NANOS_PER_DAY + 1);
+        observe(&escrow);
+        escrow
+    }
+
+    fn change(escrow: &mut EscrowRecord, status: EscrowStatus, day: u64) {
+        escrow.status = status;
+        escrow.updated_at = day * NANOS_PER_DAY + 2;
+        observe(escrow);
+    }
+
+    #[test]
+    fn series_merges_days_into_buckets() {
+        created("a", 100, 10);
+        created("b", 200, 11);
+        let mut c = created("c", 300, 12);
+        change(&mut c, EscrowStatus::Released, 13);
+        created("d", 400, 20);
+
+        let points = series(10 * NANOS_PER_DAY, 14 * NANOS_PER_DAY, 2);
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].bucket_start, 10 * NANOS_PER_DAY);
+        assert_eq!(points[0].created, 2);
+        assert_eq!(points[0].created_volume_satoshis, 300);
+        assert_eq!(points[1].bucket_start, 12 * NANOS_PER_DAY);
+        assert_eq!(points[1].created, 1);
+        assert_eq!(points[1].released, 1);
+        assert_eq!(points[1].released_volume_satoshis, 300);
+
+        // Buckets start at `from`, and days without activity are left out
+        let points = series(11 * NANOS_PER_DAY, 21 * NANOS_PER_DAY, 7);
+        let starts: Vec<u64> = points.iter().map(|p| p.bucket_start / NANOS_PER_DAY).collect();
+        assert_eq!(starts, vec![11, 18]);
+        assert_eq!(points[0].created, 2);
+        assert_eq!(points[1].created, 1);
+    }
+
+    #[test]
+    fn series_of_an_empty_range_is_empty() {
+        created("a", 100, 10);
+        assert!(series(11 * NANOS_PER_DAY, 11 * NANOS_PER_DAY, 1).is_empty());
+        assert!(series(12 * NANOS_PER_DAY, 11 * NANOS_PER_DAY, 1).is_empty());
+        // A bucket size of 0 is treated as a day
+        assert_eq!(series(10 * NANOS_PER_DAY, 11 * NANOS_PER_DAY, 0).len(), 1);
+    }
+
+    #[test]
+    fn status_changes_are_counted_once() {
+        let mut escrow = created("a", 100, 10);
+        change(&mut escrow, EscrowStatus::Disputed, 11);
+        change(&mut escrow, EscrowStatus::Disputed, 11);
+        change(&mut escrow, EscrowStatus::Refunded, 12);
+
+        let summary = summary();
+        assert_eq!(summary.total_escrows, 1);
+        assert_eq!(summary.dispute_rate_bps, 10_000);
+        assert_eq!(summary.refund_rate_bps, 10_000);
+        let points = series(10 * NANOS_PER_DAY, 13 * NANOS_PER_DAY, 1);
+        assert_eq!(points.iter().map(|p| p.disputed).sum::<u64>(), 1);
+        assert_eq!(points.iter().map(|p| p.refunded).sum::<u64>(), 1);
+    }
+
+    #[test]
+    fn median_reads_the_duration_counts() {
+        let counts: BTreeMap<u64, u64> = [(10, 2), (20, 1), (40, 1)].into_iter().collect();
+        assert_eq!(median(&counts, 4), Some(15));
+        assert_eq!(median(&counts, 0), None);
+
+        let counts: BTreeMap<u64, u64> = [(10, 1), (20, 3), (40, 1)].into_iter().collect();
+        assert_eq!(median(&counts, 5), Some(20));
+    }
+
+    #[test]
+    fn median_time_to_release_is_tracked() {
+        for (i, days) in [1, 2, 4].into_iter().enumerate() {
+            let id = format!("r{}", i);
+            let mut escrow = created(&id, 100, 0);
+            change(&mut escrow, EscrowStatus::Released, days);
+        }
+        // Released at 2ns past the day, created 1ns past midnight
+        let median = summary().median_time_to_release_seconds.unwrap();
+        assert_eq!(median, 2 * NANOS_PER_DAY / 1_000_000_000);
+    }
+}
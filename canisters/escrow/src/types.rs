@@ -1,4 +1,5 @@
 use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
 use serde::Serialize;
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -9,20 +10,134 @@ pub struct UTXO {
     pub confirmations: u32,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum EscrowStatus {
     Created,
     Funded,
+    Shipped,
     Delivered,
     Released,
     Refunded,
     Disputed,
 }
 
+// Which side of the trade the creator is on; the other party takes the other
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartyRole {
+    Buyer,
+    Seller,
+}
+
+// Shared with the wallet; an escrow currency is only accepted once amount
+// limits are configured for it
+pub use common::Asset as Currency;
+pub use common::CanisterStatus;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ShipmentInfo {
+    pub tracking_reference: Option<String>,
+    pub shipped_at: u64,
+    pub inspection_deadline: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Milestone {
+    pub description: String,
+    pub amount_satoshis: u64,
+}
+
+// Fields a party may propose to change; `None` leaves the field untouched
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AmendmentChanges {
+    pub amount_satoshis: Option<u64>,
+    pub time_lock_unix: Option<u64>,
+    pub inspection_period_days: Option<u32>,
+    pub milestones: Option<Vec<Milestone>>,
+    pub metadata: Option<Vec<(String, String)>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AmendmentStatus {
+    Pending,
+    Accepted,
+    Rejected,
+    Withdrawn,
+}
+
+// Funding correction owed after an accepted amount change
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum FundingAdjustment {
+    TopUp(u64),
+    Refund(u64),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Amendment {
+    pub amendment_id: u32,
+    pub proposed_by: Principal,
+    pub changes: AmendmentChanges,
+    pub status: AmendmentStatus,
+    pub proposed_at: u64,
+    pub resolved_at: Option<u64>,
+    pub funding_adjustment: Option<FundingAdjustment>,
+}
+
+// The creator's own cosigner key (compressed SEC1); the counterparty
+// registers its key from its own principal
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MultisigKeys {
+    pub creator_pubkey: Vec<u8>,
+}
+
+// Each party supplies only its own key, so neither can hold two of the
+// three. `witness_script` is set, and the deposit address issued, once the
+// counterparty has registered its key.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MultisigInfo {
+    pub creator_pubkey: Vec<u8>,
+    pub counterparty_pubkey: Option<Vec<u8>>,
+    pub canister_pubkey: Vec<u8>,
+    pub witness_script: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SettlementKind {
+    Release,
+    Refund,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SettlementStatus {
+    AwaitingSignatures,
+    Broadcast,
+    Confirmed,
+}
+
+// Payout of a multisig escrow; `psbt` is BIP-174 serialized and already
+// carries the canister's signatures
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Settlement {
+    pub kind: SettlementKind,
+    pub destination_address: String,
+    pub psbt: Vec<u8>,
+    pub txid: String,
+    pub fee_satoshis: u64,
+    pub status: SettlementStatus,
+    // Height of the block that mined `txid`; confirmations are counted from
+    // it even after the payout output has been spent
+    pub block_height: Option<u32>,
+    pub confirmations: u32,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// Escrow funded from the buyer's wallet balance instead of a deposit; the
+// hold is settled in the wallet canister once the escrow closes
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub enum Currency {
-    BTC,
-    CkBTC,
+pub enum WalletHoldStatus {
+    Held,
+    Released,
+    Returned,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -30,6 +145,7 @@ pub struct EscrowRecord {
     pub escrow_id: String,
     pub creator_id: Principal,
     pub counterparty_id: Principal,
+    pub creator_role: PartyRole,
     pub amount_satoshis: u64,
     pub currency: Currency,
     pub deposit_address: String,
@@ -42,14 +158,79 @@ pub struct EscrowRecord {
     pub tags: Vec<String>,
     pub creator_confirmed_delivery: bool,
     pub counterparty_confirmed_delivery: bool,
+    pub inspection_period_days: u32,
+    pub shipment: Option<ShipmentInfo>,
+    pub milestones: Vec<Milestone>,
+    pub metadata: Vec<(String, String)>,
+    pub amendments: Vec<Amendment>,
+    pub funding_adjustment: Option<FundingAdjustment>,
+    pub multisig: Option<MultisigInfo>,
+    pub settlement: Option<Settlement>,
+    pub wallet_hold: Option<WalletHoldStatus>,
+}
+
+impl EscrowRecord {
+    pub fn buyer_id(&self) -> Principal {
+        match self.creator_role {
+            PartyRole::Buyer => self.creator_id,
+            PartyRole::Seller => self.counterparty_id,
+        }
+    }
+
+    pub fn seller_id(&self) -> Principal {
+        match self.creator_role {
+            PartyRole::Buyer => self.counterparty_id,
+            PartyRole::Seller => self.creator_id,
+        }
+    }
+}
+
+// A new BTC escrow between two fixed parties, for unit tests
+#[cfg(test)]
+impl EscrowRecord {
+    pub fn sample(escrow_id: &str, amount_satoshis: u64, created_at: u64) -> EscrowRecord {
+        EscrowRecord {
+            escrow_id: escrow_id.to_string(),
+            creator_id: Principal::from_slice(&[1]),
+            counterparty_id: Principal::from_slice(&[2]),
+            creator_role: PartyRole::Buyer,
+            amount_satoshis,
+            currency: Currency::BTC,
+            deposit_address: String::new(),
+            utxos: Vec::new(),
+            status: EscrowStatus::Created,
+            time_lock_unix: None,
+            created_at,
+            updated_at: created_at,
+            ai_risk_score: None,
+            tags: Vec::new(),
+            creator_confirmed_delivery: false,
+            counterparty_confirmed_delivery: false,
+            inspection_period_days: 7,
+            shipment: None,
+            milestones: Vec::new(),
+            metadata: Vec::new(),
+            amendments: Vec::new(),
+            funding_adjustment: None,
+            multisig: None,
+            settlement: None,
+            wallet_hold: None,
+        }
+    }
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct CreateEscrowParams {
     pub counterparty_id: Principal,
+    // Defaults to Buyer
+    pub creator_role: Option<PartyRole>,
     pub amount_satoshis: u64,
     pub currency: Currency,
     pub time_lock_unix: Option<u64>,
+    pub inspection_period_days: Option<u32>,
+    pub milestones: Option<Vec<Milestone>>,
+    pub metadata: Option<Vec<(String, String)>>,
+    pub multisig: Option<MultisigKeys>,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -58,6 +239,102 @@ pub struct CreateEscrowResult {
     pub deposit_address: String,
 }
 
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CurrencyLimits {
+    pub currency: Currency,
+    pub min_amount_satoshis: u64,
+    pub max_amount_satoshis: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CreationLimits {
+    pub max_open_escrows_per_principal: u32,
+    pub max_creations_per_window: u32,
+    pub window_seconds: u64,
+    pub amount_limits: Vec<CurrencyLimits>,
+}
+
+// Deployment settings, passed as the init/upgrade argument or set by a
+// controller
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EscrowConfig {
+    pub bitcoin_network: BitcoinNetwork,
+    pub ecdsa_key_name: String,
+    pub wallet_canister: Option<Principal>,
+    pub reputation_canister: Option<Principal>,
+    pub identity_canister: Option<Principal>,
+    pub ai_orchestration_canister: Option<Principal>,
+    // Settlement fee rate used when the fee percentiles are empty, and the
+    // cap applied to the network rate
+    pub fallback_fee_rate_sat_per_vb: u64,
+    pub max_fee_rate_sat_per_vb: u64,
+    // Below this balance the canister refuses new escrows and settlements
+    pub min_cycles_balance: u128,
+}
+
+// Every field is optional; on upgrade, omitted fields keep their current value
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct EscrowInitArgs {
+    pub config: Option<EscrowConfig>,
+    pub creation_limits: Option<CreationLimits>,
+    pub admins: Option<Vec<Principal>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PauseState {
+    pub creation_paused: bool,
+    pub payouts_paused: bool,
+    pub reason: Option<String>,
+    pub updated_at: u64,
+    pub updated_by: Option<Principal>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StatusStats {
+    pub status: EscrowStatus,
+    pub count: u64,
+    pub volume_satoshis: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CurrencyStats {
+    pub currency: Currency,
+    pub count: u64,
+    pub volume_satoshis: u64,
+}
+
+// Rates are in basis points (1/100 of a percent)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EscrowStats {
+    pub total_escrows: u64,
+    pub by_status: Vec<StatusStats>,
+    pub by_currency: Vec<CurrencyStats>,
+    pub median_time_to_release_seconds: Option<u64>,
+    pub dispute_rate_bps: u32,
+    pub refund_rate_bps: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SeriesPoint {
+    pub bucket_start: u64,
+    pub created: u64,
+    pub created_volume_satoshis: u64,
+    pub released: u64,
+    pub released_volume_satoshis: u64,
+    pub refunded: u64,
+    pub disputed: u64,
+}
+
+// `escrow_candid` is the candid encoding of the EscrowRecord; its SHA-256 is
+// the value certified at `escrows/<escrow_id>` in `witness`
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CertifiedEscrow {
+    pub escrow_id: String,
+    pub escrow_candid: Option<Vec<u8>>,
+    pub certificate: Option<Vec<u8>>,
+    pub witness: Vec<u8>,
+}
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub enum EscrowError {
     NotFound,
@@ -67,6 +344,17 @@ pub enum EscrowError {
     TimeLockNotExpired,
     AlreadyConfirmed,
     InvalidAmount,
+    InvalidInspectionPeriod,
+    InvalidAmendment(String),
+    AmendmentPending,
+    InvalidAddress,
+    InvalidPublicKey,
+    AmountOutOfRange { min: u64, max: u64 },
+    TooManyOpenEscrows,
+    RateLimited,
+    Paused,
+    LowCycles,
+    UnsupportedCurrency,
     InternalError(String),
 }
 
@@ -4,6 +4,7 @@ use ic_cdk_macros::{init, post_upgrade, pre_upgrade};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use candid::{CandidType, Deserialize, Principal};
+use common::CanisterStatus;
 use serde::Serialize;
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -169,7 +170,11 @@ fn update_profile(params: UpdateProfileParams) -> std::result::Result<UserProfil
 
 #[update]
 fn update_kyc_level(principal_id: Principal, kyc_level: u8) -> std::result::Result<UserProfile, ProfileError> {
-    // In production, verify caller is authorized
+    // The wallet sizes withdrawal limits by this level, so only controllers
+    // may set it
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err(ProfileError::Unauthorized);
+    }
     
     if kyc_level > 3 {
         return Err(ProfileError::InvalidInput("KYC level must be 0-3".to_string()));
@@ -189,7 +194,11 @@ fn update_kyc_level(principal_id: Principal, kyc_level: u8) -> std::result::Resu
 
 #[update]
 fn set_agent_status(principal_id: Principal, is_agent: bool) -> std::result::Result<UserProfile, ProfileError> {
-    // In production, verify caller is authorized
+    // The wallet lets agents run cash-ins and cash-outs, so only controllers
+    // may set it
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err(ProfileError::Unauthorized);
+    }
     
     PROFILES.with(|profiles| {
         let mut profiles_map = profiles.borrow_mut();
@@ -234,4 +243,13 @@ fn get_total_users() -> u64 {
     PROFILES.with(|profiles| profiles.borrow().len() as u64)
 }
 
+// Operational status for monitoring (cycles, memory, record counts)
+#[query]
+fn get_canister_status() -> CanisterStatus {
+    common::canister_status(vec![
+        ("profiles".to_string(), PROFILES.with(|p| p.borrow().len() as u64)),
+        ("usernames".to_string(), USERNAME_INDEX.with(|u| u.borrow().len() as u64)),
+    ])
+}
+
 ic_cdk::export_candid!();
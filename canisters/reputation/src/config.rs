@@ -0,0 +1,67 @@
+// Controller-managed configuration and the trusted writer set.
+//
+// Only writers may change reputation: controllers, the configured escrow
+// canister and principals a controller adds (e.g. an admin service). Both
+// survive upgrades.
+use crate::*;
+use std::collections::BTreeSet;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReputationConfig {
+    // Checked for every escrow a mutation refers to
+    pub escrow_canister: Option<Principal>,
+}
+
+thread_local! {
+    static CONFIG: RefCell<ReputationConfig> = RefCell::new(ReputationConfig::default());
+    static WRITERS: RefCell<BTreeSet<Principal>> = RefCell::new(BTreeSet::new());
+}
+
+pub fn get() -> ReputationConfig {
+    CONFIG.with(|config| config.borrow().clone())
+}
+
+pub fn set(new_config: ReputationConfig) -> std::result::Result<(), ReputationError> {
+    if new_config.escrow_canister == Some(Principal::anonymous()) {
+        return Err(ReputationError::InvalidConfig);
+    }
+
+    CONFIG.with(|config| *config.borrow_mut() = new_config);
+    Ok(())
+}
+
+pub fn escrow_canister() -> std::result::Result<Principal, ReputationError> {
+    CONFIG
+        .with(|config| config.borrow().escrow_canister)
+        .ok_or(ReputationError::InvalidConfig)
+}
+
+pub fn is_writer(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+        || CONFIG.with(|config| config.borrow().escrow_canister == Some(*principal))
+        || WRITERS.with(|w| w.borrow().contains(principal))
+}
+
+pub fn require_writer() -> std::result::Result<(), ReputationError> {
+    if !is_writer(&caller()) {
+        return Err(ReputationError::Unauthorized);
+    }
+    Ok(())
+}
+
+pub fn writers() -> Vec<Principal> {
+    WRITERS.with(|w| w.borrow().iter().cloned().collect())
+}
+
+pub fn add_writer(writer: Principal) {
+    WRITERS.with(|w| w.borrow_mut().insert(writer));
+}
+
+pub fn remove_writer(writer: &Principal) {
+    WRITERS.with(|w| w.borrow_mut().remove(writer));
+}
+
+pub fn restore(config: ReputationConfig, writers: Vec<Principal>) {
+    CONFIG.with(|c| *c.borrow_mut() = config);
+    WRITERS.with(|w| *w.borrow_mut() = writers.into_iter().collect());
+}
@@ -0,0 +1,82 @@
+// Escrow verification for reputation changes.
+//
+// Every mutation names an escrow, which is looked up on the escrow canister:
+// it has to exist, the user has to be one of its parties and it has to be in
+// a state that justifies the change. Each (escrow, user, kind) counts once;
+// the counted keys are kept in stable memory.
+use crate::memory::{self, Memory};
+use crate::*;
+use ic_stable_structures::StableBTreeMap;
+
+// The escrow canister's status variant; all cases are needed to decode it
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum EscrowStatus {
+    Created,
+    Funded,
+    Shipped,
+    Delivered,
+    Released,
+    Refunded,
+    Disputed,
+}
+
+// The fields of the escrow canister's `EscrowRecord` read here
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct EscrowRecord {
+    creator_id: Principal,
+    counterparty_id: Principal,
+    status: EscrowStatus,
+}
+
+// (escrow_id, user, kind), e.g. kind "deal", "dispute" or "badge"
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RecordedKey {
+    pub escrow_id: String,
+    pub user_id: Principal,
+    pub kind: String,
+}
+
+thread_local! {
+    static RECORDED: RefCell<StableBTreeMap<RecordedKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::RECORDED)));
+}
+
+pub fn key(escrow_id: &str, user_id: Principal, kind: &str) -> RecordedKey {
+    RecordedKey {
+        escrow_id: escrow_id.to_string(),
+        user_id,
+        kind: kind.to_string(),
+    }
+}
+
+pub fn is_recorded(key: &RecordedKey) -> bool {
+    RECORDED.with(|r| r.borrow().contains_key(key))
+}
+
+// False if the key was already recorded
+pub fn mark_recorded(key: RecordedKey) -> bool {
+    RECORDED.with(|r| r.borrow_mut().insert(key, ()).is_none())
+}
+
+pub async fn verify(
+    escrow_id: &str,
+    user_id: Principal,
+    allowed: &[EscrowStatus],
+) -> std::result::Result<(), ReputationError> {
+    let escrow_canister = config::escrow_canister()?;
+    let (escrow,): (Option<EscrowRecord>,) =
+        ic_cdk::call(escrow_canister, "get_escrow", (escrow_id.to_string(),))
+            .await
+            .map_err(|(code, msg)| {
+                ReputationError::EscrowCheckFailed(format!("{:?}: {}", code, msg))
+            })?;
+    let escrow = escrow.ok_or(ReputationError::EscrowNotFound)?;
+
+    if user_id != escrow.creator_id && user_id != escrow.counterparty_id {
+        return Err(ReputationError::EscrowNotEligible);
+    }
+    if !allowed.contains(&escrow.status) {
+        return Err(ReputationError::EscrowNotEligible);
+    }
+    Ok(())
+}
@@ -2,10 +2,17 @@ use ic_cdk::api::time;
 use ic_cdk::{caller, query, update};
 use ic_cdk_macros::{init, post_upgrade, pre_upgrade};
 use std::cell::RefCell;
-use std::collections::HashMap;
 use candid::{CandidType, Deserialize, Principal};
+use common::CanisterStatus;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use memory::Memory;
 use serde::Serialize;
 
+mod config;
+mod escrows;
+mod memory;
+mod upgrade;
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct ReputationProfile {
     pub user_id: Principal,
@@ -28,34 +35,63 @@ pub struct DisputeRecord {
     pub resolved_at: Option<u64>,
 }
 
+// Key of a user's dispute, so a user's disputes are one range of the map
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UserDispute {
+    pub user_id: Principal,
+    pub dispute_id: String,
+}
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub enum ReputationError {
     NotFound,
     Unauthorized,
     InvalidScore,
+    InvalidConfig,
+    // The escrow was already counted for this user and kind of change
+    AlreadyRecorded,
+    EscrowNotFound,
+    // The user is not a party to the escrow, or it is in the wrong state
+    EscrowNotEligible,
+    EscrowCheckFailed(String),
 }
 
+// Kept in stable memory (see memory.rs), so they need no copying on upgrade
 thread_local! {
-    static REPUTATIONS: RefCell<HashMap<Principal, ReputationProfile>> = RefCell::new(HashMap::new());
-    static DISPUTES: RefCell<HashMap<String, DisputeRecord>> = RefCell::new(HashMap::new());
-    static USER_DISPUTES: RefCell<HashMap<Principal, Vec<String>>> = RefCell::new(HashMap::new());
-    static DISPUTE_COUNTER: RefCell<u64> = RefCell::new(0);
+    static REPUTATIONS: RefCell<StableBTreeMap<Principal, ReputationProfile, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::REPUTATIONS)));
+    static DISPUTES: RefCell<StableBTreeMap<String, DisputeRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::DISPUTES)));
+    static USER_DISPUTES: RefCell<StableBTreeMap<UserDispute, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::USER_DISPUTES)));
+    static DISPUTE_COUNTER: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(memory::get(memory::DISPUTE_COUNTER), 0)
+            .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to open the dispute counter: {:?}", err)))
+    );
 }
 
 #[init]
-fn init() {
+fn init(config: Option<config::ReputationConfig>) {
+    if let Err(err) = config::set(config.unwrap_or_default()) {
+        ic_cdk::trap(&format!("Invalid init arguments: {:?}", err));
+    }
     ic_cdk::println!("Reputation canister initialized");
 }
 
 #[pre_upgrade]
-fn pre_upgrade() {}
+fn pre_upgrade() {
+    upgrade::save();
+}
 
 #[post_upgrade]
-fn post_upgrade() {}
+fn post_upgrade() {
+    upgrade::restore();
+}
 
+// The user's profile, or a new one that `save_reputation` stores
 fn get_or_create_reputation(user_id: Principal) -> ReputationProfile {
     REPUTATIONS.with(|reps| {
-        reps.borrow_mut().entry(user_id).or_insert_with(|| {
+        reps.borrow().get(&user_id).unwrap_or_else(|| {
             ReputationProfile {
                 user_id,
                 completed_deals: 0,
@@ -65,10 +101,14 @@ fn get_or_create_reputation(user_id: Principal) -> ReputationProfile {
                 badges: vec![],
                 last_update: time(),
             }
-        }).clone()
+        })
     })
 }
 
+fn save_reputation(profile: &ReputationProfile) {
+    REPUTATIONS.with(|reps| reps.borrow_mut().insert(profile.user_id, profile.clone()));
+}
+
 fn calculate_trust_score(profile: &ReputationProfile) -> u8 {
     let mut score = 50u8;
     
@@ -93,7 +133,7 @@ fn calculate_trust_score(profile: &ReputationProfile) -> u8 {
 #[query]
 fn get_reputation(user_id: Principal) -> Option<ReputationProfile> {
     REPUTATIONS.with(|reps| {
-        reps.borrow().get(&user_id).cloned()
+        reps.borrow().get(&user_id)
     })
 }
 
@@ -102,59 +142,72 @@ fn get_my_reputation() -> Option<ReputationProfile> {
     get_reputation(caller())
 }
 
+// Reserve the (escrow, user, kind) key after verification; the check before
+// the call keeps repeats cheap, this one closes the race with a concurrent call
+fn claim(key: escrows::RecordedKey) -> std::result::Result<(), ReputationError> {
+    if !escrows::mark_recorded(key) {
+        return Err(ReputationError::AlreadyRecorded);
+    }
+    Ok(())
+}
+
+// Counted once per escrow and party, after the escrow has been released
 #[update]
-fn record_completed_deal(user_id: Principal, response_time_seconds: u64) -> std::result::Result<ReputationProfile, ReputationError> {
-    // In production, verify caller is escrow canister
+async fn record_completed_deal(user_id: Principal, escrow_id: String, response_time_seconds: u64) -> std::result::Result<ReputationProfile, ReputationError> {
+    config::require_writer()?;
+    let key = escrows::key(&escrow_id, user_id, "deal");
+    if escrows::is_recorded(&key) {
+        return Err(ReputationError::AlreadyRecorded);
+    }
+    escrows::verify(&escrow_id, user_id, &[escrows::EscrowStatus::Released]).await?;
+    claim(key)?;
     
-    REPUTATIONS.with(|reps| {
-        let mut reps_map = reps.borrow_mut();
-        let profile = reps_map.entry(user_id).or_insert_with(|| {
-            ReputationProfile {
-                user_id,
-                completed_deals: 0,
-                dispute_count: 0,
-                avg_response_time_seconds: 0,
-                trust_score: 50,
-                badges: vec![],
-                last_update: time(),
-            }
-        });
-        
-        // Update completed deals
-        profile.completed_deals += 1;
-        
-        // Update average response time
-        if profile.avg_response_time_seconds == 0 {
-            profile.avg_response_time_seconds = response_time_seconds;
-        } else {
-            profile.avg_response_time_seconds = 
-                (profile.avg_response_time_seconds + response_time_seconds) / 2;
-        }
-        
-        // Award milestone badges
-        match profile.completed_deals {
-            10 => profile.badges.push("10 Deals".to_string()),
-            50 => profile.badges.push("50 Deals".to_string()),
-            100 => profile.badges.push("100 Deals".to_string()),
-            _ => {}
-        }
-        
-        // Recalculate trust score
-        profile.trust_score = calculate_trust_score(profile);
-        profile.last_update = time();
-        
-        Ok(profile.clone())
-    })
+    let mut profile = get_or_create_reputation(user_id);
+    
+    // Update completed deals
+    profile.completed_deals += 1;
+    
+    // Update average response time
+    if profile.avg_response_time_seconds == 0 {
+        profile.avg_response_time_seconds = response_time_seconds;
+    } else {
+        profile.avg_response_time_seconds = 
+            (profile.avg_response_time_seconds + response_time_seconds) / 2;
+    }
+    
+    // Award milestone badges
+    match profile.completed_deals {
+        10 => profile.badges.push("10 Deals".to_string()),
+        50 => profile.badges.push("50 Deals".to_string()),
+        100 => profile.badges.push("100 Deals".to_string()),
+        _ => {}
+    }
+    
+    // Recalculate trust score
+    profile.trust_score = calculate_trust_score(&profile);
+    profile.last_update = time();
+    save_reputation(&profile);
+    
+    Ok(profile)
 }
 
+// Counted once per escrow and party, while the escrow is disputed
 #[update]
-fn record_dispute(user_id: Principal, escrow_id: String, dispute_type: String) -> std::result::Result<ReputationProfile, ReputationError> {
-    // In production, verify caller is escrow canister
+async fn record_dispute(user_id: Principal, escrow_id: String, dispute_type: String) -> std::result::Result<ReputationProfile, ReputationError> {
+    config::require_writer()?;
+    let key = escrows::key(&escrow_id, user_id, "dispute");
+    if escrows::is_recorded(&key) {
+        return Err(ReputationError::AlreadyRecorded);
+    }
+    escrows::verify(&escrow_id, user_id, &[escrows::EscrowStatus::Disputed]).await?;
+    claim(key)?;
     
     let dispute_id = DISPUTE_COUNTER.with(|counter| {
         let mut c = counter.borrow_mut();
-        *c += 1;
-        format!("DISP-{:010}", *c)
+        let next = c.get() + 1;
+        c.set(next)
+            .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to save the dispute counter: {:?}", err)));
+        format!("DISP-{:010}", next)
     });
     
     let dispute = DisputeRecord {
@@ -172,88 +225,120 @@ fn record_dispute(user_id: Principal, escrow_id: String, dispute_type: String) -
     });
     
     USER_DISPUTES.with(|user_disputes| {
-        user_disputes.borrow_mut()
-            .entry(user_id)
-            .or_insert_with(Vec::new)
-            .push(dispute_id);
+        user_disputes.borrow_mut().insert(UserDispute { user_id, dispute_id }, ());
     });
     
-    REPUTATIONS.with(|reps| {
-        let mut reps_map = reps.borrow_mut();
-        let profile = reps_map.entry(user_id).or_insert_with(|| {
-            ReputationProfile {
-                user_id,
-                completed_deals: 0,
-                dispute_count: 0,
-                avg_response_time_seconds: 0,
-                trust_score: 50,
-                badges: vec![],
-                last_update: time(),
-            }
-        });
-        
-        profile.dispute_count += 1;
-        profile.trust_score = calculate_trust_score(profile);
-        profile.last_update = time();
-        
-        Ok(profile.clone())
-    })
+    let mut profile = get_or_create_reputation(user_id);
+    profile.dispute_count += 1;
+    profile.trust_score = calculate_trust_score(&profile);
+    profile.last_update = time();
+    save_reputation(&profile);
+    
+    Ok(profile)
 }
 
+// Resolved once, after the escrow's own dispute has been settled
 #[update]
-fn resolve_dispute(dispute_id: String, resolution: String) -> std::result::Result<ReputationProfile, ReputationError> {
-    // In production, verify caller is authorized
+async fn resolve_dispute(dispute_id: String, resolution: String) -> std::result::Result<ReputationProfile, ReputationError> {
+    config::require_writer()?;
+    let dispute = DISPUTES.with(|disputes| disputes.borrow().get(&dispute_id))
+        .ok_or(ReputationError::NotFound)?;
+    if dispute.resolved_at.is_some() {
+        return Err(ReputationError::AlreadyRecorded);
+    }
+    escrows::verify(
+        &dispute.escrow_id,
+        dispute.user_id,
+        &[escrows::EscrowStatus::Released, escrows::EscrowStatus::Refunded],
+    )
+    .await?;
     
     DISPUTES.with(|disputes| {
         let mut disputes_map = disputes.borrow_mut();
-        let dispute = disputes_map.get_mut(&dispute_id)
+        let mut dispute = disputes_map.get(&dispute_id)
             .ok_or(ReputationError::NotFound)?;
+        if dispute.resolved_at.is_some() {
+            return Err(ReputationError::AlreadyRecorded);
+        }
         
         dispute.resolution = resolution;
         dispute.resolved_at = Some(time());
         
         let user_id = dispute.user_id;
+        disputes_map.insert(dispute_id, dispute);
         
         REPUTATIONS.with(|reps| {
-            let profile = reps.borrow().get(&user_id).cloned()
+            let profile = reps.borrow().get(&user_id)
                 .ok_or(ReputationError::NotFound)?;
             Ok(profile)
         })
     })
 }
 
+// A badge is earned through a released escrow the user was a party to; each
+// escrow earns a user at most one badge
 #[update]
-fn award_badge(user_id: Principal, badge: String) -> std::result::Result<ReputationProfile, ReputationError> {
-    // In production, verify caller is authorized
+async fn award_badge(user_id: Principal, escrow_id: String, badge: String) -> std::result::Result<ReputationProfile, ReputationError> {
+    config::require_writer()?;
+    let key = escrows::key(&escrow_id, user_id, "badge");
+    if escrows::is_recorded(&key) {
+        return Err(ReputationError::AlreadyRecorded);
+    }
+    escrows::verify(&escrow_id, user_id, &[escrows::EscrowStatus::Released]).await?;
+    claim(key)?;
     
-    REPUTATIONS.with(|reps| {
-        let mut reps_map = reps.borrow_mut();
-        let profile = reps_map.entry(user_id).or_insert_with(|| {
-            ReputationProfile {
-                user_id,
-                completed_deals: 0,
-                dispute_count: 0,
-                avg_response_time_seconds: 0,
-                trust_score: 50,
-                badges: vec![],
-                last_update: time(),
-            }
-        });
-        
-        if !profile.badges.contains(&badge) {
-            profile.badges.push(badge);
-            profile.trust_score = calculate_trust_score(profile);
-            profile.last_update = time();
-        }
-        
-        Ok(profile.clone())
-    })
+    let mut profile = get_or_create_reputation(user_id);
+    if !profile.badges.contains(&badge) {
+        profile.badges.push(badge);
+        profile.trust_score = calculate_trust_score(&profile);
+        profile.last_update = time();
+        save_reputation(&profile);
+    }
+    
+    Ok(profile)
+}
+
+#[query]
+fn get_config() -> config::ReputationConfig {
+    config::get()
+}
+
+#[update]
+fn set_config(new_config: config::ReputationConfig) -> std::result::Result<config::ReputationConfig, ReputationError> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err(ReputationError::Unauthorized);
+    }
+    config::set(new_config)?;
+    Ok(config::get())
+}
+
+#[query]
+fn get_writers() -> Vec<Principal> {
+    config::writers()
+}
+
+#[update]
+fn add_writer(writer: Principal) -> std::result::Result<Vec<Principal>, ReputationError> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err(ReputationError::Unauthorized);
+    }
+    config::add_writer(writer);
+    Ok(config::writers())
+}
+
+#[update]
+fn remove_writer(writer: Principal) -> std::result::Result<Vec<Principal>, ReputationError> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err(ReputationError::Unauthorized);
+    }
+    config::remove_writer(&writer);
+    Ok(config::writers())
 }
 
 #[query]
 fn get_top_users(limit: u64) -> Vec<ReputationProfile> {
     REPUTATIONS.with(|reps| {
-        let mut profiles: Vec<ReputationProfile> = reps.borrow().values().cloned().collect();
+        let mut profiles: Vec<ReputationProfile> = reps.borrow().iter().map(|(_, profile)| profile).collect();
         profiles.sort_by(|a, b| b.trust_score.cmp(&a.trust_score));
         profiles.truncate(limit as usize);
         profiles
@@ -263,18 +348,29 @@ fn get_top_users(limit: u64) -> Vec<ReputationProfile> {
 #[query]
 fn get_dispute_history(user_id: Principal) -> Vec<DisputeRecord> {
     USER_DISPUTES.with(|user_disputes| {
-        let dispute_ids = user_disputes.borrow()
-            .get(&user_id)
-            .cloned()
-            .unwrap_or_default();
+        let start = UserDispute { user_id, dispute_id: String::new() };
+        let dispute_ids: Vec<String> = user_disputes.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.user_id == user_id)
+            .map(|(key, _)| key.dispute_id)
+            .collect();
         
         DISPUTES.with(|disputes| {
             let disputes_map = disputes.borrow();
             dispute_ids.iter()
-                .filter_map(|id| disputes_map.get(id).cloned())
+                .filter_map(|id| disputes_map.get(id))
                 .collect()
         })
     })
 }
 
+// Operational status for monitoring (cycles, memory, record counts)
+#[query]
+fn get_canister_status() -> CanisterStatus {
+    common::canister_status(vec![
+        ("reputations".to_string(), REPUTATIONS.with(|r| r.borrow().len())),
+        ("disputes".to_string(), DISPUTES.with(|d| d.borrow().len())),
+    ])
+}
+
 ic_cdk::export_candid!();
@@ -0,0 +1,82 @@
+// Stable memory layout.
+//
+// Stable memory is split into virtual memories by a `MemoryManager`: one
+// holds the heap state written at upgrade time (config and writers), the
+// others back the stable structures holding profiles, disputes and counted
+// escrows, which live in stable memory all the time.
+use candid::{decode_one, encode_one};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const UPGRADES: MemoryId = MemoryId::new(0);
+pub const REPUTATIONS: MemoryId = MemoryId::new(1);
+pub const DISPUTES: MemoryId = MemoryId::new(2);
+pub const USER_DISPUTES: MemoryId = MemoryId::new(3);
+pub const DISPUTE_COUNTER: MemoryId = MemoryId::new(4);
+pub const RECORDED: MemoryId = MemoryId::new(5);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+// Candid-encoded `Storable` for the records kept in stable structures
+macro_rules! candid_storable {
+    ($($type:ty),* $(,)?) => {$(
+        impl Storable for $type {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(encode_one(self).unwrap_or_else(|err| {
+                    ic_cdk::trap(&format!("Failed to encode {}: {}", stringify!($type), err))
+                }))
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                decode_one(&bytes).unwrap_or_else(|err| {
+                    ic_cdk::trap(&format!("Failed to decode {}: {}", stringify!($type), err))
+                })
+            }
+
+            const BOUND: Bound = Bound::Unbounded;
+        }
+    )*};
+}
+
+candid_storable!(
+    crate::ReputationProfile,
+    crate::DisputeRecord,
+    crate::UserDispute,
+    crate::escrows::RecordedKey,
+);
+
+// Heap state saved in `pre_upgrade`, as a length-prefixed blob
+pub fn write_upgrade_state(bytes: &[u8]) {
+    let mut memory = get(UPGRADES);
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write(&(bytes.len() as u64).to_le_bytes())
+        .and_then(|_| writer.write(bytes))
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory saving reputation state"));
+}
+
+// None if no state was ever saved in this layout
+pub fn read_upgrade_state() -> Option<Vec<u8>> {
+    let memory = get(UPGRADES);
+    if memory.size() == 0 {
+        return None;
+    }
+    let mut len = [0u8; 8];
+    memory.read(0, &mut len);
+    let mut bytes = vec![0u8; u64::from_le_bytes(len) as usize];
+    memory.read(8, &mut bytes);
+    Some(bytes)
+}
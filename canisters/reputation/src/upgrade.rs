@@ -0,0 +1,96 @@
+// Upgrade persistence for the reputation canister's heap state.
+//
+// Profiles, disputes and counted escrows live in stable structures (see
+// memory.rs); only config and writers are saved here, as `(version, candid
+// bytes)`. `post_upgrade` decodes the layout of whichever version saved it;
+// add a `StableStateVn` and a `migrate_vn` when the layout changes.
+use crate::*;
+use candid::{decode_args, decode_one, encode_args, encode_one};
+
+const STATE_VERSION: u32 = 1;
+
+#[derive(CandidType, Deserialize)]
+struct StableStateV1 {
+    config: config::ReputationConfig,
+    writers: Vec<Principal>,
+}
+
+pub fn save() {
+    let state = StableStateV1 {
+        config: config::get(),
+        writers: config::writers(),
+    };
+    let bytes = encode_one(&state)
+        .and_then(|bytes| encode_args((STATE_VERSION, bytes)))
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to encode reputation state: {}", err)));
+    memory::write_upgrade_state(&bytes);
+}
+
+fn decode<T: for<'de> Deserialize<'de> + CandidType>(bytes: &[u8]) -> T {
+    decode_one(bytes)
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to decode reputation state: {}", err)))
+}
+
+pub fn restore() {
+    // Releases before versioned state kept nothing in stable memory. Any
+    // state that fails to decode must not be silently dropped.
+    let Some(saved) = memory::read_upgrade_state() else {
+        ic_cdk::println!("No saved reputation state found, starting empty");
+        return;
+    };
+    let (version, bytes) = decode_args::<(u32, Vec<u8>)>(&saved)
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to restore reputation state: {}", err)));
+
+    let state: StableStateV1 = match version {
+        1 => decode(&bytes),
+        other => ic_cdk::trap(&format!("Unsupported reputation state version {}", other)),
+    };
+    config::restore(state.config, state.writers);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn saved_state_round_trips() {
+        config::restore(
+            config::ReputationConfig { escrow_canister: Some(user(9)) },
+            vec![user(1), user(2)],
+        );
+
+        save();
+        config::restore(config::ReputationConfig::default(), Vec::new());
+        restore();
+
+        assert_eq!(config::get().escrow_canister, Some(user(9)));
+        assert_eq!(config::writers(), vec![user(1), user(2)]);
+    }
+
+    #[test]
+    fn nothing_saved_starts_empty() {
+        restore();
+        assert_eq!(config::get().escrow_canister, None);
+        assert!(config::writers().is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn unknown_versions_are_rejected() {
+        let bytes = encode_args((STATE_VERSION + 1, Vec::<u8>::new())).unwrap();
+        memory::write_upgrade_state(&bytes);
+        restore();
+    }
+
+    #[test]
+    #[should_panic]
+    fn undecodable_state_is_rejected() {
+        let bytes = encode_args((STATE_VERSION, vec![1u8, 2, 3])).unwrap();
+        memory::write_upgrade_state(&bytes);
+        restore();
+    }
+}
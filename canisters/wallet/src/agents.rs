@@ -0,0 +1,473 @@
+// Agent cash-in and cash-out.
+//
+// Agents are users flagged `is_agent` in the identity canister who exchange
+// cash for ckBTC in person. Whoever gives up ckBTC has it held first: the
+// agent on a cash-in, the user on a cash-out. Each transaction gets a
+// six-digit confirmation code that only the user can see; once the cash has
+// changed hands the user tells the agent the code, and the agent completes
+// the transaction with it, which releases the hold to the other side.
+// Pending transactions can be cancelled by either side and expire after
+// `PENDING_TTL_NANOS`; too many wrong codes cancel them as well.
+//
+// In history the user's entries are `cash_in`/`cash_out` and the agent's
+// `agent_cash_in`/`agent_cash_out`.
+//
+// Each agent keeps a cash float in USD cents. A cash-out reserves its cash
+// amount from the float while pending; a completed cash-in adds to it.
+//
+// Holds are keyed by `hold_id`, so an agent transaction ID can never name an
+// escrow's hold.
+use crate::*;
+use std::collections::BTreeSet;
+
+const PENDING_TTL_NANOS: u64 = 30 * 60 * 1_000_000_000;
+const MAX_CODE_ATTEMPTS: u8 = 3;
+const ASSET: Asset = Asset::CkBTC;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AgentTransactionType {
+    // Cash from the user, ckBTC from the agent
+    CashIn,
+    // ckBTC from the user, cash from the agent
+    CashOut,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AgentTransactionStatus {
+    Pending,
+    Completed,
+    Cancelled,
+    Expired,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AgentTransaction {
+    pub id: String,
+    pub agent_id: Principal,
+    pub user_id: Principal,
+    pub tx_type: AgentTransactionType,
+    pub amount_satoshis: u64,
+    pub amount_usd_cents: u64,
+    pub status: AgentTransactionStatus,
+    // Only returned to the user
+    pub confirmation_code: Option<String>,
+    pub failed_attempts: u8,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AgentFloat {
+    pub agent_id: Principal,
+    pub cash_float_usd_cents: u64,
+    pub max_transaction_usd_cents: u64,
+    pub total_transactions: u64,
+    pub updated_at: u64,
+}
+
+pub type AgentTransactions = HashMap<String, AgentTransaction>;
+pub type AgentFloats = HashMap<Principal, AgentFloat>;
+
+thread_local! {
+    static AGENT_TRANSACTIONS: RefCell<AgentTransactions> = RefCell::new(HashMap::new());
+    static FLOATS: RefCell<AgentFloats> = RefCell::new(HashMap::new());
+    // (expires_at, id) of pending transactions; rebuilt on restore
+    static EXPIRIES: RefCell<BTreeSet<(u64, String)>> = RefCell::new(BTreeSet::new());
+}
+
+fn hold_id(id: &str) -> String {
+    format!("{}{}", holds::AGENT_PREFIX, id)
+}
+
+async fn require_agent(agent_id: Principal) -> std::result::Result<(), WalletError> {
+    if identity::is_agent(agent_id).await? {
+        Ok(())
+    } else {
+        Err(WalletError::NotAnAgent)
+    }
+}
+
+async fn confirmation_code() -> std::result::Result<String, WalletError> {
+    let (bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, msg)| {
+            WalletError::TransferFailed(format!("Randomness unavailable: {:?} {}", code, msg))
+        })?;
+    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    Ok(format!("{:06}", value % 1_000_000))
+}
+
+fn get(id: &str) -> Option<AgentTransaction> {
+    AGENT_TRANSACTIONS.with(|txs| txs.borrow().get(id).cloned())
+}
+
+fn store(tx: &AgentTransaction) {
+    EXPIRIES.with(|e| {
+        let key = (tx.expires_at, tx.id.clone());
+        if tx.status == AgentTransactionStatus::Pending {
+            e.borrow_mut().insert(key);
+        } else {
+            e.borrow_mut().remove(&key);
+        }
+    });
+    AGENT_TRANSACTIONS.with(|txs| txs.borrow_mut().insert(tx.id.clone(), tx.clone()));
+}
+
+pub fn float(agent_id: Principal) -> Option<AgentFloat> {
+    FLOATS.with(|f| f.borrow().get(&agent_id).cloned())
+}
+
+pub async fn set_float(
+    agent_id: Principal,
+    cash_float_usd_cents: u64,
+    max_transaction_usd_cents: u64,
+) -> std::result::Result<AgentFloat, WalletError> {
+    require_agent(agent_id).await?;
+    if max_transaction_usd_cents == 0 {
+        return Err(WalletError::InvalidAmount);
+    }
+
+    // Cash reserved by pending cash-outs stays reserved
+    let reserved = AGENT_TRANSACTIONS.with(|txs| {
+        txs.borrow()
+            .values()
+            .filter(|tx| {
+                tx.agent_id == agent_id
+                    && tx.tx_type == AgentTransactionType::CashOut
+                    && tx.status == AgentTransactionStatus::Pending
+            })
+            .try_fold(0u64, |sum, tx| sum.checked_add(tx.amount_usd_cents))
+            .ok_or(WalletError::ArithmeticOverflow)
+    })?;
+    let available = cash_float_usd_cents
+        .checked_sub(reserved)
+        .ok_or(WalletError::InsufficientFloat)?;
+
+    let now = time();
+    FLOATS.with(|f| {
+        let mut floats = f.borrow_mut();
+        let entry = floats.entry(agent_id).or_insert_with(|| AgentFloat {
+            agent_id,
+            cash_float_usd_cents: 0,
+            max_transaction_usd_cents,
+            total_transactions: 0,
+            updated_at: now,
+        });
+        entry.cash_float_usd_cents = available;
+        entry.max_transaction_usd_cents = max_transaction_usd_cents;
+        entry.updated_at = now;
+        Ok(entry.clone())
+    })
+}
+
+fn check_amounts(
+    agent_id: Principal,
+    amount_satoshis: u64,
+    amount_usd_cents: u64,
+) -> std::result::Result<AgentFloat, WalletError> {
+    if amount_satoshis == 0 || amount_usd_cents == 0 {
+        return Err(WalletError::InvalidAmount);
+    }
+    let float = float(agent_id).ok_or(WalletError::InsufficientFloat)?;
+    if amount_usd_cents > float.max_transaction_usd_cents {
+        return Err(WalletError::InvalidArgument(format!(
+            "Agent accepts at most {} USD cents per transaction",
+            float.max_transaction_usd_cents
+        )));
+    }
+    Ok(float)
+}
+
+fn adjust_float(
+    agent_id: Principal,
+    add: bool,
+    amount_usd_cents: u64,
+    now: u64,
+) -> std::result::Result<(), WalletError> {
+    FLOATS.with(|f| {
+        let mut floats = f.borrow_mut();
+        let float = floats.get_mut(&agent_id).ok_or(WalletError::InsufficientFloat)?;
+        float.cash_float_usd_cents = if add {
+            float
+                .cash_float_usd_cents
+                .checked_add(amount_usd_cents)
+                .ok_or(WalletError::ArithmeticOverflow)?
+        } else {
+            float
+                .cash_float_usd_cents
+                .checked_sub(amount_usd_cents)
+                .ok_or(WalletError::InsufficientFloat)?
+        };
+        float.updated_at = now;
+        Ok(())
+    })
+}
+
+// Shared by both flows once the awaits are done: hold the payer's ckBTC and
+// record the pending transaction
+fn open(
+    agent_id: Principal,
+    user_id: Principal,
+    tx_type: AgentTransactionType,
+    amount_satoshis: u64,
+    amount_usd_cents: u64,
+    code: String,
+) -> std::result::Result<AgentTransaction, WalletError> {
+    // Checked again: the float may have changed while we awaited
+    check_amounts(agent_id, amount_satoshis, amount_usd_cents)?;
+
+    let now = time();
+    let id = generate_tx_id();
+    let (payer, label) = match tx_type {
+        AgentTransactionType::CashIn => (agent_id, "agent_cash_in"),
+        AgentTransactionType::CashOut => (user_id, "cash_out"),
+    };
+
+    if tx_type == AgentTransactionType::CashOut {
+        adjust_float(agent_id, false, amount_usd_cents, now)?;
+    }
+    let held = holds::create(
+        HoldParams {
+            escrow_id: hold_id(&id),
+            user_id: payer,
+            amount: amount_satoshis,
+            currency: ASSET,
+        },
+        HoldOrigin::Agent,
+        label,
+        now,
+    );
+    if let Err(err) = held {
+        if tx_type == AgentTransactionType::CashOut {
+            adjust_float(agent_id, true, amount_usd_cents, now)?;
+        }
+        return Err(err);
+    }
+
+    let tx = AgentTransaction {
+        id,
+        agent_id,
+        user_id,
+        tx_type,
+        amount_satoshis,
+        amount_usd_cents,
+        status: AgentTransactionStatus::Pending,
+        confirmation_code: Some(code),
+        failed_attempts: 0,
+        created_at: now,
+        expires_at: now + PENDING_TTL_NANOS,
+        completed_at: None,
+    };
+    store(&tx);
+    Ok(tx)
+}
+
+// Called by the agent after receiving the user's cash
+pub async fn start_cash_in(
+    agent_id: Principal,
+    user_id: Principal,
+    amount_satoshis: u64,
+    amount_usd_cents: u64,
+) -> std::result::Result<AgentTransaction, WalletError> {
+    if user_id == agent_id || user_id == Principal::anonymous() {
+        return Err(WalletError::InvalidArgument("Invalid user".to_string()));
+    }
+    check_amounts(agent_id, amount_satoshis, amount_usd_cents)?;
+    require_agent(agent_id).await?;
+    let code = confirmation_code().await?;
+    let tx = open(
+        agent_id,
+        user_id,
+        AgentTransactionType::CashIn,
+        amount_satoshis,
+        amount_usd_cents,
+        code,
+    )?;
+    Ok(view(tx, agent_id))
+}
+
+// Called by the user, who is then shown the code to give the agent
+pub async fn request_cash_out(
+    user_id: Principal,
+    agent_id: Principal,
+    amount_satoshis: u64,
+    amount_usd_cents: u64,
+) -> std::result::Result<AgentTransaction, WalletError> {
+    if user_id == agent_id {
+        return Err(WalletError::InvalidArgument("Invalid agent".to_string()));
+    }
+    let float = check_amounts(agent_id, amount_satoshis, amount_usd_cents)?;
+    if float.cash_float_usd_cents < amount_usd_cents {
+        return Err(WalletError::InsufficientFloat);
+    }
+    require_agent(agent_id).await?;
+    let code = confirmation_code().await?;
+    open(
+        agent_id,
+        user_id,
+        AgentTransactionType::CashOut,
+        amount_satoshis,
+        amount_usd_cents,
+        code,
+    )
+}
+
+fn recipient_label(tx: &AgentTransaction) -> &'static str {
+    match tx.tx_type {
+        AgentTransactionType::CashIn => "cash_in",
+        AgentTransactionType::CashOut => "agent_cash_out",
+    }
+}
+
+// Close a pending transaction without moving funds: the hold goes back to
+// the payer and a cash-out's reserved cash back to the float
+fn close(
+    mut tx: AgentTransaction,
+    status: AgentTransactionStatus,
+    now: u64,
+) -> std::result::Result<AgentTransaction, WalletError> {
+    holds::settle(
+        &hold_id(&tx.id),
+        HoldOrigin::Agent,
+        HoldStatus::Returned,
+        recipient_label(&tx),
+        now,
+    )?;
+    if tx.tx_type == AgentTransactionType::CashOut {
+        adjust_float(tx.agent_id, true, tx.amount_usd_cents, now)?;
+    }
+    tx.status = status;
+    store(&tx);
+    Ok(tx)
+}
+
+pub fn complete(
+    agent_id: Principal,
+    id: &str,
+    code: &str,
+) -> std::result::Result<AgentTransaction, WalletError> {
+    let mut tx = get(id)
+        .filter(|tx| tx.agent_id == agent_id)
+        .ok_or(WalletError::NotFound)?;
+    if tx.status != AgentTransactionStatus::Pending {
+        return Err(WalletError::InvalidArgument("Transaction is not pending".to_string()));
+    }
+    let now = time();
+    if now >= tx.expires_at {
+        close(tx, AgentTransactionStatus::Expired, now)?;
+        return Err(WalletError::InvalidArgument("Transaction has expired".to_string()));
+    }
+
+    if tx.confirmation_code.as_deref() != Some(code) {
+        tx.failed_attempts += 1;
+        let attempts_left = MAX_CODE_ATTEMPTS.saturating_sub(tx.failed_attempts);
+        if attempts_left == 0 {
+            close(tx, AgentTransactionStatus::Cancelled, now)?;
+        } else {
+            store(&tx);
+        }
+        return Err(WalletError::InvalidConfirmationCode { attempts_left });
+    }
+
+    let recipient = match tx.tx_type {
+        AgentTransactionType::CashIn => tx.user_id,
+        AgentTransactionType::CashOut => tx.agent_id,
+    };
+    holds::settle(
+        &hold_id(&tx.id),
+        HoldOrigin::Agent,
+        HoldStatus::Released { to: recipient },
+        recipient_label(&tx),
+        now,
+    )?;
+    if tx.tx_type == AgentTransactionType::CashIn {
+        adjust_float(agent_id, true, tx.amount_usd_cents, now)?;
+    }
+    FLOATS.with(|f| {
+        if let Some(float) = f.borrow_mut().get_mut(&agent_id) {
+            float.total_transactions += 1;
+            float.updated_at = now;
+        }
+    });
+
+    tx.status = AgentTransactionStatus::Completed;
+    tx.completed_at = Some(now);
+    store(&tx);
+    Ok(view(tx, agent_id))
+}
+
+pub fn cancel(caller_id: Principal, id: &str) -> std::result::Result<AgentTransaction, WalletError> {
+    let tx = get(id)
+        .filter(|tx| tx.agent_id == caller_id || tx.user_id == caller_id)
+        .ok_or(WalletError::NotFound)?;
+    if tx.status != AgentTransactionStatus::Pending {
+        return Err(WalletError::InvalidArgument("Transaction is not pending".to_string()));
+    }
+    let tx = close(tx, AgentTransactionStatus::Cancelled, time())?;
+    Ok(view(tx, caller_id))
+}
+
+pub fn expire_due(now: u64) {
+    let due_ids: Vec<String> = EXPIRIES.with(|e| {
+        e.borrow()
+            .range(..(now.saturating_add(1), String::new()))
+            .map(|(_, id)| id.clone())
+            .collect()
+    });
+    let due: Vec<AgentTransaction> = due_ids
+        .iter()
+        .filter_map(|id| get(id))
+        .filter(|tx| tx.status == AgentTransactionStatus::Pending)
+        .collect();
+    for tx in due {
+        let id = tx.id.clone();
+        if let Err(err) = close(tx, AgentTransactionStatus::Expired, now) {
+            ic_cdk::println!("Expiring agent transaction {} failed: {:?}", id, err);
+        }
+    }
+}
+
+// The confirmation code is hidden from everyone but the user
+fn view(mut tx: AgentTransaction, viewer: Principal) -> AgentTransaction {
+    if viewer != tx.user_id {
+        tx.confirmation_code = None;
+    }
+    tx
+}
+
+// Transactions where the caller is the agent or the user, newest first
+pub fn for_principal(principal: Principal) -> Vec<AgentTransaction> {
+    let mut txs: Vec<AgentTransaction> = AGENT_TRANSACTIONS.with(|txs| {
+        txs.borrow()
+            .values()
+            .filter(|tx| tx.agent_id == principal || tx.user_id == principal)
+            .cloned()
+            .map(|tx| view(tx, principal))
+            .collect()
+    });
+    txs.sort_by_key(|tx| std::cmp::Reverse(tx.created_at));
+    txs
+}
+
+pub fn count() -> u64 {
+    AGENT_TRANSACTIONS.with(|txs| txs.borrow().len() as u64)
+}
+
+pub fn snapshot() -> (AgentTransactions, AgentFloats) {
+    (
+        AGENT_TRANSACTIONS.with(|txs| txs.borrow().clone()),
+        FLOATS.with(|f| f.borrow().clone()),
+    )
+}
+
+pub fn restore(transactions: AgentTransactions, floats: AgentFloats) {
+    let expiries = transactions
+        .values()
+        .filter(|tx| tx.status == AgentTransactionStatus::Pending)
+        .map(|tx| (tx.expires_at, tx.id.clone()))
+        .collect();
+    EXPIRIES.with(|e| *e.borrow_mut() = expiries);
+    AGENT_TRANSACTIONS.with(|txs| *txs.borrow_mut() = transactions);
+    FLOATS.with(|f| *f.borrow_mut() = floats);
+}
@@ -0,0 +1,635 @@
+// Append-only, hash-chained log of balance changes.
+//
+// Every operation that changes what an account owns is appended as a block.
+// Blocks are hashed as ICRC-3 values (representation-independent hashing, so
+// the hash does not depend on how a block is encoded), and each block
+// carries its parent's hash, so altering any block breaks the chain after it.
+// Replaying the log gives each account's `available + held +
+// pending_withdrawals` per asset: holds only move funds into `held`, and
+// withdrawals are burned once their transaction has been submitted.
+//
+// The log, the tip hash and the per-account index live in stable memory, so
+// upgrades neither copy nor re-hash them. The index also keeps each account's
+// running totals, so a statement can start anywhere without replaying the
+// account's history.
+use crate::memory::{self, Memory};
+use crate::*;
+use candid::{decode_one, encode_one};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, StableCell, StableLog, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::{Borrow, Cow};
+
+pub const MAX_BLOCKS_PER_REQUEST: u64 = 1_000;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum Operation {
+    Mint {
+        to: Principal,
+        asset: Asset,
+        amount: u64,
+        source: Option<CreditSource>,
+    },
+    Transfer {
+        from: Principal,
+        to: Principal,
+        asset: Asset,
+        amount: u64,
+        memo: Option<String>,
+    },
+    // Amount plus network fee of a submitted withdrawal
+    Burn {
+        from: Principal,
+        asset: Asset,
+        amount: u64,
+        withdrawal_id: String,
+    },
+    Hold {
+        from: Principal,
+        asset: Asset,
+        amount: u64,
+        escrow_id: String,
+    },
+    // A returned hold is released back to the payer (`to == from`)
+    Release {
+        from: Principal,
+        to: Principal,
+        asset: Asset,
+        amount: u64,
+        escrow_id: String,
+    },
+}
+
+impl Operation {
+    // Signed change to each account's total
+    fn deltas(&self) -> Vec<(Principal, Asset, i128)> {
+        match self {
+            Operation::Mint { to, asset, amount, .. } => vec![(*to, *asset, *amount as i128)],
+            Operation::Burn { from, asset, amount, .. } => vec![(*from, *asset, -(*amount as i128))],
+            // Only moves funds into `held`
+            Operation::Hold { .. } => Vec::new(),
+            Operation::Transfer { from, to, asset, amount, .. }
+            | Operation::Release { from, to, asset, amount, .. } => {
+                vec![(*from, *asset, -(*amount as i128)), (*to, *asset, *amount as i128)]
+            }
+        }
+    }
+
+    fn accounts(&self) -> Vec<Principal> {
+        match self {
+            Operation::Mint { to, .. } => vec![*to],
+            Operation::Burn { from, .. } | Operation::Hold { from, .. } => vec![*from],
+            Operation::Transfer { from, to, .. } | Operation::Release { from, to, .. } => {
+                if from == to {
+                    vec![*from]
+                } else {
+                    vec![*from, *to]
+                }
+            }
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Block {
+    pub index: u64,
+    pub parent_hash: Option<Vec<u8>>,
+    pub timestamp: u64,
+    pub operation: Operation,
+}
+
+// An account's net amount of each asset after one of its blocks
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct AccountTotals(pub Vec<(Asset, i128)>);
+
+impl AccountTotals {
+    fn apply(&mut self, asset: Asset, delta: i128) {
+        match self.0.iter_mut().find(|(a, _)| *a == asset) {
+            Some((_, total)) => *total += delta,
+            None => self.0.push((asset, delta)),
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct GetBlocksResponse {
+    pub chain_length: u64,
+    pub tip_hash: Option<Vec<u8>>,
+    pub blocks: Vec<Block>,
+}
+
+impl Storable for Block {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(
+            encode_one(self)
+                .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to encode block: {}", err))),
+        )
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_one(&bytes)
+            .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to decode block: {}", err)))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for AccountTotals {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(
+            encode_one(self)
+                .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to encode totals: {}", err))),
+        )
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_one(&bytes)
+            .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to decode totals: {}", err)))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// ICRC-3 value; blocks are hashed in this representation. Natural numbers
+// here never exceed a `u64`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(u64),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+fn leb128(mut value: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return out;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+impl Value {
+    // The ICRC-3 representation-independent hash
+    pub fn hash(&self) -> [u8; 32] {
+        match self {
+            Value::Blob(bytes) => Sha256::digest(bytes).into(),
+            Value::Text(text) => Sha256::digest(text.as_bytes()).into(),
+            Value::Nat(value) => Sha256::digest(leb128(*value)).into(),
+            Value::Array(items) => {
+                let mut hasher = Sha256::new();
+                for item in items {
+                    hasher.update(item.hash());
+                }
+                hasher.finalize().into()
+            }
+            // Pairs are hashed as hash(key) ++ hash(value), sorted
+            Value::Map(fields) => {
+                let mut pairs: Vec<Vec<u8>> = fields
+                    .iter()
+                    .map(|(key, value)| {
+                        let mut pair = Sha256::digest(key.as_bytes()).to_vec();
+                        pair.extend_from_slice(&value.hash());
+                        pair
+                    })
+                    .collect();
+                pairs.sort();
+                let mut hasher = Sha256::new();
+                for pair in pairs {
+                    hasher.update(pair);
+                }
+                hasher.finalize().into()
+            }
+        }
+    }
+}
+
+fn field(name: &str, value: Value) -> (String, Value) {
+    (name.to_string(), value)
+}
+
+// ICRC-1 account without a subaccount
+fn account(principal: &Principal) -> Value {
+    Value::Array(vec![Value::Blob(principal.as_slice().to_vec())])
+}
+
+fn source_text(source: &CreditSource) -> String {
+    match source {
+        CreditSource::Deposit { txid, vout } => format!("deposit:{}:{}", txid, vout),
+        CreditSource::EscrowPayout { escrow_id } => format!("escrow_payout:{}", escrow_id),
+        CreditSource::LedgerDeposit { asset, block_index } => {
+            format!("ledger_deposit:{}:{}", asset.symbol(), block_index)
+        }
+    }
+}
+
+impl Operation {
+    // Block type and transaction fields; the ICRC-1 operations use their
+    // ICRC-3 block types
+    fn to_value(&self) -> (&'static str, Vec<(String, Value)>) {
+        match self {
+            Operation::Mint { to, asset, amount, source } => {
+                let mut tx = vec![
+                    field("to", account(to)),
+                    field("amt", Value::Nat(*amount)),
+                    field("asset", Value::Text(asset.symbol().to_string())),
+                ];
+                if let Some(source) = source {
+                    tx.push(field("source", Value::Text(source_text(source))));
+                }
+                ("1mint", tx)
+            }
+            Operation::Transfer { from, to, asset, amount, memo } => {
+                let mut tx = vec![
+                    field("from", account(from)),
+                    field("to", account(to)),
+                    field("amt", Value::Nat(*amount)),
+                    field("asset", Value::Text(asset.symbol().to_string())),
+                ];
+                if let Some(memo) = memo {
+                    tx.push(field("memo", Value::Blob(memo.as_bytes().to_vec())));
+                }
+                ("1xfer", tx)
+            }
+            Operation::Burn { from, asset, amount, withdrawal_id } => (
+                "1burn",
+                vec![
+                    field("from", account(from)),
+                    field("amt", Value::Nat(*amount)),
+                    field("asset", Value::Text(asset.symbol().to_string())),
+                    field("withdrawal_id", Value::Text(withdrawal_id.clone())),
+                ],
+            ),
+            Operation::Hold { from, asset, amount, escrow_id } => (
+                "hold",
+                vec![
+                    field("from", account(from)),
+                    field("amt", Value::Nat(*amount)),
+                    field("asset", Value::Text(asset.symbol().to_string())),
+                    field("escrow_id", Value::Text(escrow_id.clone())),
+                ],
+            ),
+            Operation::Release { from, to, asset, amount, escrow_id } => (
+                "release",
+                vec![
+                    field("from", account(from)),
+                    field("to", account(to)),
+                    field("amt", Value::Nat(*amount)),
+                    field("asset", Value::Text(asset.symbol().to_string())),
+                    field("escrow_id", Value::Text(escrow_id.clone())),
+                ],
+            ),
+        }
+    }
+}
+
+impl Block {
+    pub fn to_value(&self) -> Value {
+        let (btype, tx) = self.operation.to_value();
+        let mut fields = vec![
+            field("btype", Value::Text(btype.to_string())),
+            field("ts", Value::Nat(self.timestamp)),
+            field("tx", Value::Map(tx)),
+        ];
+        if let Some(parent_hash) = &self.parent_hash {
+            fields.push(field("phash", Value::Blob(parent_hash.clone())));
+        }
+        Value::Map(fields)
+    }
+}
+
+thread_local! {
+    static BLOCKS: RefCell<StableLog<Block, Memory, Memory>> = RefCell::new(
+        StableLog::init(memory::get(memory::BLOCK_INDEX), memory::get(memory::BLOCK_DATA))
+            .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to open the block log: {:?}", err)))
+    );
+    // Hash of the last block; empty while the log is
+    static TIP_HASH: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(memory::get(memory::BLOCK_TIP), Vec::new())
+            .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to open the tip hash: {:?}", err)))
+    );
+    // (account, block index) for every block touching the account, with the
+    // account's totals after the block
+    static ACCOUNT_BLOCKS: RefCell<StableBTreeMap<(Principal, u64), AccountTotals, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::ACCOUNT_BLOCKS)));
+    // Principals besides controllers allowed to read the whole log
+    static AUDITORS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+}
+
+pub fn hash(block: &Block) -> Vec<u8> {
+    block.to_value().hash().to_vec()
+}
+
+// Checks indices and parent hashes and returns the tip hash, or the
+// position of the first block that does not fit
+pub fn check_chain(
+    blocks: impl IntoIterator<Item = impl Borrow<Block>>,
+) -> std::result::Result<Option<Vec<u8>>, u64> {
+    let mut tip = None;
+    for (position, block) in blocks.into_iter().enumerate() {
+        let block = block.borrow();
+        if block.index != position as u64 || block.parent_hash != tip {
+            return Err(position as u64);
+        }
+        tip = Some(hash(block));
+    }
+    Ok(tip)
+}
+
+// Re-hashes the whole log against the stored tip; the error is the position
+// of the first block that does not fit (the length if only the tip is off)
+pub fn verify() -> std::result::Result<(), u64> {
+    let tip = BLOCKS.with(|blocks| check_chain(blocks.borrow().iter()))?;
+    if tip != tip_hash() {
+        return Err(count());
+    }
+    Ok(())
+}
+
+fn tip_hash() -> Option<Vec<u8>> {
+    TIP_HASH.with(|tip| Some(tip.borrow().get().clone()).filter(|hash| !hash.is_empty()))
+}
+
+pub fn append(operation: Operation, now: u64) -> u64 {
+    let block = Block {
+        index: count(),
+        parent_hash: tip_hash(),
+        timestamp: now,
+        operation,
+    };
+    BLOCKS
+        .with(|blocks| blocks.borrow().append(&block))
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory for the block log"));
+    TIP_HASH
+        .with(|tip| tip.borrow_mut().set(hash(&block)))
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to store the tip hash: {:?}", err)));
+    let deltas = block.operation.deltas();
+    for account in block.operation.accounts() {
+        let mut totals = totals_before(account, block.index);
+        for (_, asset, delta) in deltas.iter().filter(|(a, _, _)| *a == account) {
+            totals.apply(*asset, *delta);
+        }
+        ACCOUNT_BLOCKS.with(|index| index.borrow_mut().insert((account, block.index), totals));
+    }
+    block.index
+}
+
+pub fn is_auditor(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal) || AUDITORS.with(|a| a.borrow().contains(principal))
+}
+
+pub fn auditors() -> Vec<Principal> {
+    AUDITORS.with(|a| a.borrow().iter().cloned().collect())
+}
+
+pub fn set_auditor(principal: Principal, allowed: bool) {
+    AUDITORS.with(|a| {
+        let mut auditors = a.borrow_mut();
+        if allowed {
+            auditors.insert(principal);
+        } else {
+            auditors.remove(&principal);
+        }
+    });
+}
+
+fn block(index: u64) -> Block {
+    BLOCKS
+        .with(|blocks| blocks.borrow().get(index))
+        .unwrap_or_else(|| ic_cdk::trap(&format!("Block {} is missing", index)))
+}
+
+pub fn get_blocks(start: u64, length: u64) -> GetBlocksResponse {
+    let length = length.min(MAX_BLOCKS_PER_REQUEST);
+    let chain_length = count();
+    let start = start.min(chain_length);
+    let end = start.saturating_add(length).min(chain_length);
+    GetBlocksResponse {
+        chain_length,
+        tip_hash: tip_hash(),
+        blocks: (start..end).map(block).collect(),
+    }
+}
+
+fn account_indices(account: Principal) -> impl Iterator<Item = u64> {
+    let indices: Vec<u64> = ACCOUNT_BLOCKS.with(|index| {
+        index
+            .borrow()
+            .range((account, 0)..=(account, u64::MAX))
+            .map(|((_, index), _)| index)
+            .collect()
+    });
+    indices.into_iter()
+}
+
+// `start` and `length` are positions in the account's own list of blocks
+pub fn account_blocks(account: Principal, start: u64, length: u64) -> Vec<Block> {
+    let length = length.min(MAX_BLOCKS_PER_REQUEST) as usize;
+    account_indices(account)
+        .skip(start as usize)
+        .take(length)
+        .map(block)
+        .collect()
+}
+
+// The account's totals before block `index`
+pub fn totals_before(account: Principal, index: u64) -> AccountTotals {
+    ACCOUNT_BLOCKS.with(|blocks| {
+        blocks
+            .borrow()
+            .range((account, 0)..(account, index))
+            .next_back()
+            .map(|(_, totals)| totals)
+            .unwrap_or_default()
+    })
+}
+
+// Blocks touching the account with an index in `start..end`, oldest first
+pub fn account_range(account: Principal, start: u64, end: u64) -> impl Iterator<Item = Block> {
+    let indices: Vec<u64> = ACCOUNT_BLOCKS.with(|index| {
+        index
+            .borrow()
+            .range((account, start)..(account, end.max(start)))
+            .map(|((_, index), _)| index)
+            .collect()
+    });
+    indices.into_iter().map(block)
+}
+
+// Index of the first block at or after `timestamp` (the length if none).
+// Timestamps never go down along the log, so this is a binary search.
+pub fn first_at(timestamp: u64) -> u64 {
+    let (mut low, mut high) = (0, count());
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if block(middle).timestamp < timestamp {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    low
+}
+
+pub fn count() -> u64 {
+    BLOCKS.with(|blocks| blocks.borrow().len())
+}
+
+// Net amount of each asset per account after the given blocks. Signed and
+// wide so a log that does not add up shows as such instead of wrapping.
+pub fn replay_blocks(blocks: impl IntoIterator<Item = Block>) -> HashMap<(Principal, Asset), i128> {
+    let mut totals: HashMap<(Principal, Asset), i128> = HashMap::new();
+    for block in blocks {
+        for (account, asset, delta) in block.operation.deltas() {
+            *totals.entry((account, asset)).or_default() += delta;
+        }
+    }
+    totals
+}
+
+// Net amount of each asset per account according to the log
+pub fn replay() -> HashMap<(Principal, Asset), i128> {
+    BLOCKS.with(|blocks| replay_blocks(blocks.borrow().iter()))
+}
+
+pub fn restore_auditors(auditors: Vec<Principal>) {
+    AUDITORS.with(|a| *a.borrow_mut() = auditors.into_iter().collect());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn mint(to: Principal, amount: u64) -> Operation {
+        Operation::Mint { to, asset: Asset::BTC, amount, source: None }
+    }
+
+    // Chains the operations the way `append` does
+    fn chain(operations: Vec<Operation>) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for (index, operation) in operations.into_iter().enumerate() {
+            blocks.push(Block {
+                index: index as u64,
+                parent_hash: blocks.last().map(hash),
+                timestamp: index as u64 * 1_000,
+                operation,
+            });
+        }
+        blocks
+    }
+
+    #[test]
+    fn leb128_matches_the_spec_examples() {
+        assert_eq!(leb128(0), vec![0x00]);
+        assert_eq!(leb128(127), vec![0x7f]);
+        assert_eq!(leb128(128), vec![0x80, 0x01]);
+        assert_eq!(leb128(624_485), vec![0xe5, 0x8e, 0x26]);
+    }
+
+    #[test]
+    fn map_hash_does_not_depend_on_field_order() {
+        let a = Value::Map(vec![field("a", Value::Nat(1)), field("b", Value::Text("x".to_string()))]);
+        let b = Value::Map(vec![field("b", Value::Text("x".to_string())), field("a", Value::Nat(1))]);
+        let c = Value::Map(vec![field("a", Value::Nat(2)), field("b", Value::Text("x".to_string()))]);
+        assert_eq!(a.hash(), b.hash());
+        assert_ne!(a.hash(), c.hash());
+    }
+
+    #[test]
+    fn check_chain_finds_the_first_broken_block() {
+        let blocks = chain(vec![mint(user(1), 10), mint(user(2), 20), mint(user(1), 30)]);
+        assert_eq!(check_chain(&blocks), Ok(Some(hash(&blocks[2]))));
+        assert_eq!(check_chain(Vec::<Block>::new()), Ok(None));
+
+        // Changing a block breaks the link to it from the next one
+        let mut altered = blocks.clone();
+        altered[1].operation = mint(user(2), 21);
+        assert_eq!(check_chain(&altered), Err(2));
+
+        let mut misnumbered = blocks.clone();
+        misnumbered[0].index = 1;
+        assert_eq!(check_chain(&misnumbered), Err(0));
+
+        assert_eq!(check_chain(&blocks[1..]), Err(0));
+    }
+
+    #[test]
+    fn replay_nets_each_account_per_asset() {
+        let (alice, bob) = (user(1), user(2));
+        let blocks = chain(vec![
+            mint(alice, 100),
+            Operation::Mint { to: alice, asset: Asset::CkBTC, amount: 7, source: None },
+            Operation::Transfer { from: alice, to: bob, asset: Asset::BTC, amount: 30, memo: None },
+            // Holding does not change what the account owns
+            Operation::Hold { from: alice, asset: Asset::BTC, amount: 50, escrow_id: "e".to_string() },
+            Operation::Release { from: alice, to: bob, asset: Asset::BTC, amount: 40, escrow_id: "e".to_string() },
+            Operation::Release { from: alice, to: alice, asset: Asset::BTC, amount: 10, escrow_id: "e".to_string() },
+            Operation::Burn { from: bob, asset: Asset::BTC, amount: 25, withdrawal_id: "w".to_string() },
+        ]);
+
+        let totals = replay_blocks(blocks);
+        assert_eq!(totals[&(alice, Asset::BTC)], 30);
+        assert_eq!(totals[&(alice, Asset::CkBTC)], 7);
+        assert_eq!(totals[&(bob, Asset::BTC)], 45);
+        assert_eq!(totals.len(), 3);
+    }
+
+    #[test]
+    fn replay_shows_a_log_that_does_not_add_up() {
+        let blocks = chain(vec![Operation::Burn {
+            from: user(1),
+            asset: Asset::BTC,
+            amount: 5,
+            withdrawal_id: "w".to_string(),
+        }]);
+        assert_eq!(replay_blocks(blocks)[&(user(1), Asset::BTC)], -5);
+    }
+
+    #[test]
+    fn verify_checks_the_stored_log_against_its_tip() {
+        assert_eq!(verify(), Ok(()));
+        append(mint(user(1), 10), 1);
+        append(mint(user(2), 20), 2);
+        assert_eq!(verify(), Ok(()));
+
+        TIP_HASH.with(|tip| tip.borrow_mut().set(vec![0; 32])).unwrap();
+        assert_eq!(verify(), Err(2));
+    }
+    #[test]
+    fn the_index_keeps_running_totals_per_account() {
+        let (alice, bob) = (user(1), user(2));
+        append(mint(alice, 100), 10);
+        append(
+            Operation::Transfer { from: alice, to: bob, asset: Asset::BTC, amount: 30, memo: None },
+            20,
+        );
+        append(Operation::Mint { to: alice, asset: Asset::ICP, amount: 7, source: None }, 30);
+
+        assert_eq!(totals_before(alice, 0), AccountTotals::default());
+        assert_eq!(totals_before(alice, 2), AccountTotals(vec![(Asset::BTC, 70)]));
+        assert_eq!(totals_before(alice, 3), AccountTotals(vec![(Asset::BTC, 70), (Asset::ICP, 7)]));
+        assert_eq!(totals_before(bob, u64::MAX), AccountTotals(vec![(Asset::BTC, 30)]));
+    }
+
+    #[test]
+    fn first_at_finds_the_range_start() {
+        for timestamp in [10, 20, 20, 30] {
+            append(mint(user(1), 1), timestamp);
+        }
+        assert_eq!(first_at(0), 0);
+        assert_eq!(first_at(20), 1);
+        assert_eq!(first_at(21), 3);
+        assert_eq!(first_at(31), 4);
+        let indices: Vec<u64> = account_range(user(1), 1, 3).map(|b| b.index).collect();
+        assert_eq!(indices, vec![1, 2]);
+    }
+}
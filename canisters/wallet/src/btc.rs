@@ -0,0 +1,351 @@
+// Bitcoin plumbing for the wallet: addresses, UTXOs, fees and threshold
+// ECDSA signing of P2WPKH spends.
+use crate::config;
+use crate::WalletError;
+use bitcoin::absolute::LockTime;
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::ecdsa::Signature as SecpSignature;
+use bitcoin::sighash::{EcdsaSighashType, SighashCache};
+use bitcoin::transaction::Version;
+use bitcoin::{
+    Address, Amount, CompressedPublicKey, Network, OutPoint, ScriptBuf, Sequence, Transaction,
+    TxIn, TxOut, Txid, Witness,
+};
+use ic_cdk::api::management_canister::bitcoin::{
+    bitcoin_get_current_fee_percentiles, bitcoin_get_utxos, bitcoin_send_transaction,
+    BitcoinNetwork, GetCurrentFeePercentilesRequest, GetUtxosRequest, SendTransactionRequest,
+    Utxo, UtxoFilter,
+};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+    SignWithEcdsaArgument,
+};
+use std::cell::RefCell;
+use std::str::FromStr;
+
+// Virtual size estimates for P2WPKH spends
+const TX_OVERHEAD_VBYTES: u64 = 11;
+const P2WPKH_INPUT_VBYTES: u64 = 68;
+const P2WPKH_OUTPUT_VBYTES: u64 = 31;
+// Upper bound for any standard output type (P2TR/P2WSH)
+const MAX_OUTPUT_VBYTES: u64 = 43;
+
+// Change below this is added to the fee instead of creating an output
+const DUST_THRESHOLD_SATOSHIS: u64 = 546;
+
+type BtcResult<T> = std::result::Result<T, WalletError>;
+
+thread_local! {
+    static CUSTODY_KEY: RefCell<Option<CompressedPublicKey>> = RefCell::new(None);
+}
+
+// A UTXO the canister can sign for, with the key that controls it
+#[derive(Clone, Debug)]
+pub struct SpendInput {
+    pub outpoint: OutPoint,
+    pub value: u64,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub public_key: CompressedPublicKey,
+}
+
+pub fn bitcoin_error(err: impl std::fmt::Debug) -> WalletError {
+    WalletError::BitcoinError(format!("{:?}", err))
+}
+
+fn bitcoin_network() -> BitcoinNetwork {
+    config::get().bitcoin_network
+}
+
+pub fn network() -> Network {
+    match bitcoin_network() {
+        BitcoinNetwork::Mainnet => Network::Bitcoin,
+        BitcoinNetwork::Testnet => Network::Testnet,
+        BitcoinNetwork::Regtest => Network::Regtest,
+    }
+}
+
+fn key_id() -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: config::get().ecdsa_key_name,
+    }
+}
+
+// Withdrawal change goes back to the custody key
+pub fn custody_derivation_path() -> Vec<Vec<u8>> {
+    vec![b"custody".to_vec()]
+}
+
+pub fn parse_address(address: &str) -> BtcResult<Address> {
+    Address::from_str(address)
+        .and_then(|a| a.require_network(network()))
+        .map_err(|_| WalletError::InvalidAddress)
+}
+
+pub fn p2wpkh_address(public_key: &CompressedPublicKey) -> Address {
+    Address::p2wpkh(public_key, network())
+}
+
+pub async fn public_key(derivation_path: Vec<Vec<u8>>) -> BtcResult<CompressedPublicKey> {
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path,
+        key_id: key_id(),
+    })
+    .await
+    .map_err(bitcoin_error)?;
+    CompressedPublicKey::from_slice(&response.public_key).map_err(bitcoin_error)
+}
+
+pub async fn custody_key() -> BtcResult<CompressedPublicKey> {
+    if let Some(key) = CUSTODY_KEY.with(|k| *k.borrow()) {
+        return Ok(key);
+    }
+    let key = public_key(custody_derivation_path()).await?;
+    CUSTODY_KEY.with(|k| *k.borrow_mut() = Some(key));
+    Ok(key)
+}
+
+// Falls back to the configured rate when the percentiles are empty (e.g.
+// regtest) and never exceeds the configured cap
+pub async fn fee_rate_sat_per_vb() -> u64 {
+    let config = config::get();
+    let percentiles = bitcoin_get_current_fee_percentiles(GetCurrentFeePercentilesRequest {
+        network: config.bitcoin_network,
+    })
+    .await
+    .map(|(p,)| p)
+    .unwrap_or_default();
+
+    // Median of the last blocks, in millisatoshi per byte
+    percentiles
+        .get(percentiles.len() / 2)
+        .map(|msat| (msat / 1000).max(1))
+        .unwrap_or(config.fallback_fee_rate_sat_per_vb)
+        .min(config.max_fee_rate_sat_per_vb)
+}
+
+pub async fn get_utxos(address: &Address) -> BtcResult<Vec<Utxo>> {
+    get_utxos_with_tip(address).await.map(|(_, utxos)| utxos)
+}
+
+// UTXOs along with the tip height, for computing confirmations
+pub async fn get_utxos_with_tip(address: &Address) -> BtcResult<(u32, Vec<Utxo>)> {
+    let (response,) = bitcoin_get_utxos(GetUtxosRequest {
+        address: address.to_string(),
+        network: bitcoin_network(),
+        filter: None,
+    })
+    .await
+    .map_err(bitcoin_error)?;
+    Ok((response.tip_height, response.utxos))
+}
+
+// The height of the block that mined an output of `txid` at `address`, if
+// one is still unspent there, and the tip height. Pages through every UTXO
+// of the address.
+pub async fn locate_output(address: &Address, txid: &str) -> BtcResult<(Option<u32>, u32)> {
+    let mut filter = None;
+    let mut tip_height = None;
+    loop {
+        let (response,) = bitcoin_get_utxos(GetUtxosRequest {
+            address: address.to_string(),
+            network: bitcoin_network(),
+            filter,
+        })
+        .await
+        .map_err(bitcoin_error)?;
+        let tip_height = *tip_height.get_or_insert(response.tip_height);
+        let height = response
+            .utxos
+            .iter()
+            .find(|u| outpoint(u).is_ok_and(|o| o.txid.to_string() == txid))
+            .map(|u| u.height);
+        match (height, response.next_page) {
+            (Some(height), _) => return Ok((Some(height), tip_height)),
+            (None, Some(page)) => filter = Some(UtxoFilter::Page(page)),
+            (None, None) => return Ok((None, tip_height)),
+        }
+    }
+}
+
+pub fn outpoint(utxo: &Utxo) -> BtcResult<OutPoint> {
+    let txid: [u8; 32] = utxo.outpoint.txid.clone().try_into().map_err(bitcoin_error)?;
+    Ok(OutPoint::new(Txid::from_byte_array(txid), utxo.outpoint.vout))
+}
+
+pub fn estimate_fee(inputs: usize, fee_rate_sat_per_vb: u64) -> u64 {
+    let vsize = TX_OVERHEAD_VBYTES
+        + P2WPKH_INPUT_VBYTES * inputs as u64
+        + MAX_OUTPUT_VBYTES
+        + P2WPKH_OUTPUT_VBYTES;
+    vsize * fee_rate_sat_per_vb
+}
+
+// Largest-first selection until the amount and the fee for the selected
+// inputs are covered. Returns the selected inputs and the fee.
+pub fn select_inputs(
+    mut candidates: Vec<SpendInput>,
+    amount: u64,
+    fee_rate_sat_per_vb: u64,
+) -> BtcResult<(Vec<SpendInput>, u64)> {
+    candidates.sort_by_key(|input| std::cmp::Reverse(input.value));
+
+    let mut selected = Vec::new();
+    let mut total = 0u64;
+    for input in candidates {
+        total += input.value;
+        selected.push(input);
+        let fee = estimate_fee(selected.len(), fee_rate_sat_per_vb);
+        if total >= amount + fee {
+            return Ok((selected, fee));
+        }
+    }
+    Err(WalletError::InsufficientBalance)
+}
+
+// Unsigned transaction paying `amount` to `destination`, with change back to
+// `change_address`. Change below dust is left to the miners and returned as
+// part of the fee.
+pub fn build_transaction(
+    inputs: &[SpendInput],
+    destination: &Address,
+    amount: u64,
+    fee: u64,
+    change_address: &Address,
+) -> (Transaction, u64) {
+    let total: u64 = inputs.iter().map(|i| i.value).sum();
+    let change = total - amount - fee;
+
+    let mut output = vec![TxOut {
+        value: Amount::from_sat(amount),
+        script_pubkey: destination.script_pubkey(),
+    }];
+    let fee = if change >= DUST_THRESHOLD_SATOSHIS {
+        output.push(TxOut {
+            value: Amount::from_sat(change),
+            script_pubkey: change_address.script_pubkey(),
+        });
+        fee
+    } else {
+        fee + change
+    };
+
+    let tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: inputs
+            .iter()
+            .map(|i| TxIn {
+                previous_output: i.outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output,
+    };
+    (tx, fee)
+}
+
+// Sign every input with threshold ECDSA under its own derivation path
+pub async fn sign_transaction(tx: &mut Transaction, inputs: &[SpendInput]) -> BtcResult<()> {
+    let mut sighashes = Vec::with_capacity(inputs.len());
+    let mut sighasher = SighashCache::new(&*tx);
+    for (index, input) in inputs.iter().enumerate() {
+        let script_code = ScriptBuf::new_p2wpkh(&input.public_key.wpubkey_hash());
+        let sighash = sighasher
+            .p2wpkh_signature_hash(
+                index,
+                &script_code,
+                Amount::from_sat(input.value),
+                EcdsaSighashType::All,
+            )
+            .map_err(bitcoin_error)?;
+        sighashes.push(sighash);
+    }
+
+    for ((txin, input), sighash) in tx.input.iter_mut().zip(inputs).zip(sighashes) {
+        let (response,) = sign_with_ecdsa(SignWithEcdsaArgument {
+            message_hash: sighash.to_byte_array().to_vec(),
+            derivation_path: input.derivation_path.clone(),
+            key_id: key_id(),
+        })
+        .await
+        .map_err(bitcoin_error)?;
+
+        let mut signature = SecpSignature::from_compact(&response.signature).map_err(bitcoin_error)?;
+        signature.normalize_s();
+
+        txin.witness = Witness::p2wpkh(
+            &bitcoin::ecdsa::Signature {
+                signature,
+                sighash_type: EcdsaSighashType::All,
+            },
+            &input.public_key.0,
+        );
+    }
+    Ok(())
+}
+
+pub async fn broadcast(tx: &Transaction) -> BtcResult<()> {
+    bitcoin_send_transaction(SendTransactionRequest {
+        transaction: serialize(tx),
+        network: bitcoin_network(),
+    })
+    .await
+    .map_err(bitcoin_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(vout: u32, value: u64) -> SpendInput {
+        SpendInput {
+            outpoint: OutPoint::new(Txid::all_zeros(), vout),
+            value,
+            derivation_path: Vec::new(),
+            // The secp256k1 generator point
+            public_key: CompressedPublicKey::from_str(
+                "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            )
+            .unwrap(),
+        }
+    }
+
+    fn vouts(inputs: &[SpendInput]) -> Vec<u32> {
+        inputs.iter().map(|i| i.outpoint.vout).collect()
+    }
+
+    #[test]
+    fn largest_inputs_are_selected_first() {
+        let candidates = vec![input(0, 1_000), input(1, 50_000), input(2, 20_000)];
+        let (selected, fee) = select_inputs(candidates, 40_000, 10).unwrap();
+        assert_eq!(vouts(&selected), vec![1]);
+        assert_eq!(fee, estimate_fee(1, 10));
+    }
+
+    #[test]
+    fn inputs_are_added_until_the_fee_is_covered() {
+        // 50_000 covers the amount but not the fee for one input
+        let candidates = vec![input(0, 50_000), input(1, 20_000), input(2, 1_000)];
+        let (selected, fee) = select_inputs(candidates, 49_000, 10).unwrap();
+        assert_eq!(vouts(&selected), vec![0, 1]);
+        assert_eq!(fee, estimate_fee(2, 10));
+        assert_eq!(fee, (11 + 2 * 68 + 43 + 31) * 10);
+    }
+
+    #[test]
+    fn selection_fails_when_the_inputs_cannot_pay() {
+        let candidates = vec![input(0, 30_000), input(1, 20_000)];
+        let fee = estimate_fee(2, 10);
+        assert!(matches!(
+            select_inputs(candidates.clone(), 50_000 - fee + 1, 10),
+            Err(WalletError::InsufficientBalance)
+        ));
+        assert!(select_inputs(candidates, 50_000 - fee, 10).is_ok());
+        assert!(matches!(select_inputs(Vec::new(), 1, 10), Err(WalletError::InsufficientBalance)));
+    }
+}
@@ -0,0 +1,62 @@
+// Wallet configuration, passed as the init/upgrade argument or set by a
+// controller.
+use crate::*;
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct WalletConfig {
+    pub bitcoin_network: BitcoinNetwork,
+    pub ecdsa_key_name: String,
+    // Withdrawal fee rate used when the fee percentiles are empty, and the cap
+    // applied to the network rate
+    pub fallback_fee_rate_sat_per_vb: u64,
+    pub max_fee_rate_sat_per_vb: u64,
+    // BTC deposits are credited after this many confirmations
+    pub min_deposit_confirmations: u32,
+    // The only caller allowed to place and settle escrow holds
+    pub escrow_canister: Option<Principal>,
+    // Source of users' KYC levels for withdrawal limits
+    pub identity_canister: Option<Principal>,
+    // ICRC-1 ledger of each ledger asset, for reconciling holdings
+    pub ledger_canisters: Option<BTreeMap<Asset, Principal>>,
+    // The API gateway identity that places and settles VAS purchase holds,
+    // and whose balance receives completed purchases
+    pub vas_operator: Option<Principal>,
+}
+
+impl Default for WalletConfig {
+    fn default() -> Self {
+        WalletConfig {
+            bitcoin_network: BitcoinNetwork::Testnet,
+            ecdsa_key_name: "test_key_1".to_string(),
+            fallback_fee_rate_sat_per_vb: 2,
+            max_fee_rate_sat_per_vb: 500,
+            min_deposit_confirmations: 6,
+            escrow_canister: None,
+            identity_canister: None,
+            ledger_canisters: None,
+            vas_operator: None,
+        }
+    }
+}
+
+thread_local! {
+    static CONFIG: RefCell<WalletConfig> = RefCell::new(WalletConfig::default());
+}
+
+pub fn get() -> WalletConfig {
+    CONFIG.with(|config| config.borrow().clone())
+}
+
+pub fn set(new_config: WalletConfig) -> std::result::Result<(), WalletError> {
+    if new_config.ecdsa_key_name.is_empty()
+        || new_config.fallback_fee_rate_sat_per_vb == 0
+        || new_config.fallback_fee_rate_sat_per_vb > new_config.max_fee_rate_sat_per_vb
+        || new_config.min_deposit_confirmations == 0
+    {
+        return Err(WalletError::InvalidConfig);
+    }
+
+    CONFIG.with(|config| *config.borrow_mut() = new_config);
+    Ok(())
+}
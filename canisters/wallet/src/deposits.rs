@@ -0,0 +1,612 @@
+// Per-user deposit addresses and BTC deposit detection.
+//
+// Each user gets a P2WPKH address derived with threshold ECDSA from their
+// principal. A sweep queries the UTXOs of recently used addresses and of
+// those with unconfirmed deposits, reports unconfirmed amounts in
+// `pending_deposits` and credits the BTC balance once a deposit has the
+// configured number of confirmations. Idle addresses are only looked at by
+// `check_deposits`. Credited deposit UTXOs form a pool that withdrawals can
+// spend, signed with the depositor's derived key.
+//
+// Ledger assets are deposited to a per-user subaccount of this canister. A
+// check moves the subaccount balance (less the ledger fee) to the main
+// account and credits what arrived there.
+use crate::btc::{self, SpendInput};
+use crate::*;
+use bitcoin::hashes::Hash;
+use bitcoin::{CompressedPublicKey, OutPoint, Txid};
+use candid::Nat;
+use std::collections::BTreeSet;
+use std::ops::Bound::{Excluded, Unbounded};
+
+// How often the heartbeat scans deposit addresses
+const DEPOSIT_SWEEP_INTERVAL_NANOS: u64 = 10 * 60 * 1_000_000_000;
+// Minimum time between two `check_deposits` calls of the same user
+const CHECK_INTERVAL_NANOS: u64 = 60 * 1_000_000_000;
+// Addresses asked for or checked within this window are swept
+const ACTIVE_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Users refreshed per sweep; the next sweep carries on after the last one
+const SWEEP_BATCH_SIZE: usize = 50;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TrackedDeposit {
+    pub user_id: Principal,
+    pub txid: String,
+    pub vout: u32,
+    pub amount: u64,
+    pub confirmations: u32,
+    pub credited: bool,
+    // Spent by a withdrawal, no longer part of the pool
+    pub spent: bool,
+}
+
+// Outpoint as (raw txid, vout)
+pub type DepositKey = (Vec<u8>, u32);
+pub type DepositKeys = HashMap<Principal, Vec<u8>>;
+pub type Deposits = HashMap<DepositKey, TrackedDeposit>;
+
+thread_local! {
+    // Derived public key per user, needed to sign pool spends
+    static DEPOSIT_KEYS: RefCell<DepositKeys> = RefCell::new(HashMap::new());
+    static DEPOSITS: RefCell<Deposits> = RefCell::new(HashMap::new());
+    static LAST_SWEEP: RefCell<u64> = RefCell::new(0);
+    static SWEEP_IN_PROGRESS: RefCell<bool> = RefCell::new(false);
+    static LAST_CHECKS: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+    // When each user last asked for or checked a deposit address. Not kept
+    // across upgrades; until the next request only unconfirmed deposits are
+    // swept
+    static LAST_ACTIVITY: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+    // Last user refreshed by the previous sweep
+    static SWEEP_CURSOR: RefCell<Option<Principal>> = RefCell::new(None);
+}
+
+#[derive(CandidType)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// ICRC-1 transfer errors; all cases are needed to decode the result
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// Clears the sweep flag however the sweep ends, including a trap in one of
+// its callbacks
+struct SweepGuard;
+
+impl SweepGuard {
+    fn acquire() -> Option<SweepGuard> {
+        if SWEEP_IN_PROGRESS.with(|s| s.replace(true)) {
+            return None;
+        }
+        Some(SweepGuard)
+    }
+}
+
+impl Drop for SweepGuard {
+    fn drop(&mut self) {
+        SWEEP_IN_PROGRESS.with(|s| *s.borrow_mut() = false);
+    }
+}
+
+fn derivation_path(user_id: &Principal) -> Vec<Vec<u8>> {
+    vec![user_id.as_slice().to_vec()]
+}
+
+pub async fn btc_address(user_id: Principal) -> std::result::Result<String, WalletError> {
+    let key = match DEPOSIT_KEYS.with(|k| k.borrow().get(&user_id).cloned()) {
+        Some(bytes) => CompressedPublicKey::from_slice(&bytes).map_err(btc::bitcoin_error)?,
+        None => {
+            let key = btc::public_key(derivation_path(&user_id)).await?;
+            DEPOSIT_KEYS.with(|k| k.borrow_mut().insert(user_id, key.to_bytes().to_vec()));
+            key
+        }
+    };
+    Ok(btc::p2wpkh_address(&key).to_string())
+}
+
+// Subaccount of this canister that receives the user's ledger deposits
+pub fn subaccount(user_id: &Principal) -> [u8; 32] {
+    let mut subaccount = [0u8; 32];
+    let bytes = user_id.as_slice();
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+// ICRC-1 account of this canister with the user's subaccount, in the textual
+// account format
+pub fn icrc_account(user_id: &Principal) -> String {
+    let owner = ic_cdk::id();
+    let subaccount = subaccount(user_id);
+
+    let mut checksum_input = owner.as_slice().to_vec();
+    checksum_input.extend_from_slice(&subaccount);
+    let checksum = base32(&crc32(&checksum_input).to_be_bytes());
+
+    let hex: String = subaccount.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}.{}", owner, checksum, hex.trim_start_matches('0'))
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// RFC 4648 base32, lowercase and unpadded (as in principal text)
+fn base32(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut out = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+// Query the user's deposit address and update pending and credited amounts
+pub async fn refresh(user_id: Principal) -> std::result::Result<WalletBalance, WalletError> {
+    let address = ADDRESSES.with(|a| {
+        a.borrow()
+            .get(&user_id)
+            .and_then(|addrs| addrs.iter().find(|a| a.currency == Asset::BTC).cloned())
+    });
+    let Some(address) = address else {
+        return Err(WalletError::NotFound);
+    };
+
+    let (tip_height, utxos) = btc::get_utxos_with_tip(&btc::parse_address(&address.address)?).await?;
+    let min_confirmations = config::get().min_deposit_confirmations;
+    let now = time();
+
+    let mut newly_credited = Vec::new();
+    DEPOSITS.with(|deposits| {
+        let mut deposits = deposits.borrow_mut();
+        // Unconfirmed deposits that vanished were dropped by a reorg
+        let current: HashSet<DepositKey> = utxos
+            .iter()
+            .map(|u| (u.outpoint.txid.clone(), u.outpoint.vout))
+            .collect();
+        deposits.retain(|key, d| d.user_id != user_id || d.credited || current.contains(key));
+
+        for utxo in &utxos {
+            let key = (utxo.outpoint.txid.clone(), utxo.outpoint.vout);
+            let deposit = deposits.entry(key.clone()).or_insert_with(|| TrackedDeposit {
+                user_id,
+                txid: btc::outpoint(utxo)
+                    .map(|o| o.txid.to_string())
+                    .unwrap_or_default(),
+                vout: utxo.outpoint.vout,
+                amount: utxo.value,
+                confirmations: 0,
+                credited: false,
+                spent: false,
+            });
+            if deposit.credited {
+                continue;
+            }
+            deposit.confirmations = tip_height.saturating_sub(utxo.height) + 1;
+            if deposit.confirmations >= min_confirmations {
+                deposit.credited = true;
+                newly_credited.push((key, deposit.clone()));
+            }
+        }
+    });
+
+    for (key, deposit) in newly_credited {
+        if !credit(deposit, now) {
+            // Left uncredited so the next check tries again
+            DEPOSITS.with(|d| {
+                if let Some(deposit) = d.borrow_mut().get_mut(&key) {
+                    deposit.credited = false;
+                }
+            });
+        }
+    }
+
+    let pending = DEPOSITS.with(|deposits| {
+        deposits
+            .borrow()
+            .values()
+            .filter(|d| d.user_id == user_id && !d.credited)
+            .fold(0u64, |sum, d| sum.saturating_add(d.amount))
+    });
+    BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let balance = balances.entry(user_id).or_insert_with(|| new_balance(user_id, now));
+        balance.asset_mut(Asset::BTC).pending_deposits = pending;
+        balance.last_updated = now;
+        Ok(balance.clone())
+    })
+}
+
+// Returns false if the deposit could not be credited
+fn credit(deposit: TrackedDeposit, now: u64) -> bool {
+    let source = CreditSource::Deposit {
+        txid: deposit.txid.clone(),
+        vout: deposit.vout,
+    };
+    // Already credited through another path (e.g. a minter)
+    if CREDITED_SOURCES.with(|sources| sources.borrow().contains(&source)) {
+        return true;
+    }
+
+    let credited = BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let balance = balances
+            .entry(deposit.user_id)
+            .or_insert_with(|| new_balance(deposit.user_id, now));
+        balance.asset_mut(Asset::BTC).credit(deposit.amount)?;
+        balance.last_updated = now;
+        Ok::<_, WalletError>(())
+    });
+    if let Err(err) = credited {
+        ic_cdk::println!("Crediting deposit {}:{} failed: {:?}", deposit.txid, deposit.vout, err);
+        return false;
+    }
+    CREDITED_SOURCES.with(|sources| sources.borrow_mut().insert(source.clone()));
+    let tx = Transaction {
+        tx_id: generate_tx_id(),
+        user_id: deposit.user_id,
+        tx_type: "deposit".to_string(),
+        amount: deposit.amount,
+        currency: Asset::BTC,
+        status: "confirmed".to_string(),
+        created_at: now,
+        confirmed_at: Some(now),
+        source: Some(source.clone()),
+        memo: None,
+    };
+    blocks::append(
+        blocks::Operation::Mint {
+            to: deposit.user_id,
+            asset: Asset::BTC,
+            amount: deposit.amount,
+            source: Some(source),
+        },
+        now,
+    );
+    TRANSACTIONS.with(|txs| txs.borrow_mut().entry(deposit.user_id).or_default().push(tx));
+    true
+}
+
+pub fn sweep_due(now: u64) -> bool {
+    LAST_SWEEP.with(|last| {
+        let mut last = last.borrow_mut();
+        if now.saturating_sub(*last) < DEPOSIT_SWEEP_INTERVAL_NANOS {
+            return false;
+        }
+        *last = now;
+        true
+    })
+}
+
+// The user is expecting a deposit; keeps their addresses in the sweep for
+// `ACTIVE_WINDOW_NANOS`
+pub fn touch(user_id: Principal, now: u64) {
+    LAST_ACTIVITY.with(|a| a.borrow_mut().insert(user_id, now));
+}
+
+// The next `SWEEP_BATCH_SIZE` users, in principal order after the previous
+// batch, among those recently active or with an unconfirmed deposit
+fn sweep_batch(now: u64) -> Vec<Principal> {
+    let mut due: BTreeSet<Principal> = LAST_ACTIVITY.with(|a| {
+        let mut a = a.borrow_mut();
+        a.retain(|_, last| now.saturating_sub(*last) < ACTIVE_WINDOW_NANOS);
+        a.keys().cloned().collect()
+    });
+    DEPOSITS.with(|d| {
+        due.extend(d.borrow().values().filter(|d| !d.credited).map(|d| d.user_id))
+    });
+
+    let batch: Vec<Principal> = match SWEEP_CURSOR.with(|c| *c.borrow()) {
+        Some(cursor) => due
+            .range((Excluded(cursor), Unbounded))
+            .chain(due.range(..=cursor))
+            .take(SWEEP_BATCH_SIZE)
+            .cloned()
+            .collect(),
+        None => due.iter().take(SWEEP_BATCH_SIZE).cloned().collect(),
+    };
+    SWEEP_CURSOR.with(|c| *c.borrow_mut() = batch.last().copied());
+    batch
+}
+
+// Refresh the next batch of deposit addresses, one user at a time
+pub async fn sweep() {
+    let Some(_guard) = SweepGuard::acquire() else {
+        return;
+    };
+    let now = time();
+    LAST_CHECKS.with(|c| {
+        c.borrow_mut()
+            .retain(|_, last| now.saturating_sub(*last) < CHECK_INTERVAL_NANOS)
+    });
+
+    for user_id in sweep_batch(now) {
+        if let Err(err) = refresh_all(user_id).await {
+            ic_cdk::println!("Deposit sweep failed for {}: {:?}", user_id, err);
+        }
+    }
+}
+
+// Refresh every deposit address of the user
+async fn refresh_all(user_id: Principal) -> std::result::Result<WalletBalance, WalletError> {
+    let assets: Vec<Asset> = ADDRESSES.with(|a| {
+        a.borrow()
+            .get(&user_id)
+            .map(|addrs| addrs.iter().map(|a| a.currency).collect())
+            .unwrap_or_default()
+    });
+    if assets.is_empty() {
+        return Err(WalletError::NotFound);
+    }
+
+    let ledgers = config::get().ledger_canisters.unwrap_or_default();
+    for asset in assets {
+        match (asset, ledgers.get(&asset)) {
+            (Asset::BTC, _) => {
+                refresh(user_id).await?;
+            }
+            (_, Some(ledger)) => claim_ledger_deposit(user_id, asset, *ledger).await?,
+            // Addresses handed out before ledgers had to be configured
+            (_, None) => {}
+        }
+    }
+    Ok(get_or_create_balance(user_id))
+}
+
+// `check_deposits`: refresh the caller's addresses now, at most once per
+// `CHECK_INTERVAL_NANOS`
+pub async fn check(user_id: Principal) -> std::result::Result<WalletBalance, WalletError> {
+    let now = time();
+    LAST_CHECKS.with(|c| {
+        let mut c = c.borrow_mut();
+        if let Some(last) = c.get(&user_id) {
+            let retry_at = last.saturating_add(CHECK_INTERVAL_NANOS);
+            if now < retry_at {
+                return Err(WalletError::RateLimited { retry_at });
+            }
+        }
+        c.insert(user_id, now);
+        Ok(())
+    })?;
+    touch(user_id, now);
+    refresh_all(user_id).await
+}
+
+pub async fn ledger_balance(ledger: Principal, account: Account) -> std::result::Result<u64, WalletError> {
+    let (balance,): (Nat,) = ic_cdk::call(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(code, msg)| {
+            WalletError::TransferFailed(format!("icrc1_balance_of failed: {:?} {}", code, msg))
+        })?;
+    nat_to_u64(&balance)
+}
+
+fn nat_to_u64(value: &Nat) -> std::result::Result<u64, WalletError> {
+    match value.0.to_u64_digits().as_slice() {
+        [] => Ok(0),
+        [value] => Ok(*value),
+        _ => Err(WalletError::ArithmeticOverflow),
+    }
+}
+
+// Move the user's deposit subaccount balance to the main account and credit
+// the amount moved
+async fn claim_ledger_deposit(
+    user_id: Principal,
+    asset: Asset,
+    ledger: Principal,
+) -> std::result::Result<(), WalletError> {
+    let subaccount = subaccount(&user_id).to_vec();
+    let balance = ledger_balance(
+        ledger,
+        Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(subaccount.clone()),
+        },
+    )
+    .await?;
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, msg)| {
+            WalletError::TransferFailed(format!("icrc1_fee failed: {:?} {}", code, msg))
+        })?;
+    let fee = nat_to_u64(&fee)?;
+    if balance <= fee {
+        return Ok(());
+    }
+
+    let amount = balance - fee;
+    let arg = TransferArg {
+        from_subaccount: Some(subaccount),
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        },
+        amount: Nat::from(amount),
+        fee: Some(Nat::from(fee)),
+        memo: None,
+        created_at_time: None,
+    };
+    let (result,): (std::result::Result<Nat, TransferError>,) =
+        ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+            .await
+            .map_err(|(code, msg)| {
+                WalletError::TransferFailed(format!("icrc1_transfer failed: {:?} {}", code, msg))
+            })?;
+    let block_index = match result {
+        Ok(block_index) => nat_to_u64(&block_index)?,
+        // A concurrent check moved the balance first
+        Err(TransferError::InsufficientFunds { .. }) => return Ok(()),
+        Err(err) => return Err(WalletError::TransferFailed(format!("{:?}", err))),
+    };
+
+    let now = time();
+    let source = CreditSource::LedgerDeposit { asset, block_index };
+    let credited = BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let balance = balances.entry(user_id).or_insert_with(|| new_balance(user_id, now));
+        balance.asset_mut(asset).credit(amount)?;
+        balance.last_updated = now;
+        Ok::<_, WalletError>(())
+    });
+    if let Err(err) = credited {
+        // The funds are in the main account, so reconciliation reports them
+        ic_cdk::println!("Crediting {} deposit {} failed: {:?}", asset.symbol(), block_index, err);
+        return Err(err);
+    }
+    CREDITED_SOURCES.with(|sources| sources.borrow_mut().insert(source.clone()));
+    blocks::append(
+        blocks::Operation::Mint {
+            to: user_id,
+            asset,
+            amount,
+            source: Some(source.clone()),
+        },
+        now,
+    );
+    TRANSACTIONS.with(|txs| {
+        txs.borrow_mut().entry(user_id).or_default().push(Transaction {
+            tx_id: generate_tx_id(),
+            user_id,
+            tx_type: "deposit".to_string(),
+            amount,
+            currency: asset,
+            status: "confirmed".to_string(),
+            created_at: now,
+            confirmed_at: Some(now),
+            source: Some(source),
+            memo: None,
+        })
+    });
+    Ok(())
+}
+
+// Credited, unspent deposit UTXOs with the keys to spend them
+pub fn spendable_inputs() -> Vec<SpendInput> {
+    DEPOSITS.with(|deposits| {
+        deposits
+            .borrow()
+            .iter()
+            .filter(|(_, d)| d.credited && !d.spent)
+            .filter_map(|((txid, vout), d)| {
+                let key = DEPOSIT_KEYS.with(|k| k.borrow().get(&d.user_id).cloned())?;
+                let txid: [u8; 32] = txid.clone().try_into().ok()?;
+                Some(SpendInput {
+                    outpoint: OutPoint::new(Txid::from_byte_array(txid), *vout),
+                    value: d.amount,
+                    derivation_path: derivation_path(&d.user_id),
+                    public_key: CompressedPublicKey::from_slice(&key).ok()?,
+                })
+            })
+            .collect()
+    })
+}
+
+pub fn is_pooled(key: &DepositKey) -> bool {
+    DEPOSITS.with(|d| d.borrow().get(key).is_some_and(|d| d.credited && !d.spent))
+}
+
+pub fn mark_spent(keys: &[DepositKey]) {
+    DEPOSITS.with(|deposits| {
+        let mut deposits = deposits.borrow_mut();
+        for key in keys {
+            if let Some(deposit) = deposits.get_mut(key) {
+                deposit.spent = true;
+            }
+        }
+    });
+}
+
+pub fn count() -> u64 {
+    DEPOSITS.with(|d| d.borrow().len() as u64)
+}
+
+pub fn snapshot() -> (DepositKeys, Deposits) {
+    (
+        DEPOSIT_KEYS.with(|k| k.borrow().clone()),
+        DEPOSITS.with(|d| d.borrow().clone()),
+    )
+}
+
+pub fn restore(keys: DepositKeys, deposits: Deposits) {
+    DEPOSIT_KEYS.with(|k| *k.borrow_mut() = keys);
+    DEPOSITS.with(|d| *d.borrow_mut() = deposits);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(n: u8) -> Principal {
+        Principal::from_slice(&[n])
+    }
+
+    fn unconfirmed(user_id: Principal) {
+        let deposit = TrackedDeposit {
+            user_id,
+            txid: String::new(),
+            vout: 0,
+            amount: 1_000,
+            confirmations: 0,
+            credited: false,
+            spent: false,
+        };
+        DEPOSITS.with(|d| d.borrow_mut().insert((vec![user_id.as_slice()[0]], 0), deposit));
+    }
+
+    #[test]
+    fn only_active_users_and_unconfirmed_deposits_are_swept() {
+        let now = ACTIVE_WINDOW_NANOS * 2;
+        touch(user(1), now - ACTIVE_WINDOW_NANOS);
+        touch(user(2), now - ACTIVE_WINDOW_NANOS + 1);
+        unconfirmed(user(3));
+        assert_eq!(sweep_batch(now), vec![user(2), user(3)]);
+    }
+
+    #[test]
+    fn batches_carry_on_after_the_previous_one() {
+        let users: Vec<Principal> = (0..(SWEEP_BATCH_SIZE + 10) as u8).map(user).collect();
+        for user_id in &users {
+            touch(*user_id, 0);
+        }
+        assert_eq!(sweep_batch(0), users[..SWEEP_BATCH_SIZE]);
+        let wrapped: Vec<Principal> = users[SWEEP_BATCH_SIZE..].iter().chain(&users[..40]).cloned().collect();
+        assert_eq!(sweep_batch(0), wrapped);
+    }
+}
@@ -0,0 +1,67 @@
+// Paginated transaction history.
+//
+// A user's transactions are stored oldest first and only ever appended, so a
+// position in that list is a stable cursor. Pages are returned newest first;
+// `next_cursor` is the position to continue from for older entries.
+use crate::*;
+
+pub const DEFAULT_PAGE_SIZE: u32 = 20;
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+// All fields are optional; `from`/`to` bound `created_at` (inclusive)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TransactionQuery {
+    pub asset: Option<Asset>,
+    pub tx_type: Option<String>,
+    pub status: Option<String>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub cursor: Option<u64>,
+    pub limit: Option<u32>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TransactionPage {
+    pub transactions: Vec<Transaction>,
+    pub next_cursor: Option<u64>,
+    // Number of transactions matching the filters, across all pages
+    pub total: u64,
+}
+
+fn matches(tx: &Transaction, query: &TransactionQuery) -> bool {
+    query.asset.is_none_or(|asset| tx.currency == asset)
+        && query.tx_type.as_ref().is_none_or(|t| &tx.tx_type == t)
+        && query.status.as_ref().is_none_or(|s| &tx.status == s)
+        && query.from.is_none_or(|from| tx.created_at >= from)
+        && query.to.is_none_or(|to| tx.created_at <= to)
+}
+
+pub fn page(user_id: Principal, query: &TransactionQuery) -> TransactionPage {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+
+    TRANSACTIONS.with(|txs| {
+        let txs = txs.borrow();
+        let all = txs.get(&user_id).map(Vec::as_slice).unwrap_or_default();
+        let total = all.iter().filter(|tx| matches(tx, query)).count() as u64;
+
+        let end = query.cursor.map_or(all.len(), |c| (c as usize).min(all.len()));
+        let mut transactions = Vec::with_capacity(limit);
+        let mut next_cursor = None;
+        for (position, tx) in all[..end].iter().enumerate().rev() {
+            if !matches(tx, query) {
+                continue;
+            }
+            if transactions.len() == limit {
+                next_cursor = Some(position as u64 + 1);
+                break;
+            }
+            transactions.push(tx.clone());
+        }
+
+        TransactionPage {
+            transactions,
+            next_cursor,
+            total,
+        }
+    })
+}
@@ -0,0 +1,247 @@
+// Escrow holds on wallet balances.
+//
+// The escrow canister can reserve part of a user's available balance for one
+// of its escrows. The held amount stays with the payer, shown as `held`,
+// until the escrow closes: on release it is transferred to the recipient, on
+// refund it goes back to the payer's available balance. There is at most one
+// hold per escrow, and repeating a call that already took effect returns the
+// hold unchanged so the escrow canister can safely retry.
+//
+// Agent cash-in/cash-out (agents.rs) and VAS purchases (vas.rs) use the same
+// holds, keyed by the agent transaction or purchase ID behind `AGENT_PREFIX`
+// or `VAS_PREFIX`. Each hold records its origin, and only that flow can
+// settle it.
+use crate::*;
+
+pub const AGENT_PREFIX: &str = "AGENT-";
+pub const VAS_PREFIX: &str = "VAS-";
+
+thread_local! {
+    static HOLDS: RefCell<HashMap<String, Hold>> = RefCell::new(HashMap::new());
+}
+
+fn require_escrow_canister() -> std::result::Result<(), WalletError> {
+    match config::get().escrow_canister {
+        Some(escrow) if escrow == caller() => Ok(()),
+        _ => Err(WalletError::Unauthorized),
+    }
+}
+
+fn record_transaction(user_id: Principal, tx: Transaction) {
+    TRANSACTIONS.with(|txs| txs.borrow_mut().entry(user_id).or_default().push(tx));
+}
+
+pub fn place(params: HoldParams, now: u64) -> std::result::Result<Hold, WalletError> {
+    require_escrow_canister()?;
+    if params.escrow_id.starts_with(AGENT_PREFIX) || params.escrow_id.starts_with(VAS_PREFIX) {
+        return Err(WalletError::InvalidArgument("Invalid escrow ID".to_string()));
+    }
+    create(params, HoldOrigin::Escrow, "escrow_out", now)
+}
+
+// Place a hold without checking the caller; `tx_type` labels the payer's
+// history entry
+pub fn create(
+    params: HoldParams,
+    origin: HoldOrigin,
+    tx_type: &str,
+    now: u64,
+) -> std::result::Result<Hold, WalletError> {
+    if params.amount == 0 {
+        return Err(WalletError::InvalidAmount);
+    }
+
+    if let Some(existing) = get(&params.escrow_id) {
+        let same = existing.origin == origin
+            && existing.user_id == params.user_id
+            && existing.amount == params.amount
+            && existing.currency == params.currency;
+        return if same { Ok(existing) } else { Err(WalletError::HoldConflict) };
+    }
+
+    BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let balance = balances
+            .get_mut(&params.user_id)
+            .ok_or(WalletError::InsufficientBalance)?;
+        balance.asset_mut(params.currency).hold(params.amount)?;
+        balance.last_updated = now;
+        Ok(())
+    })?;
+
+    let tx_id = generate_tx_id();
+    record_transaction(
+        params.user_id,
+        Transaction {
+            tx_id: tx_id.clone(),
+            user_id: params.user_id,
+            tx_type: tx_type.to_string(),
+            amount: params.amount,
+            currency: params.currency,
+            status: "pending".to_string(),
+            created_at: now,
+            confirmed_at: None,
+            source: None,
+            memo: Some(params.escrow_id.clone()),
+        },
+    );
+
+    blocks::append(
+        blocks::Operation::Hold {
+            from: params.user_id,
+            asset: params.currency,
+            amount: params.amount,
+            escrow_id: params.escrow_id.clone(),
+        },
+        now,
+    );
+
+    let hold = Hold {
+        escrow_id: params.escrow_id,
+        user_id: params.user_id,
+        amount: params.amount,
+        currency: params.currency,
+        status: HoldStatus::Active,
+        tx_id,
+        created_at: now,
+        updated_at: now,
+        origin,
+    };
+    HOLDS.with(|holds| holds.borrow_mut().insert(hold.escrow_id.clone(), hold.clone()));
+    Ok(hold)
+}
+
+// Take the held amount off the payer and set the hold's final status. On
+// release, `tx_type` labels the recipient's history entry. A hold placed by
+// another flow is reported as not found.
+pub fn settle(
+    escrow_id: &str,
+    origin: HoldOrigin,
+    status: HoldStatus,
+    tx_type: &str,
+    now: u64,
+) -> std::result::Result<Hold, WalletError> {
+    let mut hold = get(escrow_id)
+        .filter(|h| h.origin == origin)
+        .ok_or(WalletError::NotFound)?;
+    if hold.status == status {
+        return Ok(hold);
+    }
+    if hold.status != HoldStatus::Active {
+        return Err(WalletError::HoldConflict);
+    }
+
+    BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        // Both sides are worked out on copies so a failure changes neither
+        let mut payer_asset = balances
+            .get(&hold.user_id)
+            .ok_or(WalletError::NotFound)?
+            .asset(hold.currency);
+        let recipient_asset = match status {
+            HoldStatus::Released { to } => {
+                payer_asset.spend_held(hold.amount)?;
+                let mut recipient_asset = balances
+                    .get(&to)
+                    .map(|b| b.asset(hold.currency))
+                    .unwrap_or_default();
+                recipient_asset.credit(hold.amount)?;
+                Some((to, recipient_asset))
+            }
+            _ => {
+                payer_asset.unhold(hold.amount)?;
+                None
+            }
+        };
+
+        let payer = balances.get_mut(&hold.user_id).ok_or(WalletError::NotFound)?;
+        *payer.asset_mut(hold.currency) = payer_asset;
+        payer.last_updated = now;
+        if let Some((to, recipient_asset)) = recipient_asset {
+            let recipient = balances.entry(to).or_insert_with(|| new_balance(to, now));
+            *recipient.asset_mut(hold.currency) = recipient_asset;
+            recipient.last_updated = now;
+        }
+        Ok::<_, WalletError>(())
+    })?;
+
+    let to = match status {
+        HoldStatus::Released { to } => to,
+        _ => hold.user_id,
+    };
+    blocks::append(
+        blocks::Operation::Release {
+            from: hold.user_id,
+            to,
+            asset: hold.currency,
+            amount: hold.amount,
+            escrow_id: hold.escrow_id.clone(),
+        },
+        now,
+    );
+
+    if to != hold.user_id {
+        set_transaction_status(hold.user_id, &hold.tx_id, "confirmed", now);
+        record_transaction(
+            to,
+            Transaction {
+                tx_id: hold.tx_id.clone(),
+                user_id: to,
+                tx_type: tx_type.to_string(),
+                amount: hold.amount,
+                currency: hold.currency,
+                status: "confirmed".to_string(),
+                created_at: now,
+                confirmed_at: Some(now),
+                source: None,
+                memo: Some(hold.escrow_id.clone()),
+            },
+        );
+    } else {
+        set_transaction_status(hold.user_id, &hold.tx_id, "returned", now);
+    }
+
+    hold.status = status;
+    hold.updated_at = now;
+    HOLDS.with(|holds| holds.borrow_mut().insert(hold.escrow_id.clone(), hold.clone()));
+    Ok(hold)
+}
+
+pub fn release(escrow_id: &str, to: Principal, now: u64) -> std::result::Result<Hold, WalletError> {
+    require_escrow_canister()?;
+    settle(escrow_id, HoldOrigin::Escrow, HoldStatus::Released { to }, "escrow_in", now)
+}
+
+pub fn refund(escrow_id: &str, now: u64) -> std::result::Result<Hold, WalletError> {
+    require_escrow_canister()?;
+    settle(escrow_id, HoldOrigin::Escrow, HoldStatus::Returned, "escrow_in", now)
+}
+
+pub fn get(escrow_id: &str) -> Option<Hold> {
+    HOLDS.with(|holds| holds.borrow().get(escrow_id).cloned())
+}
+
+pub fn for_user(user_id: Principal) -> Vec<Hold> {
+    HOLDS.with(|holds| {
+        let mut user_holds: Vec<Hold> = holds
+            .borrow()
+            .values()
+            .filter(|h| h.user_id == user_id)
+            .cloned()
+            .collect();
+        user_holds.sort_by_key(|h| std::cmp::Reverse(h.created_at));
+        user_holds
+    })
+}
+
+pub fn count() -> u64 {
+    HOLDS.with(|holds| holds.borrow().len() as u64)
+}
+
+pub fn snapshot() -> HashMap<String, Hold> {
+    HOLDS.with(|holds| holds.borrow().clone())
+}
+
+pub fn restore(holds: HashMap<String, Hold>) {
+    HOLDS.with(|h| *h.borrow_mut() = holds);
+}
@@ -0,0 +1,93 @@
+// Deduplication of retried transfers.
+//
+// A transfer sent with an idempotency key is remembered per caller for
+// `DEDUP_WINDOW_NANOS`. A retry with the same key inside the window gets the
+// original result back instead of moving funds again. Only successful
+// transfers are remembered, so a failed attempt can be retried with its key.
+use crate::*;
+use std::collections::VecDeque;
+
+pub const DEDUP_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+pub const MAX_KEY_LEN: usize = 64;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CompletedTransfer {
+    pub params: TransferParams,
+    pub result: WalletBalance,
+    pub completed_at: u64,
+}
+
+pub type CompletedTransfers = HashMap<(Principal, String), CompletedTransfer>;
+
+thread_local! {
+    static COMPLETED: RefCell<CompletedTransfers> = RefCell::new(HashMap::new());
+    // Keys in completion order, so expired entries are dropped oldest first
+    static EXPIRY_QUEUE: RefCell<VecDeque<(u64, Principal, String)>> = RefCell::new(VecDeque::new());
+}
+
+pub fn validate_key(key: &str) -> std::result::Result<(), WalletError> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(WalletError::TransferFailed(format!(
+            "Idempotency key must be 1 to {} bytes",
+            MAX_KEY_LEN
+        )));
+    }
+    Ok(())
+}
+
+fn prune(now: u64) {
+    EXPIRY_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        while let Some((completed_at, _, _)) = queue.front() {
+            if completed_at.saturating_add(DEDUP_WINDOW_NANOS) > now {
+                break;
+            }
+            let (_, user_id, key) = queue.pop_front().unwrap();
+            COMPLETED.with(|c| c.borrow_mut().remove(&(user_id, key)));
+        }
+    });
+}
+
+// The original result for a retried transfer, or `None` if the key is new.
+// Reusing a key for a different transfer is rejected.
+pub fn lookup(
+    user_id: Principal,
+    key: &str,
+    params: &TransferParams,
+    now: u64,
+) -> std::result::Result<Option<WalletBalance>, WalletError> {
+    prune(now);
+    COMPLETED.with(|c| match c.borrow().get(&(user_id, key.to_string())) {
+        Some(previous) if previous.params == *params => Ok(Some(previous.result.clone())),
+        Some(_) => Err(WalletError::IdempotencyKeyReused),
+        None => Ok(None),
+    })
+}
+
+pub fn record(user_id: Principal, key: String, params: TransferParams, result: WalletBalance, now: u64) {
+    EXPIRY_QUEUE.with(|q| q.borrow_mut().push_back((now, user_id, key.clone())));
+    COMPLETED.with(|c| {
+        c.borrow_mut().insert(
+            (user_id, key),
+            CompletedTransfer { params, result, completed_at: now },
+        )
+    });
+}
+
+pub fn count() -> u64 {
+    COMPLETED.with(|c| c.borrow().len() as u64)
+}
+
+pub fn snapshot() -> CompletedTransfers {
+    COMPLETED.with(|c| c.borrow().clone())
+}
+
+pub fn restore(completed: CompletedTransfers) {
+    let mut queue: Vec<_> = completed
+        .iter()
+        .map(|((user_id, key), transfer)| (transfer.completed_at, *user_id, key.clone()))
+        .collect();
+    queue.sort();
+    EXPIRY_QUEUE.with(|q| *q.borrow_mut() = queue.into());
+    COMPLETED.with(|c| *c.borrow_mut() = completed);
+}
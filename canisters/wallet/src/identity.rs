@@ -0,0 +1,25 @@
+// Profile lookups in the identity canister.
+use crate::*;
+
+// The fields of the identity canister's `UserProfile` the wallet uses
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Profile {
+    pub kyc_level: u8,
+    pub is_agent: bool,
+}
+
+pub async fn profile(user_id: Principal) -> std::result::Result<Option<Profile>, WalletError> {
+    let identity = config::get()
+        .identity_canister
+        .ok_or(WalletError::InvalidConfig)?;
+    let (profile,): (Option<Profile>,) = ic_cdk::call(identity, "get_profile", (user_id,))
+        .await
+        .map_err(|(code, msg)| {
+            WalletError::TransferFailed(format!("Profile lookup failed: {:?} {}", code, msg))
+        })?;
+    Ok(profile)
+}
+
+pub async fn is_agent(user_id: Principal) -> std::result::Result<bool, WalletError> {
+    Ok(profile(user_id).await?.is_some_and(|p| p.is_agent))
+}
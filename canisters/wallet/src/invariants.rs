@@ -0,0 +1,351 @@
+// Solvency and consistency checks.
+//
+// `total_supply` is what users are owed per asset: the sum of every
+// balance's available, held and pending-withdrawal amounts. `check` compares
+// it with what the canister actually holds (BTC in custody and deposit
+// UTXOs, ledger assets on their ICRC-1 ledgers), and cross-checks each
+// balance against the block log, its holds and its withdrawals. Problems are
+// reported, never fixed automatically.
+use crate::*;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AssetReconciliation {
+    pub asset: Asset,
+    pub liabilities: u64,
+    // None when it could not be determined (e.g. no ledger configured)
+    pub holdings: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct InvariantReport {
+    pub checked_at: u64,
+    pub assets: Vec<AssetReconciliation>,
+    pub discrepancies: Vec<String>,
+}
+
+pub fn total_supply() -> std::result::Result<Vec<(Asset, u64)>, WalletError> {
+    let mut totals: BTreeMap<Asset, u64> = Asset::ALL.into_iter().map(|a| (a, 0)).collect();
+    BALANCES.with(|balances| {
+        for balance in balances.borrow().values() {
+            for (asset, b) in &balance.balances {
+                let total = totals.entry(*asset).or_default();
+                *total = total
+                    .checked_add(b.total()?)
+                    .ok_or(WalletError::ArithmeticOverflow)?;
+            }
+        }
+        Ok(totals.into_iter().collect())
+    })
+}
+
+// Balances that disagree with the block log, the active holds or the
+// withdrawals in flight
+fn account_discrepancies() -> Vec<String> {
+    let mut discrepancies = Vec::new();
+    let mut logged = blocks::replay();
+
+    let mut held: HashMap<(Principal, Asset), u64> = HashMap::new();
+    for hold in holds::snapshot().into_values() {
+        if hold.status == HoldStatus::Active {
+            let entry = held.entry((hold.user_id, hold.currency)).or_default();
+            *entry = entry.saturating_add(hold.amount);
+        }
+    }
+
+    // Scheduled withdrawals reserve their amount; the fee is added once the
+    // transaction is built
+    let mut withdrawing: HashMap<Principal, u64> = HashMap::new();
+    for w in withdrawals::snapshot().0.into_values() {
+        if matches!(w.status, WithdrawalStatus::Scheduled { .. } | WithdrawalStatus::Pending) {
+            let entry = withdrawing.entry(w.user_id).or_default();
+            *entry = entry.saturating_add(w.amount).saturating_add(w.fee);
+        }
+    }
+
+    BALANCES.with(|balances| {
+        for (user_id, balance) in balances.borrow().iter() {
+            for (asset, b) in &balance.balances {
+                let key = (*user_id, *asset);
+                let total = b.available as i128 + b.held as i128 + b.pending_withdrawals as i128;
+                let expected = logged.remove(&key).unwrap_or(0);
+                if total != expected {
+                    discrepancies.push(format!(
+                        "{} {}: balance totals {} but the block log gives {}",
+                        user_id,
+                        asset.symbol(),
+                        total,
+                        expected
+                    ));
+                }
+
+                let expected_held = held.remove(&key).unwrap_or(0);
+                if b.held != expected_held {
+                    discrepancies.push(format!(
+                        "{} {}: {} held but active holds total {}",
+                        user_id,
+                        asset.symbol(),
+                        b.held,
+                        expected_held
+                    ));
+                }
+
+                let expected_pending = match asset {
+                    Asset::BTC => withdrawing.remove(user_id).unwrap_or(0),
+                    _ => 0,
+                };
+                if b.pending_withdrawals != expected_pending {
+                    discrepancies.push(format!(
+                        "{} {}: {} pending withdrawal but withdrawals in flight total {}",
+                        user_id,
+                        asset.symbol(),
+                        b.pending_withdrawals,
+                        expected_pending
+                    ));
+                }
+            }
+        }
+    });
+
+    // Whatever is left has no balance record at all
+    for ((user_id, asset), amount) in logged.into_iter().filter(|(_, amount)| *amount != 0) {
+        discrepancies.push(format!(
+            "{} {}: no balance but the block log gives {}",
+            user_id,
+            asset.symbol(),
+            amount
+        ));
+    }
+    for ((user_id, asset), amount) in held {
+        discrepancies.push(format!(
+            "{} {}: no balance but active holds total {}",
+            user_id,
+            asset.symbol(),
+            amount
+        ));
+    }
+    for (user_id, amount) in withdrawing {
+        discrepancies.push(format!(
+            "{} BTC: no balance but withdrawals in flight total {}",
+            user_id, amount
+        ));
+    }
+    discrepancies
+}
+
+// Confirmed custody UTXOs not yet spent by a submitted withdrawal, plus
+// credited deposits still at their deposit address. The change output of a
+// withdrawal is not counted until it confirms, so a small shortfall right
+// after a withdrawal is expected.
+async fn btc_holdings() -> std::result::Result<u64, WalletError> {
+    let custody_key = btc::custody_key().await?;
+    let utxos = btc::get_utxos(&btc::p2wpkh_address(&custody_key)).await?;
+
+    let (withdrawals, reserved, _) = withdrawals::snapshot();
+    let spent: HashSet<(Vec<u8>, u32)> = reserved
+        .into_iter()
+        .filter(|(_, id)| {
+            withdrawals
+                .get(id)
+                .is_some_and(|w| matches!(w.status, WithdrawalStatus::Submitted | WithdrawalStatus::Confirmed))
+        })
+        .map(|(outpoint, _)| outpoint)
+        .collect();
+
+    let custody = utxos
+        .iter()
+        .filter(|u| !spent.contains(&(u.outpoint.txid.clone(), u.outpoint.vout)))
+        .map(|u| u.value);
+    let deposited = deposits::spendable_inputs().into_iter().map(|input| input.value);
+    custody.chain(deposited).try_fold(0u64, |sum, value| {
+        sum.checked_add(value).ok_or(WalletError::ArithmeticOverflow)
+    })
+}
+
+// The canister's main account plus the deposit subaccount of every user
+// with an address or a balance in the asset
+async fn ledger_holdings(ledger: Principal, asset: Asset) -> std::result::Result<u64, WalletError> {
+    let mut users: Vec<Principal> = BALANCES.with(|balances| {
+        balances
+            .borrow()
+            .values()
+            .filter(|b| b.balances.contains_key(&asset))
+            .map(|b| b.user_id)
+            .collect()
+    });
+    ADDRESSES.with(|addresses| {
+        for (user_id, list) in addresses.borrow().iter() {
+            if list.iter().any(|a| a.currency == asset) {
+                users.push(*user_id);
+            }
+        }
+    });
+    users.sort();
+    users.dedup();
+
+    let owner = ic_cdk::id();
+    let mut total = deposits::ledger_balance(ledger, deposits::Account { owner, subaccount: None }).await?;
+    for user_id in users {
+        let account = deposits::Account {
+            owner,
+            subaccount: Some(deposits::subaccount(&user_id).to_vec()),
+        };
+        total = total
+            .checked_add(deposits::ledger_balance(ledger, account).await?)
+            .ok_or(WalletError::ArithmeticOverflow)?;
+    }
+    Ok(total)
+}
+
+// Compare what users are owed with what was found on chain or on the ledger,
+// reporting a shortfall or holdings that could not be read
+fn reconcile(
+    asset: Asset,
+    liabilities: u64,
+    holdings: Option<std::result::Result<u64, WalletError>>,
+    discrepancies: &mut Vec<String>,
+) -> AssetReconciliation {
+    let holdings = match holdings {
+        Some(Ok(holdings)) => Some(holdings),
+        Some(Err(err)) => {
+            discrepancies.push(format!("{} holdings cannot be read: {:?}", asset.symbol(), err));
+            None
+        }
+        None => None,
+    };
+    if let Some(holdings) = holdings.filter(|h| *h < liabilities) {
+        discrepancies.push(format!(
+            "{} shortfall: holdings {} are {} below liabilities {}",
+            asset.symbol(),
+            holdings,
+            liabilities - holdings,
+            liabilities
+        ));
+    }
+    AssetReconciliation {
+        asset,
+        liabilities,
+        holdings,
+    }
+}
+
+pub async fn check() -> InvariantReport {
+    let mut discrepancies = Vec::new();
+    let supply = match total_supply() {
+        Ok(supply) => supply,
+        Err(err) => {
+            discrepancies.push(format!("Total supply cannot be computed: {:?}", err));
+            Vec::new()
+        }
+    };
+    if let Err(position) = blocks::verify() {
+        discrepancies.push(format!("Block log is broken at block {}", position));
+    }
+    discrepancies.extend(account_discrepancies());
+
+    let ledgers = config::get().ledger_canisters.unwrap_or_default();
+    let mut assets = Vec::new();
+    for (asset, liabilities) in supply {
+        let holdings = match (asset, ledgers.get(&asset)) {
+            (Asset::BTC, _) => Some(btc_holdings().await),
+            (_, Some(ledger)) => Some(ledger_holdings(*ledger, asset).await),
+            (_, None) => None,
+        };
+        assets.push(reconcile(asset, liabilities, holdings, &mut discrepancies));
+    }
+
+    InvariantReport {
+        checked_at: time(),
+        assets,
+        discrepancies,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn set_balance(user_id: Principal, asset: Asset, available: u64, held: u64) {
+        BALANCES.with(|balances| {
+            let mut balances = balances.borrow_mut();
+            let balance = balances.entry(user_id).or_insert_with(|| new_balance(user_id, 0));
+            let b = balance.asset_mut(asset);
+            b.available = available;
+            b.held = held;
+        });
+    }
+
+    fn mint(to: Principal, asset: Asset, amount: u64) {
+        blocks::append(blocks::Operation::Mint { to, asset, amount, source: None }, 0);
+    }
+
+    #[test]
+    fn total_supply_sums_every_balance() {
+        set_balance(user(1), Asset::BTC, 100, 20);
+        set_balance(user(2), Asset::BTC, 5, 0);
+        set_balance(user(2), Asset::CkBTC, 7, 0);
+
+        let supply: BTreeMap<Asset, u64> = total_supply().unwrap().into_iter().collect();
+        assert_eq!(supply[&Asset::BTC], 125);
+        assert_eq!(supply[&Asset::CkBTC], 7);
+        // Every asset is reported, with or without balances
+        assert_eq!(supply.len(), Asset::ALL.len());
+
+        set_balance(user(3), Asset::BTC, u64::MAX, 0);
+        assert!(matches!(total_supply(), Err(WalletError::ArithmeticOverflow)));
+    }
+
+    #[test]
+    fn balances_matching_the_log_have_no_discrepancies() {
+        set_balance(user(1), Asset::BTC, 100, 0);
+        mint(user(1), Asset::BTC, 100);
+        assert!(account_discrepancies().is_empty());
+    }
+
+    #[test]
+    fn balances_are_cross_checked_with_the_log_and_holds() {
+        // Held funds without an active hold
+        set_balance(user(1), Asset::BTC, 70, 30);
+        mint(user(1), Asset::BTC, 100);
+        // More than the log ever credited
+        set_balance(user(2), Asset::BTC, 50, 0);
+        mint(user(2), Asset::BTC, 40);
+        // Credited in the log but no balance record
+        mint(user(3), Asset::CkBTC, 10);
+
+        let discrepancies = account_discrepancies();
+        assert_eq!(discrepancies.len(), 3, "{:?}", discrepancies);
+        assert!(discrepancies.iter().any(|d| d.contains("30 held but active holds total 0")));
+        assert!(discrepancies.iter().any(|d| d.contains("balance totals 50 but the block log gives 40")));
+        assert!(discrepancies.iter().any(|d| d.contains("no balance but the block log gives 10")));
+    }
+
+    #[test]
+    fn shortfalls_and_unreadable_holdings_are_reported() {
+        let mut discrepancies = Vec::new();
+        let covered = reconcile(Asset::BTC, 100, Some(Ok(100)), &mut discrepancies);
+        assert_eq!(covered.holdings, Some(100));
+        let unknown = reconcile(Asset::ICP, 100, None, &mut discrepancies);
+        assert_eq!(unknown.holdings, None);
+        assert!(discrepancies.is_empty());
+
+        reconcile(Asset::BTC, 100, Some(Ok(60)), &mut discrepancies);
+        let failed = reconcile(
+            Asset::CkBTC,
+            100,
+            Some(Err(WalletError::TransferFailed("unreachable".to_string()))),
+            &mut discrepancies,
+        );
+        assert_eq!(failed.holdings, None);
+        assert_eq!(
+            discrepancies,
+            vec![
+                "BTC shortfall: holdings 60 are 40 below liabilities 100".to_string(),
+                "ckBTC holdings cannot be read: TransferFailed(\"unreachable\")".to_string(),
+            ]
+        );
+    }
+}
@@ -1,26 +1,150 @@
 use ic_cdk::api::time;
 use ic_cdk::{caller, query, update};
-use ic_cdk_macros::{init, post_upgrade, pre_upgrade};
+use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use candid::{CandidType, Deserialize, Principal};
+use common::{Asset, AssetInfo, CanisterStatus, HoldParams};
+pub use common::WalletError;
 use serde::Serialize;
 
+mod agents;
+mod blocks;
+mod btc;
+mod config;
+mod deposits;
+mod history;
+mod holds;
+mod idempotency;
+mod identity;
+mod invariants;
+mod memory;
+mod payment_requests;
+mod security;
+mod statements;
+mod upgrade;
+mod vas;
+mod withdrawals;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AssetBalance {
+    pub available: u64,
+    pub pending_deposits: u64,
+    pub pending_withdrawals: u64,
+    // Reserved for open escrows (see holds.rs); not spendable
+    pub held: u64,
+}
+
+// Balance changes go through these so they cannot wrap: each one either
+// applies in full or leaves the balance unchanged
+impl AssetBalance {
+    // Everything the user owns: available, held and on its way out
+    pub fn total(&self) -> std::result::Result<u64, WalletError> {
+        self.available
+            .checked_add(self.held)
+            .and_then(|sum| sum.checked_add(self.pending_withdrawals))
+            .ok_or(WalletError::ArithmeticOverflow)
+    }
+
+    pub fn credit(&mut self, amount: u64) -> std::result::Result<(), WalletError> {
+        self.available = self
+            .available
+            .checked_add(amount)
+            .ok_or(WalletError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    pub fn debit(&mut self, amount: u64) -> std::result::Result<(), WalletError> {
+        self.available = self
+            .available
+            .checked_sub(amount)
+            .ok_or(WalletError::InsufficientBalance)?;
+        Ok(())
+    }
+
+    pub fn hold(&mut self, amount: u64) -> std::result::Result<(), WalletError> {
+        shift(&mut self.available, &mut self.held, amount, WalletError::InsufficientBalance)
+    }
+
+    pub fn unhold(&mut self, amount: u64) -> std::result::Result<(), WalletError> {
+        shift(&mut self.held, &mut self.available, amount, WalletError::ArithmeticOverflow)
+    }
+
+    // The held amount leaves this balance (paid to someone else)
+    pub fn spend_held(&mut self, amount: u64) -> std::result::Result<(), WalletError> {
+        self.held = self
+            .held
+            .checked_sub(amount)
+            .ok_or(WalletError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    pub fn reserve_withdrawal(&mut self, amount: u64) -> std::result::Result<(), WalletError> {
+        shift(
+            &mut self.available,
+            &mut self.pending_withdrawals,
+            amount,
+            WalletError::InsufficientBalance,
+        )
+    }
+
+    pub fn unreserve_withdrawal(&mut self, amount: u64) -> std::result::Result<(), WalletError> {
+        shift(
+            &mut self.pending_withdrawals,
+            &mut self.available,
+            amount,
+            WalletError::ArithmeticOverflow,
+        )
+    }
+
+    // The withdrawal was submitted, so the reserved amount leaves the wallet
+    pub fn complete_withdrawal(&mut self, amount: u64) -> std::result::Result<(), WalletError> {
+        self.pending_withdrawals = self
+            .pending_withdrawals
+            .checked_sub(amount)
+            .ok_or(WalletError::ArithmeticOverflow)?;
+        Ok(())
+    }
+}
+
+// Move `amount` between two buckets, failing with `short` if `from` does not
+// have it
+fn shift(
+    from: &mut u64,
+    to: &mut u64,
+    amount: u64,
+    short: WalletError,
+) -> std::result::Result<(), WalletError> {
+    let new_from = from.checked_sub(amount).ok_or(short)?;
+    let new_to = to.checked_add(amount).ok_or(WalletError::ArithmeticOverflow)?;
+    *from = new_from;
+    *to = new_to;
+    Ok(())
+}
+
+// All of a principal's balances, keyed by asset
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct WalletBalance {
     pub user_id: Principal,
-    pub btc_balance: u64,
-    pub ckbtc_balance: u64,
-    pub pending_deposits: u64,
-    pub pending_withdrawals: u64,
+    pub balances: BTreeMap<Asset, AssetBalance>,
     pub last_updated: u64,
 }
 
+impl WalletBalance {
+    pub fn asset(&self, asset: Asset) -> AssetBalance {
+        self.balances.get(&asset).cloned().unwrap_or_default()
+    }
+
+    pub fn asset_mut(&mut self, asset: Asset) -> &mut AssetBalance {
+        self.balances.entry(asset).or_default()
+    }
+}
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct DepositAddress {
     pub user_id: Principal,
     pub address: String,
-    pub currency: String,
+    pub currency: Asset,
     pub created_at: u64,
 }
 
@@ -30,27 +154,100 @@ pub struct Transaction {
     pub user_id: Principal,
     pub tx_type: String,
     pub amount: u64,
-    pub currency: String,
+    pub currency: Asset,
     pub status: String,
     pub created_at: u64,
     pub confirmed_at: Option<u64>,
+    pub source: Option<CreditSource>,
+    pub memo: Option<String>,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+// Where a credit came from; each source can be credited only once
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CreditSource {
+    Deposit { txid: String, vout: u32 },
+    EscrowPayout { escrow_id: String },
+    // Ledger transfer from the user's deposit subaccount to the main account
+    LedgerDeposit { asset: Asset, block_index: u64 },
+}
+
+// Retries carrying the same `idempotency_key` return the first result instead
+// of transferring again (see idempotency.rs)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct TransferParams {
     pub to: Principal,
     pub amount: u64,
-    pub currency: String,
+    pub currency: Asset,
+    pub idempotency_key: Option<String>,
+    pub memo: Option<String>,
 }
 
+pub const MAX_MEMO_LEN: usize = 256;
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-pub enum WalletError {
-    InsufficientBalance,
-    InvalidAddress,
-    InvalidAmount,
-    TransferFailed(String),
-    NotFound,
-    Unauthorized,
+pub struct WithdrawParams {
+    pub destination_address: String,
+    pub amount: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum WithdrawalStatus {
+    // Delayed by the withdrawal policy; cancellable until `execute_at`
+    Scheduled { execute_at: u64 },
+    Pending,
+    Submitted,
+    // Buried under enough blocks at the destination
+    Confirmed,
+    Failed(String),
+    Cancelled,
+    // Never seen in a block after submission; the amount stays withdrawn
+    // and the inputs reserved until someone looks into it
+    Dropped,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Withdrawal {
+    pub withdrawal_id: String,
+    pub user_id: Principal,
+    pub destination_address: String,
+    pub amount: u64,
+    pub fee: u64,
+    pub bitcoin_txid: Option<String>,
+    // Height of the block that mined the transaction, recorded the first
+    // time its output is seen at the destination
+    pub block_height: Option<u32>,
+    pub status: WithdrawalStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum HoldStatus {
+    Active,
+    Released { to: Principal },
+    Returned,
+}
+
+// Who placed a hold; only the same flow may settle it
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum HoldOrigin {
+    Escrow,
+    Agent,
+    Vas,
+}
+
+// Funds reserved from `user_id`'s balance for an escrow
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Hold {
+    pub escrow_id: String,
+    pub user_id: Principal,
+    pub amount: u64,
+    pub currency: Asset,
+    pub status: HoldStatus,
+    pub tx_id: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub origin: HoldOrigin,
 }
 
 thread_local! {
@@ -58,35 +255,58 @@ thread_local! {
     static ADDRESSES: RefCell<HashMap<Principal, Vec<DepositAddress>>> = RefCell::new(HashMap::new());
     static TRANSACTIONS: RefCell<HashMap<Principal, Vec<Transaction>>> = RefCell::new(HashMap::new());
     static TX_COUNTER: RefCell<u64> = RefCell::new(0);
+    // Principals allowed to credit balances (deposit watcher, escrow canister)
+    static MINTERS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+    static CREDITED_SOURCES: RefCell<HashSet<CreditSource>> = RefCell::new(HashSet::new());
 }
 
 #[init]
-fn init() {
+fn init(config: Option<config::WalletConfig>) {
+    if let Some(config) = config {
+        if config::set(config).is_err() {
+            ic_cdk::trap("Invalid wallet configuration");
+        }
+    }
     ic_cdk::println!("Wallet canister initialized");
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    // Store state before upgrade
+    upgrade::save();
 }
 
 #[post_upgrade]
-fn post_upgrade() {
-    // Restore state after upgrade
+fn post_upgrade(config: Option<config::WalletConfig>) {
+    upgrade::restore();
+    if let Some(config) = config {
+        if config::set(config).is_err() {
+            ic_cdk::trap("Invalid wallet configuration");
+        }
+    }
+}
+
+#[heartbeat]
+fn heartbeat() {
+    if deposits::sweep_due(time()) {
+        ic_cdk::spawn(deposits::sweep());
+    }
+    withdrawals::execute_due(time());
+    withdrawals::check_due(time());
+    agents::expire_due(time());
+    payment_requests::expire_due(time());
+}
+
+fn new_balance(user_id: Principal, now: u64) -> WalletBalance {
+    WalletBalance {
+        user_id,
+        balances: BTreeMap::new(),
+        last_updated: now,
+    }
 }
 
 fn get_or_create_balance(user_id: Principal) -> WalletBalance {
     BALANCES.with(|balances| {
-        balances.borrow_mut().entry(user_id).or_insert_with(|| {
-            WalletBalance {
-                user_id,
-                btc_balance: 0,
-                ckbtc_balance: 0,
-                pending_deposits: 0,
-                pending_withdrawals: 0,
-                last_updated: time(),
-            }
-        }).clone()
+        balances.borrow_mut().entry(user_id).or_insert_with(|| new_balance(user_id, time())).clone()
     })
 }
 
@@ -98,6 +318,21 @@ fn generate_tx_id() -> String {
     })
 }
 
+fn set_transaction_status(user_id: Principal, tx_id: &str, status: &str, now: u64) {
+    TRANSACTIONS.with(|txs| {
+        if let Some(tx) = txs
+            .borrow_mut()
+            .get_mut(&user_id)
+            .and_then(|txs| txs.iter_mut().find(|tx| tx.tx_id == tx_id))
+        {
+            tx.status = status.to_string();
+            if status == "confirmed" {
+                tx.confirmed_at = Some(now);
+            }
+        }
+    });
+}
+
 #[query]
 fn get_balance(user_id: Principal) -> Option<WalletBalance> {
     BALANCES.with(|balances| {
@@ -110,32 +345,53 @@ fn get_my_balance() -> Option<WalletBalance> {
     get_balance(caller())
 }
 
-#[update]
-fn get_deposit_address(currency: String) -> std::result::Result<DepositAddress, WalletError> {
-    let user_id = caller();
-    
-    // Check if user already has an address for this currency
-    let existing = ADDRESSES.with(|addresses| {
+#[query]
+fn get_supported_assets() -> Vec<AssetInfo> {
+    Asset::ALL.iter().map(Asset::info).collect()
+}
+
+fn existing_deposit_address(user_id: Principal, currency: Asset) -> Option<DepositAddress> {
+    ADDRESSES.with(|addresses| {
         addresses.borrow()
             .get(&user_id)
             .and_then(|addrs| addrs.iter().find(|a| a.currency == currency).cloned())
-    });
+    })
+}
+
+// BTC addresses are P2WPKH addresses of a key derived from the caller's
+// principal; ledger assets are deposited to a per-user subaccount of this
+// canister
+#[update]
+async fn get_deposit_address(currency: Asset) -> std::result::Result<DepositAddress, WalletError> {
+    let user_id = caller();
+    if user_id == Principal::anonymous() {
+        return Err(WalletError::Unauthorized);
+    }
+    deposits::touch(user_id, time());
     
-    if let Some(addr) = existing {
+    // Check if user already has an address for this currency
+    if let Some(addr) = existing_deposit_address(user_id, currency) {
         return Ok(addr);
     }
     
-    // Generate new address (mock for now)
-    let address = match currency.as_str() {
-        "BTC" => format!("tb1q{:x}", time() % 1000000),
-        "ckBTC" => format!("ckbtc-{:x}", time() % 1000000),
-        _ => return Err(WalletError::InvalidAddress),
+    let address = match currency {
+        Asset::BTC => deposits::btc_address(user_id).await?,
+        // Ledger deposits can only be credited with a configured ledger
+        _ if !config::get().ledger_canisters.unwrap_or_default().contains_key(&currency) => {
+            return Err(WalletError::InvalidConfig);
+        }
+        _ => deposits::icrc_account(&user_id),
     };
     
+    // A concurrent call may have stored it while we awaited the key
+    if let Some(addr) = existing_deposit_address(user_id, currency) {
+        return Ok(addr);
+    }
+    
     let deposit_address = DepositAddress {
         user_id,
         address: address.clone(),
-        currency: currency.clone(),
+        currency,
         created_at: time(),
     };
     
@@ -152,6 +408,13 @@ fn get_deposit_address(currency: String) -> std::result::Result<DepositAddress,
     Ok(deposit_address)
 }
 
+// Check the caller's deposit addresses now instead of waiting for the
+// periodic sweep; rate limited per caller
+#[update]
+async fn check_deposits() -> std::result::Result<WalletBalance, WalletError> {
+    deposits::check(caller()).await
+}
+
 #[query]
 fn get_my_addresses() -> Vec<DepositAddress> {
     let user_id = caller();
@@ -163,133 +426,203 @@ fn get_my_addresses() -> Vec<DepositAddress> {
     })
 }
 
-#[update]
-fn transfer(params: TransferParams) -> std::result::Result<WalletBalance, WalletError> {
-    let from = caller();
-    
-    if params.amount == 0 {
-        return Err(WalletError::InvalidAmount);
-    }
-    
-    if from == params.to {
-        return Err(WalletError::TransferFailed("Cannot transfer to yourself".to_string()));
-    }
-    
+// Move funds between two balances and record the transfer in both histories
+// and the block log. Returns the sender's balance and the transaction id.
+fn apply_transfer(
+    from: Principal,
+    to: Principal,
+    amount: u64,
+    currency: Asset,
+    memo: Option<String>,
+    now: u64,
+) -> std::result::Result<(WalletBalance, String), WalletError> {
     BALANCES.with(|balances| {
         let mut balances_map = balances.borrow_mut();
         
-        // Get sender balance
-        let sender_balance = balances_map.get_mut(&from)
-            .ok_or(WalletError::InsufficientBalance)?;
-        
-        // Check sufficient balance
-        let balance_to_check = match params.currency.as_str() {
-            "BTC" => &mut sender_balance.btc_balance,
-            "ckBTC" => &mut sender_balance.ckbtc_balance,
-            _ => return Err(WalletError::InvalidAddress),
-        };
-        
-        if *balance_to_check < params.amount {
-            return Err(WalletError::InsufficientBalance);
-        }
+        // Apply both sides on copies first so a failure changes neither
+        let mut sender_asset = balances_map
+            .get(&from)
+            .ok_or(WalletError::InsufficientBalance)?
+            .asset(currency);
+        sender_asset.debit(amount)?;
+        let mut recipient_asset = balances_map
+            .get(&to)
+            .map(|b| b.asset(currency))
+            .unwrap_or_default();
+        recipient_asset.credit(amount)?;
         
         // Deduct from sender
-        *balance_to_check -= params.amount;
-        sender_balance.last_updated = time();
+        let sender_balance = balances_map.get_mut(&from)
+            .ok_or(WalletError::InsufficientBalance)?;
+        *sender_balance.asset_mut(currency) = sender_asset;
+        sender_balance.last_updated = now;
         let updated_sender = sender_balance.clone();
         
         // Get or create recipient balance
-        let recipient_balance = balances_map.entry(params.to)
-            .or_insert_with(|| WalletBalance {
-                user_id: params.to,
-                btc_balance: 0,
-                ckbtc_balance: 0,
-                pending_deposits: 0,
-                pending_withdrawals: 0,
-                last_updated: time(),
-            });
+        let recipient_balance = balances_map.entry(to)
+            .or_insert_with(|| new_balance(to, now));
         
         // Add to recipient
-        match params.currency.as_str() {
-            "BTC" => recipient_balance.btc_balance += params.amount,
-            "ckBTC" => recipient_balance.ckbtc_balance += params.amount,
-            _ => unreachable!(),
-        };
-        recipient_balance.last_updated = time();
+        *recipient_balance.asset_mut(currency) = recipient_asset;
+        recipient_balance.last_updated = now;
         
         // Record transaction for both parties
         let tx_id = generate_tx_id();
-        let now = time();
         
         let sender_tx = Transaction {
             tx_id: tx_id.clone(),
             user_id: from,
             tx_type: "transfer_out".to_string(),
-            amount: params.amount,
-            currency: params.currency.clone(),
+            amount,
+            currency,
             status: "confirmed".to_string(),
             created_at: now,
             confirmed_at: Some(now),
+            source: None,
+            memo: memo.clone(),
         };
         
         let recipient_tx = Transaction {
             tx_id: tx_id.clone(),
-            user_id: params.to,
+            user_id: to,
             tx_type: "transfer_in".to_string(),
-            amount: params.amount,
-            currency: params.currency,
+            amount,
+            currency,
             status: "confirmed".to_string(),
             created_at: now,
             confirmed_at: Some(now),
+            source: None,
+            memo: memo.clone(),
         };
         
         TRANSACTIONS.with(|txs| {
             let mut txs_map = txs.borrow_mut();
             txs_map.entry(from).or_insert_with(Vec::new).push(sender_tx);
-            txs_map.entry(params.to).or_insert_with(Vec::new).push(recipient_tx);
+            txs_map.entry(to).or_insert_with(Vec::new).push(recipient_tx);
         });
+        blocks::append(
+            blocks::Operation::Transfer {
+                from,
+                to,
+                asset: currency,
+                amount,
+                memo,
+            },
+            now,
+        );
         
-        Ok(updated_sender)
+        Ok((updated_sender, tx_id))
     })
 }
 
+#[update]
+fn transfer(params: TransferParams) -> std::result::Result<WalletBalance, WalletError> {
+    let from = caller();
+    let now = time();
+    
+    if let Some(key) = &params.idempotency_key {
+        idempotency::validate_key(key)?;
+        if let Some(previous) = idempotency::lookup(from, key, &params, now)? {
+            return Ok(previous);
+        }
+    }
+    
+    if params.amount == 0 {
+        return Err(WalletError::InvalidAmount);
+    }
+    
+    if from == params.to {
+        return Err(WalletError::TransferFailed("Cannot transfer to yourself".to_string()));
+    }
+    
+    if params.memo.as_ref().is_some_and(|memo| memo.len() > MAX_MEMO_LEN) {
+        return Err(WalletError::TransferFailed(format!(
+            "Memo must be at most {} bytes",
+            MAX_MEMO_LEN
+        )));
+    }
+    
+    let (updated_sender, _) = apply_transfer(
+        from,
+        params.to,
+        params.amount,
+        params.currency,
+        params.memo.clone(),
+        now,
+    )?;
+    
+    if let Some(key) = params.idempotency_key.clone() {
+        idempotency::record(from, key, params, updated_sender.clone(), now);
+    }
+    
+    Ok(updated_sender)
+}
+
+// Another user's history is only visible to controllers and auditors
 #[query]
-fn get_transactions(user_id: Principal) -> Vec<Transaction> {
-    TRANSACTIONS.with(|txs| {
-        txs.borrow()
-            .get(&user_id)
-            .cloned()
-            .unwrap_or_default()
-    })
+fn get_transactions(
+    user_id: Principal,
+    query: history::TransactionQuery,
+) -> std::result::Result<history::TransactionPage, WalletError> {
+    let caller_id = caller();
+    if caller_id != user_id && !blocks::is_auditor(&caller_id) {
+        return Err(WalletError::Unauthorized);
+    }
+    Ok(history::page(user_id, &query))
+}
+
+#[query]
+fn get_my_transactions(query: history::TransactionQuery) -> history::TransactionPage {
+    history::page(caller(), &query)
 }
 
+// Statement of balances and movements for an inclusive time range (in
+// nanoseconds); another user's is only available to controllers and auditors
 #[query]
-fn get_my_transactions() -> Vec<Transaction> {
-    get_transactions(caller())
+fn get_statement(
+    user_id: Principal,
+    from: u64,
+    to: u64,
+) -> std::result::Result<statements::Statement, WalletError> {
+    let caller_id = caller();
+    if caller_id != user_id && !blocks::is_auditor(&caller_id) {
+        return Err(WalletError::Unauthorized);
+    }
+    statements::generate(user_id, from, to, time())
 }
 
+#[query]
+fn get_my_statement(from: u64, to: u64) -> std::result::Result<statements::Statement, WalletError> {
+    statements::generate(caller(), from, to, time())
+}
+
+// Credit a confirmed deposit or escrow payout. Only allow-listed minters can
+// call this, and each source is credited at most once.
 #[update]
-fn update_balance(user_id: Principal, amount: u64, currency: String) -> std::result::Result<WalletBalance, WalletError> {
-    // In production, add authorization check
+fn update_balance(
+    user_id: Principal,
+    amount: u64,
+    currency: Asset,
+    source: CreditSource,
+) -> std::result::Result<WalletBalance, WalletError> {
+    if !MINTERS.with(|minters| minters.borrow().contains(&caller())) {
+        return Err(WalletError::Unauthorized);
+    }
+    
+    if amount == 0 {
+        return Err(WalletError::InvalidAmount);
+    }
+    
+    if CREDITED_SOURCES.with(|sources| sources.borrow().contains(&source)) {
+        return Err(WalletError::DuplicateCredit);
+    }
     
     BALANCES.with(|balances| {
         let mut balances_map = balances.borrow_mut();
         let balance = balances_map.entry(user_id)
-            .or_insert_with(|| WalletBalance {
-                user_id,
-                btc_balance: 0,
-                ckbtc_balance: 0,
-                pending_deposits: 0,
-                pending_withdrawals: 0,
-                last_updated: time(),
-            });
-        
-        match currency.as_str() {
-            "BTC" => balance.btc_balance += amount,
-            "ckBTC" => balance.ckbtc_balance += amount,
-            _ => return Err(WalletError::InvalidAddress),
-        };
+            .or_insert_with(|| new_balance(user_id, time()));
         
+        balance.asset_mut(currency).credit(amount)?;
         balance.last_updated = time();
         
         // Record deposit transaction
@@ -302,6 +635,8 @@ fn update_balance(user_id: Principal, amount: u64, currency: String) -> std::res
             status: "confirmed".to_string(),
             created_at: time(),
             confirmed_at: Some(time()),
+            source: Some(source.clone()),
+            memo: None,
         };
         
         TRANSACTIONS.with(|txs| {
@@ -310,9 +645,464 @@ fn update_balance(user_id: Principal, amount: u64, currency: String) -> std::res
                 .or_insert_with(Vec::new)
                 .push(tx);
         });
+        blocks::append(
+            blocks::Operation::Mint {
+                to: user_id,
+                asset: currency,
+                amount,
+                source: Some(source.clone()),
+            },
+            time(),
+        );
+        CREDITED_SOURCES.with(|sources| sources.borrow_mut().insert(source));
         
         Ok(balance.clone())
     })
 }
 
+// Withdraw BTC to an external address on the configured network. The fee
+// is charged on top of the amount.
+#[update]
+async fn withdraw(params: WithdrawParams) -> std::result::Result<Withdrawal, WalletError> {
+    let user_id = caller();
+    if user_id == Principal::anonymous() {
+        return Err(WalletError::Unauthorized);
+    }
+    withdrawals::withdraw(user_id, params).await
+}
+
+#[query]
+fn get_withdrawal(withdrawal_id: String) -> Option<Withdrawal> {
+    withdrawals::get(&withdrawal_id).filter(|w| w.user_id == caller())
+}
+
+#[query]
+fn get_my_withdrawals() -> Vec<Withdrawal> {
+    withdrawals::for_user(caller())
+}
+
+// Only scheduled withdrawals, i.e. those still waiting out the delay, can be
+// cancelled
+#[update]
+fn cancel_withdrawal(withdrawal_id: String) -> std::result::Result<Withdrawal, WalletError> {
+    withdrawals::cancel(caller(), &withdrawal_id)
+}
+
+#[query]
+fn get_withdrawal_policy() -> security::WithdrawalPolicy {
+    security::policy()
+}
+
+#[update]
+fn set_withdrawal_policy(
+    policy: security::WithdrawalPolicy,
+) -> std::result::Result<security::WithdrawalPolicy, WalletError> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err(WalletError::Unauthorized);
+    }
+    security::set_policy(policy)?;
+    Ok(security::policy())
+}
+
+// New withdrawal addresses become usable after the policy's cooling-off
+// period
+#[update]
+fn add_withdrawal_address(
+    address: String,
+    label: Option<String>,
+) -> std::result::Result<security::AllowedAddress, WalletError> {
+    let user_id = caller();
+    if user_id == Principal::anonymous() {
+        return Err(WalletError::Unauthorized);
+    }
+    security::add_address(user_id, address, label, time())
+}
+
+#[update]
+fn remove_withdrawal_address(address: String) -> Vec<security::AllowedAddress> {
+    security::remove_address(caller(), &address)
+}
+
+#[query]
+fn get_my_withdrawal_addresses() -> Vec<security::AllowedAddress> {
+    security::allowed_addresses(caller())
+}
+
+// An update call because the KYC level is looked up in the identity canister
+#[update]
+async fn get_my_withdrawal_limit() -> security::WithdrawalLimit {
+    let user_id = caller();
+    let kyc_level = security::kyc_level(user_id).await;
+    security::limit(user_id, kyc_level, time())
+}
+
+// Escrow holds; only the configured escrow canister may place or settle them
+#[update]
+fn place_hold(params: HoldParams) -> std::result::Result<Hold, WalletError> {
+    holds::place(params, time())
+}
+
+#[update]
+fn release_hold(escrow_id: String, to: Principal) -> std::result::Result<Hold, WalletError> {
+    holds::release(&escrow_id, to, time())
+}
+
+#[update]
+fn return_hold(escrow_id: String) -> std::result::Result<Hold, WalletError> {
+    holds::refund(&escrow_id, time())
+}
+
+// Only the payer can see a hold
+#[query]
+fn get_hold(escrow_id: String) -> Option<Hold> {
+    holds::get(&escrow_id).filter(|hold| hold.user_id == caller())
+}
+
+#[query]
+fn get_my_holds() -> Vec<Hold> {
+    holds::for_user(caller())
+}
+
+// Payment requests: the requester asks, the payer pays or declines
+#[update]
+fn create_payment_request(
+    params: payment_requests::CreatePaymentRequestParams,
+) -> std::result::Result<payment_requests::PaymentRequest, WalletError> {
+    payment_requests::create(caller(), params, time())
+}
+
+#[update]
+fn pay_payment_request(id: String) -> std::result::Result<payment_requests::PaymentRequest, WalletError> {
+    payment_requests::pay(caller(), &id, time())
+}
+
+#[update]
+fn decline_payment_request(id: String) -> std::result::Result<payment_requests::PaymentRequest, WalletError> {
+    payment_requests::decline(caller(), &id, time())
+}
+
+#[update]
+fn cancel_payment_request(id: String) -> std::result::Result<payment_requests::PaymentRequest, WalletError> {
+    payment_requests::cancel(caller(), &id, time())
+}
+
+#[query]
+fn get_payment_request(id: String) -> Option<payment_requests::PaymentRequest> {
+    payment_requests::get(caller(), &id)
+}
+
+#[query]
+fn get_my_pending_payment_requests() -> payment_requests::PendingPaymentRequests {
+    payment_requests::pending_for(caller(), time())
+}
+
+// Agent cash-in/cash-out in ckBTC; amounts in satoshis and USD cents
+#[update]
+async fn set_agent_float(
+    cash_float_usd_cents: u64,
+    max_transaction_usd_cents: u64,
+) -> std::result::Result<agents::AgentFloat, WalletError> {
+    agents::set_float(caller(), cash_float_usd_cents, max_transaction_usd_cents).await
+}
+
+#[query]
+fn get_agent_float(agent_id: Principal) -> Option<agents::AgentFloat> {
+    agents::float(agent_id)
+}
+
+#[update]
+async fn start_cash_in(
+    user_id: Principal,
+    amount_satoshis: u64,
+    amount_usd_cents: u64,
+) -> std::result::Result<agents::AgentTransaction, WalletError> {
+    agents::start_cash_in(caller(), user_id, amount_satoshis, amount_usd_cents).await
+}
+
+#[update]
+async fn request_cash_out(
+    agent_id: Principal,
+    amount_satoshis: u64,
+    amount_usd_cents: u64,
+) -> std::result::Result<agents::AgentTransaction, WalletError> {
+    let user_id = caller();
+    if user_id == Principal::anonymous() {
+        return Err(WalletError::Unauthorized);
+    }
+    agents::request_cash_out(user_id, agent_id, amount_satoshis, amount_usd_cents).await
+}
+
+// The agent completes with the code the user gives them in person
+#[update]
+fn complete_agent_transaction(
+    id: String,
+    confirmation_code: String,
+) -> std::result::Result<agents::AgentTransaction, WalletError> {
+    agents::complete(caller(), &id, &confirmation_code)
+}
+
+#[update]
+fn cancel_agent_transaction(id: String) -> std::result::Result<agents::AgentTransaction, WalletError> {
+    agents::cancel(caller(), &id)
+}
+
+#[query]
+fn get_my_agent_transactions() -> Vec<agents::AgentTransaction> {
+    agents::for_principal(caller())
+}
+
+// VAS purchases; holding and settling is limited to the VAS operator, and a
+// hold needs the user's approval of the purchase ID first
+#[update]
+fn approve_vas_purchase(
+    purchase_id: String,
+    amount: u64,
+    currency: Asset,
+) -> std::result::Result<vas::VasApproval, WalletError> {
+    let user_id = caller();
+    if user_id == Principal::anonymous() {
+        return Err(WalletError::Unauthorized);
+    }
+    vas::approve(user_id, purchase_id, amount, currency, time())
+}
+
+#[update]
+fn hold_vas_purchase(params: vas::VasPurchaseParams) -> std::result::Result<vas::VasPurchase, WalletError> {
+    vas::hold(params, time())
+}
+
+#[update]
+fn complete_vas_purchase(
+    purchase_id: String,
+    provider_reference: String,
+) -> std::result::Result<vas::VasPurchase, WalletError> {
+    vas::complete(&purchase_id, provider_reference, time())
+}
+
+#[update]
+fn fail_vas_purchase(purchase_id: String, reason: String) -> std::result::Result<vas::VasPurchase, WalletError> {
+    vas::fail(&purchase_id, reason, time())
+}
+
+#[query]
+fn get_vas_purchase(purchase_id: String) -> Option<vas::VasPurchase> {
+    let caller_id = caller();
+    vas::get(&purchase_id)
+        .filter(|p| p.params.user_id == caller_id || config::get().vas_operator == Some(caller_id))
+}
+
+#[query]
+fn get_my_vas_purchases() -> Vec<vas::VasPurchase> {
+    vas::for_user(caller())
+}
+
+// Address holding the wallet's BTC; withdrawals are paid from its UTXOs
+#[update]
+async fn get_custody_address() -> std::result::Result<String, WalletError> {
+    let key = btc::custody_key().await?;
+    Ok(btc::p2wpkh_address(&key).to_string())
+}
+
+#[query]
+fn get_config() -> config::WalletConfig {
+    config::get()
+}
+
+#[update]
+fn set_config(new_config: config::WalletConfig) -> std::result::Result<config::WalletConfig, WalletError> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err(WalletError::Unauthorized);
+    }
+    config::set(new_config)?;
+    Ok(config::get())
+}
+
+#[query]
+fn get_minters() -> Vec<Principal> {
+    MINTERS.with(|minters| minters.borrow().iter().cloned().collect())
+}
+
+#[update]
+fn add_minter(minter: Principal) -> std::result::Result<Vec<Principal>, WalletError> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err(WalletError::Unauthorized);
+    }
+    MINTERS.with(|minters| minters.borrow_mut().insert(minter));
+    Ok(get_minters())
+}
+
+#[update]
+fn remove_minter(minter: Principal) -> std::result::Result<Vec<Principal>, WalletError> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err(WalletError::Unauthorized);
+    }
+    MINTERS.with(|minters| minters.borrow_mut().remove(&minter));
+    Ok(get_minters())
+}
+
+// Hash-chained block log; readable in full by controllers and auditors, and
+// per account by the account itself
+#[query]
+fn get_blocks(start: u64, length: u64) -> std::result::Result<blocks::GetBlocksResponse, WalletError> {
+    if !blocks::is_auditor(&caller()) {
+        return Err(WalletError::Unauthorized);
+    }
+    Ok(blocks::get_blocks(start, length))
+}
+
+#[query]
+fn get_account_blocks(
+    account: Principal,
+    start: u64,
+    length: u64,
+) -> std::result::Result<Vec<blocks::Block>, WalletError> {
+    let caller_id = caller();
+    if caller_id != account && !blocks::is_auditor(&caller_id) {
+        return Err(WalletError::Unauthorized);
+    }
+    Ok(blocks::account_blocks(account, start, length))
+}
+
+#[query]
+fn get_auditors() -> Vec<Principal> {
+    blocks::auditors()
+}
+
+#[update]
+fn add_auditor(auditor: Principal) -> std::result::Result<Vec<Principal>, WalletError> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err(WalletError::Unauthorized);
+    }
+    // Auditors only read; the VAS operator moves funds and needs its own key
+    if config::get().vas_operator == Some(auditor) {
+        return Err(WalletError::InvalidArgument(
+            "The VAS operator cannot be an auditor".to_string(),
+        ));
+    }
+    blocks::set_auditor(auditor, true);
+    Ok(blocks::auditors())
+}
+
+#[update]
+fn remove_auditor(auditor: Principal) -> std::result::Result<Vec<Principal>, WalletError> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err(WalletError::Unauthorized);
+    }
+    blocks::set_auditor(auditor, false);
+    Ok(blocks::auditors())
+}
+
+// Sum of all balances per asset, including held and pending-withdrawal
+// amounts
+#[query]
+fn get_total_supply() -> std::result::Result<Vec<(Asset, u64)>, WalletError> {
+    invariants::total_supply()
+}
+
+// Compares what users are owed with what the canister holds, and each
+// balance with the block log, holds and withdrawals
+#[update]
+async fn check_invariants() -> std::result::Result<invariants::InvariantReport, WalletError> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err(WalletError::Unauthorized);
+    }
+    Ok(invariants::check().await)
+}
+
+// Operational status for monitoring (cycles, memory, record counts)
+#[query]
+fn get_canister_status() -> CanisterStatus {
+    common::canister_status(vec![
+        ("balances".to_string(), BALANCES.with(|b| b.borrow().len() as u64)),
+        (
+            "deposit_addresses".to_string(),
+            ADDRESSES.with(|a| a.borrow().values().map(|v| v.len() as u64).sum()),
+        ),
+        (
+            "transactions".to_string(),
+            TRANSACTIONS.with(|t| t.borrow().values().map(|v| v.len() as u64).sum()),
+        ),
+        ("withdrawals".to_string(), withdrawals::count()),
+        ("tracked_deposits".to_string(), deposits::count()),
+        ("idempotency_keys".to_string(), idempotency::count()),
+        ("holds".to_string(), holds::count()),
+        ("agent_transactions".to_string(), agents::count()),
+        ("vas_purchases".to_string(), vas::count()),
+        ("payment_requests".to_string(), payment_requests::count()),
+        ("blocks".to_string(), blocks::count()),
+    ])
+}
+
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balance(available: u64, held: u64, pending_withdrawals: u64) -> AssetBalance {
+        AssetBalance { available, pending_deposits: 0, pending_withdrawals, held }
+    }
+
+    #[test]
+    fn failed_changes_leave_the_balance_unchanged() {
+        let mut b = balance(u64::MAX - 1, 5, 0);
+        assert!(matches!(b.credit(2), Err(WalletError::ArithmeticOverflow)));
+        assert!(matches!(b.debit(u64::MAX), Err(WalletError::InsufficientBalance)));
+        assert!(matches!(b.spend_held(6), Err(WalletError::ArithmeticOverflow)));
+        assert!(matches!(b.complete_withdrawal(1), Err(WalletError::ArithmeticOverflow)));
+        assert_eq!((b.available, b.held, b.pending_withdrawals), (u64::MAX - 1, 5, 0));
+
+        b.credit(1).unwrap();
+        b.debit(u64::MAX - 10).unwrap();
+        b.spend_held(5).unwrap();
+        assert_eq!((b.available, b.held), (10, 0));
+    }
+
+    #[test]
+    fn holds_and_withdrawals_move_funds_between_buckets() {
+        let mut b = balance(100, 0, 0);
+        b.hold(30).unwrap();
+        b.reserve_withdrawal(50).unwrap();
+        assert_eq!((b.available, b.held, b.pending_withdrawals), (20, 30, 50));
+        assert_eq!(b.total().unwrap(), 100);
+
+        assert!(matches!(b.hold(21), Err(WalletError::InsufficientBalance)));
+        assert!(matches!(b.reserve_withdrawal(21), Err(WalletError::InsufficientBalance)));
+        assert!(matches!(b.unhold(31), Err(WalletError::ArithmeticOverflow)));
+        assert!(matches!(b.unreserve_withdrawal(51), Err(WalletError::ArithmeticOverflow)));
+        assert_eq!((b.available, b.held, b.pending_withdrawals), (20, 30, 50));
+
+        b.unhold(30).unwrap();
+        b.unreserve_withdrawal(10).unwrap();
+        b.complete_withdrawal(40).unwrap();
+        assert_eq!((b.available, b.held, b.pending_withdrawals), (60, 0, 0));
+    }
+
+    #[test]
+    fn shift_checks_both_sides() {
+        let (mut from, mut to) = (10, u64::MAX - 5);
+        assert!(matches!(
+            shift(&mut from, &mut to, 11, WalletError::InsufficientBalance),
+            Err(WalletError::InsufficientBalance)
+        ));
+        // `from` has the amount, but `to` would wrap
+        assert!(matches!(
+            shift(&mut from, &mut to, 6, WalletError::InsufficientBalance),
+            Err(WalletError::ArithmeticOverflow)
+        ));
+        assert_eq!((from, to), (10, u64::MAX - 5));
+
+        shift(&mut from, &mut to, 5, WalletError::InsufficientBalance).unwrap();
+        assert_eq!((from, to), (5, u64::MAX));
+    }
+
+    #[test]
+    fn total_fails_instead_of_wrapping() {
+        assert!(matches!(balance(u64::MAX, 1, 0).total(), Err(WalletError::ArithmeticOverflow)));
+        assert!(matches!(balance(1, 0, u64::MAX).total(), Err(WalletError::ArithmeticOverflow)));
+        // Incoming deposits are not owned yet
+        let mut b = balance(1, 2, 3);
+        b.pending_deposits = u64::MAX;
+        assert_eq!(b.total().unwrap(), 6);
+    }
+}
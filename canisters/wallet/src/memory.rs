@@ -0,0 +1,49 @@
+// Stable memory layout.
+//
+// Stable memory is split into virtual memories by a `MemoryManager`: one
+// holds the heap state written at upgrade time, the others back stable
+// structures that live in stable memory all the time (the block log).
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _};
+use std::cell::RefCell;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const UPGRADES: MemoryId = MemoryId::new(0);
+pub const BLOCK_INDEX: MemoryId = MemoryId::new(1);
+pub const BLOCK_DATA: MemoryId = MemoryId::new(2);
+pub const BLOCK_TIP: MemoryId = MemoryId::new(3);
+pub const ACCOUNT_BLOCKS: MemoryId = MemoryId::new(4);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+// Heap state saved in `pre_upgrade`, as a length-prefixed blob
+pub fn write_upgrade_state(bytes: &[u8]) {
+    let mut memory = get(UPGRADES);
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write(&(bytes.len() as u64).to_le_bytes())
+        .and_then(|_| writer.write(bytes))
+        .unwrap_or_else(|_| ic_cdk::trap("Out of stable memory saving wallet state"));
+}
+
+// None if no state was ever saved in this layout
+pub fn read_upgrade_state() -> Option<Vec<u8>> {
+    let memory = get(UPGRADES);
+    if memory.size() == 0 {
+        return None;
+    }
+    let mut len = [0u8; 8];
+    memory.read(0, &mut len);
+    let mut bytes = vec![0u8; u64::from_le_bytes(len) as usize];
+    memory.read(8, &mut bytes);
+    Some(bytes)
+}
@@ -0,0 +1,326 @@
+// Payment requests (request-to-pay).
+//
+// A requester asks a payer for an amount of one asset. Nothing is held while
+// the request is pending; the payer approves it with one call, which makes an
+// ordinary transfer to the requester, or declines it. The requester can
+// cancel it, and pending requests expire at `expires_at`. Closed requests
+// are dropped `RETENTION_NANOS` after they closed.
+//
+// `store` keeps `Index` in step with the requests, so the heartbeat and
+// `create` never scan them all.
+use crate::*;
+use std::collections::BTreeSet;
+
+const DEFAULT_TTL_SECONDS: u64 = 7 * 24 * 60 * 60;
+const MAX_TTL_SECONDS: u64 = 30 * 24 * 60 * 60;
+// Pending requests a requester can have open at once
+const MAX_PENDING_PER_REQUESTER: usize = 50;
+const RETENTION_NANOS: u64 = 90 * 24 * 60 * 60 * 1_000_000_000;
+// Closed requests dropped per heartbeat
+const MAX_EVICTIONS: usize = 500;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CreatePaymentRequestParams {
+    pub payer: Principal,
+    pub amount: u64,
+    pub currency: Asset,
+    pub memo: Option<String>,
+    // Defaults to a week, at most 30 days
+    pub expires_in_seconds: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PaymentRequestStatus {
+    Pending,
+    Paid { tx_id: String },
+    Declined,
+    Cancelled,
+    Expired,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PaymentRequest {
+    pub id: String,
+    pub requester: Principal,
+    pub payer: Principal,
+    pub amount: u64,
+    pub currency: Asset,
+    pub memo: Option<String>,
+    pub status: PaymentRequestStatus,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub updated_at: u64,
+}
+
+// Pending requests seen from one side: those the caller has to pay and
+// those the caller is waiting on
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PendingPaymentRequests {
+    pub to_pay: Vec<PaymentRequest>,
+    pub requested: Vec<PaymentRequest>,
+}
+
+pub type PaymentRequests = HashMap<String, PaymentRequest>;
+
+// Derived from the requests; rebuilt on restore
+#[derive(Default)]
+struct Index {
+    // (expires_at, id) of pending requests
+    expiries: BTreeSet<(u64, String)>,
+    // (closed at, id) of closed requests
+    closed: BTreeSet<(u64, String)>,
+    pending_per_requester: HashMap<Principal, usize>,
+}
+
+impl Index {
+    fn add(&mut self, request: &PaymentRequest) {
+        if request.status == PaymentRequestStatus::Pending {
+            self.expiries.insert((request.expires_at, request.id.clone()));
+            *self.pending_per_requester.entry(request.requester).or_default() += 1;
+        } else {
+            self.closed.insert((request.updated_at, request.id.clone()));
+        }
+    }
+
+    fn remove(&mut self, request: &PaymentRequest) {
+        if request.status == PaymentRequestStatus::Pending {
+            self.expiries.remove(&(request.expires_at, request.id.clone()));
+            if let Some(count) = self.pending_per_requester.get_mut(&request.requester) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    self.pending_per_requester.remove(&request.requester);
+                }
+            }
+        } else {
+            self.closed.remove(&(request.updated_at, request.id.clone()));
+        }
+    }
+}
+
+thread_local! {
+    static REQUESTS: RefCell<PaymentRequests> = RefCell::new(HashMap::new());
+    static INDEX: RefCell<Index> = RefCell::new(Index::default());
+}
+
+fn store(request: &PaymentRequest) {
+    let previous = REQUESTS.with(|r| r.borrow_mut().insert(request.id.clone(), request.clone()));
+    INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = previous {
+            index.remove(&previous);
+        }
+        index.add(request);
+    });
+}
+
+// Only the requester and the payer can see a request
+pub fn get(caller_id: Principal, id: &str) -> Option<PaymentRequest> {
+    REQUESTS
+        .with(|r| r.borrow().get(id).cloned())
+        .filter(|r| r.requester == caller_id || r.payer == caller_id)
+}
+
+pub fn create(
+    requester: Principal,
+    params: CreatePaymentRequestParams,
+    now: u64,
+) -> std::result::Result<PaymentRequest, WalletError> {
+    if requester == Principal::anonymous() {
+        return Err(WalletError::Unauthorized);
+    }
+    if params.payer == requester || params.payer == Principal::anonymous() {
+        return Err(WalletError::InvalidArgument("Invalid payer".to_string()));
+    }
+    if params.amount == 0 {
+        return Err(WalletError::InvalidAmount);
+    }
+    if params.memo.as_ref().is_some_and(|memo| memo.len() > MAX_MEMO_LEN) {
+        return Err(WalletError::InvalidArgument(format!(
+            "Memo must be at most {} bytes",
+            MAX_MEMO_LEN
+        )));
+    }
+    let ttl_seconds = params.expires_in_seconds.unwrap_or(DEFAULT_TTL_SECONDS);
+    if ttl_seconds == 0 || ttl_seconds > MAX_TTL_SECONDS {
+        return Err(WalletError::InvalidArgument(format!(
+            "Expiry must be between 1 and {} seconds",
+            MAX_TTL_SECONDS
+        )));
+    }
+
+    let open = INDEX.with(|index| {
+        index
+            .borrow()
+            .pending_per_requester
+            .get(&requester)
+            .copied()
+            .unwrap_or(0)
+    });
+    if open >= MAX_PENDING_PER_REQUESTER {
+        return Err(WalletError::InvalidArgument(format!(
+            "At most {} pending payment requests",
+            MAX_PENDING_PER_REQUESTER
+        )));
+    }
+
+    let request = PaymentRequest {
+        id: generate_tx_id(),
+        requester,
+        payer: params.payer,
+        amount: params.amount,
+        currency: params.currency,
+        memo: params.memo,
+        status: PaymentRequestStatus::Pending,
+        created_at: now,
+        expires_at: now.saturating_add(ttl_seconds * 1_000_000_000),
+        updated_at: now,
+    };
+    store(&request);
+    Ok(request)
+}
+
+// Pending requests past their expiry are marked expired on first touch
+fn pending(
+    caller_id: Principal,
+    id: &str,
+    now: u64,
+) -> std::result::Result<PaymentRequest, WalletError> {
+    let mut request = get(caller_id, id).ok_or(WalletError::NotFound)?;
+    if request.status != PaymentRequestStatus::Pending {
+        return Err(WalletError::InvalidArgument("Payment request is not pending".to_string()));
+    }
+    if now >= request.expires_at {
+        request.status = PaymentRequestStatus::Expired;
+        request.updated_at = now;
+        store(&request);
+        return Err(WalletError::InvalidArgument("Payment request has expired".to_string()));
+    }
+    Ok(request)
+}
+
+// Paying again after a successful payment returns the paid request
+pub fn pay(payer: Principal, id: &str, now: u64) -> std::result::Result<PaymentRequest, WalletError> {
+    if let Some(request) = get(payer, id).filter(|r| r.payer == payer) {
+        if matches!(request.status, PaymentRequestStatus::Paid { .. }) {
+            return Ok(request);
+        }
+    }
+
+    let mut request = pending(payer, id, now)?;
+    if request.payer != payer {
+        return Err(WalletError::Unauthorized);
+    }
+
+    let (_, tx_id) = apply_transfer(
+        payer,
+        request.requester,
+        request.amount,
+        request.currency,
+        request.memo.clone(),
+        now,
+    )?;
+    request.status = PaymentRequestStatus::Paid { tx_id };
+    request.updated_at = now;
+    store(&request);
+    Ok(request)
+}
+
+pub fn decline(payer: Principal, id: &str, now: u64) -> std::result::Result<PaymentRequest, WalletError> {
+    let mut request = pending(payer, id, now)?;
+    if request.payer != payer {
+        return Err(WalletError::Unauthorized);
+    }
+    request.status = PaymentRequestStatus::Declined;
+    request.updated_at = now;
+    store(&request);
+    Ok(request)
+}
+
+pub fn cancel(requester: Principal, id: &str, now: u64) -> std::result::Result<PaymentRequest, WalletError> {
+    let mut request = pending(requester, id, now)?;
+    if request.requester != requester {
+        return Err(WalletError::Unauthorized);
+    }
+    request.status = PaymentRequestStatus::Cancelled;
+    request.updated_at = now;
+    store(&request);
+    Ok(request)
+}
+
+// Expire pending requests that are due and drop closed ones past retention
+pub fn expire_due(now: u64) {
+    let due: Vec<String> = INDEX.with(|index| {
+        index
+            .borrow()
+            .expiries
+            .range(..(now.saturating_add(1), String::new()))
+            .map(|(_, id)| id.clone())
+            .collect()
+    });
+    for id in due {
+        if let Some(mut request) = REQUESTS.with(|r| r.borrow().get(&id).cloned()) {
+            request.status = PaymentRequestStatus::Expired;
+            request.updated_at = now;
+            store(&request);
+        }
+    }
+
+    let cutoff = now.saturating_sub(RETENTION_NANOS);
+    let evicted: Vec<(u64, String)> = INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let evicted: Vec<(u64, String)> = index
+            .closed
+            .range(..(cutoff, String::new()))
+            .take(MAX_EVICTIONS)
+            .cloned()
+            .collect();
+        for key in &evicted {
+            index.closed.remove(key);
+        }
+        evicted
+    });
+    REQUESTS.with(|r| {
+        let mut requests = r.borrow_mut();
+        for (_, id) in evicted {
+            requests.remove(&id);
+        }
+    });
+}
+
+// Newest first
+pub fn pending_for(principal: Principal, now: u64) -> PendingPaymentRequests {
+    let mut to_pay = Vec::new();
+    let mut requested = Vec::new();
+    REQUESTS.with(|r| {
+        for request in r.borrow().values() {
+            if request.status != PaymentRequestStatus::Pending || now >= request.expires_at {
+                continue;
+            }
+            if request.payer == principal {
+                to_pay.push(request.clone());
+            } else if request.requester == principal {
+                requested.push(request.clone());
+            }
+        }
+    });
+    to_pay.sort_by_key(|r| std::cmp::Reverse(r.created_at));
+    requested.sort_by_key(|r| std::cmp::Reverse(r.created_at));
+    PendingPaymentRequests { to_pay, requested }
+}
+
+pub fn count() -> u64 {
+    REQUESTS.with(|r| r.borrow().len() as u64)
+}
+
+pub fn snapshot() -> PaymentRequests {
+    REQUESTS.with(|r| r.borrow().clone())
+}
+
+pub fn restore(requests: PaymentRequests) {
+    let mut index = Index::default();
+    for request in requests.values() {
+        index.add(request);
+    }
+    INDEX.with(|i| *i.borrow_mut() = index);
+    REQUESTS.with(|r| *r.borrow_mut() = requests);
+}
@@ -0,0 +1,210 @@
+// Withdrawal and transfer security controls.
+//
+// Meant to limit the damage of a stolen session: withdrawals can be
+// restricted to a per-user allow-list of addresses, which only become usable
+// after a cooling-off period; the amount withdrawn over a rolling window is
+// capped according to the user's `kyc_level` in the identity canister; and
+// large withdrawals are held back for a delay during which the owner can
+// cancel them.
+
+use crate::*;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const MAX_LABEL_LEN: usize = 64;
+const MAX_ALLOWED_ADDRESSES: usize = 20;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct WithdrawalPolicy {
+    pub require_allow_list: bool,
+    pub address_cooling_off_seconds: u64,
+    pub limit_window_seconds: u64,
+    // Satoshis per window, indexed by kyc_level; higher levels use the last
+    // entry
+    pub limits_by_kyc_level: Vec<u64>,
+    // Withdrawals of at least this amount wait `delay_seconds` before they
+    // are sent and can be cancelled until then
+    pub delay_threshold: Option<u64>,
+    pub delay_seconds: u64,
+}
+
+impl Default for WithdrawalPolicy {
+    fn default() -> Self {
+        WithdrawalPolicy {
+            require_allow_list: true,
+            address_cooling_off_seconds: 24 * 60 * 60,
+            limit_window_seconds: 24 * 60 * 60,
+            limits_by_kyc_level: vec![1_000_000, 5_000_000, 50_000_000, 200_000_000],
+            delay_threshold: Some(10_000_000),
+            delay_seconds: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AllowedAddress {
+    pub address: String,
+    pub label: Option<String>,
+    pub added_at: u64,
+    pub usable_from: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct WithdrawalLimit {
+    pub kyc_level: u8,
+    pub limit: u64,
+    pub used: u64,
+    pub remaining: u64,
+    pub window_seconds: u64,
+}
+
+pub type AllowedAddresses = HashMap<Principal, Vec<AllowedAddress>>;
+
+thread_local! {
+    static POLICY: RefCell<WithdrawalPolicy> = RefCell::new(WithdrawalPolicy::default());
+    static ALLOWED_ADDRESSES: RefCell<AllowedAddresses> = RefCell::new(HashMap::new());
+}
+
+pub fn policy() -> WithdrawalPolicy {
+    POLICY.with(|p| p.borrow().clone())
+}
+
+pub fn set_policy(policy: WithdrawalPolicy) -> std::result::Result<(), WalletError> {
+    if policy.limit_window_seconds == 0
+        || policy.limits_by_kyc_level.is_empty()
+        || (policy.delay_threshold.is_some() && policy.delay_seconds == 0)
+    {
+        return Err(WalletError::InvalidConfig);
+    }
+    POLICY.with(|p| *p.borrow_mut() = policy);
+    Ok(())
+}
+
+pub fn allowed_addresses(user_id: Principal) -> Vec<AllowedAddress> {
+    ALLOWED_ADDRESSES.with(|a| a.borrow().get(&user_id).cloned().unwrap_or_default())
+}
+
+pub fn add_address(
+    user_id: Principal,
+    address: String,
+    label: Option<String>,
+    now: u64,
+) -> std::result::Result<AllowedAddress, WalletError> {
+    btc::parse_address(&address)?;
+    check_label(&label)?;
+
+    let cooling_off = policy().address_cooling_off_seconds.saturating_mul(NANOS_PER_SECOND);
+    ALLOWED_ADDRESSES.with(|a| {
+        let mut all = a.borrow_mut();
+        let addresses = all.entry(user_id).or_default();
+        // Re-adding only updates the label; it never shortens the cooling-off
+        if let Some(existing) = addresses.iter_mut().find(|a| a.address == address) {
+            existing.label = label;
+            return Ok(existing.clone());
+        }
+        if addresses.len() >= MAX_ALLOWED_ADDRESSES {
+            return Err(WalletError::InvalidArgument(format!(
+                "At most {} withdrawal addresses are allowed",
+                MAX_ALLOWED_ADDRESSES
+            )));
+        }
+        let entry = AllowedAddress {
+            address,
+            label,
+            added_at: now,
+            usable_from: now.saturating_add(cooling_off),
+        };
+        addresses.push(entry.clone());
+        Ok(entry)
+    })
+}
+
+fn check_label(label: &Option<String>) -> std::result::Result<(), WalletError> {
+    if label.as_ref().is_some_and(|l| l.len() > MAX_LABEL_LEN) {
+        return Err(WalletError::InvalidArgument(format!(
+            "Label must be at most {} bytes",
+            MAX_LABEL_LEN
+        )));
+    }
+    Ok(())
+}
+
+pub fn remove_address(user_id: Principal, address: &str) -> Vec<AllowedAddress> {
+    ALLOWED_ADDRESSES.with(|a| {
+        let mut all = a.borrow_mut();
+        let addresses = all.entry(user_id).or_default();
+        addresses.retain(|a| a.address != address);
+        addresses.clone()
+    })
+}
+
+// Level 0 when the identity canister is not configured, has no profile for
+// the user or cannot be reached
+pub async fn kyc_level(user_id: Principal) -> u8 {
+    if config::get().identity_canister.is_none() {
+        return 0;
+    }
+    match identity::profile(user_id).await {
+        Ok(profile) => profile.map_or(0, |p| p.kyc_level),
+        Err(err) => {
+            ic_cdk::println!("KYC lookup for {} failed: {:?}", user_id, err);
+            0
+        }
+    }
+}
+
+pub fn limit(user_id: Principal, kyc_level: u8, now: u64) -> WithdrawalLimit {
+    let policy = policy();
+    let limits = &policy.limits_by_kyc_level;
+    let limit = limits[(kyc_level as usize).min(limits.len() - 1)];
+    let window_start = now.saturating_sub(policy.limit_window_seconds.saturating_mul(NANOS_PER_SECOND));
+    let used = withdrawals::withdrawn_since(user_id, window_start);
+    WithdrawalLimit {
+        kyc_level,
+        limit,
+        used,
+        remaining: limit.saturating_sub(used),
+        window_seconds: policy.limit_window_seconds,
+    }
+}
+
+// Checks a withdrawal against the allow-list and the rolling limit. Returns
+// when it may be sent: now (`None`) or after the large-withdrawal delay.
+// Must run in the same message as the reservation so concurrent withdrawals
+// cannot both pass the limit.
+pub fn check(
+    user_id: Principal,
+    params: &WithdrawParams,
+    kyc_level: u8,
+    now: u64,
+) -> std::result::Result<Option<u64>, WalletError> {
+    let policy = policy();
+
+    if policy.require_allow_list {
+        let entry = allowed_addresses(user_id)
+            .into_iter()
+            .find(|a| a.address == params.destination_address)
+            .ok_or(WalletError::AddressNotAllowed)?;
+        if now < entry.usable_from {
+            return Err(WalletError::AddressCoolingOff {
+                usable_from: entry.usable_from,
+            });
+        }
+    }
+
+    let remaining = limit(user_id, kyc_level, now).remaining;
+    if params.amount > remaining {
+        return Err(WalletError::WithdrawalLimitExceeded { remaining });
+    }
+
+    let delayed = policy.delay_threshold.is_some_and(|t| params.amount >= t);
+    Ok(delayed.then(|| now.saturating_add(policy.delay_seconds.saturating_mul(NANOS_PER_SECOND))))
+}
+
+pub fn snapshot() -> (WithdrawalPolicy, AllowedAddresses) {
+    (policy(), ALLOWED_ADDRESSES.with(|a| a.borrow().clone()))
+}
+
+pub fn restore(policy: WithdrawalPolicy, allowed: AllowedAddresses) {
+    POLICY.with(|p| *p.borrow_mut() = policy);
+    ALLOWED_ADDRESSES.with(|a| *a.borrow_mut() = allowed);
+}
@@ -0,0 +1,302 @@
+// Account statements for a date range.
+//
+// Built from the block log rather than the transaction history, so opening
+// and closing balances are exact: each is the account's `available + held +
+// pending_withdrawals` at that point. Placing a hold, or returning one to its
+// payer, does not change that total and is left out.
+use crate::*;
+
+// Entries per statement; longer ranges have to be split
+pub const MAX_STATEMENT_ENTRIES: usize = 10_000;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StatementEntry {
+    pub block_index: u64,
+    pub timestamp: u64,
+    // deposit, escrow_payout, credit, transfer_in, transfer_out, withdrawal,
+    // hold_payment or hold_release
+    pub kind: String,
+    // Escrow/hold ID, withdrawal ID or deposit outpoint
+    pub reference: Option<String>,
+    pub counterparty: Option<Principal>,
+    pub memo: Option<String>,
+    pub credit: u64,
+    pub debit: u64,
+    pub balance: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AssetStatement {
+    pub asset: Asset,
+    pub opening_balance: u64,
+    pub total_credits: u64,
+    pub total_debits: u64,
+    pub closing_balance: u64,
+    pub entries: Vec<StatementEntry>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Statement {
+    pub user_id: Principal,
+    // Inclusive bounds on block timestamps
+    pub from: u64,
+    pub to: u64,
+    pub generated_at: u64,
+    // Assets with a balance or activity in the range
+    pub assets: Vec<AssetStatement>,
+}
+
+struct Movement {
+    asset: Asset,
+    kind: &'static str,
+    reference: Option<String>,
+    counterparty: Option<Principal>,
+    memo: Option<String>,
+    // Signed change to the account's total
+    delta: i128,
+}
+
+fn movement(account: Principal, operation: &blocks::Operation) -> Option<Movement> {
+    use blocks::Operation;
+
+    let signed = |from: Principal, amount: u64| {
+        if from == account {
+            -(amount as i128)
+        } else {
+            amount as i128
+        }
+    };
+    match operation {
+        Operation::Mint { asset, amount, source, .. } => {
+            let (kind, reference) = match source {
+                Some(CreditSource::Deposit { txid, vout }) => ("deposit", Some(format!("{}:{}", txid, vout))),
+                Some(CreditSource::EscrowPayout { escrow_id }) => ("escrow_payout", Some(escrow_id.clone())),
+                Some(CreditSource::LedgerDeposit { block_index, .. }) => ("deposit", Some(block_index.to_string())),
+                None => ("credit", None),
+            };
+            Some(Movement {
+                asset: *asset,
+                kind,
+                reference,
+                counterparty: None,
+                memo: None,
+                delta: *amount as i128,
+            })
+        }
+        Operation::Transfer { from, to, asset, amount, memo } => Some(Movement {
+            asset: *asset,
+            kind: if *from == account { "transfer_out" } else { "transfer_in" },
+            reference: None,
+            counterparty: Some(if *from == account { *to } else { *from }),
+            memo: memo.clone(),
+            delta: signed(*from, *amount),
+        }),
+        Operation::Burn { asset, amount, withdrawal_id, .. } => Some(Movement {
+            asset: *asset,
+            kind: "withdrawal",
+            reference: Some(withdrawal_id.clone()),
+            counterparty: None,
+            memo: None,
+            delta: -(*amount as i128),
+        }),
+        Operation::Hold { .. } => None,
+        Operation::Release { from, to, .. } if from == to => None,
+        Operation::Release { from, to, asset, amount, escrow_id } => Some(Movement {
+            asset: *asset,
+            kind: if *from == account { "hold_payment" } else { "hold_release" },
+            reference: Some(escrow_id.clone()),
+            counterparty: Some(if *from == account { *to } else { *from }),
+            memo: None,
+            delta: signed(*from, *amount),
+        }),
+    }
+}
+
+fn apply(balance: u64, delta: i128) -> std::result::Result<u64, WalletError> {
+    u64::try_from(balance as i128 + delta).map_err(|_| WalletError::ArithmeticOverflow)
+}
+
+fn empty(asset: Asset, opening_balance: u64) -> AssetStatement {
+    AssetStatement {
+        asset,
+        opening_balance,
+        total_credits: 0,
+        total_debits: 0,
+        closing_balance: opening_balance,
+        entries: Vec::new(),
+    }
+}
+
+pub fn generate(
+    user_id: Principal,
+    from: u64,
+    to: u64,
+    now: u64,
+) -> std::result::Result<Statement, WalletError> {
+    if from > to {
+        return Err(WalletError::InvalidArgument("Start of range is after its end".to_string()));
+    }
+
+    // Only the blocks in the range are read; the opening balances come from
+    // the account's totals just before it
+    let start = blocks::first_at(from);
+    let end = to.checked_add(1).map_or_else(blocks::count, blocks::first_at);
+
+    let mut statements: BTreeMap<Asset, AssetStatement> = BTreeMap::new();
+    for (asset, total) in blocks::totals_before(user_id, start).0 {
+        let opening_balance = u64::try_from(total).map_err(|_| WalletError::ArithmeticOverflow)?;
+        statements.insert(asset, empty(asset, opening_balance));
+    }
+
+    let mut entries = 0;
+    for block in blocks::account_range(user_id, start, end) {
+        let Some(movement) = movement(user_id, &block.operation) else {
+            continue;
+        };
+        let statement = statements
+            .entry(movement.asset)
+            .or_insert_with(|| empty(movement.asset, 0));
+
+        let balance = apply(statement.closing_balance, movement.delta)?;
+        statement.closing_balance = balance;
+
+        entries += 1;
+        if entries > MAX_STATEMENT_ENTRIES {
+            return Err(WalletError::InvalidArgument(format!(
+                "More than {} entries in range; request a shorter period",
+                MAX_STATEMENT_ENTRIES
+            )));
+        }
+        let (credit, debit) = if movement.delta >= 0 {
+            (movement.delta as u64, 0)
+        } else {
+            (0, movement.delta.unsigned_abs() as u64)
+        };
+        statement.total_credits = statement
+            .total_credits
+            .checked_add(credit)
+            .ok_or(WalletError::ArithmeticOverflow)?;
+        statement.total_debits = statement
+            .total_debits
+            .checked_add(debit)
+            .ok_or(WalletError::ArithmeticOverflow)?;
+        statement.entries.push(StatementEntry {
+            block_index: block.index,
+            timestamp: block.timestamp,
+            kind: movement.kind.to_string(),
+            reference: movement.reference,
+            counterparty: movement.counterparty,
+            memo: movement.memo,
+            credit,
+            debit,
+            balance,
+        });
+    }
+
+    Ok(Statement {
+        user_id,
+        from,
+        to,
+        generated_at: now,
+        assets: statements
+            .into_values()
+            .filter(|s| s.opening_balance > 0 || s.closing_balance > 0 || !s.entries.is_empty())
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blocks::Operation;
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn deposit(to: Principal, amount: u64, txid: &str) -> Operation {
+        let source = Some(CreditSource::Deposit { txid: txid.to_string(), vout: 0 });
+        Operation::Mint { to, asset: Asset::BTC, amount, source }
+    }
+
+    fn transfer(from: Principal, to: Principal, amount: u64) -> Operation {
+        Operation::Transfer { from, to, asset: Asset::BTC, amount, memo: None }
+    }
+
+    #[test]
+    fn opening_and_closing_balances_bracket_the_range() {
+        let (alice, bob) = (user(1), user(2));
+        blocks::append(deposit(alice, 1_000, "a"), 10);
+        blocks::append(transfer(alice, bob, 300), 20);
+        blocks::append(deposit(alice, 500, "b"), 30);
+        blocks::append(transfer(bob, alice, 100), 40);
+        blocks::append(deposit(alice, 9_999, "c"), 50);
+
+        let statement = generate(alice, 20, 40, 99).unwrap();
+        assert_eq!(statement.generated_at, 99);
+        let [btc] = &statement.assets[..] else {
+            panic!("expected one asset, got {:?}", statement.assets);
+        };
+        assert_eq!(btc.opening_balance, 1_000);
+        assert_eq!(btc.total_credits, 600);
+        assert_eq!(btc.total_debits, 300);
+        assert_eq!(btc.closing_balance, 1_300);
+
+        let kinds: Vec<&str> = btc.entries.iter().map(|e| e.kind.as_str()).collect();
+        assert_eq!(kinds, vec!["transfer_out", "deposit", "transfer_in"]);
+        let balances: Vec<u64> = btc.entries.iter().map(|e| e.balance).collect();
+        assert_eq!(balances, vec![700, 1_200, 1_300]);
+        assert_eq!(btc.entries[0].counterparty, Some(bob));
+        assert_eq!(btc.entries[1].reference.as_deref(), Some("b:0"));
+    }
+
+    #[test]
+    fn holds_only_show_once_paid() {
+        let (alice, bob) = (user(1), user(2));
+        let escrow_id = "ESC-1".to_string();
+        blocks::append(deposit(alice, 1_000, "a"), 10);
+        blocks::append(
+            Operation::Hold { from: alice, asset: Asset::BTC, amount: 400, escrow_id: escrow_id.clone() },
+            20,
+        );
+        blocks::append(
+            Operation::Release { from: alice, to: bob, asset: Asset::BTC, amount: 250, escrow_id: escrow_id.clone() },
+            30,
+        );
+        blocks::append(
+            Operation::Release { from: alice, to: alice, asset: Asset::BTC, amount: 150, escrow_id },
+            40,
+        );
+
+        let statement = generate(alice, 0, 100, 100).unwrap();
+        let entries = &statement.assets[0].entries;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].kind, "hold_payment");
+        assert_eq!(entries[1].debit, 250);
+        assert_eq!(statement.assets[0].closing_balance, 750);
+
+        let statement = generate(bob, 0, 100, 100).unwrap();
+        assert_eq!(statement.assets[0].entries[0].kind, "hold_release");
+        assert_eq!(statement.assets[0].closing_balance, 250);
+    }
+
+    #[test]
+    fn quiet_assets_and_bad_ranges() {
+        let alice = user(1);
+        assert!(generate(alice, 0, 100, 100).unwrap().assets.is_empty());
+        assert!(matches!(generate(alice, 2, 1, 100), Err(WalletError::InvalidArgument(_))));
+
+        // A balance carried into a range without activity still shows
+        blocks::append(deposit(alice, 1_000, "a"), 10);
+        let statement = generate(alice, 50, 100, 100).unwrap();
+        assert_eq!(statement.assets[0].opening_balance, 1_000);
+        assert_eq!(statement.assets[0].closing_balance, 1_000);
+        assert!(statement.assets[0].entries.is_empty());
+    }
+
+    #[test]
+    fn a_log_that_goes_negative_is_an_error() {
+        blocks::append(transfer(user(1), user(2), 1), 10);
+        assert!(matches!(generate(user(1), 0, 100, 100), Err(WalletError::ArithmeticOverflow)));
+    }
+}
@@ -0,0 +1,184 @@
+// Upgrade persistence for the wallet's heap state.
+//
+// The state is written to its own virtual memory (see memory.rs) as
+// `(version, candid bytes)`. `post_upgrade` decodes the layout of whichever
+// version saved it; add a `StableStateVn` and a `migrate_vn` when the layout
+// changes. The block log is not part of it; it lives in stable memory
+// already.
+use crate::*;
+use candid::{decode_args, decode_one, encode_args, encode_one};
+
+const STATE_VERSION: u32 = 1;
+
+#[derive(CandidType, Deserialize)]
+struct StableStateV1 {
+    balances: HashMap<Principal, WalletBalance>,
+    addresses: HashMap<Principal, Vec<DepositAddress>>,
+    transactions: HashMap<Principal, Vec<Transaction>>,
+    tx_counter: u64,
+    minters: HashSet<Principal>,
+    credited_sources: HashSet<CreditSource>,
+    config: config::WalletConfig,
+    withdrawals: HashMap<String, Withdrawal>,
+    reserved_outpoints: withdrawals::ReservedOutpoints,
+    signed_withdrawals: HashMap<String, Vec<u8>>,
+    deposit_keys: deposits::DepositKeys,
+    deposits: deposits::Deposits,
+    completed_transfers: idempotency::CompletedTransfers,
+    holds: HashMap<String, Hold>,
+    auditors: Vec<Principal>,
+    withdrawal_policy: security::WithdrawalPolicy,
+    allowed_addresses: security::AllowedAddresses,
+    agent_transactions: agents::AgentTransactions,
+    agent_floats: agents::AgentFloats,
+    vas_purchases: vas::VasPurchases,
+    vas_approvals: vas::VasApprovals,
+    payment_requests: payment_requests::PaymentRequests,
+}
+
+pub fn save() {
+    let (withdrawals, reserved_outpoints, signed_withdrawals) = withdrawals::snapshot();
+    let (deposit_keys, tracked_deposits) = deposits::snapshot();
+    let (withdrawal_policy, allowed_addresses) = security::snapshot();
+    let (agent_transactions, agent_floats) = agents::snapshot();
+    let (vas_purchases, vas_approvals) = vas::snapshot();
+    let state = StableStateV1 {
+        balances: BALANCES.with(|b| b.borrow().clone()),
+        addresses: ADDRESSES.with(|a| a.borrow().clone()),
+        transactions: TRANSACTIONS.with(|t| t.borrow().clone()),
+        tx_counter: TX_COUNTER.with(|c| *c.borrow()),
+        minters: MINTERS.with(|m| m.borrow().clone()),
+        credited_sources: CREDITED_SOURCES.with(|s| s.borrow().clone()),
+        config: config::get(),
+        withdrawals,
+        reserved_outpoints,
+        signed_withdrawals,
+        deposit_keys,
+        deposits: tracked_deposits,
+        completed_transfers: idempotency::snapshot(),
+        holds: holds::snapshot(),
+        auditors: blocks::auditors(),
+        withdrawal_policy,
+        allowed_addresses,
+        agent_transactions,
+        agent_floats,
+        vas_purchases,
+        vas_approvals,
+        payment_requests: payment_requests::snapshot(),
+    };
+    let bytes = encode_one(&state)
+        .and_then(|bytes| encode_args((STATE_VERSION, bytes)))
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to encode wallet state: {}", err)));
+    memory::write_upgrade_state(&bytes);
+}
+
+fn decode<T: for<'de> Deserialize<'de> + CandidType>(bytes: &[u8]) -> T {
+    decode_one(bytes)
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to decode wallet state: {}", err)))
+}
+
+pub fn restore() {
+    // Releases before versioned state kept nothing in stable memory. Any
+    // state that fails to decode must not be silently dropped.
+    let Some(saved) = memory::read_upgrade_state() else {
+        ic_cdk::println!("No saved wallet state found, starting empty");
+        return;
+    };
+    let (version, bytes) = decode_args::<(u32, Vec<u8>)>(&saved)
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to restore wallet state: {}", err)));
+
+    let state: StableStateV1 = match version {
+        1 => decode(&bytes),
+        other => ic_cdk::trap(&format!("Unsupported wallet state version {}", other)),
+    };
+
+    BALANCES.with(|b| *b.borrow_mut() = state.balances);
+    ADDRESSES.with(|a| *a.borrow_mut() = state.addresses);
+    TRANSACTIONS.with(|t| *t.borrow_mut() = state.transactions);
+    TX_COUNTER.with(|c| *c.borrow_mut() = state.tx_counter);
+    MINTERS.with(|m| *m.borrow_mut() = state.minters);
+    CREDITED_SOURCES.with(|s| *s.borrow_mut() = state.credited_sources);
+    if let Err(err) = config::set(state.config) {
+        ic_cdk::trap(&format!("Invalid saved wallet config: {:?}", err));
+    }
+    withdrawals::restore(state.withdrawals, state.reserved_outpoints, state.signed_withdrawals);
+    deposits::restore(state.deposit_keys, state.deposits);
+    idempotency::restore(state.completed_transfers);
+    holds::restore(state.holds);
+    security::restore(state.withdrawal_policy, state.allowed_addresses);
+    agents::restore(state.agent_transactions, state.agent_floats);
+    vas::restore(state.vas_purchases, state.vas_approvals);
+    payment_requests::restore(state.payment_requests);
+    blocks::restore_auditors(state.auditors);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    #[test]
+    fn saved_state_round_trips() {
+        let mut balance = new_balance(user(), 5);
+        balance.asset_mut(Asset::BTC).available = 100;
+        balance.asset_mut(Asset::BTC).held = 30;
+        BALANCES.with(|b| b.borrow_mut().insert(user(), balance));
+        TX_COUNTER.with(|c| *c.borrow_mut() = 42);
+        MINTERS.with(|m| m.borrow_mut().insert(user()));
+        let hold = Hold {
+            escrow_id: "ESC-1".to_string(),
+            user_id: user(),
+            amount: 30,
+            currency: Asset::BTC,
+            status: HoldStatus::Active,
+            tx_id: "TX-1".to_string(),
+            created_at: 5,
+            updated_at: 5,
+            origin: HoldOrigin::Escrow,
+        };
+        holds::restore([("ESC-1".to_string(), hold)].into_iter().collect());
+        blocks::set_auditor(user(), true);
+
+        save();
+        BALANCES.with(|b| b.borrow_mut().clear());
+        TX_COUNTER.with(|c| *c.borrow_mut() = 0);
+        MINTERS.with(|m| m.borrow_mut().clear());
+        holds::restore(HashMap::new());
+        blocks::restore_auditors(Vec::new());
+        restore();
+
+        let balance = BALANCES.with(|b| b.borrow()[&user()].asset(Asset::BTC));
+        assert_eq!((balance.available, balance.held), (100, 30));
+        assert_eq!(TX_COUNTER.with(|c| *c.borrow()), 42);
+        assert!(MINTERS.with(|m| m.borrow().contains(&user())));
+        let holds = holds::snapshot();
+        assert_eq!(holds["ESC-1"].origin, HoldOrigin::Escrow);
+        assert_eq!(blocks::auditors(), vec![user()]);
+    }
+
+    #[test]
+    fn nothing_saved_starts_empty() {
+        restore();
+        assert!(BALANCES.with(|b| b.borrow().is_empty()));
+        assert_eq!(holds::count(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn unknown_versions_are_rejected() {
+        let bytes = encode_args((STATE_VERSION + 1, Vec::<u8>::new())).unwrap();
+        memory::write_upgrade_state(&bytes);
+        restore();
+    }
+
+    #[test]
+    #[should_panic]
+    fn undecodable_state_is_rejected() {
+        let bytes = encode_args((STATE_VERSION, vec![1u8, 2, 3])).unwrap();
+        memory::write_upgrade_state(&bytes);
+        restore();
+    }
+}
@@ -0,0 +1,271 @@
+// Value-added services (airtime, data, bill payments) paid from the wallet.
+//
+// The API gateway quotes the purchase and talks to the provider; the wallet
+// only holds the user's funds while it does. Once the provider has answered,
+// the gateway finalizes the purchase, which releases the hold to the VAS
+// operator's own balance, or fails it, which returns the hold to the user.
+// Only the configured `vas_operator` can place and settle these holds, and
+// only for purchases the user approved first: `approve` lets the operator
+// hold up to an amount for one purchase ID (the gateway's quote ID) until
+// `APPROVAL_TTL_NANOS` passes. Each approval is used by one hold.
+use crate::*;
+
+const MAX_FIELD_LEN: usize = 64;
+const APPROVAL_TTL_NANOS: u64 = 15 * 60 * 1_000_000_000;
+const MAX_APPROVALS_PER_USER: usize = 10;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum VasServiceType {
+    Airtime,
+    Data,
+    BillPayment,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VasPurchaseParams {
+    // Chosen by the gateway; repeating a call with the same ID is a no-op
+    pub purchase_id: String,
+    pub user_id: Principal,
+    pub service_type: VasServiceType,
+    pub provider: String,
+    // Phone number or account the service is bought for
+    pub recipient: String,
+    pub amount_usd_cents: u64,
+    pub amount: u64,
+    pub currency: Asset,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum VasPurchaseStatus {
+    Pending,
+    Completed { provider_reference: String },
+    Failed { reason: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct VasPurchase {
+    pub params: VasPurchaseParams,
+    pub status: VasPurchaseStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct VasApproval {
+    pub purchase_id: String,
+    // Most the operator may hold for the purchase
+    pub amount: u64,
+    pub currency: Asset,
+    pub expires_at: u64,
+}
+
+pub type VasPurchases = HashMap<String, VasPurchase>;
+pub type VasApprovals = HashMap<Principal, Vec<VasApproval>>;
+
+thread_local! {
+    static PURCHASES: RefCell<VasPurchases> = RefCell::new(HashMap::new());
+    static APPROVALS: RefCell<VasApprovals> = RefCell::new(HashMap::new());
+}
+
+fn require_operator() -> std::result::Result<Principal, WalletError> {
+    match config::get().vas_operator {
+        Some(operator) if operator == caller() => Ok(operator),
+        _ => Err(WalletError::Unauthorized),
+    }
+}
+
+// Holds are keyed by ID across escrows, agents and VAS
+fn hold_id(purchase_id: &str) -> String {
+    format!("{}{}", holds::VAS_PREFIX, purchase_id)
+}
+
+pub fn get(purchase_id: &str) -> Option<VasPurchase> {
+    PURCHASES.with(|p| p.borrow().get(purchase_id).cloned())
+}
+
+fn store(purchase: &VasPurchase) {
+    PURCHASES.with(|p| {
+        p.borrow_mut()
+            .insert(purchase.params.purchase_id.clone(), purchase.clone())
+    });
+}
+
+pub fn approve(
+    user_id: Principal,
+    purchase_id: String,
+    amount: u64,
+    currency: Asset,
+    now: u64,
+) -> std::result::Result<VasApproval, WalletError> {
+    if purchase_id.is_empty() || purchase_id.len() > MAX_FIELD_LEN {
+        return Err(WalletError::InvalidArgument(format!(
+            "Purchase ID must be 1 to {} bytes",
+            MAX_FIELD_LEN
+        )));
+    }
+    if amount == 0 {
+        return Err(WalletError::InvalidAmount);
+    }
+    if get(&purchase_id).is_some() {
+        return Err(WalletError::HoldConflict);
+    }
+
+    APPROVALS.with(|a| {
+        let mut all = a.borrow_mut();
+        let approvals = all.entry(user_id).or_default();
+        approvals.retain(|a| a.expires_at > now && a.purchase_id != purchase_id);
+        if approvals.len() >= MAX_APPROVALS_PER_USER {
+            return Err(WalletError::InvalidArgument(format!(
+                "At most {} VAS purchases can await approval",
+                MAX_APPROVALS_PER_USER
+            )));
+        }
+        let approval = VasApproval {
+            purchase_id,
+            amount,
+            currency,
+            expires_at: now.saturating_add(APPROVAL_TTL_NANOS),
+        };
+        approvals.push(approval.clone());
+        Ok(approval)
+    })
+}
+
+fn is_approved(params: &VasPurchaseParams, now: u64) -> bool {
+    APPROVALS.with(|a| {
+        a.borrow().get(&params.user_id).is_some_and(|approvals| {
+            approvals.iter().any(|a| {
+                a.purchase_id == params.purchase_id
+                    && a.currency == params.currency
+                    && params.amount <= a.amount
+                    && now < a.expires_at
+            })
+        })
+    })
+}
+
+fn use_approval(user_id: Principal, purchase_id: &str) {
+    APPROVALS.with(|a| {
+        let mut all = a.borrow_mut();
+        if let Some(approvals) = all.get_mut(&user_id) {
+            approvals.retain(|a| a.purchase_id != purchase_id);
+            if approvals.is_empty() {
+                all.remove(&user_id);
+            }
+        }
+    });
+}
+
+pub fn hold(params: VasPurchaseParams, now: u64) -> std::result::Result<VasPurchase, WalletError> {
+    require_operator()?;
+
+    let invalid = [&params.purchase_id, &params.provider, &params.recipient]
+        .iter()
+        .any(|field| field.is_empty() || field.len() > MAX_FIELD_LEN);
+    if invalid {
+        return Err(WalletError::InvalidArgument(format!(
+            "Purchase ID, provider and recipient must be 1 to {} bytes",
+            MAX_FIELD_LEN
+        )));
+    }
+
+    if let Some(existing) = get(&params.purchase_id) {
+        return if existing.params == params {
+            Ok(existing)
+        } else {
+            Err(WalletError::HoldConflict)
+        };
+    }
+    if !is_approved(&params, now) {
+        return Err(WalletError::Unauthorized);
+    }
+
+    holds::create(
+        HoldParams {
+            escrow_id: hold_id(&params.purchase_id),
+            user_id: params.user_id,
+            amount: params.amount,
+            currency: params.currency,
+        },
+        HoldOrigin::Vas,
+        "vas_purchase",
+        now,
+    )?;
+    use_approval(params.user_id, &params.purchase_id);
+
+    let purchase = VasPurchase {
+        params,
+        status: VasPurchaseStatus::Pending,
+        created_at: now,
+        updated_at: now,
+    };
+    store(&purchase);
+    Ok(purchase)
+}
+
+// Settle a pending purchase; settling it again the same way is a no-op
+fn settle(
+    purchase_id: &str,
+    status: VasPurchaseStatus,
+    now: u64,
+) -> std::result::Result<VasPurchase, WalletError> {
+    let operator = require_operator()?;
+    let mut purchase = get(purchase_id).ok_or(WalletError::NotFound)?;
+    if purchase.status == status {
+        return Ok(purchase);
+    }
+    if purchase.status != VasPurchaseStatus::Pending {
+        return Err(WalletError::HoldConflict);
+    }
+
+    let hold_status = match status {
+        VasPurchaseStatus::Completed { .. } => HoldStatus::Released { to: operator },
+        _ => HoldStatus::Returned,
+    };
+    holds::settle(&hold_id(purchase_id), HoldOrigin::Vas, hold_status, "vas_settlement", now)?;
+
+    purchase.status = status;
+    purchase.updated_at = now;
+    store(&purchase);
+    Ok(purchase)
+}
+
+pub fn complete(
+    purchase_id: &str,
+    provider_reference: String,
+    now: u64,
+) -> std::result::Result<VasPurchase, WalletError> {
+    settle(purchase_id, VasPurchaseStatus::Completed { provider_reference }, now)
+}
+
+pub fn fail(purchase_id: &str, reason: String, now: u64) -> std::result::Result<VasPurchase, WalletError> {
+    settle(purchase_id, VasPurchaseStatus::Failed { reason }, now)
+}
+
+pub fn for_user(user_id: Principal) -> Vec<VasPurchase> {
+    let mut purchases: Vec<VasPurchase> = PURCHASES.with(|p| {
+        p.borrow()
+            .values()
+            .filter(|p| p.params.user_id == user_id)
+            .cloned()
+            .collect()
+    });
+    purchases.sort_by_key(|p| std::cmp::Reverse(p.created_at));
+    purchases
+}
+
+pub fn count() -> u64 {
+    PURCHASES.with(|p| p.borrow().len() as u64)
+}
+
+pub fn snapshot() -> (VasPurchases, VasApprovals) {
+    (
+        PURCHASES.with(|p| p.borrow().clone()),
+        APPROVALS.with(|a| a.borrow().clone()),
+    )
+}
+
+pub fn restore(purchases: VasPurchases, approvals: VasApprovals) {
+    PURCHASES.with(|p| *p.borrow_mut() = purchases);
+    APPROVALS.with(|a| *a.borrow_mut() = approvals);
+}
@@ -0,0 +1,698 @@
+// BTC withdrawals to external addresses.
+//
+// The transaction is built first, then the amount plus its fee is moved from
+// the available BTC balance to `pending_withdrawals` before anything is
+// signed. Once the transaction has been submitted the pending amount leaves
+// the wallet; if signing or submission fails it goes back to the balance.
+//
+// Withdrawals that `security::check` delays are only scheduled: the amount
+// is moved to `pending_withdrawals` right away, and the heartbeat sends them
+// once the delay is over unless the owner cancelled them first.
+//
+// A submitted withdrawal is confirmed once its transaction is
+// `CONFIRMATIONS_REQUIRED` blocks deep, or marked dropped if it is never
+// mined within `DROP_TIMEOUT_NANOS`. One left `Pending` (e.g. the call
+// trapped after the reservation) is retried after `PENDING_TIMEOUT_NANOS`:
+// rebroadcast if it was signed, failed and released otherwise.
+use crate::btc::{self, SpendInput};
+use crate::*;
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::Hash;
+use bitcoin::{Address, Transaction as BitcoinTransaction};
+use std::collections::BTreeSet;
+
+const CONFIRMATIONS_REQUIRED: u32 = 6;
+const PENDING_TIMEOUT_NANOS: u64 = 30 * 60 * 1_000_000_000;
+// A submitted transaction not mined within this long is given up on
+const DROP_TIMEOUT_NANOS: u64 = 3 * 24 * 60 * 60 * 1_000_000_000;
+// How often the heartbeat looks at submitted and stalled withdrawals
+const CHECK_INTERVAL_NANOS: u64 = 10 * 60 * 1_000_000_000;
+
+// Outpoint (raw txid, vout) -> withdrawal spending it
+pub type ReservedOutpoints = HashMap<(Vec<u8>, u32), String>;
+
+thread_local! {
+    static WITHDRAWALS: RefCell<HashMap<String, Withdrawal>> = RefCell::new(HashMap::new());
+    // Outpoints spent by withdrawals, so concurrent withdrawals never pick the
+    // same UTXO while the spend is unconfirmed
+    static RESERVED_OUTPOINTS: RefCell<ReservedOutpoints> = RefCell::new(HashMap::new());
+    // Signed transactions of pending withdrawals, kept until they settle so a
+    // stalled withdrawal can be rebroadcast
+    static SIGNED: RefCell<HashMap<String, Vec<u8>>> = RefCell::new(HashMap::new());
+    // (execute_at, withdrawal_id) of scheduled withdrawals, so the heartbeat
+    // only looks at the due ones; rebuilt on restore
+    static SCHEDULED: RefCell<BTreeSet<(u64, String)>> = RefCell::new(BTreeSet::new());
+    static LAST_CHECK: RefCell<u64> = RefCell::new(0);
+}
+
+fn outpoint_key(input: &SpendInput) -> (Vec<u8>, u32) {
+    (input.outpoint.txid.to_byte_array().to_vec(), input.outpoint.vout)
+}
+
+fn record_transaction(withdrawal: &Withdrawal) {
+    TRANSACTIONS.with(|txs| {
+        txs.borrow_mut().entry(withdrawal.user_id).or_default().push(Transaction {
+            tx_id: withdrawal.withdrawal_id.clone(),
+            user_id: withdrawal.user_id,
+            tx_type: "withdrawal".to_string(),
+            amount: withdrawal.amount,
+            currency: Asset::BTC,
+            status: "pending".to_string(),
+            created_at: withdrawal.created_at,
+            confirmed_at: None,
+            source: None,
+            memo: None,
+        })
+    });
+}
+
+fn store(withdrawal: &Withdrawal) {
+    WITHDRAWALS.with(|w| {
+        w.borrow_mut()
+            .insert(withdrawal.withdrawal_id.clone(), withdrawal.clone())
+    });
+}
+
+// Move the amount of a scheduled withdrawal between available and pending
+fn shift_scheduled_amount(withdrawal: &Withdrawal, to_pending: bool, now: u64) -> std::result::Result<(), WalletError> {
+    BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let balance = balances
+            .get_mut(&withdrawal.user_id)
+            .ok_or(WalletError::InsufficientBalance)?;
+        let btc = balance.asset_mut(Asset::BTC);
+        if to_pending {
+            btc.reserve_withdrawal(withdrawal.amount)?;
+        } else {
+            btc.unreserve_withdrawal(withdrawal.amount)?;
+        }
+        balance.last_updated = now;
+        Ok(())
+    })
+}
+
+// Everything `reserve` needs that takes an await to find out
+struct Prepared {
+    destination: Address,
+    change_address: Address,
+    candidates: Vec<SpendInput>,
+    fee_rate_sat_per_vb: u64,
+}
+
+// Select inputs, build the transaction, charge the user and record the
+// pending withdrawal. Must not await, so selection and reservation happen
+// atomically. A scheduled withdrawal keeps its ID and history entry.
+fn reserve(
+    user_id: Principal,
+    params: WithdrawParams,
+    scheduled: Option<Withdrawal>,
+    prepared: Prepared,
+) -> std::result::Result<(Withdrawal, BitcoinTransaction, Vec<SpendInput>), WalletError> {
+    let Prepared {
+        destination,
+        change_address,
+        candidates,
+        fee_rate_sat_per_vb,
+    } = prepared;
+    let candidates = RESERVED_OUTPOINTS.with(|reserved| {
+        let reserved = reserved.borrow();
+        candidates
+            .into_iter()
+            .filter(|input| !reserved.contains_key(&outpoint_key(input)))
+            .collect()
+    });
+    let amount = params.amount;
+    let (inputs, estimated_fee) = btc::select_inputs(candidates, amount, fee_rate_sat_per_vb)?;
+    let (tx, fee) = btc::build_transaction(&inputs, &destination, amount, estimated_fee, &change_address);
+    let total = amount.checked_add(fee).ok_or(WalletError::ArithmeticOverflow)?;
+
+    let now = time();
+    BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let balance = balances.get_mut(&user_id).ok_or(WalletError::InsufficientBalance)?;
+        balance.asset_mut(Asset::BTC).reserve_withdrawal(total)?;
+        balance.last_updated = now;
+        Ok(())
+    })?;
+
+    let is_new = scheduled.is_none();
+    let withdrawal = match scheduled {
+        Some(scheduled) => Withdrawal {
+            fee,
+            status: WithdrawalStatus::Pending,
+            updated_at: now,
+            ..scheduled
+        },
+        None => Withdrawal {
+            withdrawal_id: generate_tx_id(),
+            user_id,
+            destination_address: params.destination_address,
+            amount,
+            fee,
+            bitcoin_txid: None,
+            block_height: None,
+            status: WithdrawalStatus::Pending,
+            created_at: now,
+            updated_at: now,
+        },
+    };
+
+    RESERVED_OUTPOINTS.with(|reserved| {
+        let mut reserved = reserved.borrow_mut();
+        for input in &inputs {
+            reserved.insert(outpoint_key(input), withdrawal.withdrawal_id.clone());
+        }
+    });
+    store(&withdrawal);
+    if is_new {
+        record_transaction(&withdrawal);
+    }
+
+    Ok((withdrawal, tx, inputs))
+}
+
+fn is_pending(withdrawal_id: &str) -> bool {
+    WITHDRAWALS.with(|w| {
+        w.borrow()
+            .get(withdrawal_id)
+            .is_some_and(|w| w.status == WithdrawalStatus::Pending)
+    })
+}
+
+// Settle the reservation: on success the pending amount leaves the wallet,
+// on failure it returns to the balance. Only a pending withdrawal is
+// settled, so a retry and a late original call cannot both settle it.
+fn finish(
+    withdrawal_id: &str,
+    outcome: std::result::Result<String, String>,
+) -> std::result::Result<Withdrawal, WalletError> {
+    let now = time();
+    let mut withdrawal = get(withdrawal_id).ok_or(WalletError::NotFound)?;
+    if withdrawal.status != WithdrawalStatus::Pending {
+        return Err(WalletError::InvalidArgument(
+            "Withdrawal is no longer pending".to_string(),
+        ));
+    }
+    // Cannot overflow: `reserve` checked the same sum
+    let total = withdrawal.amount + withdrawal.fee;
+
+    SIGNED.with(|signed| signed.borrow_mut().remove(withdrawal_id));
+    match &outcome {
+        Ok(txid) => {
+            withdrawal.bitcoin_txid = Some(txid.clone());
+            withdrawal.status = WithdrawalStatus::Submitted;
+            let spent: Vec<_> = RESERVED_OUTPOINTS.with(|reserved| {
+                reserved
+                    .borrow()
+                    .iter()
+                    .filter(|(_, id)| id.as_str() == withdrawal_id)
+                    .map(|(outpoint, _)| outpoint.clone())
+                    .collect()
+            });
+            deposits::mark_spent(&spent);
+        }
+        Err(reason) => {
+            withdrawal.status = WithdrawalStatus::Failed(reason.clone());
+            RESERVED_OUTPOINTS.with(|reserved| {
+                reserved.borrow_mut().retain(|_, id| id != withdrawal_id)
+            });
+        }
+    }
+    withdrawal.updated_at = now;
+
+    BALANCES.with(|balances| {
+        if let Some(balance) = balances.borrow_mut().get_mut(&withdrawal.user_id) {
+            let btc = balance.asset_mut(Asset::BTC);
+            let settled = if outcome.is_ok() {
+                btc.complete_withdrawal(total)
+            } else {
+                btc.unreserve_withdrawal(total)
+            };
+            // Only possible if the balance was already inconsistent, which
+            // `check_invariants` reports
+            if let Err(err) = settled {
+                ic_cdk::println!("Settling withdrawal {} failed: {:?}", withdrawal_id, err);
+            }
+            balance.last_updated = now;
+        }
+    });
+    if outcome.is_ok() {
+        blocks::append(
+            blocks::Operation::Burn {
+                from: withdrawal.user_id,
+                asset: Asset::BTC,
+                amount: total,
+                withdrawal_id: withdrawal.withdrawal_id.clone(),
+            },
+            now,
+        );
+    }
+    let status = if outcome.is_ok() { "submitted" } else { "failed" };
+    set_transaction_status(withdrawal.user_id, withdrawal_id, status, now);
+    store(&withdrawal);
+
+    Ok(withdrawal)
+}
+
+async fn sign_and_submit(
+    withdrawal_id: &str,
+    mut tx: BitcoinTransaction,
+    inputs: &[SpendInput],
+) -> std::result::Result<String, WalletError> {
+    btc::sign_transaction(&mut tx, inputs).await?;
+    // A retry may have failed and released the withdrawal while signing
+    if !is_pending(withdrawal_id) {
+        return Err(WalletError::InvalidArgument(
+            "Withdrawal is no longer pending".to_string(),
+        ));
+    }
+    SIGNED.with(|signed| signed.borrow_mut().insert(withdrawal_id.to_string(), serialize(&tx)));
+    btc::broadcast(&tx).await?;
+    Ok(tx.compute_txid().to_string())
+}
+
+async fn prepare(destination: Address) -> std::result::Result<Prepared, WalletError> {
+    let custody_key = btc::custody_key().await?;
+    let custody_address = btc::p2wpkh_address(&custody_key);
+    let fee_rate = btc::fee_rate_sat_per_vb().await;
+    let utxos = btc::get_utxos(&custody_address).await?;
+
+    // Outpoints that are neither unspent custody UTXOs nor in the deposit
+    // pool have been spent and confirmed
+    RESERVED_OUTPOINTS.with(|reserved| {
+        let unspent: HashSet<(Vec<u8>, u32)> = utxos
+            .iter()
+            .map(|u| (u.outpoint.txid.clone(), u.outpoint.vout))
+            .collect();
+        let mut reserved = reserved.borrow_mut();
+        reserved.retain(|outpoint, id| {
+            unspent.contains(outpoint)
+                || deposits::is_pooled(outpoint)
+                || WITHDRAWALS.with(|w| {
+                    w.borrow()
+                        .get(id)
+                        .is_some_and(|w| w.status == WithdrawalStatus::Pending)
+                })
+        });
+    });
+
+    let mut candidates = utxos
+        .iter()
+        .map(|utxo| {
+            Ok(SpendInput {
+                outpoint: btc::outpoint(utxo)?,
+                value: utxo.value,
+                derivation_path: btc::custody_derivation_path(),
+                public_key: custody_key,
+            })
+        })
+        .collect::<std::result::Result<Vec<_>, WalletError>>()?;
+    candidates.extend(deposits::spendable_inputs());
+
+    Ok(Prepared {
+        destination,
+        change_address: custody_address,
+        candidates,
+        fee_rate_sat_per_vb: fee_rate,
+    })
+}
+
+async fn submit(
+    withdrawal: Withdrawal,
+    tx: BitcoinTransaction,
+    inputs: Vec<SpendInput>,
+) -> std::result::Result<Withdrawal, WalletError> {
+    match sign_and_submit(&withdrawal.withdrawal_id, tx, &inputs).await {
+        Ok(submitted) => finish(&withdrawal.withdrawal_id, Ok(submitted)),
+        Err(err) => {
+            let _ = finish(&withdrawal.withdrawal_id, Err(format!("{:?}", err)));
+            Err(err)
+        }
+    }
+}
+
+fn schedule(
+    user_id: Principal,
+    params: WithdrawParams,
+    execute_at: u64,
+    now: u64,
+) -> std::result::Result<Withdrawal, WalletError> {
+    let withdrawal = Withdrawal {
+        withdrawal_id: generate_tx_id(),
+        user_id,
+        destination_address: params.destination_address,
+        amount: params.amount,
+        fee: 0,
+        bitcoin_txid: None,
+        block_height: None,
+        status: WithdrawalStatus::Scheduled { execute_at },
+        created_at: now,
+        updated_at: now,
+    };
+    shift_scheduled_amount(&withdrawal, true, now)?;
+    store(&withdrawal);
+    SCHEDULED.with(|s| s.borrow_mut().insert((execute_at, withdrawal.withdrawal_id.clone())));
+    record_transaction(&withdrawal);
+    Ok(withdrawal)
+}
+
+pub async fn withdraw(
+    user_id: Principal,
+    params: WithdrawParams,
+) -> std::result::Result<Withdrawal, WalletError> {
+    let destination = btc::parse_address(&params.destination_address)?;
+    if params.amount <= destination.script_pubkey().minimal_non_dust().to_sat() {
+        return Err(WalletError::InvalidAmount);
+    }
+    let available = BALANCES.with(|b| {
+        b.borrow()
+            .get(&user_id)
+            .map_or(0, |b| b.asset(Asset::BTC).available)
+    });
+    if available < params.amount {
+        return Err(WalletError::InsufficientBalance);
+    }
+
+    let kyc_level = security::kyc_level(user_id).await;
+    let now = time();
+    if let Some(execute_at) = security::check(user_id, &params, kyc_level, now)? {
+        return schedule(user_id, params, execute_at, now);
+    }
+
+    let prepared = prepare(destination).await?;
+
+    // Checked again after the awaits, together with the reservation
+    let now = time();
+    if let Some(execute_at) = security::check(user_id, &params, kyc_level, now)? {
+        return schedule(user_id, params, execute_at, now);
+    }
+    let (withdrawal, tx, inputs) = reserve(user_id, params, None, prepared)?;
+    submit(withdrawal, tx, inputs).await
+}
+
+// Cancel a scheduled withdrawal before it is sent
+pub fn cancel(user_id: Principal, withdrawal_id: &str) -> std::result::Result<Withdrawal, WalletError> {
+    let mut withdrawal = get(withdrawal_id)
+        .filter(|w| w.user_id == user_id)
+        .ok_or(WalletError::NotFound)?;
+    let WithdrawalStatus::Scheduled { execute_at } = withdrawal.status else {
+        return Err(WalletError::InvalidArgument(
+            "Only scheduled withdrawals can be cancelled".to_string(),
+        ));
+    };
+
+    let now = time();
+    shift_scheduled_amount(&withdrawal, false, now)?;
+    SCHEDULED.with(|s| s.borrow_mut().remove(&(execute_at, withdrawal_id.to_string())));
+    withdrawal.status = WithdrawalStatus::Cancelled;
+    withdrawal.updated_at = now;
+    store(&withdrawal);
+    set_transaction_status(user_id, withdrawal_id, "cancelled", now);
+    Ok(withdrawal)
+}
+
+// Send a scheduled withdrawal whose delay is over. Its status is already
+// `Pending`, so it can no longer be cancelled.
+async fn execute(withdrawal: Withdrawal) {
+    let params = WithdrawParams {
+        destination_address: withdrawal.destination_address.clone(),
+        amount: withdrawal.amount,
+    };
+    let prepared = match btc::parse_address(&params.destination_address) {
+        Ok(destination) => prepare(destination).await,
+        Err(err) => Err(err),
+    };
+
+    // The scheduled amount goes back to the balance, and `reserve` charges it
+    // again together with the fee
+    let now = time();
+    let _ = shift_scheduled_amount(&withdrawal, false, now);
+    let reserved = prepared.and_then(|prepared| {
+        reserve(withdrawal.user_id, params, Some(withdrawal.clone()), prepared)
+    });
+    match reserved {
+        Ok((withdrawal, tx, inputs)) => {
+            let _ = submit(withdrawal, tx, inputs).await;
+        }
+        Err(err) => {
+            let failed = Withdrawal {
+                status: WithdrawalStatus::Failed(format!("{:?}", err)),
+                updated_at: now,
+                ..withdrawal
+            };
+            store(&failed);
+            set_transaction_status(failed.user_id, &failed.withdrawal_id, "failed", now);
+        }
+    }
+}
+
+pub fn execute_due(now: u64) {
+    let due_ids: Vec<String> = SCHEDULED.with(|s| {
+        let mut s = s.borrow_mut();
+        let later = s.split_off(&(now.saturating_add(1), String::new()));
+        std::mem::replace(&mut *s, later)
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect()
+    });
+    let due: Vec<Withdrawal> = WITHDRAWALS.with(|w| {
+        let mut w = w.borrow_mut();
+        let mut due = Vec::new();
+        for id in &due_ids {
+            if let Some(w) = w.get_mut(id) {
+                if matches!(w.status, WithdrawalStatus::Scheduled { .. }) {
+                    w.status = WithdrawalStatus::Pending;
+                    w.updated_at = now;
+                    due.push(w.clone());
+                }
+            }
+        }
+        due
+    });
+    for withdrawal in due {
+        ic_cdk::spawn(execute(withdrawal));
+    }
+}
+
+// Rebroadcast a stalled withdrawal that was signed, fail one that was not.
+// A signed transaction may already be out, so it is never failed here; a
+// broadcast error leaves it pending for the next check.
+async fn retry(withdrawal_id: String) {
+    let signed = SIGNED.with(|signed| signed.borrow().get(&withdrawal_id).cloned());
+    let outcome = match signed {
+        Some(bytes) => {
+            let tx: BitcoinTransaction = match deserialize(&bytes) {
+                Ok(tx) => tx,
+                Err(err) => {
+                    ic_cdk::println!("Stored withdrawal {} is invalid: {}", withdrawal_id, err);
+                    return;
+                }
+            };
+            if let Err(err) = btc::broadcast(&tx).await {
+                ic_cdk::println!("Rebroadcasting withdrawal {} failed: {:?}", withdrawal_id, err);
+                return;
+            }
+            Ok(tx.compute_txid().to_string())
+        }
+        None => Err("Timed out before the transaction was signed".to_string()),
+    };
+    if let Err(err) = finish(&withdrawal_id, outcome) {
+        ic_cdk::println!("Retrying withdrawal {} failed: {:?}", withdrawal_id, err);
+    }
+}
+
+// Where a submitted withdrawal stands given the height it was mined at, if
+// known: confirmed once deep enough, dropped if it was never seen within
+// `DROP_TIMEOUT_NANOS` of submission, still submitted otherwise
+fn progress(withdrawal: &Withdrawal, height: Option<u32>, tip_height: u32, now: u64) -> WithdrawalStatus {
+    match height {
+        Some(height) if tip_height.saturating_sub(height).saturating_add(1) >= CONFIRMATIONS_REQUIRED => {
+            WithdrawalStatus::Confirmed
+        }
+        None if now.saturating_sub(withdrawal.updated_at) >= DROP_TIMEOUT_NANOS => WithdrawalStatus::Dropped,
+        _ => WithdrawalStatus::Submitted,
+    }
+}
+
+// Record the block height the first time the transaction is seen at the
+// destination, so the withdrawal still confirms after the recipient spends
+// the output, then settle it once deep enough or dropped
+async fn confirm(withdrawal: Withdrawal) {
+    let Some(txid) = withdrawal.bitcoin_txid.clone() else {
+        return;
+    };
+    let located = match btc::parse_address(&withdrawal.destination_address) {
+        Ok(destination) => btc::locate_output(&destination, &txid).await,
+        Err(err) => Err(err),
+    };
+    let (found_height, tip_height) = match located {
+        Ok(located) => located,
+        Err(err) => {
+            ic_cdk::println!("Checking withdrawal {} failed: {:?}", withdrawal.withdrawal_id, err);
+            return;
+        }
+    };
+
+    let now = time();
+    let settled = WITHDRAWALS.with(|w| {
+        let mut w = w.borrow_mut();
+        let w = match w.get_mut(&withdrawal.withdrawal_id) {
+            Some(w) if w.status == WithdrawalStatus::Submitted => w,
+            _ => return None,
+        };
+        if w.block_height.is_none() && found_height.is_some() {
+            w.block_height = found_height;
+            w.updated_at = now;
+        }
+        let status = progress(w, w.block_height, tip_height, now);
+        if status == WithdrawalStatus::Submitted {
+            return None;
+        }
+        w.status = status.clone();
+        w.updated_at = now;
+        Some(status)
+    });
+    match settled {
+        Some(WithdrawalStatus::Confirmed) => {
+            set_transaction_status(withdrawal.user_id, &withdrawal.withdrawal_id, "confirmed", now)
+        }
+        Some(_) => set_transaction_status(withdrawal.user_id, &withdrawal.withdrawal_id, "dropped", now),
+        None => {}
+    }
+}
+
+// Called from the heartbeat; looks at submitted and stalled withdrawals at
+// most once per `CHECK_INTERVAL_NANOS`
+pub fn check_due(now: u64) {
+    let due = LAST_CHECK.with(|last| {
+        let mut last = last.borrow_mut();
+        let due = now.saturating_sub(*last) >= CHECK_INTERVAL_NANOS;
+        if due {
+            *last = now;
+        }
+        due
+    });
+    if !due {
+        return;
+    }
+
+    let (stalled, submitted): (Vec<Withdrawal>, Vec<Withdrawal>) = WITHDRAWALS.with(|w| {
+        w.borrow()
+            .values()
+            .filter(|w| match w.status {
+                WithdrawalStatus::Pending => now.saturating_sub(w.updated_at) >= PENDING_TIMEOUT_NANOS,
+                WithdrawalStatus::Submitted => true,
+                _ => false,
+            })
+            .cloned()
+            .partition(|w| w.status == WithdrawalStatus::Pending)
+    });
+    for withdrawal in stalled {
+        ic_cdk::spawn(retry(withdrawal.withdrawal_id));
+    }
+    for withdrawal in submitted {
+        ic_cdk::spawn(confirm(withdrawal));
+    }
+}
+
+// Amount withdrawn or on its way out since `since`, for the rolling limit
+pub fn withdrawn_since(user_id: Principal, since: u64) -> u64 {
+    WITHDRAWALS.with(|w| {
+        w.borrow()
+            .values()
+            .filter(|w| w.user_id == user_id && w.created_at >= since)
+            .filter(|w| !matches!(w.status, WithdrawalStatus::Failed(_) | WithdrawalStatus::Cancelled))
+            .fold(0u64, |sum, w| sum.saturating_add(w.amount))
+    })
+}
+
+pub fn get(withdrawal_id: &str) -> Option<Withdrawal> {
+    WITHDRAWALS.with(|w| w.borrow().get(withdrawal_id).cloned())
+}
+
+pub fn for_user(user_id: Principal) -> Vec<Withdrawal> {
+    let mut withdrawals: Vec<Withdrawal> = WITHDRAWALS.with(|w| {
+        w.borrow()
+            .values()
+            .filter(|w| w.user_id == user_id)
+            .cloned()
+            .collect()
+    });
+    withdrawals.sort_by_key(|w| std::cmp::Reverse(w.created_at));
+    withdrawals
+}
+
+pub fn count() -> u64 {
+    WITHDRAWALS.with(|w| w.borrow().len() as u64)
+}
+
+pub fn snapshot() -> (HashMap<String, Withdrawal>, ReservedOutpoints, HashMap<String, Vec<u8>>) {
+    (
+        WITHDRAWALS.with(|w| w.borrow().clone()),
+        RESERVED_OUTPOINTS.with(|r| r.borrow().clone()),
+        SIGNED.with(|s| s.borrow().clone()),
+    )
+}
+
+pub fn restore(
+    withdrawals: HashMap<String, Withdrawal>,
+    reserved_outpoints: ReservedOutpoints,
+    signed: HashMap<String, Vec<u8>>,
+) {
+    let scheduled = withdrawals
+        .values()
+        .filter_map(|w| match w.status {
+            WithdrawalStatus::Scheduled { execute_at } => Some((execute_at, w.withdrawal_id.clone())),
+            _ => None,
+        })
+        .collect();
+    SCHEDULED.with(|s| *s.borrow_mut() = scheduled);
+    WITHDRAWALS.with(|w| *w.borrow_mut() = withdrawals);
+    RESERVED_OUTPOINTS.with(|r| *r.borrow_mut() = reserved_outpoints);
+    SIGNED.with(|s| *s.borrow_mut() = signed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUBMITTED_AT: u64 = 1_000;
+
+    fn submitted() -> Withdrawal {
+        Withdrawal {
+            withdrawal_id: "w1".to_string(),
+            user_id: Principal::anonymous(),
+            destination_address: String::new(),
+            amount: 10_000,
+            fee: 500,
+            bitcoin_txid: Some("txid".to_string()),
+            block_height: None,
+            status: WithdrawalStatus::Submitted,
+            created_at: SUBMITTED_AT,
+            updated_at: SUBMITTED_AT,
+        }
+    }
+
+    #[test]
+    fn a_mined_withdrawal_confirms_once_deep_enough() {
+        let withdrawal = submitted();
+        let shallow = 100 + CONFIRMATIONS_REQUIRED - 2;
+        assert_eq!(progress(&withdrawal, Some(100), shallow, SUBMITTED_AT), WithdrawalStatus::Submitted);
+        assert_eq!(progress(&withdrawal, Some(100), shallow + 1, SUBMITTED_AT), WithdrawalStatus::Confirmed);
+    }
+
+    #[test]
+    fn a_recorded_height_outlives_the_timeout() {
+        let withdrawal = submitted();
+        let late = SUBMITTED_AT + DROP_TIMEOUT_NANOS;
+        assert_eq!(progress(&withdrawal, Some(100), 100, late), WithdrawalStatus::Submitted);
+    }
+
+    #[test]
+    fn an_unseen_withdrawal_is_dropped_after_the_timeout() {
+        let withdrawal = submitted();
+        let late = SUBMITTED_AT + DROP_TIMEOUT_NANOS;
+        assert_eq!(progress(&withdrawal, None, 100, late - 1), WithdrawalStatus::Submitted);
+        assert_eq!(progress(&withdrawal, None, 100, late), WithdrawalStatus::Dropped);
+    }
+}
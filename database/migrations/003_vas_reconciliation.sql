@@ -0,0 +1,14 @@
+-- Migration: Track wallet settlement of VAS purchases for the reconciliation job
+-- Created: 2026-10-19
+
+-- Whether the wallet hold has been released or returned (or was never placed)
+ALTER TABLE vas_transactions ADD COLUMN IF NOT EXISTS wallet_settled BOOLEAN NOT NULL DEFAULT FALSE;
+-- Reason passed to the wallet when the purchase failed; retries must repeat it
+ALTER TABLE vas_transactions ADD COLUMN IF NOT EXISTS failure_reason TEXT;
+ALTER TABLE vas_transactions ADD COLUMN IF NOT EXISTS last_attempt_at TIMESTAMP WITH TIME ZONE;
+
+-- Purchases finished before this migration are taken as settled
+UPDATE vas_transactions SET wallet_settled = TRUE WHERE status <> 'pending';
+
+CREATE INDEX IF NOT EXISTS idx_vas_transactions_unsettled
+    ON vas_transactions(created_at) WHERE NOT wallet_settled;
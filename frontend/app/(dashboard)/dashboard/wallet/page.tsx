@@ -4,6 +4,7 @@ import { useState } from 'react';
 import { useAuth } from '@/lib/auth-provider';
 import { useIcp } from '@/lib/context/IcpContext';
 import { useWallet } from '@/lib/hooks/useWallet';
+import { assetFromSymbol, assetSymbol, availableBalance, heldBalance } from '@/lib/canisters/wallet';
 import DashboardLayout from '@/components/dashboard/DashboardLayout';
 import { Principal } from '@dfinity/principal';
 import { QRCodeSVG } from 'qrcode.react';
@@ -37,6 +38,8 @@ export default function WalletPage() {
     const [successMsg, setSuccessMsg] = useState<string | null>(null);
     const [depositAddress, setDepositAddress] = useState<string | null>(null);
     const [copied, setCopied] = useState(false);
+    // Reused when a failed send is retried so the wallet can't apply it twice
+    const [transferKey, setTransferKey] = useState(() => crypto.randomUUID());
 
     const handleSend = async () => {
         if (!recipient || !amount) return;
@@ -49,8 +52,11 @@ export default function WalletPage() {
             await transfer({
                 to: Principal.fromText(recipient),
                 amount: amountSatoshis,
-                currency
+                currency: assetFromSymbol(currency),
+                idempotency_key: [transferKey],
+                memo: []
             });
+            setTransferKey(crypto.randomUUID());
             setSuccessMsg('Transfer successful!');
             setRecipient('');
             setAmount('');
@@ -108,8 +114,21 @@ export default function WalletPage() {
         );
     }
 
-    const btcBalance = balance ? bigIntToNumber(balance.btc_balance) / 100000000 : 0;
-    const ckbtcBalance = balance ? bigIntToNumber(balance.ckbtc_balance) / 100000000 : 0;
+    const btcBalance = bigIntToNumber(availableBalance(balance, 'BTC')) / 100000000;
+    const ckbtcBalance = bigIntToNumber(availableBalance(balance, 'ckBTC')) / 100000000;
+    const btcHeld = bigIntToNumber(heldBalance(balance, 'BTC')) / 100000000;
+    const ckbtcHeld = bigIntToNumber(heldBalance(balance, 'ckBTC')) / 100000000;
+    const isIncoming = (txType: string) => ['deposit', 'transfer_in', 'escrow_in', 'cash_in', 'agent_cash_out'].includes(txType);
+    const txLabels: Record<string, string> = {
+        deposit: 'Deposit',
+        transfer_in: 'Received',
+        escrow_in: 'Escrow payout',
+        escrow_out: 'Escrow',
+        cash_in: 'Cash in',
+        cash_out: 'Cash out',
+        agent_cash_in: 'Agent cash-in',
+        agent_cash_out: 'Agent cash-out',
+    };
     const totalBalance = btcBalance + ckbtcBalance;
 
     return (
@@ -397,8 +416,8 @@ export default function WalletPage() {
                 {activeTab === 'assets' ? (
                     <div style={{ display: 'flex', flexDirection: 'column', gap: '1rem' }}>
                         {[
-                            { symbol: 'ckBTC', name: 'Chain Key Bitcoin', balance: ckbtcBalance, icon: '₿' },
-                            { symbol: 'BTC', name: 'Bitcoin', balance: btcBalance, icon: '₿' }
+                            { symbol: 'ckBTC', name: 'Chain Key Bitcoin', balance: ckbtcBalance, held: ckbtcHeld, icon: '₿' },
+                            { symbol: 'BTC', name: 'Bitcoin', balance: btcBalance, held: btcHeld, icon: '₿' }
                         ].map((asset) => (
                             <div key={asset.symbol} style={{
                                 display: 'flex',
@@ -433,6 +452,11 @@ export default function WalletPage() {
                                     <div style={{ color: '#8b92a7', fontSize: '0.875rem' }}>
                                         ≈ ${(asset.balance * 43000).toFixed(2)}
                                     </div>
+                                    {asset.held > 0 && (
+                                        <div style={{ color: '#f59e0b', fontSize: '0.75rem' }}>
+                                            {asset.held.toFixed(8)} held in escrow
+                                        </div>
+                                    )}
                                 </div>
                             </div>
                         ))}
@@ -459,17 +483,17 @@ export default function WalletPage() {
                                             width: '40px',
                                             height: '40px',
                                             borderRadius: '50%',
-                                            backgroundColor: tx.tx_type === 'deposit' || tx.tx_type === 'transfer_in' ? '#10b98120' : '#ef444420',
-                                            color: tx.tx_type === 'deposit' || tx.tx_type === 'transfer_in' ? '#10b981' : '#ef4444',
+                                            backgroundColor: isIncoming(tx.tx_type) ? '#10b98120' : '#ef444420',
+                                            color: isIncoming(tx.tx_type) ? '#10b981' : '#ef4444',
                                             display: 'flex',
                                             alignItems: 'center',
                                             justifyContent: 'center'
                                         }}>
-                                            {tx.tx_type === 'deposit' || tx.tx_type === 'transfer_in' ? <ArrowDownLeft size={20} /> : <ArrowUpRight size={20} />}
+                                            {isIncoming(tx.tx_type) ? <ArrowDownLeft size={20} /> : <ArrowUpRight size={20} />}
                                         </div>
                                         <div>
                                             <div style={{ color: 'white', fontWeight: 600 }}>
-                                                {tx.tx_type === 'deposit' ? 'Deposit' : tx.tx_type === 'transfer_in' ? 'Received' : 'Sent'}
+                                                {txLabels[tx.tx_type] ?? 'Sent'}
                                             </div>
                                             <div style={{ color: '#8b92a7', fontSize: '0.875rem' }}>
                                                 {new Date(bigIntToNumber(tx.created_at) / 1000000).toLocaleString()}
@@ -478,10 +502,10 @@ export default function WalletPage() {
                                     </div>
                                     <div style={{ textAlign: 'right' }}>
                                         <div style={{
-                                            color: tx.tx_type === 'deposit' || tx.tx_type === 'transfer_in' ? '#10b981' : 'white',
+                                            color: isIncoming(tx.tx_type) ? '#10b981' : 'white',
                                             fontWeight: 600
                                         }}>
-                                            {tx.tx_type === 'deposit' || tx.tx_type === 'transfer_in' ? '+' : '-'}{formatBTC(tx.amount)} {tx.currency}
+                                            {isIncoming(tx.tx_type) ? '+' : '-'}{formatBTC(tx.amount)} {assetSymbol(tx.currency)}
                                         </div>
                                         <div style={{ color: '#8b92a7', fontSize: '0.875rem' }}>
                                             {tx.status}
@@ -2,37 +2,123 @@
 
 import { IDL } from '@dfinity/candid';
 
-const WalletBalance = IDL.Record({
-    user_id: IDL.Principal,
-    btc_balance: IDL.Nat64,
-    ckbtc_balance: IDL.Nat64,
+const Asset = IDL.Variant({
+    BTC: IDL.Null,
+    CkBTC: IDL.Null,
+    CkETH: IDL.Null,
+    CkUSDC: IDL.Null,
+    ICP: IDL.Null,
+});
+
+const AssetInfo = IDL.Record({
+    asset: Asset,
+    symbol: IDL.Text,
+    name: IDL.Text,
+    decimals: IDL.Nat8,
+});
+
+const AssetBalance = IDL.Record({
+    available: IDL.Nat64,
     pending_deposits: IDL.Nat64,
     pending_withdrawals: IDL.Nat64,
+    held: IDL.Nat64,
+});
+
+const WalletBalance = IDL.Record({
+    user_id: IDL.Principal,
+    balances: IDL.Vec(IDL.Tuple(Asset, AssetBalance)),
     last_updated: IDL.Nat64,
 });
 
 const DepositAddress = IDL.Record({
     user_id: IDL.Principal,
     address: IDL.Text,
-    currency: IDL.Text,
+    currency: Asset,
     created_at: IDL.Nat64,
 });
 
+const CreditSource = IDL.Variant({
+    Deposit: IDL.Record({ txid: IDL.Text, vout: IDL.Nat32 }),
+    EscrowPayout: IDL.Record({ escrow_id: IDL.Text }),
+    LedgerDeposit: IDL.Record({ asset: Asset, block_index: IDL.Nat64 }),
+});
+
 const Transaction = IDL.Record({
     tx_id: IDL.Text,
     user_id: IDL.Principal,
     tx_type: IDL.Text,
     amount: IDL.Nat64,
-    currency: IDL.Text,
+    currency: Asset,
     status: IDL.Text,
     created_at: IDL.Nat64,
     confirmed_at: IDL.Opt(IDL.Nat64),
+    source: IDL.Opt(CreditSource),
+    memo: IDL.Opt(IDL.Text),
+});
+
+const TransactionQuery = IDL.Record({
+    asset: IDL.Opt(Asset),
+    tx_type: IDL.Opt(IDL.Text),
+    status: IDL.Opt(IDL.Text),
+    from: IDL.Opt(IDL.Nat64),
+    to: IDL.Opt(IDL.Nat64),
+    cursor: IDL.Opt(IDL.Nat64),
+    limit: IDL.Opt(IDL.Nat32),
+});
+
+const TransactionPage = IDL.Record({
+    transactions: IDL.Vec(Transaction),
+    next_cursor: IDL.Opt(IDL.Nat64),
+    total: IDL.Nat64,
 });
 
 const TransferParams = IDL.Record({
     to: IDL.Principal,
     amount: IDL.Nat64,
-    currency: IDL.Text,
+    currency: Asset,
+    idempotency_key: IDL.Opt(IDL.Text),
+    memo: IDL.Opt(IDL.Text),
+});
+
+const CreatePaymentRequestParams = IDL.Record({
+    payer: IDL.Principal,
+    amount: IDL.Nat64,
+    currency: Asset,
+    memo: IDL.Opt(IDL.Text),
+    expires_in_seconds: IDL.Opt(IDL.Nat64),
+});
+
+const PaymentRequestStatus = IDL.Variant({
+    Pending: IDL.Null,
+    Paid: IDL.Record({ tx_id: IDL.Text }),
+    Declined: IDL.Null,
+    Cancelled: IDL.Null,
+    Expired: IDL.Null,
+});
+
+const PaymentRequest = IDL.Record({
+    id: IDL.Text,
+    requester: IDL.Principal,
+    payer: IDL.Principal,
+    amount: IDL.Nat64,
+    currency: Asset,
+    memo: IDL.Opt(IDL.Text),
+    status: PaymentRequestStatus,
+    created_at: IDL.Nat64,
+    expires_at: IDL.Nat64,
+    updated_at: IDL.Nat64,
+});
+
+const VasApproval = IDL.Record({
+    purchase_id: IDL.Text,
+    amount: IDL.Nat64,
+    currency: Asset,
+    expires_at: IDL.Nat64,
+});
+
+const PendingPaymentRequests = IDL.Record({
+    to_pay: IDL.Vec(PaymentRequest),
+    requested: IDL.Vec(PaymentRequest),
 });
 
 const WalletError = IDL.Variant({
@@ -42,6 +128,20 @@ const WalletError = IDL.Variant({
     TransferFailed: IDL.Text,
     NotFound: IDL.Null,
     Unauthorized: IDL.Null,
+    DuplicateCredit: IDL.Null,
+    InvalidConfig: IDL.Null,
+    BitcoinError: IDL.Text,
+    IdempotencyKeyReused: IDL.Null,
+    HoldConflict: IDL.Null,
+    InvalidArgument: IDL.Text,
+    AddressNotAllowed: IDL.Null,
+    AddressCoolingOff: IDL.Record({ usable_from: IDL.Nat64 }),
+    WithdrawalLimitExceeded: IDL.Record({ remaining: IDL.Nat64 }),
+    ArithmeticOverflow: IDL.Null,
+    NotAnAgent: IDL.Null,
+    InsufficientFloat: IDL.Null,
+    InvalidConfirmationCode: IDL.Record({ attempts_left: IDL.Nat8 }),
+    RateLimited: IDL.Record({ retry_at: IDL.Nat64 }),
 });
 
 const Result = (T: any) => IDL.Variant({
@@ -52,10 +152,17 @@ const Result = (T: any) => IDL.Variant({
 export const walletIdlFactory = ({ IDL }: { IDL: typeof import('@dfinity/candid').IDL }) => {
     return IDL.Service({
         get_my_balance: IDL.Func([], [IDL.Opt(WalletBalance)], ['query']),
-        get_my_transactions: IDL.Func([], [IDL.Vec(Transaction)], ['query']),
-        get_deposit_address: IDL.Func([IDL.Text], [Result(DepositAddress)], []),
+        get_my_transactions: IDL.Func([TransactionQuery], [TransactionPage], ['query']),
+        get_supported_assets: IDL.Func([], [IDL.Vec(AssetInfo)], ['query']),
+        get_deposit_address: IDL.Func([Asset], [Result(DepositAddress)], []),
         transfer: IDL.Func([TransferParams], [Result(WalletBalance)], []),
-        update_balance: IDL.Func([IDL.Principal, IDL.Nat64, IDL.Text], [Result(WalletBalance)], []),
+        update_balance: IDL.Func([IDL.Principal, IDL.Nat64, Asset, CreditSource], [Result(WalletBalance)], []),
+        create_payment_request: IDL.Func([CreatePaymentRequestParams], [Result(PaymentRequest)], []),
+        pay_payment_request: IDL.Func([IDL.Text], [Result(PaymentRequest)], []),
+        decline_payment_request: IDL.Func([IDL.Text], [Result(PaymentRequest)], []),
+        cancel_payment_request: IDL.Func([IDL.Text], [Result(PaymentRequest)], []),
+        get_my_pending_payment_requests: IDL.Func([], [PendingPaymentRequests], ['query']),
+        approve_vas_purchase: IDL.Func([IDL.Text, IDL.Nat64, Asset], [Result(VasApproval)], []),
     });
 };
 
@@ -5,37 +5,164 @@ import { Principal } from '@dfinity/principal';
 import { createActor, CANISTER_IDS } from './agent';
 import { walletIdlFactory } from './wallet.idl';
 
-export interface WalletBalance {
-    user_id: Principal;
-    btc_balance: bigint;
-    ckbtc_balance: bigint;
+export type Asset =
+    | { BTC: null }
+    | { CkBTC: null }
+    | { CkETH: null }
+    | { CkUSDC: null }
+    | { ICP: null };
+
+export interface AssetInfo {
+    asset: Asset;
+    symbol: string;
+    name: string;
+    decimals: number;
+}
+
+// Amounts are in the asset's smallest unit (see AssetInfo.decimals)
+export interface AssetBalance {
+    available: bigint;
     pending_deposits: bigint;
     pending_withdrawals: bigint;
+    held: bigint; // reserved for open escrows
+}
+
+export interface WalletBalance {
+    user_id: Principal;
+    balances: Array<[Asset, AssetBalance]>;
     last_updated: bigint;
 }
 
+const ASSET_SYMBOLS: Record<string, Asset> = {
+    BTC: { BTC: null },
+    ckBTC: { CkBTC: null },
+    ckETH: { CkETH: null },
+    ckUSDC: { CkUSDC: null },
+    ICP: { ICP: null },
+};
+
+export function assetFromSymbol(symbol: string): Asset {
+    const asset = ASSET_SYMBOLS[symbol];
+    if (!asset) throw new Error(`Unsupported asset: ${symbol}`);
+    return asset;
+}
+
+export function assetSymbol(asset: Asset): string {
+    const key = Object.keys(asset)[0];
+    return Object.keys(ASSET_SYMBOLS).find((symbol) => symbol.toLowerCase() === key.toLowerCase()) ?? key;
+}
+
+export function availableBalance(balance: WalletBalance | null, symbol: string): bigint {
+    const entry = balance?.balances.find(([asset]) => assetSymbol(asset) === symbol);
+    return entry ? entry[1].available : BigInt(0);
+}
+
+export function heldBalance(balance: WalletBalance | null, symbol: string): bigint {
+    const entry = balance?.balances.find(([asset]) => assetSymbol(asset) === symbol);
+    return entry ? entry[1].held : BigInt(0);
+}
+
 export interface DepositAddress {
     user_id: Principal;
     address: string;
-    currency: string;
+    currency: Asset;
     created_at: bigint;
 }
 
+export type CreditSource =
+    | { Deposit: { txid: string; vout: number } }
+    | { EscrowPayout: { escrow_id: string } }
+    | { LedgerDeposit: { asset: Asset; block_index: bigint } };
+
 export interface Transaction {
     tx_id: string;
     user_id: Principal;
     tx_type: string;
     amount: bigint;
-    currency: string;
+    currency: Asset;
     status: string;
     created_at: bigint;
     confirmed_at: bigint | null;
+    source: [CreditSource] | [];
+    memo: [string] | [];
+}
+
+// Optional filters; pages are newest first, pass next_cursor back as cursor
+export interface TransactionQuery {
+    asset: [Asset] | [];
+    tx_type: [string] | [];
+    status: [string] | [];
+    from: [bigint] | [];
+    to: [bigint] | [];
+    cursor: [bigint] | [];
+    limit: [number] | [];
+}
+
+export interface TransactionPage {
+    transactions: Transaction[];
+    next_cursor: [bigint] | [];
+    total: bigint;
 }
 
+export const EMPTY_TRANSACTION_QUERY: TransactionQuery = {
+    asset: [],
+    tx_type: [],
+    status: [],
+    from: [],
+    to: [],
+    cursor: [],
+    limit: [],
+};
+
+// Retrying with the same idempotency_key returns the original result
 export interface TransferParams {
     to: Principal;
     amount: bigint;
-    currency: string;
+    currency: Asset;
+    idempotency_key: [string] | [];
+    memo: [string] | [];
+}
+
+// Paying a request transfers the amount to the requester
+export interface CreatePaymentRequestParams {
+    payer: Principal;
+    amount: bigint;
+    currency: Asset;
+    memo: [string] | [];
+    expires_in_seconds: [bigint] | [];
+}
+
+export type PaymentRequestStatus =
+    | { Pending: null }
+    | { Paid: { tx_id: string } }
+    | { Declined: null }
+    | { Cancelled: null }
+    | { Expired: null };
+
+export interface PaymentRequest {
+    id: string;
+    requester: Principal;
+    payer: Principal;
+    amount: bigint;
+    currency: Asset;
+    memo: [string] | [];
+    status: PaymentRequestStatus;
+    created_at: bigint;
+    expires_at: bigint;
+    updated_at: bigint;
+}
+
+// Lets the API gateway hold up to `amount` for the quoted VAS purchase
+export interface VasApproval {
+    purchase_id: string;
+    amount: bigint;
+    currency: Asset;
+    expires_at: bigint;
+}
+
+export interface PendingPaymentRequests {
+    to_pay: PaymentRequest[];
+    requested: PaymentRequest[];
 }
 
 export type WalletError =
@@ -44,16 +171,37 @@ export type WalletError =
     | { InvalidAmount: null }
     | { TransferFailed: string }
     | { NotFound: null }
-    | { Unauthorized: null };
+    | { Unauthorized: null }
+    | { DuplicateCredit: null }
+    | { InvalidConfig: null }
+    | { BitcoinError: string }
+    | { IdempotencyKeyReused: null }
+    | { HoldConflict: null }
+    | { InvalidArgument: string }
+    | { AddressNotAllowed: null }
+    | { AddressCoolingOff: { usable_from: bigint } }
+    | { WithdrawalLimitExceeded: { remaining: bigint } }
+    | { ArithmeticOverflow: null }
+    | { NotAnAgent: null }
+    | { InsufficientFloat: null }
+    | { InvalidConfirmationCode: { attempts_left: number } }
+    | { RateLimited: { retry_at: bigint } };
 
 export type Result<T> = { Ok: T } | { Err: WalletError };
 
 export interface WalletActor {
     get_my_balance: () => Promise<[WalletBalance] | []>;
-    get_my_transactions: () => Promise<Transaction[]>;
-    get_deposit_address: (currency: string) => Promise<Result<DepositAddress>>;
+    get_my_transactions: (query: TransactionQuery) => Promise<TransactionPage>;
+    get_supported_assets: () => Promise<AssetInfo[]>;
+    get_deposit_address: (currency: Asset) => Promise<Result<DepositAddress>>;
     transfer: (params: TransferParams) => Promise<Result<WalletBalance>>;
-    update_balance: (userId: Principal, amount: bigint, currency: string) => Promise<Result<WalletBalance>>;
+    update_balance: (userId: Principal, amount: bigint, currency: Asset, source: CreditSource) => Promise<Result<WalletBalance>>;
+    create_payment_request: (params: CreatePaymentRequestParams) => Promise<Result<PaymentRequest>>;
+    pay_payment_request: (id: string) => Promise<Result<PaymentRequest>>;
+    decline_payment_request: (id: string) => Promise<Result<PaymentRequest>>;
+    cancel_payment_request: (id: string) => Promise<Result<PaymentRequest>>;
+    get_my_pending_payment_requests: () => Promise<PendingPaymentRequests>;
+    approve_vas_purchase: (purchaseId: string, amount: bigint, currency: Asset) => Promise<Result<VasApproval>>;
 }
 
 class WalletCanisterClient {
@@ -75,12 +223,17 @@ class WalletCanisterClient {
         return result[0] ?? null;
     }
 
-    async getMyTransactions(): Promise<Transaction[]> {
+    async getMyTransactions(query: Partial<TransactionQuery> = {}): Promise<TransactionPage> {
+        const actor = await this.getActor();
+        return await actor.get_my_transactions({ ...EMPTY_TRANSACTION_QUERY, ...query });
+    }
+
+    async getSupportedAssets(): Promise<AssetInfo[]> {
         const actor = await this.getActor();
-        return await actor.get_my_transactions();
+        return await actor.get_supported_assets();
     }
 
-    async getDepositAddress(currency: string): Promise<DepositAddress> {
+    async getDepositAddress(currency: Asset): Promise<DepositAddress> {
         const actor = await this.getActor();
         const result = await actor.get_deposit_address(currency);
 
@@ -102,10 +255,71 @@ class WalletCanisterClient {
         }
     }
 
-    // For testing/demo purposes only - in production this would be restricted
-    async updateBalance(userId: Principal, amount: bigint, currency: string): Promise<WalletBalance> {
+    // Only succeeds for identities on the wallet's minter allow-list
+    async updateBalance(userId: Principal, amount: bigint, currency: Asset, source: CreditSource): Promise<WalletBalance> {
+        const actor = await this.getActor();
+        const result = await actor.update_balance(userId, amount, currency, source);
+
+        if ('Ok' in result) {
+            return result.Ok;
+        } else {
+            throw new Error(this.formatError(result.Err));
+        }
+    }
+
+    async createPaymentRequest(params: CreatePaymentRequestParams): Promise<PaymentRequest> {
+        const actor = await this.getActor();
+        const result = await actor.create_payment_request(params);
+
+        if ('Ok' in result) {
+            return result.Ok;
+        } else {
+            throw new Error(this.formatError(result.Err));
+        }
+    }
+
+    async payPaymentRequest(id: string): Promise<PaymentRequest> {
+        const actor = await this.getActor();
+        const result = await actor.pay_payment_request(id);
+
+        if ('Ok' in result) {
+            return result.Ok;
+        } else {
+            throw new Error(this.formatError(result.Err));
+        }
+    }
+
+    async declinePaymentRequest(id: string): Promise<PaymentRequest> {
+        const actor = await this.getActor();
+        const result = await actor.decline_payment_request(id);
+
+        if ('Ok' in result) {
+            return result.Ok;
+        } else {
+            throw new Error(this.formatError(result.Err));
+        }
+    }
+
+    async cancelPaymentRequest(id: string): Promise<PaymentRequest> {
         const actor = await this.getActor();
-        const result = await actor.update_balance(userId, amount, currency);
+        const result = await actor.cancel_payment_request(id);
+
+        if ('Ok' in result) {
+            return result.Ok;
+        } else {
+            throw new Error(this.formatError(result.Err));
+        }
+    }
+
+    async getMyPendingPaymentRequests(): Promise<PendingPaymentRequests> {
+        const actor = await this.getActor();
+        return await actor.get_my_pending_payment_requests();
+    }
+
+    // Approve a VAS quote before asking the gateway to buy it
+    async approveVasPurchase(quoteId: string, amountSatoshis: bigint, currency: Asset): Promise<VasApproval> {
+        const actor = await this.getActor();
+        const result = await actor.approve_vas_purchase(quoteId, amountSatoshis, currency);
 
         if ('Ok' in result) {
             return result.Ok;
@@ -121,6 +335,30 @@ class WalletCanisterClient {
         if ('TransferFailed' in error) return `Transfer failed: ${error.TransferFailed}`;
         if ('NotFound' in error) return 'Not found';
         if ('Unauthorized' in error) return 'Unauthorized';
+        if ('DuplicateCredit' in error) return 'This deposit has already been credited';
+        if ('InvalidConfig' in error) return 'Invalid wallet configuration';
+        if ('BitcoinError' in error) return `Bitcoin error: ${error.BitcoinError}`;
+        if ('HoldConflict' in error) return 'An escrow hold already exists with different terms';
+        if ('IdempotencyKeyReused' in error) return 'This request ID was already used for a different transfer';
+        if ('InvalidArgument' in error) return error.InvalidArgument;
+        if ('ArithmeticOverflow' in error) return 'Amount is too large';
+        if ('NotAnAgent' in error) return 'Not a registered agent';
+        if ('InsufficientFloat' in error) return 'The agent does not have enough cash for this amount';
+        if ('InvalidConfirmationCode' in error) {
+            return `Wrong confirmation code (${error.InvalidConfirmationCode.attempts_left} attempts left)`;
+        }
+        if ('RateLimited' in error) {
+            const retryAt = new Date(Number(error.RateLimited.retry_at / 1_000_000n));
+            return `Too many requests, try again after ${retryAt.toLocaleTimeString()}`;
+        }
+        if ('AddressNotAllowed' in error) return 'This address is not on your withdrawal allow-list';
+        if ('AddressCoolingOff' in error) {
+            const usableFrom = new Date(Number(error.AddressCoolingOff.usable_from / 1_000_000n));
+            return `This address can be used from ${usableFrom.toLocaleString()}`;
+        }
+        if ('WithdrawalLimitExceeded' in error) {
+            return `Withdrawal limit exceeded: ${error.WithdrawalLimitExceeded.remaining} sats remaining`;
+        }
         return 'Unknown error';
     }
 
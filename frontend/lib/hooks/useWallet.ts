@@ -2,7 +2,7 @@
 
 import { useState, useEffect, useCallback } from 'react';
 import { Principal } from '@dfinity/principal';
-import { walletCanister, WalletBalance, Transaction, TransferParams } from '../canisters/wallet';
+import { walletCanister, WalletBalance, Transaction, TransferParams, assetFromSymbol } from '../canisters/wallet';
 import { useIcp } from '../context/IcpContext';
 
 export function useWallet() {
@@ -23,12 +23,12 @@ export function useWallet() {
         setError(null);
 
         try {
-            const [bal, txs] = await Promise.all([
+            const [bal, page] = await Promise.all([
                 walletCanister.getMyBalance(),
-                walletCanister.getMyTransactions()
+                walletCanister.getMyTransactions({ limit: [50] })
             ]);
             setBalance(bal);
-            setTransactions(txs);
+            setTransactions(page.transactions);
         } catch (err) {
             const message = err instanceof Error ? err.message : 'Failed to fetch wallet data';
             setError(message);
@@ -51,7 +51,7 @@ export function useWallet() {
 
     const getDepositAddress = async (currency: string) => {
         try {
-            return await walletCanister.getDepositAddress(currency);
+            return await walletCanister.getDepositAddress(assetFromSymbol(currency));
         } catch (err) {
             throw err;
         }
@@ -68,11 +68,12 @@ export function useWallet() {
         }
     };
 
-    // For demo: faucet function
+    // For demo: faucet function (the identity must be an allow-listed minter)
     const faucet = async (amount: bigint, currency: string) => {
         if (!principal) return;
         try {
-            const newBalance = await walletCanister.updateBalance(principal, amount, currency);
+            const source = { Deposit: { txid: `faucet-${Date.now()}`, vout: 0 } };
+            const newBalance = await walletCanister.updateBalance(principal, amount, assetFromSymbol(currency), source);
             setBalance(newBalance);
             await fetchData();
             return newBalance;
@@ -29,6 +29,64 @@ REPUTATION_ID=$(dfx canister id reputation)
 AI_ID=$(dfx canister id ai_orchestration)
 IDENTITY_ID=$(dfx canister id identity)
 
+# Link the escrow canister to the others (local replica: regtest + dfx test key)
+echo "🔗 Configuring escrow canister..."
+dfx canister call escrow set_config "(record {
+    bitcoin_network = variant { regtest };
+    ecdsa_key_name = \"dfx_test_key\";
+    wallet_canister = opt principal \"$WALLET_ID\";
+    reputation_canister = opt principal \"$REPUTATION_ID\";
+    identity_canister = opt principal \"$IDENTITY_ID\";
+    ai_orchestration_canister = opt principal \"$AI_ID\";
+    fallback_fee_rate_sat_per_vb = 2 : nat64;
+    max_fee_rate_sat_per_vb = 500 : nat64;
+    min_cycles_balance = 1_000_000_000_000 : nat;
+})"
+
+# Reputation changes are accepted from the escrow canister (and controllers)
+# and checked against its escrows
+echo "🔗 Configuring reputation canister..."
+dfx canister call reputation set_config "(record {
+    escrow_canister = opt principal \"$ESCROW_ID\";
+})"
+
+# Wallet network settings for the local replica. The API gateway signs VAS
+# calls as VAS_OPERATOR (defaults to the current dfx identity, so point
+# VAS_OPERATOR_PEM at its identity.pem)
+VAS_OPERATOR=${VAS_OPERATOR:-$(dfx identity get-principal)}
+echo "🔗 Configuring wallet canister..."
+dfx canister call wallet set_config "(record {
+    bitcoin_network = variant { regtest };
+    ecdsa_key_name = \"dfx_test_key\";
+    fallback_fee_rate_sat_per_vb = 2 : nat64;
+    max_fee_rate_sat_per_vb = 500 : nat64;
+    min_deposit_confirmations = 1 : nat32;
+    escrow_canister = opt principal \"$ESCROW_ID\";
+    identity_canister = opt principal \"$IDENTITY_ID\";
+    vas_operator = opt principal \"$VAS_OPERATOR\";
+})"
+
+# Short cooling-off and delay so withdrawals can be tried out locally
+dfx canister call wallet set_withdrawal_policy "(record {
+    require_allow_list = true;
+    address_cooling_off_seconds = 60 : nat64;
+    limit_window_seconds = 86_400 : nat64;
+    limits_by_kyc_level = vec { 1_000_000 : nat64; 5_000_000 : nat64; 50_000_000 : nat64; 200_000_000 : nat64 };
+    delay_threshold = opt (10_000_000 : nat64);
+    delay_seconds = 120 : nat64;
+})"
+
+# The escrow canister credits payouts to the wallet
+dfx canister call wallet add_minter "(principal \"$ESCROW_ID\")"
+
+# The gateway reads users' statements as STATEMENTS_AUDITOR, which must not
+# be the VAS operator (point STATEMENTS_IDENTITY_PEM at its identity.pem)
+if [ -n "$STATEMENTS_AUDITOR" ]; then
+    dfx canister call wallet add_auditor "(principal \"$STATEMENTS_AUDITOR\")"
+else
+    echo "⚠️  STATEMENTS_AUDITOR not set; statements stay disabled"
+fi
+
 echo "✅ Canister deployment complete!"
 echo ""
 echo "Canister IDs:"
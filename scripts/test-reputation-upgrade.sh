@@ -0,0 +1,85 @@
+#!/bin/bash
+
+# Reputation upgrade round-trip test: record a deal, a dispute and a badge
+# against real escrows, upgrade the canister and check that profiles,
+# disputes, the dispute counter and the counted escrows survive.
+# Requires a running local replica set up by scripts/deploy-local.sh, run as
+# a controller (controllers are reputation writers and escrow admins).
+
+set -euo pipefail
+
+echo "📦 Deploying reputation canister..."
+dfx deploy reputation
+
+USER=$(dfx identity get-principal)
+dfx identity new reputation-test-seller --storage-mode plaintext > /dev/null 2>&1 || true
+SELLER=$(dfx identity get-principal --identity reputation-test-seller)
+
+fail() {
+    echo "❌ $1"
+    exit 1
+}
+
+# Create a ckBTC escrow funded from the caller's wallet and put it in dispute
+disputed_escrow() {
+    local escrow_id
+    escrow_id=$(dfx canister call escrow create_escrow "(record {
+        counterparty_id = principal \"$SELLER\";
+        amount_satoshis = 1_000 : nat64;
+        currency = variant { CkBTC };
+        time_lock_unix = null;
+        inspection_period_days = null;
+        milestones = null;
+        metadata = null;
+        multisig = null;
+    })" | grep -o 'escrow_id = "[^"]*"' | cut -d '"' -f 2)
+    [ -n "$escrow_id" ] || fail "Could not create escrow"
+    dfx canister call escrow fund_from_wallet "(\"$escrow_id\")" > /dev/null
+    dfx canister call escrow mark_disputed "(\"$escrow_id\", \"non_delivery\")" > /dev/null
+    echo "$escrow_id"
+}
+
+echo "💰 Funding test escrows..."
+dfx canister call wallet add_minter "(principal \"$USER\")" > /dev/null
+SOURCE="variant { EscrowPayout = record { escrow_id = \"reputation-test-$(date +%s)\" } }"
+dfx canister call wallet update_balance "(principal \"$USER\", 10_000 : nat64, variant { CkBTC }, $SOURCE)" > /dev/null
+
+echo "⭐ Seeding reputation state..."
+ESCROW_ID=$(disputed_escrow)
+dfx canister call reputation record_dispute "(principal \"$USER\", \"$ESCROW_ID\", \"non_delivery\")" > /dev/null
+dfx canister call escrow resolve_dispute "(\"$ESCROW_ID\", \"release\")" > /dev/null
+DISPUTE_ID=$(dfx canister call reputation get_dispute_history "(principal \"$USER\")" | grep -o 'DISP-[0-9]*' | tail -n 1)
+dfx canister call reputation resolve_dispute "(\"$DISPUTE_ID\", \"released to seller\")" > /dev/null
+dfx canister call reputation record_completed_deal "(principal \"$USER\", \"$ESCROW_ID\", 120 : nat64)" > /dev/null
+dfx canister call reputation award_badge "(principal \"$USER\", \"$ESCROW_ID\", \"Verified Seller\")" > /dev/null
+
+REPUTATION_BEFORE=$(dfx canister call reputation get_reputation "(principal \"$USER\")")
+DISPUTES_BEFORE=$(dfx canister call reputation get_dispute_history "(principal \"$USER\")")
+TOP_BEFORE=$(dfx canister call reputation get_top_users '(10 : nat64)')
+CONFIG_BEFORE=$(dfx canister call reputation get_config)
+
+echo "🔄 Upgrading reputation canister..."
+dfx deploy reputation --upgrade-unchanged
+
+REPUTATION_AFTER=$(dfx canister call reputation get_reputation "(principal \"$USER\")")
+DISPUTES_AFTER=$(dfx canister call reputation get_dispute_history "(principal \"$USER\")")
+TOP_AFTER=$(dfx canister call reputation get_top_users '(10 : nat64)')
+CONFIG_AFTER=$(dfx canister call reputation get_config)
+
+echo "$REPUTATION_AFTER" | grep -q "completed_deals" || fail "Reputation profile lost across upgrade"
+[ "$REPUTATION_BEFORE" == "$REPUTATION_AFTER" ] || fail "Reputation profile changed across upgrade"
+[ "$DISPUTES_BEFORE" == "$DISPUTES_AFTER" ] || fail "Dispute history changed across upgrade"
+[ "$TOP_BEFORE" == "$TOP_AFTER" ] || fail "Top users changed across upgrade"
+[ "$CONFIG_BEFORE" == "$CONFIG_AFTER" ] || fail "Configuration changed across upgrade"
+
+# An escrow counted before the upgrade must not count again
+dfx canister call reputation record_completed_deal "(principal \"$USER\", \"$ESCROW_ID\", 120 : nat64)" \
+    | grep -q "AlreadyRecorded" || fail "Escrow counted twice after upgrade"
+
+# The counter must continue, not restart and reuse dispute ids
+SECOND_ESCROW_ID=$(disputed_escrow)
+dfx canister call reputation record_dispute "(principal \"$USER\", \"$SECOND_ESCROW_ID\", \"non_delivery\")" > /dev/null
+DISPUTE_IDS=$(dfx canister call reputation get_dispute_history "(principal \"$USER\")" | grep -o 'DISP-[0-9]*')
+[ "$(echo "$DISPUTE_IDS" | sort | uniq -d)" == "" ] || fail "Dispute ids reused after upgrade"
+
+echo "✅ Reputation state survived the upgrade"
@@ -0,0 +1,55 @@
+#!/bin/bash
+
+# Wallet upgrade round-trip test: credit a balance, upgrade the canister and
+# check that balances, history and the transaction counter survive.
+# Requires a running local replica (dfx start --background).
+
+set -euo pipefail
+
+echo "📦 Deploying wallet canister..."
+dfx deploy wallet
+
+ME=$(dfx identity get-principal)
+
+echo "💰 Seeding wallet state..."
+dfx canister call wallet add_minter "(principal \"$ME\")" > /dev/null
+SOURCE="variant { Deposit = record { txid = \"upgrade-test-$(date +%s)\"; vout = 0 : nat32 } }"
+dfx canister call wallet update_balance "(principal \"$ME\", 12_345 : nat64, variant { BTC }, $SOURCE)" > /dev/null
+dfx canister call wallet get_deposit_address '(variant { CkBTC })' > /dev/null
+
+BALANCE_BEFORE=$(dfx canister call wallet get_balance "(principal \"$ME\")")
+HISTORY_BEFORE=$(dfx canister call wallet get_transactions "(principal \"$ME\", record { limit = opt 100 })")
+ADDRESSES_BEFORE=$(dfx canister call wallet get_my_addresses)
+MINTERS_BEFORE=$(dfx canister call wallet get_minters)
+SUPPLY_BEFORE=$(dfx canister call wallet get_total_supply)
+
+echo "🔄 Upgrading wallet canister..."
+dfx deploy wallet --upgrade-unchanged
+
+BALANCE_AFTER=$(dfx canister call wallet get_balance "(principal \"$ME\")")
+HISTORY_AFTER=$(dfx canister call wallet get_transactions "(principal \"$ME\", record { limit = opt 100 })")
+ADDRESSES_AFTER=$(dfx canister call wallet get_my_addresses)
+MINTERS_AFTER=$(dfx canister call wallet get_minters)
+SUPPLY_AFTER=$(dfx canister call wallet get_total_supply)
+
+fail() {
+    echo "❌ $1"
+    exit 1
+}
+
+[ "$BALANCE_BEFORE" == "$BALANCE_AFTER" ] || fail "Balance changed across upgrade"
+[ "$HISTORY_BEFORE" == "$HISTORY_AFTER" ] || fail "Transaction history changed across upgrade"
+[ "$ADDRESSES_BEFORE" == "$ADDRESSES_AFTER" ] || fail "Deposit addresses changed across upgrade"
+[ "$MINTERS_BEFORE" == "$MINTERS_AFTER" ] || fail "Minter allow-list changed across upgrade"
+[ "$SUPPLY_BEFORE" == "$SUPPLY_AFTER" ] || fail "Total supply changed across upgrade"
+
+# Credited sources must still be de-duplicated
+dfx canister call wallet update_balance "(principal \"$ME\", 12_345 : nat64, variant { BTC }, $SOURCE)" \
+    | grep -q DuplicateCredit || fail "Deposit credited twice after upgrade"
+
+# The counter must continue, not restart and reuse transaction ids
+dfx canister call wallet update_balance "(principal \"$ME\", 1 : nat64, variant { BTC }, variant { Deposit = record { txid = \"upgrade-test-$(date +%s)\"; vout = 1 : nat32 } })" > /dev/null
+TX_IDS=$(dfx canister call wallet get_transactions "(principal \"$ME\", record { limit = opt 100 })" | grep -o 'TX-[0-9]*')
+[ "$(echo "$TX_IDS" | sort | uniq -d)" == "" ] || fail "Transaction ids reused after upgrade"
+
+echo "✅ Wallet state survived the upgrade"
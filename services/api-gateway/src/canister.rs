@@ -1,6 +1,129 @@
 // Canister client module
 // This will contain IC Agent integration for calling canisters
 
+use candid::{CandidType, Deserialize, Principal};
+use common::{Asset, WalletError};
+use ic_agent::hash_tree::{HashTree, LookupResult};
+use ic_agent::identity::{BasicIdentity, Secp256k1Identity};
+use ic_agent::{Certificate, Identity};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+// Certified answers whose certificate is older than this are rejected; a
+// replica could otherwise serve an outdated but validly signed state
+const MAX_CERTIFICATE_AGE: Duration = Duration::from_secs(5 * 60);
+
+// Mirrors the escrow canister's `CertifiedEscrow`
+#[derive(CandidType, Deserialize)]
+struct CertifiedEscrow {
+    escrow_id: String,
+    escrow_candid: Option<Vec<u8>>,
+    certificate: Option<Vec<u8>>,
+    witness: Vec<u8>,
+}
+
+// Mirrors the escrow canister's `EscrowStatus`
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug)]
+pub enum EscrowStatus {
+    Created,
+    Funded,
+    Shipped,
+    Delivered,
+    Released,
+    Refunded,
+    Disputed,
+}
+
+// The fields of the escrow canister's `EscrowRecord` the gateway returns
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct EscrowSummary {
+    pub escrow_id: String,
+    pub creator_id: Principal,
+    pub counterparty_id: Principal,
+    pub amount_satoshis: u64,
+    pub currency: Asset,
+    pub deposit_address: String,
+    pub status: EscrowStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// Mirrors the wallet canister's `VasServiceType`
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum VasServiceType {
+    Airtime,
+    Data,
+    BillPayment,
+}
+
+// Mirrors the wallet canister's `VasPurchaseParams`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VasPurchaseParams {
+    pub purchase_id: String,
+    pub user_id: Principal,
+    pub service_type: VasServiceType,
+    pub provider: String,
+    pub recipient: String,
+    pub amount_usd_cents: u64,
+    pub amount: u64,
+    pub currency: Asset,
+}
+
+// Only success or failure of a VAS call matters here, so the returned
+// purchase record is not decoded
+type VasPurchaseResult = Result<candid::Reserved, WalletError>;
+
+// Mirrors the wallet canister's `VasPurchaseStatus`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum VasPurchaseStatus {
+    Pending,
+    Completed { provider_reference: String },
+    Failed { reason: String },
+}
+
+// The fields of the wallet canister's `VasPurchase` the gateway needs
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VasPurchaseSummary {
+    pub status: VasPurchaseStatus,
+}
+
+// Mirrors the wallet canister's `StatementEntry`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StatementEntry {
+    pub block_index: u64,
+    pub timestamp: u64,
+    pub kind: String,
+    pub reference: Option<String>,
+    pub counterparty: Option<Principal>,
+    pub memo: Option<String>,
+    pub credit: u64,
+    pub debit: u64,
+    pub balance: u64,
+}
+
+// Mirrors the wallet canister's `AssetStatement`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AssetStatement {
+    pub asset: Asset,
+    pub opening_balance: u64,
+    pub total_credits: u64,
+    pub total_debits: u64,
+    pub closing_balance: u64,
+    pub entries: Vec<StatementEntry>,
+}
+
+// Mirrors the wallet canister's `Statement`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Statement {
+    pub user_id: Principal,
+    pub from: u64,
+    pub to: u64,
+    pub generated_at: u64,
+    pub assets: Vec<AssetStatement>,
+}
+
 pub struct CanisterClient {
     pub agent: ic_agent::Agent,
 }
@@ -19,5 +142,266 @@ impl CanisterClient {
         Ok(Self { agent })
     }
 
-    // Add methods to call specific canisters here
+    /// Build a client that signs its calls with the identity in a dfx PEM
+    /// file (secp256k1 or Ed25519).
+    pub async fn with_identity_pem(
+        ic_host: &str,
+        pem_path: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let identity: Box<dyn Identity> = match Secp256k1Identity::from_pem_file(pem_path) {
+            Ok(identity) => Box::new(identity),
+            Err(_) => Box::new(BasicIdentity::from_pem_file(pem_path)?),
+        };
+
+        let mut client = Self::new(ic_host).await?;
+        client.agent.set_arc_identity(identity.into());
+        Ok(client)
+    }
+
+    /// Query `get_certified_escrow` and verify the answer against the subnet
+    /// certificate. Returns the candid-encoded `EscrowRecord`, or `None` when
+    /// the certificate proves the escrow does not exist.
+    pub async fn get_verified_escrow(
+        &self,
+        escrow_canister: Principal,
+        escrow_id: &str,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let response = self
+            .agent
+            .query(&escrow_canister, "get_certified_escrow")
+            .with_arg(candid::encode_one(escrow_id)?)
+            .call()
+            .await?;
+        let certified: CertifiedEscrow = candid::decode_one(&response)?;
+
+        if certified.escrow_id != escrow_id {
+            return Err("certified response is for a different escrow".into());
+        }
+
+        let certificate: Certificate = serde_cbor::from_slice(
+            &certified.certificate.ok_or("response carries no certificate")?,
+        )?;
+        self.agent.verify(&certificate, escrow_canister)?;
+        check_certificate_time(ic_agent::lookup_value(&certificate, ["time".as_bytes()])?)?;
+
+        let certified_data = ic_agent::lookup_value(
+            &certificate,
+            [
+                "canister".as_bytes(),
+                escrow_canister.as_slice(),
+                "certified_data".as_bytes(),
+            ],
+        )?;
+
+        let witness: HashTree<Vec<u8>> = serde_cbor::from_slice(&certified.witness)?;
+        if witness.digest().as_slice() != certified_data {
+            return Err("witness does not match certified data".into());
+        }
+
+        match witness.lookup_path([b"escrows".as_slice(), escrow_id.as_bytes()]) {
+            LookupResult::Found(hash) => {
+                let record = certified
+                    .escrow_candid
+                    .ok_or("certified escrow missing from response")?;
+                if Sha256::digest(&record)[..] != hash[..] {
+                    return Err("escrow record does not match certified hash".into());
+                }
+                Ok(Some(record))
+            }
+            LookupResult::Absent if certified.escrow_candid.is_none() => Ok(None),
+            _ => Err("witness does not prove the escrow's presence or absence".into()),
+        }
+    }
+}
+
+// The certificate's `time` is nanoseconds since the epoch, LEB128-encoded
+fn check_certificate_time(time: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut nanos: u64 = 0;
+    for (i, byte) in time.iter().enumerate() {
+        if i >= 10 {
+            return Err("certificate time is malformed".into());
+        }
+        nanos |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let certified_at = UNIX_EPOCH + Duration::from_nanos(nanos);
+    let age = SystemTime::now()
+        .duration_since(certified_at)
+        .unwrap_or(Duration::ZERO);
+    if age > MAX_CERTIFICATE_AGE {
+        return Err(format!("certificate is stale ({}s old)", age.as_secs()).into());
+    }
+    Ok(())
+}
+
+// The escrow canister, read through certified queries
+pub struct EscrowCanister {
+    client: CanisterClient,
+    canister_id: Principal,
+}
+
+impl EscrowCanister {
+    /// Built from ESCROW_CANISTER_ID; None when it is missing or invalid
+    pub async fn from_env(ic_host: &str) -> Option<Self> {
+        let Ok(canister_id) = std::env::var("ESCROW_CANISTER_ID") else {
+            warn!("ESCROW_CANISTER_ID not set; escrow reads disabled");
+            return None;
+        };
+
+        let canister_id = match Principal::from_text(&canister_id) {
+            Ok(canister_id) => canister_id,
+            Err(e) => {
+                error!("Invalid ESCROW_CANISTER_ID: {}", e);
+                return None;
+            }
+        };
+        match CanisterClient::new(ic_host).await {
+            Ok(client) => Some(Self { client, canister_id }),
+            Err(e) => {
+                error!("Failed to create escrow canister client: {}", e);
+                None
+            }
+        }
+    }
+
+    /// The escrow as certified by the subnet, or None if it provably does
+    /// not exist
+    pub async fn get_escrow(&self, escrow_id: &str) -> Result<Option<EscrowSummary>, Box<dyn std::error::Error>> {
+        match self.client.get_verified_escrow(self.canister_id, escrow_id).await? {
+            Some(record) => Ok(Some(candid::decode_one(&record)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+// A wallet client signing as the identity in the PEM file named by
+// `pem_var`; None when WALLET_CANISTER_ID or that variable is missing or
+// invalid
+async fn wallet_client(ic_host: &str, pem_var: &str) -> Option<(CanisterClient, Principal)> {
+    let (Ok(canister_id), Ok(pem_path)) = (std::env::var("WALLET_CANISTER_ID"), std::env::var(pem_var)) else {
+        warn!("WALLET_CANISTER_ID or {} not set; wallet calls with it disabled", pem_var);
+        return None;
+    };
+
+    let canister_id = match Principal::from_text(&canister_id) {
+        Ok(canister_id) => canister_id,
+        Err(e) => {
+            error!("Invalid WALLET_CANISTER_ID: {}", e);
+            return None;
+        }
+    };
+    match CanisterClient::with_identity_pem(ic_host, &pem_path).await {
+        Ok(client) => {
+            info!("Calling the wallet as {:?} ({})", client.agent.get_principal(), pem_var);
+            Some((client, canister_id))
+        }
+        Err(e) => {
+            error!("Failed to load the identity in {}: {}", pem_var, e);
+            None
+        }
+    }
+}
+
+// The wallet canister, called as its configured `vas_operator` to place and
+// settle VAS holds
+pub struct WalletCanister {
+    client: CanisterClient,
+    canister_id: Principal,
+}
+
+impl WalletCanister {
+    /// Built from WALLET_CANISTER_ID and VAS_OPERATOR_PEM; None when either
+    /// is missing or invalid
+    pub async fn from_env(ic_host: &str) -> Option<Self> {
+        let (client, canister_id) = wallet_client(ic_host, "VAS_OPERATOR_PEM").await?;
+        Some(Self { client, canister_id })
+    }
+
+    async fn vas_call(&self, method: &str, arg: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+        let response = self
+            .client
+            .agent
+            .update(&self.canister_id, method)
+            .with_arg(arg)
+            .call_and_wait()
+            .await?;
+        let result: VasPurchaseResult = candid::decode_one(&response)?;
+        result.map(|_| ()).map_err(|err| format!("{:?}", err).into())
+    }
+
+    /// Hold the purchase amount in the user's wallet balance
+    pub async fn hold_vas_purchase(&self, params: VasPurchaseParams) -> Result<(), Box<dyn std::error::Error>> {
+        self.vas_call("hold_vas_purchase", candid::encode_one(params)?).await
+    }
+
+    /// Release the held amount to the VAS operator
+    pub async fn complete_vas_purchase(
+        &self,
+        purchase_id: &str,
+        provider_reference: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let arg = candid::encode_args((purchase_id, provider_reference))?;
+        self.vas_call("complete_vas_purchase", arg).await
+    }
+
+    /// Return the held amount to the user
+    pub async fn fail_vas_purchase(&self, purchase_id: &str, reason: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let arg = candid::encode_args((purchase_id, reason))?;
+        self.vas_call("fail_vas_purchase", arg).await
+    }
+
+    /// The wallet's record of a purchase, None if no hold was ever placed.
+    /// Made as an update call so the answer goes through consensus.
+    pub async fn get_vas_purchase(
+        &self,
+        purchase_id: &str,
+    ) -> Result<Option<VasPurchaseSummary>, Box<dyn std::error::Error>> {
+        let response = self
+            .client
+            .agent
+            .update(&self.canister_id, "get_vas_purchase")
+            .with_arg(candid::encode_one(purchase_id)?)
+            .call_and_wait()
+            .await?;
+        Ok(candid::decode_one(&response)?)
+    }
+
+}
+
+// The wallet canister, called as a read-only auditor to build users'
+// statements. A separate identity from the VAS operator, so a leaked
+// statements key cannot move funds.
+pub struct WalletAuditor {
+    client: CanisterClient,
+    canister_id: Principal,
+}
+
+impl WalletAuditor {
+    /// Built from WALLET_CANISTER_ID and STATEMENTS_IDENTITY_PEM; None when
+    /// either is missing or invalid
+    pub async fn from_env(ic_host: &str) -> Option<Self> {
+        let (client, canister_id) = wallet_client(ic_host, "STATEMENTS_IDENTITY_PEM").await?;
+        Some(Self { client, canister_id })
+    }
+
+    /// A user's statement for an inclusive range of nanosecond timestamps
+    pub async fn get_statement(
+        &self,
+        user_id: Principal,
+        from: u64,
+        to: u64,
+    ) -> Result<Statement, Box<dyn std::error::Error>> {
+        let response = self
+            .client
+            .agent
+            .query(&self.canister_id, "get_statement")
+            .with_arg(candid::encode_args((user_id, from, to))?)
+            .call()
+            .await?;
+        let result: Result<Statement, WalletError> = candid::decode_one(&response)?;
+        result.map_err(|err| format!("{:?}", err).into())
+    }
 }
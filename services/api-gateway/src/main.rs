@@ -1,6 +1,7 @@
 use axum::{
     extract::State,
     http::StatusCode,
+    middleware,
     response::IntoResponse,
     routing::{get, post},
     Json, Router,
@@ -13,6 +14,8 @@ use sqlx::postgres::PgPoolOptions;
 mod canister;
 mod models;
 mod auth;
+mod statements;
+mod vas;
 
 use models::*;
 
@@ -20,6 +23,10 @@ use models::*;
 struct AppState {
     ic_host: String,
     db_pool: sqlx::PgPool,
+    vas: Arc<vas::VasService>,
+    wallet: Option<Arc<canister::WalletCanister>>,
+    wallet_auditor: Option<Arc<canister::WalletAuditor>>,
+    escrow: Option<Arc<canister::EscrowCanister>>,
 }
 
 #[tokio::main]
@@ -46,7 +53,20 @@ async fn main() {
     
     info!("Database connected successfully");
 
-    let state = Arc::new(AppState { ic_host, db_pool });
+    let wallet = canister::WalletCanister::from_env(&ic_host).await.map(Arc::new);
+    let wallet_auditor = canister::WalletAuditor::from_env(&ic_host).await.map(Arc::new);
+    let escrow = canister::EscrowCanister::from_env(&ic_host).await.map(Arc::new);
+    let vas = Arc::new(vas::VasService::from_env());
+    let state = Arc::new(AppState { ic_host, db_pool, vas, wallet, wallet_auditor, escrow });
+    tokio::spawn(vas::reconcile(state.clone()));
+
+    // Routes that need a signed-in user
+    let user_routes = Router::new()
+        .route("/api/vas/quote", post(vas::quote))
+        .route("/api/vas/purchase", post(vas::purchase))
+        .route("/api/vas/purchases", get(vas::list_purchases))
+        .route("/api/wallet/statement", get(statements::get_statement))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::auth_middleware));
 
     // Build router
     let app = Router::new()
@@ -66,6 +86,9 @@ async fn main() {
         .route("/api/wallet/transactions", get(get_transactions))
         // Reputation routes
         .route("/api/reputation/:id", get(get_reputation))
+        // VAS routes
+        .route("/api/vas/providers", get(vas::list_providers))
+        .merge(user_routes)
         // Profile routes
         .route("/api/profile", get(get_profile))
         .route("/api/profile", post(update_profile))
@@ -105,17 +128,31 @@ async fn create_escrow(
     }))
 }
 
+// Read through a certified query, so a single faulty replica cannot forge
+// or roll back the answer
 async fn get_escrow(
-    State(_state): State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
     axum::extract::Path(id): axum::extract::Path<String>,
-) -> Result<Json<serde_json::Value>, AppError> {
+) -> Result<axum::response::Response, AppError> {
     info!("Getting escrow: {}", id);
     
-    // TODO: Call escrow canister
-    Ok(Json(serde_json::json!({
-        "escrow_id": id,
-        "status": "FUNDED"
-    })))
+    let escrow_canister = state
+        .escrow
+        .as_ref()
+        .ok_or_else(|| AppError("Escrow canister is not configured".to_string()))?;
+    let escrow = escrow_canister
+        .get_escrow(&id)
+        .await
+        .map_err(|e| AppError(format!("Escrow verification failed: {}", e)))?;
+    
+    Ok(match escrow {
+        Some(escrow) => Json(escrow).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": "Escrow not found" })),
+        )
+            .into_response(),
+    })
 }
 
 async fn confirm_delivery(
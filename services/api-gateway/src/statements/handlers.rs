@@ -0,0 +1,218 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use candid::Principal;
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use super::models::*;
+use crate::canister::Statement;
+use crate::AppState;
+
+const MAX_RANGE_DAYS: i64 = 366;
+
+// Download the caller's wallet statement as JSON or CSV
+pub async fn get_statement(
+    State(state): State<Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<StatementQuery>,
+) -> Result<Response, StatementError> {
+    if query.from > query.to {
+        return Err(StatementError::ValidationError("from must not be after to".to_string()));
+    }
+    if (query.to - query.from).num_days() >= MAX_RANGE_DAYS {
+        return Err(StatementError::ValidationError(format!(
+            "A statement covers at most {} days",
+            MAX_RANGE_DAYS
+        )));
+    }
+
+    let wallet = state.wallet_auditor.as_ref().ok_or(StatementError::NotConfigured)?;
+    let principal_id: Option<String> = sqlx::query_scalar(
+        "SELECT principal_id FROM users WHERE id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(&state.db_pool)
+    .await
+    .map_err(|e| StatementError::DatabaseError(e.to_string()))?
+    .flatten();
+    let principal = principal_id
+        .and_then(|p| Principal::from_text(p).ok())
+        .ok_or(StatementError::NoWallet)?;
+
+    // Midnight at the start of `from` to the last nanosecond of `to`
+    let start = query.from.and_time(NaiveTime::MIN).and_utc();
+    let end = (query.to + Duration::days(1)).and_time(NaiveTime::MIN).and_utc();
+    let statement = wallet
+        .get_statement(principal, to_nanos(start)?, to_nanos(end)? - 1)
+        .await
+        .map_err(|e| StatementError::WalletError(e.to_string()))?;
+
+    let response = to_response(statement, &query);
+    let filename = format!("statement-{}-{}", query.from, query.to);
+    Ok(match query.format {
+        StatementFormat::Json => (
+            [(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.json\"", filename))],
+            Json(response),
+        )
+            .into_response(),
+        StatementFormat::Csv => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.csv\"", filename)),
+            ],
+            to_csv(&response),
+        )
+            .into_response(),
+    })
+}
+
+fn to_nanos(time: DateTime<Utc>) -> Result<u64, StatementError> {
+    time.timestamp_nanos_opt()
+        .and_then(|nanos| u64::try_from(nanos).ok())
+        .ok_or_else(|| StatementError::ValidationError("Date out of range".to_string()))
+}
+
+fn from_nanos(nanos: u64) -> DateTime<Utc> {
+    DateTime::from_timestamp_nanos(nanos as i64)
+}
+
+fn to_response(statement: Statement, query: &StatementQuery) -> StatementResponse {
+    StatementResponse {
+        principal_id: statement.user_id.to_text(),
+        from: query.from,
+        to: query.to,
+        generated_at: from_nanos(statement.generated_at),
+        assets: statement
+            .assets
+            .into_iter()
+            .map(|asset| AssetStatementResponse {
+                asset: asset.asset.symbol().to_string(),
+                decimals: asset.asset.decimals(),
+                opening_balance: asset.opening_balance,
+                total_credits: asset.total_credits,
+                total_debits: asset.total_debits,
+                closing_balance: asset.closing_balance,
+                entries: asset
+                    .entries
+                    .into_iter()
+                    .map(|entry| StatementEntryResponse {
+                        timestamp: from_nanos(entry.timestamp),
+                        block_index: entry.block_index,
+                        kind: entry.kind,
+                        reference: entry.reference,
+                        counterparty: entry.counterparty.map(|p| p.to_text()),
+                        memo: entry.memo,
+                        credit: entry.credit,
+                        debit: entry.debit,
+                        balance: entry.balance,
+                    })
+                    .collect(),
+            })
+            .collect(),
+    }
+}
+
+// Amount in whole units, e.g. 12345 sats -> "0.00012345"
+fn format_amount(amount: u64, decimals: u8) -> String {
+    let scale = 10u64.pow(decimals as u32);
+    format!(
+        "{}.{:0width$}",
+        amount / scale,
+        amount % scale,
+        width = decimals as usize
+    )
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// One section per asset: an opening balance row, the entries, then a
+// closing balance row carrying the period's totals
+fn to_csv(statement: &StatementResponse) -> String {
+    let mut csv = String::from("asset,timestamp,kind,reference,counterparty,memo,credit,debit,balance\n");
+    let start = statement.from.and_time(NaiveTime::MIN).and_utc();
+    // The end of the period, or now for a period that has not ended yet
+    let end = (statement.to + Duration::days(1))
+        .and_time(NaiveTime::MIN)
+        .and_utc()
+        .min(statement.generated_at);
+    for asset in &statement.assets {
+        let amount = |value: u64| format_amount(value, asset.decimals);
+        let mut row = |fields: [String; 9]| {
+            let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        };
+
+        row([
+            asset.asset.clone(),
+            start.to_rfc3339(),
+            "opening_balance".to_string(),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            amount(asset.opening_balance),
+        ]);
+        for entry in &asset.entries {
+            row([
+                asset.asset.clone(),
+                entry.timestamp.to_rfc3339(),
+                entry.kind.clone(),
+                entry.reference.clone().unwrap_or_default(),
+                entry.counterparty.clone().unwrap_or_default(),
+                entry.memo.clone().unwrap_or_default(),
+                amount(entry.credit),
+                amount(entry.debit),
+                amount(entry.balance),
+            ]);
+        }
+        row([
+            asset.asset.clone(),
+            end.to_rfc3339(),
+            "closing_balance".to_string(),
+            String::new(),
+            String::new(),
+            String::new(),
+            amount(asset.total_credits),
+            amount(asset.total_debits),
+            amount(asset.closing_balance),
+        ]);
+    }
+    csv
+}
+
+// Error handling
+#[derive(Debug)]
+pub enum StatementError {
+    ValidationError(String),
+    DatabaseError(String),
+    WalletError(String),
+    NoWallet,
+    NotConfigured,
+}
+
+impl IntoResponse for StatementError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            StatementError::ValidationError(msg) => (StatusCode::BAD_REQUEST, msg),
+            StatementError::DatabaseError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", msg)),
+            StatementError::WalletError(msg) => (StatusCode::BAD_GATEWAY, format!("Wallet error: {}", msg)),
+            StatementError::NoWallet => (StatusCode::BAD_REQUEST, "No wallet principal linked to this account".to_string()),
+            StatementError::NotConfigured => (StatusCode::SERVICE_UNAVAILABLE, "Wallet calls are not configured".to_string()),
+        };
+
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
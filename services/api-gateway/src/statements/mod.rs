@@ -0,0 +1,4 @@
+pub mod models;
+pub mod handlers;
+
+pub use handlers::*;
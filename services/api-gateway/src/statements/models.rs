@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, NaiveDate, Utc};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatementFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+// Statement requests; both dates are inclusive (UTC)
+#[derive(Debug, Deserialize)]
+pub struct StatementQuery {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    #[serde(default)]
+    pub format: StatementFormat,
+}
+
+// Statement responses. Amounts are in the asset's smallest unit; `decimals`
+// gives the scale.
+#[derive(Debug, Serialize)]
+pub struct StatementResponse {
+    pub principal_id: String,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub generated_at: DateTime<Utc>,
+    pub assets: Vec<AssetStatementResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AssetStatementResponse {
+    pub asset: String,
+    pub decimals: u8,
+    pub opening_balance: u64,
+    pub total_credits: u64,
+    pub total_debits: u64,
+    pub closing_balance: u64,
+    pub entries: Vec<StatementEntryResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatementEntryResponse {
+    pub timestamp: DateTime<Utc>,
+    pub block_index: u64,
+    pub kind: String,
+    pub reference: Option<String>,
+    pub counterparty: Option<String>,
+    pub memo: Option<String>,
+    pub credit: u64,
+    pub debit: u64,
+    pub balance: u64,
+}
@@ -0,0 +1,481 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use candid::Principal;
+use chrono::{Duration, Utc};
+use common::Asset;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::{error, warn};
+use uuid::Uuid;
+use validator::Validate;
+
+use super::models::*;
+use super::provider::*;
+use crate::canister::{VasPurchaseParams, VasPurchaseStatus, WalletCanister};
+use crate::AppState;
+
+const QUOTE_EXPIRY_MINUTES: i64 = 5;
+// Fixed rate used until a price feed is wired in ($60,000)
+const DEFAULT_BTC_USD_PRICE_CENTS: u64 = 6_000_000;
+const SATS_PER_BTC: u128 = 100_000_000;
+// The reconciliation job runs every RECONCILE_INTERVAL, leaves a purchase to
+// the request that created it for RECONCILE_AFTER_MINUTES and retries one at
+// most every RECONCILE_RETRY_MINUTES
+const RECONCILE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+const RECONCILE_AFTER_MINUTES: i32 = 2;
+const RECONCILE_RETRY_MINUTES: i32 = 5;
+const RECONCILE_BATCH: i64 = 50;
+
+pub struct VasService {
+    providers: Vec<Arc<dyn VasProvider>>,
+    quotes: Mutex<HashMap<Uuid, Quote>>,
+    btc_usd_price_cents: u64,
+}
+
+impl VasService {
+    pub fn from_env() -> Self {
+        let btc_usd_price_cents = std::env::var("BTC_USD_PRICE_CENTS")
+            .ok()
+            .and_then(|price| price.parse().ok())
+            .filter(|price| *price > 0)
+            .unwrap_or(DEFAULT_BTC_USD_PRICE_CENTS);
+
+        Self {
+            providers: vec![Arc::new(MockProvider)],
+            quotes: Mutex::new(HashMap::new()),
+            btc_usd_price_cents,
+        }
+    }
+
+    fn provider(&self, name: &str) -> Result<Arc<dyn VasProvider>, VasError> {
+        self.providers
+            .iter()
+            .find(|p| p.name() == name)
+            .cloned()
+            .ok_or_else(|| VasError::ValidationError(format!("Unknown provider: {}", name)))
+    }
+
+    fn to_satoshis(&self, usd_cents: u64) -> Result<u64, VasError> {
+        let price = self.btc_usd_price_cents as u128;
+        let sats = (usd_cents as u128 * SATS_PER_BTC).div_ceil(price);
+        u64::try_from(sats).map_err(|_| VasError::ValidationError("Amount too large".to_string()))
+    }
+
+    // A quote can be used once, by the user it was issued to, before it expires
+    fn take_quote(&self, user_id: Uuid, quote_id: Uuid) -> Result<Quote, VasError> {
+        let mut quotes = self.quotes.lock().unwrap();
+        match quotes.get(&quote_id) {
+            Some(quote) if quote.user_id == user_id => {}
+            _ => return Err(VasError::QuoteNotFound),
+        }
+        let quote = quotes.remove(&quote_id).unwrap();
+        if quote.expires_at < Utc::now() {
+            return Err(VasError::QuoteExpired);
+        }
+        Ok(quote)
+    }
+}
+
+// List providers and the services they sell
+pub async fn list_providers(
+    State(state): State<Arc<AppState>>,
+) -> Json<Vec<ProviderInfo>> {
+    let service_types = [ServiceType::Airtime, ServiceType::Data, ServiceType::BillPayment];
+    Json(
+        state
+            .vas
+            .providers
+            .iter()
+            .map(|p| ProviderInfo {
+                name: p.name().to_string(),
+                service_types: service_types.into_iter().filter(|t| p.supports(*t)).collect(),
+            })
+            .collect(),
+    )
+}
+
+// Price a purchase in sats
+pub async fn quote(
+    State(state): State<Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<QuoteRequest>,
+) -> Result<Json<Quote>, VasError> {
+    payload.validate().map_err(|e| VasError::ValidationError(e.to_string()))?;
+
+    let vas = &state.vas;
+    let provider = vas.provider(&payload.provider)?;
+    if !provider.supports(payload.service_type) {
+        return Err(VasError::ValidationError(format!(
+            "{} does not sell {}",
+            payload.provider,
+            payload.service_type.as_str()
+        )));
+    }
+
+    let order = ProviderOrder {
+        reference: Uuid::new_v4(),
+        service_type: payload.service_type,
+        recipient: payload.recipient.clone(),
+        amount_usd_cents: payload.amount_usd_cents,
+    };
+    let fee_usd_cents = provider.quote_fee(&order).await.map_err(VasError::Provider)?;
+    let total_usd_cents = payload
+        .amount_usd_cents
+        .checked_add(fee_usd_cents)
+        .ok_or_else(|| VasError::ValidationError("Amount too large".to_string()))?;
+
+    let quote = Quote {
+        quote_id: order.reference,
+        user_id,
+        service_type: payload.service_type,
+        provider: payload.provider,
+        recipient: payload.recipient,
+        amount_usd_cents: payload.amount_usd_cents,
+        fee_usd_cents,
+        amount_satoshis: vas.to_satoshis(total_usd_cents)?,
+        expires_at: Utc::now() + Duration::minutes(QUOTE_EXPIRY_MINUTES),
+    };
+
+    let mut quotes = vas.quotes.lock().unwrap();
+    let now = Utc::now();
+    quotes.retain(|_, q| q.expires_at >= now);
+    quotes.insert(quote.quote_id, quote.clone());
+
+    Ok(Json(quote))
+}
+
+// Hold the quoted sats in the wallet (the user must have approved the quote
+// there first), then buy from the provider and settle the hold (see
+// `deliver`). Whatever is left open is finished by `reconcile`.
+pub async fn purchase(
+    State(state): State<Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<PurchaseRequest>,
+) -> Result<Json<PurchaseResponse>, VasError> {
+    let vas = &state.vas;
+    let wallet = state.wallet.as_ref().ok_or(VasError::NotConfigured)?;
+    let quote = vas.take_quote(user_id, payload.quote_id)?;
+    let provider = vas.provider(&quote.provider)?;
+
+    let principal_id: Option<String> = sqlx::query_scalar(
+        "SELECT principal_id FROM users WHERE id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(&state.db_pool)
+    .await
+    .map_err(|e| VasError::DatabaseError(e.to_string()))?
+    .flatten();
+    let user_principal = principal_id
+        .and_then(|p| Principal::from_text(p).ok())
+        .ok_or(VasError::NoWallet)?;
+
+    // The user approved the quote ID in the wallet, so it names the purchase
+    let purchase_id = quote.quote_id;
+    sqlx::query(
+        "INSERT INTO vas_transactions
+         (id, user_id, service_type, provider, recipient, amount_usd, amount_satoshis, status, metadata)
+         VALUES ($1, $2, $3, $4, $5, ($6::bigint)::numeric / 100, $7, 'pending', $8)"
+    )
+    .bind(purchase_id)
+    .bind(user_id)
+    .bind(quote.service_type.as_str())
+    .bind(&quote.provider)
+    .bind(&quote.recipient)
+    .bind(quote.amount_usd_cents as i64)
+    .bind(quote.amount_satoshis as i64)
+    .bind(serde_json::json!({
+        "fee_usd_cents": quote.fee_usd_cents,
+    }))
+    .execute(&state.db_pool)
+    .await
+    .map_err(|e| VasError::DatabaseError(e.to_string()))?;
+
+    let params = VasPurchaseParams {
+        purchase_id: purchase_id.to_string(),
+        user_id: user_principal,
+        service_type: quote.service_type.into(),
+        provider: quote.provider.clone(),
+        recipient: quote.recipient.clone(),
+        amount_usd_cents: quote.amount_usd_cents,
+        amount: quote.amount_satoshis,
+        currency: Asset::CkBTC,
+    };
+    let held = wallet
+        .hold_vas_purchase(params)
+        .await
+        .map_err(|e| e.to_string());
+    if let Err(reason) = held {
+        // A failed call may still have placed the hold, so the wallet is
+        // left for the reconciliation job to check
+        let outcome = Outcome::failed(format!("Could not hold funds: {}", reason));
+        record(&state, purchase_id, &outcome, false).await?;
+        return Err(VasError::HoldFailed(reason));
+    }
+
+    let order = ProviderOrder {
+        reference: purchase_id,
+        service_type: quote.service_type,
+        recipient: quote.recipient.clone(),
+        amount_usd_cents: quote.amount_usd_cents,
+    };
+    let outcome = deliver(&state, wallet, provider.as_ref(), &order).await?;
+
+    Ok(Json(PurchaseResponse {
+        purchase_id,
+        status: outcome.status.to_string(),
+        provider_reference: outcome.provider_reference,
+        amount_satoshis: quote.amount_satoshis,
+        failure_reason: outcome.failure_reason,
+    }))
+}
+
+struct Outcome {
+    status: &'static str,
+    provider_reference: Option<String>,
+    failure_reason: Option<String>,
+}
+
+impl Outcome {
+    fn completed(provider_reference: String) -> Self {
+        Outcome {
+            status: "completed",
+            provider_reference: Some(provider_reference),
+            failure_reason: None,
+        }
+    }
+
+    fn failed(reason: String) -> Self {
+        Outcome {
+            status: "failed",
+            provider_reference: None,
+            failure_reason: Some(reason),
+        }
+    }
+}
+
+// Buy from the provider and settle the hold: released to the VAS operator
+// on success, returned on rejection. If the provider cannot be reached the
+// outcome is unknown, so the purchase stays pending with the funds held.
+// Safe to repeat: providers de-duplicate on the purchase ID and settling the
+// same way twice is a no-op in the wallet.
+async fn deliver(
+    state: &AppState,
+    wallet: &WalletCanister,
+    provider: &dyn VasProvider,
+    order: &ProviderOrder,
+) -> Result<Outcome, VasError> {
+    let outcome = match provider.purchase(order).await {
+        Ok(receipt) => Outcome::completed(receipt.provider_reference),
+        Err(ProviderError::Rejected(reason)) => Outcome::failed(reason),
+        Err(err @ ProviderError::Unavailable(_)) => {
+            warn!("VAS purchase {} left pending: {}", order.reference, err);
+            return Ok(Outcome {
+                status: "pending",
+                provider_reference: None,
+                failure_reason: Some(err.to_string()),
+            });
+        }
+    };
+    let settled = settle_wallet(wallet, order.reference, &outcome).await;
+    record(state, order.reference, &outcome, settled).await?;
+    Ok(outcome)
+}
+
+// Complete or fail the purchase in the wallet; false if the wallet could not
+// be told, which the reconciliation job retries
+async fn settle_wallet(wallet: &WalletCanister, purchase_id: Uuid, outcome: &Outcome) -> bool {
+    let id = purchase_id.to_string();
+    let settled = if outcome.status == "completed" {
+        let reference = outcome.provider_reference.as_deref().unwrap_or_default();
+        wallet.complete_vas_purchase(&id, reference).await
+    } else {
+        let reason = outcome.failure_reason.as_deref().unwrap_or_default();
+        wallet.fail_vas_purchase(&id, reason).await
+    };
+    match settled {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to settle VAS purchase {} in wallet: {}", id, e);
+            false
+        }
+    }
+}
+
+// Background job finishing what a request left open: purchases still pending
+// at the provider, and holds whose complete or fail call did not go through.
+// The wallet's record of each purchase decides what is left to do.
+pub async fn reconcile(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(RECONCILE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = reconcile_due(&state).await {
+            error!("VAS reconciliation failed: {:?}", e);
+        }
+    }
+}
+
+async fn reconcile_due(state: &AppState) -> Result<(), VasError> {
+    let Some(wallet) = state.wallet.as_ref() else {
+        return Ok(());
+    };
+    let purchases = sqlx::query_as::<_, UnsettledPurchase>(
+        "SELECT id, service_type, provider, recipient,
+                (amount_usd * 100)::bigint AS amount_usd_cents,
+                status, provider_reference, failure_reason
+         FROM vas_transactions
+         WHERE NOT wallet_settled
+           AND created_at < NOW() - make_interval(mins => $1)
+           AND (last_attempt_at IS NULL OR last_attempt_at < NOW() - make_interval(mins => $2))
+         ORDER BY created_at
+         LIMIT $3"
+    )
+    .bind(RECONCILE_AFTER_MINUTES)
+    .bind(RECONCILE_RETRY_MINUTES)
+    .bind(RECONCILE_BATCH)
+    .fetch_all(&state.db_pool)
+    .await
+    .map_err(|e| VasError::DatabaseError(e.to_string()))?;
+
+    for purchase in purchases {
+        let id = purchase.id;
+        sqlx::query("UPDATE vas_transactions SET last_attempt_at = NOW() WHERE id = $1")
+            .bind(id)
+            .execute(&state.db_pool)
+            .await
+            .map_err(|e| VasError::DatabaseError(e.to_string()))?;
+        if let Err(e) = reconcile_purchase(state, wallet, purchase).await {
+            warn!("Reconciling VAS purchase {} failed: {:?}", id, e);
+        }
+    }
+    Ok(())
+}
+
+async fn reconcile_purchase(
+    state: &AppState,
+    wallet: &WalletCanister,
+    purchase: UnsettledPurchase,
+) -> Result<(), VasError> {
+    let in_wallet = wallet
+        .get_vas_purchase(&purchase.id.to_string())
+        .await
+        .map_err(|e| VasError::Wallet(e.to_string()))?;
+
+    let recorded = match purchase.status.as_str() {
+        "completed" => Some(Outcome::completed(purchase.provider_reference.unwrap_or_default())),
+        "pending" => None,
+        _ => Some(Outcome::failed(purchase.failure_reason.unwrap_or_default())),
+    };
+    match (in_wallet.map(|p| p.status), recorded) {
+        // Nothing was ever held, so nothing can be owed either way
+        (None, None) => {
+            let outcome = Outcome::failed("Funds were never held".to_string());
+            record(state, purchase.id, &outcome, true).await
+        }
+        (None, Some(outcome)) => record(state, purchase.id, &outcome, true).await,
+        (Some(VasPurchaseStatus::Pending), None) => {
+            let service_type = ServiceType::parse(&purchase.service_type).ok_or_else(|| {
+                VasError::ValidationError(format!("Unknown service type: {}", purchase.service_type))
+            })?;
+            let provider = state.vas.provider(&purchase.provider)?;
+            let order = ProviderOrder {
+                reference: purchase.id,
+                service_type,
+                recipient: purchase.recipient,
+                amount_usd_cents: purchase.amount_usd_cents as u64,
+            };
+            deliver(state, wallet, provider.as_ref(), &order).await.map(|_| ())
+        }
+        (Some(VasPurchaseStatus::Pending), Some(outcome)) => {
+            if settle_wallet(wallet, purchase.id, &outcome).await {
+                record(state, purchase.id, &outcome, true).await?;
+            }
+            Ok(())
+        }
+        // Already settled in the wallet, which the database follows
+        (Some(VasPurchaseStatus::Completed { provider_reference }), _) => {
+            record(state, purchase.id, &Outcome::completed(provider_reference), true).await
+        }
+        (Some(VasPurchaseStatus::Failed { reason }), _) => {
+            record(state, purchase.id, &Outcome::failed(reason), true).await
+        }
+    }
+}
+
+// List the caller's VAS purchases, newest first
+pub async fn list_purchases(
+    State(state): State<Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<Vec<VasTransaction>>, VasError> {
+    let purchases = sqlx::query_as::<_, VasTransaction>(
+        "SELECT id, service_type, provider, recipient,
+                (amount_usd * 100)::bigint AS amount_usd_cents, amount_satoshis,
+                status, provider_reference, created_at, completed_at
+         FROM vas_transactions WHERE user_id = $1
+         ORDER BY created_at DESC LIMIT 100"
+    )
+    .bind(user_id)
+    .fetch_all(&state.db_pool)
+    .await
+    .map_err(|e| VasError::DatabaseError(e.to_string()))?;
+
+    Ok(Json(purchases))
+}
+
+async fn record(
+    state: &AppState,
+    purchase_id: Uuid,
+    outcome: &Outcome,
+    wallet_settled: bool,
+) -> Result<(), VasError> {
+    sqlx::query(
+        "UPDATE vas_transactions
+         SET status = $2, provider_reference = $3, failure_reason = $4,
+             wallet_settled = $5, completed_at = NOW()
+         WHERE id = $1"
+    )
+    .bind(purchase_id)
+    .bind(outcome.status)
+    .bind(&outcome.provider_reference)
+    .bind(&outcome.failure_reason)
+    .bind(wallet_settled)
+    .execute(&state.db_pool)
+    .await
+    .map_err(|e| VasError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+// Error handling
+#[derive(Debug)]
+pub enum VasError {
+    ValidationError(String),
+    DatabaseError(String),
+    Provider(ProviderError),
+    HoldFailed(String),
+    Wallet(String),
+    QuoteNotFound,
+    QuoteExpired,
+    NoWallet,
+    NotConfigured,
+}
+
+impl IntoResponse for VasError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            VasError::ValidationError(msg) => (StatusCode::BAD_REQUEST, msg),
+            VasError::DatabaseError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", msg)),
+            VasError::Provider(err) => (StatusCode::BAD_GATEWAY, err.to_string()),
+            VasError::HoldFailed(msg) => (StatusCode::PAYMENT_REQUIRED, format!("Could not hold funds: {}", msg)),
+            VasError::Wallet(msg) => (StatusCode::BAD_GATEWAY, format!("Wallet call failed: {}", msg)),
+            VasError::QuoteNotFound => (StatusCode::NOT_FOUND, "Quote not found".to_string()),
+            VasError::QuoteExpired => (StatusCode::GONE, "Quote expired".to_string()),
+            VasError::NoWallet => (StatusCode::BAD_REQUEST, "No wallet principal linked to this account".to_string()),
+            VasError::NotConfigured => (StatusCode::SERVICE_UNAVAILABLE, "VAS purchases are not configured".to_string()),
+        };
+
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
@@ -0,0 +1,5 @@
+pub mod models;
+pub mod provider;
+pub mod handlers;
+
+pub use handlers::*;
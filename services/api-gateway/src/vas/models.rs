@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+
+use crate::canister::VasServiceType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceType {
+    Airtime,
+    Data,
+    BillPayment,
+}
+
+impl ServiceType {
+    // Value stored in vas_transactions.service_type
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServiceType::Airtime => "airtime",
+            ServiceType::Data => "data",
+            ServiceType::BillPayment => "bill_payment",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "airtime" => Some(ServiceType::Airtime),
+            "data" => Some(ServiceType::Data),
+            "bill_payment" => Some(ServiceType::BillPayment),
+            _ => None,
+        }
+    }
+}
+
+impl From<ServiceType> for VasServiceType {
+    fn from(service_type: ServiceType) -> Self {
+        match service_type {
+            ServiceType::Airtime => VasServiceType::Airtime,
+            ServiceType::Data => VasServiceType::Data,
+            ServiceType::BillPayment => VasServiceType::BillPayment,
+        }
+    }
+}
+
+// VAS requests
+#[derive(Debug, Deserialize, Validate)]
+pub struct QuoteRequest {
+    pub service_type: ServiceType,
+    #[validate(length(min = 1, max = 64, message = "Provider must be 1 to 64 characters"))]
+    pub provider: String,
+    #[validate(length(min = 1, max = 64, message = "Recipient must be 1 to 64 characters"))]
+    pub recipient: String,
+    #[validate(range(min = 1, message = "Amount must be positive"))]
+    pub amount_usd_cents: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PurchaseRequest {
+    pub quote_id: Uuid,
+}
+
+// VAS responses
+#[derive(Debug, Serialize)]
+pub struct ProviderInfo {
+    pub name: String,
+    pub service_types: Vec<ServiceType>,
+}
+
+// Price of a purchase in sats, valid until `expires_at`. Before purchasing,
+// the user approves `quote_id` for `amount_satoshis` ckBTC in the wallet
+// (`approve_vas_purchase`); the quote ID becomes the purchase ID.
+#[derive(Debug, Clone, Serialize)]
+pub struct Quote {
+    pub quote_id: Uuid,
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub service_type: ServiceType,
+    pub provider: String,
+    pub recipient: String,
+    pub amount_usd_cents: u64,
+    pub fee_usd_cents: u64,
+    pub amount_satoshis: u64,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PurchaseResponse {
+    pub purchase_id: Uuid,
+    pub status: String,
+    pub provider_reference: Option<String>,
+    pub amount_satoshis: u64,
+    pub failure_reason: Option<String>,
+}
+
+// Database models
+// A purchase whose wallet hold may still need settling
+#[derive(Debug, sqlx::FromRow)]
+pub struct UnsettledPurchase {
+    pub id: Uuid,
+    pub service_type: String,
+    pub provider: String,
+    pub recipient: String,
+    pub amount_usd_cents: i64,
+    pub status: String,
+    pub provider_reference: Option<String>,
+    pub failure_reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct VasTransaction {
+    pub id: Uuid,
+    pub service_type: String,
+    pub provider: String,
+    pub recipient: String,
+    pub amount_usd_cents: i64,
+    pub amount_satoshis: i64,
+    pub status: String,
+    pub provider_reference: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
@@ -0,0 +1,88 @@
+use std::future::Future;
+use std::pin::Pin;
+use uuid::Uuid;
+
+use super::models::ServiceType;
+
+// An order as sent to a provider; `reference` is our purchase id, so a
+// provider that de-duplicates on it will not deliver twice
+#[derive(Debug, Clone)]
+pub struct ProviderOrder {
+    pub reference: Uuid,
+    pub service_type: ServiceType,
+    pub recipient: String,
+    pub amount_usd_cents: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProviderReceipt {
+    pub provider_reference: String,
+}
+
+#[derive(Debug)]
+pub enum ProviderError {
+    // The provider refused the order (unknown recipient, unsupported amount)
+    Rejected(String),
+    // The provider could not be reached or answered with an error
+    Unavailable(String),
+}
+
+impl std::fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProviderError::Rejected(msg) => write!(f, "Rejected by provider: {}", msg),
+            ProviderError::Unavailable(msg) => write!(f, "Provider unavailable: {}", msg),
+        }
+    }
+}
+
+pub type ProviderFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, ProviderError>> + Send + 'a>>;
+
+// Adapter for an airtime/data/bill payment aggregator (VTU, Reloadly, ...)
+pub trait VasProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn supports(&self, service_type: ServiceType) -> bool;
+
+    // Provider fee on top of the face value
+    fn quote_fee<'a>(&'a self, order: &'a ProviderOrder) -> ProviderFuture<'a, u64>;
+
+    fn purchase<'a>(&'a self, order: &'a ProviderOrder) -> ProviderFuture<'a, ProviderReceipt>;
+}
+
+// Local provider for development: charges 1% (at least 10 cents). Recipients
+// starting with "000" are rejected and those starting with "999" time out;
+// every other purchase succeeds.
+pub struct MockProvider;
+
+impl VasProvider for MockProvider {
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    fn supports(&self, _service_type: ServiceType) -> bool {
+        true
+    }
+
+    fn quote_fee<'a>(&'a self, order: &'a ProviderOrder) -> ProviderFuture<'a, u64> {
+        Box::pin(async move { Ok((order.amount_usd_cents / 100).max(10)) })
+    }
+
+    fn purchase<'a>(&'a self, order: &'a ProviderOrder) -> ProviderFuture<'a, ProviderReceipt> {
+        Box::pin(async move {
+            if order.recipient.starts_with("000") {
+                return Err(ProviderError::Rejected("Unknown recipient".to_string()));
+            }
+            if order.recipient.starts_with("999") {
+                return Err(ProviderError::Unavailable("Request timed out".to_string()));
+            }
+            Ok(ProviderReceipt {
+                provider_reference: format!(
+                    "MOCK-{}-{}",
+                    order.service_type.as_str().to_uppercase(),
+                    order.reference.simple()
+                ),
+            })
+        })
+    }
+}